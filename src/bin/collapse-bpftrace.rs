@@ -0,0 +1,132 @@
+use std::io;
+use std::path::PathBuf;
+
+use env_logger::Env;
+use inferno::collapse::bpftrace::{Folder, Options};
+use inferno::collapse::common::DEFAULT_NTHREADS;
+use inferno::collapse::Collapse;
+use inferno::folded::Recursion;
+use inferno::rules::Rules;
+use lazy_static::lazy_static;
+use structopt::StructOpt;
+
+lazy_static! {
+    static ref NTHREADS: String = format!("{}", *DEFAULT_NTHREADS);
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "inferno-collapse-bpftrace",
+    author = "",
+    after_help = "\
+[1] This processes the map dumps printed by bpftrace, for example:
+        bpftrace -e 'profile:hz:99 { @[ustack, kstack] = count(); }' > out.bpftrace
+    Scalar key elements (like comm) become root frames. Stacks are emitted user
+    stack first, then kernel stack."
+)]
+struct Opt {
+    // ************* //
+    // *** FLAGS *** //
+    // ************* //
+    /// Include raw addresses where symbols can't be found
+    #[structopt(long = "addrs")]
+    addrs: bool,
+
+    /// Demangle function names
+    #[structopt(long = "demangle")]
+    demangle: bool,
+
+    /// Annotate kernel functions with a _[k]. A lone stack is only known to be a kernel stack if
+    /// its frames have kernel addresses, so use --kstack for symbolized @[kstack] maps
+    #[structopt(long = "kernel")]
+    kernel: bool,
+
+    /// Take keys with a single stack whose frames don't say where it came from to be kernel
+    /// stacks, like those of @[kstack]
+    #[structopt(long = "kstack")]
+    kstack: bool,
+
+    /// Silence all log output
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Verbose logging mode (-v, -vv, -vvv)
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
+    verbose: usize,
+
+    // *************** //
+    // *** OPTIONS *** //
+    // *************** //
+    /// Merge the calls of functions that call themselves into one frame (a;a;a;b becomes a;b), or
+    /// with =cycles, also cycles of functions that call each other (a;b;a;b;c becomes a;b;c)
+    #[structopt(
+        long = "collapse-recursion",
+        value_name = "KIND",
+        raw(possible_values = r#"&["direct", "cycles"]"#),
+        raw(require_equals = "true")
+    )]
+    collapse_recursion: Option<Option<Recursion>>,
+
+    /// Number of threads to use
+    #[structopt(
+        short = "n",
+        long = "nthreads",
+        raw(default_value = "&NTHREADS"),
+        value_name = "UINT"
+    )]
+    nthreads: usize,
+
+    /// Filter and rewrite the stacks with the rules in this file, one per line, such as
+    /// "frame '^\[unknown\]$' drop-frame" (see the inferno::rules documentation)
+    #[structopt(
+        long = "rules",
+        value_name = "PATH",
+        parse(try_from_str = "Rules::from_file")
+    )]
+    rules: Option<Rules>,
+
+    // ************ //
+    // *** ARGS *** //
+    // ************ //
+    /// bpftrace output file, or STDIN if not specified
+    #[structopt(value_name = "PATH")]
+    infile: Option<PathBuf>,
+}
+
+impl Opt {
+    fn into_parts(self) -> (Option<PathBuf>, Options) {
+        (
+            self.infile,
+            Options {
+                annotate_kernel: self.kernel,
+                assume_kernel: self.kstack,
+                demangle: self.demangle,
+                include_addrs: self.addrs,
+                nthreads: self.nthreads,
+                collapse_recursion: self
+                    .collapse_recursion
+                    .map(|kind| kind.unwrap_or(Recursion::Direct)),
+                rules: self.rules.unwrap_or_default(),
+            },
+        )
+    }
+}
+
+fn main() -> io::Result<()> {
+    let opt = Opt::from_args();
+
+    // Initialize logger
+    if !opt.quiet {
+        env_logger::Builder::from_env(Env::default().default_filter_or(match opt.verbose {
+            0 => "warn",
+            1 => "info",
+            2 => "debug",
+            _ => "trace",
+        }))
+        .default_format_timestamp(false)
+        .init();
+    }
+
+    let (infile, options) = opt.into_parts();
+    Folder::from(options).collapse_file(infile.as_ref(), io::stdout().lock())
+}
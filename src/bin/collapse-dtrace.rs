@@ -2,8 +2,11 @@ use std::io;
 use std::path::PathBuf;
 
 use env_logger::Env;
+use inferno::collapse::common::{self, Split, DEFAULT_NTHREADS};
 use inferno::collapse::dtrace::{Folder, Options};
-use inferno::collapse::{Collapse, DEFAULT_NTHREADS};
+use inferno::collapse::Collapse;
+use inferno::folded::Recursion;
+use inferno::rules::Rules;
 use lazy_static::lazy_static;
 use structopt::StructOpt;
 
@@ -34,6 +37,11 @@ struct Opt {
     #[structopt(long = "includeoffset")]
     includeoffset: bool,
 
+    /// With --split, also draw a flame graph of each process or thread, into a file next to its
+    /// stacks with an .svg extension
+    #[structopt(long = "split-svg", requires = "split")]
+    split_svg: bool,
+
     /// Silence all log output
     #[structopt(short = "q", long = "quiet")]
     quiet: bool,
@@ -45,6 +53,16 @@ struct Opt {
     // *************** //
     // *** OPTIONS *** //
     // *************** //
+    /// Merge the calls of functions that call themselves into one frame (a;a;a;b becomes a;b), or
+    /// with =cycles, also cycles of functions that call each other (a;b;a;b;c becomes a;b;c)
+    #[structopt(
+        long = "collapse-recursion",
+        value_name = "KIND",
+        raw(possible_values = r#"&["direct", "cycles"]"#),
+        raw(require_equals = "true")
+    )]
+    collapse_recursion: Option<Option<Recursion>>,
+
     /// Number of threads to use.
     #[structopt(
         short = "n",
@@ -54,6 +72,31 @@ struct Opt {
     )]
     nthreads: usize,
 
+    /// Filter and rewrite the stacks with the rules in this file, one per line, such as
+    /// "frame '^\[unknown\]$' drop-frame" (see the inferno::rules documentation)
+    #[structopt(
+        long = "rules",
+        value_name = "PATH",
+        parse(try_from_str = "Rules::from_file")
+    )]
+    rules: Option<Rules>,
+
+    /// Write the stacks of each process or thread to a file of its own, named by --split-output,
+    /// instead of writing all stacks to STDOUT. The stacks have to be aggregated with the execname
+    /// and pid (and tid) of their process, like @[execname, pid, ustack()]
+    #[structopt(
+        long = "split",
+        value_name = "BY",
+        raw(possible_values = r#"&["process", "thread"]"#)
+    )]
+    split: Option<Split>,
+
+    /// Path of the files of --split, with {comm}, {pid}, and {tid} replaced by those of each
+    /// process or thread [default: {comm}-{pid}.folded, or {comm}-{pid}-{tid}.folded when
+    /// splitting by thread]
+    #[structopt(long = "split-output", value_name = "TEMPLATE")]
+    split_output: Option<String>,
+
     // ************ //
     // *** ARGS *** //
     // ************ //
@@ -70,6 +113,10 @@ impl Opt {
                 demangle: self.demangle,
                 includeoffset: self.includeoffset,
                 nthreads: self.nthreads,
+                collapse_recursion: self
+                    .collapse_recursion
+                    .map(|kind| kind.unwrap_or(Recursion::Direct)),
+                rules: self.rules.unwrap_or_default(),
             },
         )
     }
@@ -90,6 +137,19 @@ fn main() -> io::Result<()> {
         .init();
     }
 
+    let split = opt.split;
+    let split_output = opt.split_output.clone();
+    let split_svg = opt.split_svg;
     let (infile, options) = opt.into_parts();
-    Folder::from(options).collapse_file(infile.as_ref(), io::stdout().lock())
+    let mut folder = Folder::from(options);
+    match split {
+        Some(split) => common::write_partitions(
+            &mut folder,
+            infile,
+            split,
+            split_output.as_deref(),
+            split_svg,
+        ),
+        None => folder.collapse_file(infile.as_ref(), io::stdout().lock()),
+    }
 }
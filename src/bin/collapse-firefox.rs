@@ -0,0 +1,103 @@
+use std::io;
+use std::path::PathBuf;
+
+use env_logger::Env;
+use inferno::collapse::firefox::{Folder, Options};
+use inferno::collapse::Collapse;
+use inferno::folded::Recursion;
+use inferno::rules::Rules;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "inferno-collapse-firefox",
+    author = "",
+    after_help = "\
+[1] This processes profiles saved by the Firefox Profiler
+    (https://profiler.firefox.com) in its processed format. All threads in
+    the profile are collapsed together unless --thread is given."
+)]
+struct Opt {
+    // ************* //
+    // *** FLAGS *** //
+    // ************* //
+    /// Silence all log output
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Use the name of each thread as the root frame
+    #[structopt(long = "thread-names")]
+    thread_names: bool,
+
+    /// Verbose logging mode (-v, -vv, -vvv)
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
+    verbose: usize,
+
+    // *************** //
+    // *** OPTIONS *** //
+    // *************** //
+    /// Merge the calls of functions that call themselves into one frame (a;a;a;b becomes a;b), or
+    /// with =cycles, also cycles of functions that call each other (a;b;a;b;c becomes a;b;c)
+    #[structopt(
+        long = "collapse-recursion",
+        value_name = "KIND",
+        raw(possible_values = r#"&["direct", "cycles"]"#),
+        raw(require_equals = "true")
+    )]
+    collapse_recursion: Option<Option<Recursion>>,
+
+    /// Filter and rewrite the stacks with the rules in this file, one per line, such as
+    /// "frame '^\[unknown\]$' drop-frame" (see the inferno::rules documentation)
+    #[structopt(
+        long = "rules",
+        value_name = "PATH",
+        parse(try_from_str = "Rules::from_file")
+    )]
+    rules: Option<Rules>,
+
+    /// Only collapse the thread with this index
+    #[structopt(long = "thread", value_name = "UINT")]
+    thread: Option<usize>,
+
+    // ************ //
+    // *** ARGS *** //
+    // ************ //
+    /// Firefox profile file, or STDIN if not specified
+    #[structopt(value_name = "PATH")]
+    infile: Option<PathBuf>,
+}
+
+impl Opt {
+    fn into_parts(self) -> (Option<PathBuf>, Options) {
+        (
+            self.infile,
+            Options {
+                thread: self.thread,
+                include_thread_name: self.thread_names,
+                collapse_recursion: self
+                    .collapse_recursion
+                    .map(|kind| kind.unwrap_or(Recursion::Direct)),
+                rules: self.rules.unwrap_or_default(),
+            },
+        )
+    }
+}
+
+fn main() -> io::Result<()> {
+    let opt = Opt::from_args();
+
+    // Initialize logger
+    if !opt.quiet {
+        env_logger::Builder::from_env(Env::default().default_filter_or(match opt.verbose {
+            0 => "warn",
+            1 => "info",
+            2 => "debug",
+            _ => "trace",
+        }))
+        .default_format_timestamp(false)
+        .init();
+    }
+
+    let (infile, options) = opt.into_parts();
+    Folder::from(options).collapse_file(infile.as_ref(), io::stdout().lock())
+}
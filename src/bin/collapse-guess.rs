@@ -2,8 +2,11 @@ use std::io;
 use std::path::PathBuf;
 
 use env_logger::Env;
+use inferno::collapse::common::DEFAULT_NTHREADS;
 use inferno::collapse::guess::{Folder, Options};
-use inferno::collapse::{Collapse, DEFAULT_NTHREADS};
+use inferno::collapse::Collapse;
+use inferno::folded::Recursion;
+use inferno::rules::Rules;
 use lazy_static::lazy_static;
 use structopt::StructOpt;
 
@@ -34,6 +37,16 @@ struct Opt {
     // *************** //
     // *** OPTIONS *** //
     // *************** //
+    /// Merge the calls of functions that call themselves into one frame (a;a;a;b becomes a;b), or
+    /// with =cycles, also cycles of functions that call each other (a;b;a;b;c becomes a;b;c)
+    #[structopt(
+        long = "collapse-recursion",
+        value_name = "KIND",
+        raw(possible_values = r#"&["direct", "cycles"]"#),
+        raw(require_equals = "true")
+    )]
+    collapse_recursion: Option<Option<Recursion>>,
+
     /// Number of threads to use
     #[structopt(
         short = "n",
@@ -43,6 +56,15 @@ struct Opt {
     )]
     nthreads: usize,
 
+    /// Filter and rewrite the stacks with the rules in this file, one per line, such as
+    /// "frame '^\[unknown\]$' drop-frame" (see the inferno::rules documentation)
+    #[structopt(
+        long = "rules",
+        value_name = "PATH",
+        parse(try_from_str = "Rules::from_file")
+    )]
+    rules: Option<Rules>,
+
     // ************ //
     // *** ARGS *** //
     // ************ //
@@ -57,6 +79,10 @@ impl Opt {
             self.infile,
             Options {
                 nthreads: self.nthreads,
+                collapse_recursion: self
+                    .collapse_recursion
+                    .map(|kind| kind.unwrap_or(Recursion::Direct)),
+                rules: self.rules.unwrap_or_default(),
             },
         )
     }
@@ -1,10 +1,18 @@
-use std::io;
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use env_logger::Env;
-use inferno::collapse::perf::{Folder, Options};
-use inferno::collapse::{Collapse, DEFAULT_NTHREADS};
+use inferno::collapse::common::{self, Split, DEFAULT_NTHREADS};
+use inferno::collapse::perf::{Folder, OffCpu, Options, TidyStrategy, Time};
+#[cfg(feature = "symbolize")]
+use inferno::collapse::symbolize::Symbolizer;
+use inferno::collapse::Collapse;
+use inferno::folded::Recursion;
+use inferno::rules::Rules;
 use lazy_static::lazy_static;
+use regex::Regex;
 use structopt::StructOpt;
 
 lazy_static! {
@@ -34,10 +42,30 @@ struct Opt {
     #[structopt(long = "all")]
     all: bool,
 
+    /// Collapse the events of every type, rather than just those of one, and write the stacks of
+    /// each type of event to a file of its own in --events-dir, named after the event
+    #[structopt(
+        long = "all-events",
+        raw(
+            conflicts_with_all = r#"&["event_filter", "flame_chart", "off_cpu", "off_cpu_wakers", "split", "window"]"#
+        )
+    )]
+    all_events: bool,
+
     /// Demangle function names
     #[structopt(long = "demangle")]
     demangle: bool,
 
+    /// Keep the samples in the order they were taken, prefixed with their timestamps, for
+    /// plotting with inferno-flamegraph --flame-chart
+    #[structopt(long = "flame-chart")]
+    flame_chart: bool,
+
+    /// With --debug-dir, also add the functions that were inlined at each address
+    #[cfg(feature = "symbolize")]
+    #[structopt(long = "inline", requires = "debug_dir")]
+    inline: bool,
+
     /// Annotate jit functions with a _[j]
     #[structopt(long = "jit")]
     jit: bool,
@@ -46,10 +74,39 @@ struct Opt {
     #[structopt(long = "kernel")]
     kernel: bool,
 
+    /// Don't tidy up function names at all (see --tidy)
+    #[structopt(long = "no-tidy", conflicts_with = "tidy")]
+    no_tidy: bool,
+
+    /// Turn scheduler events (sched:sched_switch and friends) into the stacks that threads
+    /// blocked in, weighted by the microseconds they were blocked for
+    #[structopt(long = "off-cpu")]
+    off_cpu: bool,
+
+    /// Like --off-cpu, but with the stacks of the threads that woke them up on top, for
+    /// inferno-flamegraph --colors wakeup
+    #[structopt(long = "off-cpu-wakers")]
+    off_cpu_wakers: bool,
+
+    /// Weight stacks by the period of their events (the number before the event name) instead
+    /// of sample count
+    #[structopt(long = "period")]
+    period: bool,
+
     /// Include PID with process names
     #[structopt(long = "pid")]
     pid: bool,
 
+    /// With --split, also draw a flame graph of each process or thread, into a file next to its
+    /// stacks with an .svg extension
+    #[structopt(long = "split-svg", requires = "split")]
+    split_svg: bool,
+
+    /// With --debug-dir, tag each function with its file and line
+    #[cfg(feature = "symbolize")]
+    #[structopt(long = "srcline", requires = "debug_dir")]
+    srcline: bool,
+
     /// Include TID and PID with process names
     #[structopt(long = "tid")]
     tid: bool,
@@ -65,10 +122,52 @@ struct Opt {
     // *************** //
     // *** OPTIONS *** //
     // *************** //
+    /// Merge the calls of functions that call themselves into one frame (a;a;a;b becomes a;b), or
+    /// with =cycles, also cycles of functions that call each other (a;b;a;b;c becomes a;b;c)
+    #[structopt(
+        long = "collapse-recursion",
+        value_name = "KIND",
+        raw(possible_values = r#"&["direct", "cycles"]"#),
+        raw(require_equals = "true")
+    )]
+    collapse_recursion: Option<Option<Recursion>>,
+
+    /// Find the functions at the addresses that perf couldn't find symbols for in the debug
+    /// information of their modules, which is looked for in this directory of debug files or
+    /// perf build-id cache (like /usr/lib/debug or ~/.debug). Can be given more than once. Use
+    /// perf script -F +dsoff for the addresses of position-independent code
+    #[cfg(feature = "symbolize")]
+    #[structopt(long = "debug-dir", value_name = "PATH", number_of_values = 1)]
+    debug_dir: Vec<PathBuf>,
+
+    /// Only collapse the events before this time, given like --start
+    #[structopt(long = "end", value_name = "TIME")]
+    end: Option<Time>,
+
     /// Event filter [default: first encountered event]
     #[structopt(long = "event-filter", value_name = "STRING")]
     event_filter: Option<String>,
 
+    /// Directory to write the files of --all-events to
+    #[structopt(long = "events-dir", value_name = "PATH", default_value = ".")]
+    events_dir: PathBuf,
+
+    /// Skip the events of processes whose whole command name matches this regular expression
+    #[structopt(long = "exclude-comm", value_name = "REGEX")]
+    exclude_comm: Option<Regex>,
+
+    /// Skip the events on this CPU. Can be given more than once
+    #[structopt(long = "exclude-cpu", value_name = "CPU", number_of_values = 1)]
+    exclude_cpu: Vec<u32>,
+
+    /// Skip the events of the process with this PID [1]. Can be given more than once
+    #[structopt(long = "exclude-pid", value_name = "PID", number_of_values = 1)]
+    exclude_pid: Vec<u32>,
+
+    /// Skip the events of the thread with this TID. Can be given more than once
+    #[structopt(long = "exclude-tid", value_name = "TID", number_of_values = 1)]
+    exclude_tid: Vec<u32>,
+
     /// Number of threads to use
     #[structopt(
         short = "n",
@@ -78,6 +177,76 @@ struct Opt {
     )]
     nthreads: usize,
 
+    /// Only keep the events of processes whose whole command name matches this regular
+    /// expression, like "java|jsvc"
+    #[structopt(long = "only-comm", value_name = "REGEX")]
+    only_comm: Option<Regex>,
+
+    /// Only keep the events on this CPU. Can be given more than once
+    #[structopt(long = "only-cpu", value_name = "CPU", number_of_values = 1)]
+    only_cpu: Vec<u32>,
+
+    /// Only keep the events of the process with this PID [1]. Can be given more than once
+    #[structopt(long = "only-pid", value_name = "PID", number_of_values = 1)]
+    only_pid: Vec<u32>,
+
+    /// Only keep the events of the thread with this TID. Can be given more than once
+    #[structopt(long = "only-tid", value_name = "TID", number_of_values = 1)]
+    only_tid: Vec<u32>,
+
+    /// Filter and rewrite the stacks with the rules in this file, one per line, such as
+    /// "frame '^\[unknown\]$' drop-frame" (see the inferno::rules documentation)
+    #[structopt(
+        long = "rules",
+        value_name = "PATH",
+        parse(try_from_str = "Rules::from_file")
+    )]
+    rules: Option<Rules>,
+
+    /// Write the stacks of each process or thread to a file of its own, named by --split-output,
+    /// instead of writing all stacks to STDOUT. The root frames then include the PID (and TID) of
+    /// each process (or thread), as with --pid (or --tid)
+    #[structopt(
+        long = "split",
+        value_name = "BY",
+        raw(possible_values = r#"&["process", "thread"]"#),
+        conflicts_with = "window"
+    )]
+    split: Option<Split>,
+
+    /// Path of the files of --split, with {comm}, {pid}, and {tid} replaced by those of each
+    /// process or thread [default: {comm}-{pid}.folded, or {comm}-{pid}-{tid}.folded when
+    /// splitting by thread]
+    #[structopt(long = "split-output", value_name = "TEMPLATE")]
+    split_output: Option<String>,
+
+    /// Only collapse the events from this time on, given as a timestamp like 4794564.109216, or
+    /// as the seconds since the first event after a +, like +2.5
+    #[structopt(long = "start", value_name = "TIME")]
+    start: Option<Time>,
+
+    /// Tidy up function names with this strategy, optionally only for some processes or
+    /// modules, instead of the default "generic" and "java:process=java". Can be given more than
+    /// once, and the strategies are applied in order. Strategies are generic, java,
+    /// cpp-templates, rust-hash, and go-packages, and the scope is :process=REGEX or
+    /// :module=REGEX, such as "java:process=java|jsvc"
+    #[structopt(long = "tidy", value_name = "STRATEGY[:SCOPE]", number_of_values = 1)]
+    tidy: Vec<TidyStrategy>,
+
+    /// Split the input into windows of this many seconds, and write the stacks of each window to
+    /// a file of its own in --window-dir, named after the timestamps the window starts and ends at
+    #[structopt(
+        long = "window",
+        value_name = "SECONDS",
+        parse(try_from_str = "parse_seconds"),
+        conflicts_with = "flame_chart"
+    )]
+    window: Option<Duration>,
+
+    /// Directory to write the files of --window to
+    #[structopt(long = "window-dir", value_name = "PATH", default_value = ".")]
+    window_dir: PathBuf,
+
     // ************ //
     // *** ARGS *** //
     // ************ //
@@ -94,11 +263,54 @@ impl Opt {
                 include_pid: self.pid,
                 include_tid: self.tid,
                 include_addrs: self.addrs,
+                #[cfg(feature = "symbolize")]
+                symbolizer: if self.debug_dir.is_empty() {
+                    None
+                } else {
+                    let mut symbolizer = Symbolizer::new(self.debug_dir);
+                    if self.inline {
+                        symbolizer = symbolizer.with_inlines();
+                    }
+                    if self.srcline {
+                        symbolizer = symbolizer.with_lines();
+                    }
+                    Some(symbolizer)
+                },
                 annotate_jit: self.jit || self.all,
                 annotate_kernel: self.kernel || self.all,
                 demangle: self.demangle,
                 event_filter: self.event_filter,
+                flame_chart: self.flame_chart,
+                weight_by_period: self.period,
+                start: self.start,
+                end: self.end,
+                off_cpu: if self.off_cpu_wakers {
+                    Some(OffCpu::Wakers)
+                } else if self.off_cpu {
+                    Some(OffCpu::Blocked)
+                } else {
+                    None
+                },
                 nthreads: self.nthreads,
+                only_pids: self.only_pid,
+                exclude_pids: self.exclude_pid,
+                only_tids: self.only_tid,
+                exclude_tids: self.exclude_tid,
+                only_cpus: self.only_cpu,
+                exclude_cpus: self.exclude_cpu,
+                only_comm: self.only_comm,
+                exclude_comm: self.exclude_comm,
+                collapse_recursion: self
+                    .collapse_recursion
+                    .map(|kind| kind.unwrap_or(Recursion::Direct)),
+                rules: self.rules.unwrap_or_default(),
+                tidy: if self.no_tidy {
+                    Vec::new()
+                } else if self.tidy.is_empty() {
+                    TidyStrategy::defaults()
+                } else {
+                    self.tidy
+                },
             },
         )
     }
@@ -119,6 +331,88 @@ fn main() -> io::Result<()> {
         .init();
     }
 
+    let all_events = opt.all_events;
+    let events_dir = opt.events_dir.clone();
+    let window = opt.window;
+    let window_dir = opt.window_dir.clone();
+    let split = opt.split;
+    let split_output = opt.split_output.clone();
+    let split_svg = opt.split_svg;
     let (infile, options) = opt.into_parts();
-    Folder::from(options).collapse_file(infile.as_ref(), io::stdout().lock())
+    let mut folder = Folder::from(options);
+    if all_events {
+        write_events(&mut folder, infile, &events_dir)
+    } else if let Some(length) = window {
+        write_windows(&mut folder, infile, length, &window_dir)
+    } else if let Some(split) = split {
+        common::write_partitions(
+            &mut folder,
+            infile,
+            split,
+            split_output.as_deref(),
+            split_svg,
+        )
+    } else {
+        folder.collapse_file(infile.as_ref(), io::stdout().lock())
+    }
+}
+
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    match s.parse::<f64>() {
+        Ok(seconds) if seconds > 0.0 && seconds.is_finite() => Ok(Duration::from_nanos(
+            (seconds * 1_000_000_000.0).round() as u64,
+        )),
+        _ => Err(format!("invalid number of seconds: {}", s)),
+    }
+}
+
+fn write_windows(
+    folder: &mut Folder,
+    infile: Option<PathBuf>,
+    length: Duration,
+    dir: &Path,
+) -> io::Result<()> {
+    let windows = match infile {
+        Some(path) => folder.collapse_windows(io::BufReader::new(File::open(path)?), length)?,
+        None => {
+            let stdin = io::stdin();
+            let stdin_guard = stdin.lock();
+            folder.collapse_windows(stdin_guard, length)?
+        }
+    };
+    for window in windows {
+        let path = dir.join(format!(
+            "{}-{}.folded",
+            timestamp(window.start),
+            timestamp(window.end)
+        ));
+        let mut writer = io::BufWriter::new(File::create(path)?);
+        window.stacks.write_to(&mut writer)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+fn write_events(folder: &mut Folder, infile: Option<PathBuf>, dir: &Path) -> io::Result<()> {
+    let events = match infile {
+        Some(path) => folder.collapse_events(io::BufReader::new(File::open(path)?))?,
+        None => {
+            let stdin = io::stdin();
+            let stdin_guard = stdin.lock();
+            folder.collapse_events(stdin_guard)?
+        }
+    };
+    for (event, stacks) in events {
+        // Raw and PMU events, like cpu/event=0x3c/, have slashes in their names.
+        let path = dir.join(format!("{}.folded", event.replace('/', "_")));
+        let mut writer = io::BufWriter::new(File::create(path)?);
+        stacks.write_to(&mut writer)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+// Formats a time like perf script formats its timestamps.
+fn timestamp(time: Duration) -> String {
+    format!("{}.{:06}", time.as_secs(), time.subsec_micros())
 }
@@ -4,6 +4,8 @@ use std::path::PathBuf;
 use env_logger::Env;
 use inferno::collapse::sample::{Folder, Options};
 use inferno::collapse::Collapse;
+use inferno::folded::Recursion;
+use inferno::rules::Rules;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -30,6 +32,28 @@ struct Opt {
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     verbose: usize,
 
+    // *************** //
+    // *** OPTIONS *** //
+    // *************** //
+    /// Merge the calls of functions that call themselves into one frame (a;a;a;b becomes a;b), or
+    /// with =cycles, also cycles of functions that call each other (a;b;a;b;c becomes a;b;c)
+    #[structopt(
+        long = "collapse-recursion",
+        value_name = "KIND",
+        raw(possible_values = r#"&["direct", "cycles"]"#),
+        raw(require_equals = "true")
+    )]
+    collapse_recursion: Option<Option<Recursion>>,
+
+    /// Filter and rewrite the stacks with the rules in this file, one per line, such as
+    /// "frame '^\[unknown\]$' drop-frame" (see the inferno::rules documentation)
+    #[structopt(
+        long = "rules",
+        value_name = "PATH",
+        parse(try_from_str = "Rules::from_file")
+    )]
+    rules: Option<Rules>,
+
     // ************ //
     // *** ARGS *** //
     // ************ //
@@ -44,6 +68,10 @@ impl Opt {
             self.infile,
             Options {
                 no_modules: self.no_modules,
+                collapse_recursion: self
+                    .collapse_recursion
+                    .map(|kind| kind.unwrap_or(Recursion::Direct)),
+                rules: self.rules.unwrap_or_default(),
             },
         )
     }
@@ -3,6 +3,7 @@ use std::path::PathBuf;
 
 use env_logger::Env;
 use inferno::differential::{self, Options};
+use inferno::rules::Rules;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -46,6 +47,18 @@ struct Opt {
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     verbose: usize,
 
+    // *************** //
+    // *** OPTIONS *** //
+    // *************** //
+    /// Filter and rewrite the stacks of both profiles with the rules in this file, one per line,
+    /// such as "frame '^\[unknown\]$' drop-frame" (see the inferno::rules documentation)
+    #[structopt(
+        long = "rules",
+        value_name = "PATH",
+        parse(try_from_str = "Rules::from_file")
+    )]
+    rules: Option<Rules>,
+
     // ************ //
     // *** ARGS *** //
     // ************ //
@@ -66,6 +79,7 @@ impl Opt {
             Options {
                 normalize: self.normalize,
                 strip_hex: self.strip_hex,
+                rules: self.rules.unwrap_or_default(),
             },
         )
     }
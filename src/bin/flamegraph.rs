@@ -3,9 +3,16 @@ use std::path::{Path, PathBuf};
 
 use env_logger::Env;
 use inferno::flamegraph::color::{BackgroundColor, PaletteMap, SearchColor};
-use inferno::flamegraph::{self, defaults, Direction, FuncFrameAttrsMap, Options, Palette};
+use inferno::flamegraph::{self, defaults, Direction, Format, FuncFrameAttrsMap, Options, Palette};
+use inferno::folded::Recursion;
+use inferno::rules::Rules;
 use structopt::StructOpt;
 
+#[cfg(feature = "png")]
+const FORMATS: &[&str] = &["svg", "pprof", "speedscope", "html", "png"];
+#[cfg(not(feature = "png"))]
+const FORMATS: &[&str] = &["svg", "pprof", "speedscope", "html"];
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "inferno-flamegraph", author = "")]
 struct Opt {
@@ -16,6 +23,12 @@ struct Opt {
     #[structopt(long = "cp")]
     cp: bool,
 
+    /// Produce a flame chart, in which the input lines keep their order and the x-axis is time.
+    /// The lines may be prefixed with timestamps, as written by inferno-collapse-perf
+    /// --flame-chart, which makes frame widths microseconds
+    #[structopt(long = "flame-chart")]
+    flame_chart: bool,
+
     /// Colors are keyed by function name hash
     #[structopt(long = "hash")]
     hash: bool,
@@ -64,6 +77,21 @@ struct Opt {
     #[structopt(long = "bgcolors", value_name = "STRING")]
     bgcolors: Option<BackgroundColor>,
 
+    /// Draw a butterfly graph of the functions whose whole names match this regular expression,
+    /// with their callers above and the functions they call below
+    #[structopt(long = "butterfly", value_name = "FUNCTION")]
+    butterfly: Option<String>,
+
+    /// Merge the calls of functions that call themselves into one frame (a;a;a;b becomes a;b), or
+    /// with =cycles, also cycles of functions that call each other (a;b;a;b;c becomes a;b;c)
+    #[structopt(
+        long = "collapse-recursion",
+        value_name = "KIND",
+        raw(possible_values = r#"&["direct", "cycles"]"#),
+        raw(require_equals = "true")
+    )]
+    collapse_recursion: Option<Option<Recursion>>,
+
     /// Set color palette
     #[structopt(
         short = "c",
@@ -92,6 +120,15 @@ struct Opt {
     )]
     factor: f64,
 
+    /// Output format
+    #[structopt(
+        long = "format",
+        default_value = "svg",
+        raw(possible_values = "FORMATS"),
+        value_name = "STRING"
+    )]
+    format: Format,
+
     /// Font size
     #[structopt(
         long = "fontsize",
@@ -150,6 +187,15 @@ struct Opt {
     #[structopt(long = "notes", value_name = "STRING")]
     notes: Option<String>,
 
+    /// Filter and rewrite the stacks with the rules in this file, one per line, such as
+    /// "frame '^\[unknown\]$' drop-frame" (see the inferno::rules documentation)
+    #[structopt(
+        long = "rules",
+        value_name = "PATH",
+        parse(try_from_str = "Rules::from_file")
+    )]
+    rules: Option<Rules>,
+
     /// Search color
     #[structopt(
         long = "search-color",
@@ -203,12 +249,29 @@ impl<'a> Opt {
                 options.title = "Icicle Graph".to_string();
             }
         }
+        if self.flame_chart {
+            options.flame_chart = true;
+            if self.title == defaults::TITLE {
+                options.title = "Flame Chart".to_string();
+            }
+        }
+        if let Some(function) = self.butterfly {
+            if self.title == defaults::TITLE {
+                options.title = format!("Butterfly Graph: {}", function);
+            }
+            options.butterfly = Some(function);
+        }
         options.negate_differentials = self.negate;
         options.factor = self.factor;
+        options.format = self.format;
         options.pretty_xml = self.pretty_xml;
         options.no_sort = self.no_sort;
         options.no_javascript = self.no_javascript;
         options.reverse_stack_order = self.reverse;
+        options.rules = self.rules.unwrap_or_default();
+        options.collapse_recursion = self
+            .collapse_recursion
+            .map(|kind| kind.unwrap_or(Recursion::Direct));
 
         // set style options
         options.subtitle = self.subtitle;
@@ -288,7 +351,8 @@ fn save_consistent_palette_if_needed(
 #[cfg(test)]
 mod tests {
     use super::Opt;
-    use inferno::flamegraph::{color, Direction, Options, Palette};
+    use inferno::flamegraph::{color, Direction, Format, Options, Palette};
+    use inferno::folded::Recursion;
     use pretty_assertions::assert_eq;
     use std::path::PathBuf;
     use std::str::FromStr;
@@ -340,9 +404,12 @@ mod tests {
             "--negate",
             "--factor",
             "0.1",
+            "--format",
+            "pprof",
             "--pretty-xml",
             "--reverse",
             "--no-javascript",
+            "--collapse-recursion",
             "test_infile1",
             "test_infile2",
         ];
@@ -368,10 +435,15 @@ mod tests {
             palette_map: Default::default(),
             func_frameattrs: Default::default(),
             direction: Direction::Inverted,
+            format: Format::Pprof,
             negate_differentials: true,
             pretty_xml: true,
             no_sort: false,
             reverse_stack_order: true,
+            flame_chart: false,
+            butterfly: None,
+            collapse_recursion: Some(Recursion::Direct),
+            rules: Default::default(),
             no_javascript: true,
         };
 
@@ -380,4 +452,21 @@ mod tests {
         assert_eq!(infiles[0], PathBuf::from_str("test_infile1").unwrap());
         assert_eq!(infiles[1], PathBuf::from_str("test_infile2").unwrap());
     }
+
+    #[test]
+    fn collapse_recursion() {
+        for &(arg, recursion) in &[
+            ("--collapse-recursion", Recursion::Direct),
+            ("--collapse-recursion=direct", Recursion::Direct),
+            ("--collapse-recursion=cycles", Recursion::Indirect),
+        ] {
+            let opt = Opt::from_iter_safe(vec!["inferno-flamegraph", arg, "test_infile"]).unwrap();
+            let (infiles, options) = opt.into_parts();
+            assert_eq!(options.collapse_recursion, Some(recursion));
+            assert_eq!(infiles, vec![PathBuf::from("test_infile")]);
+        }
+        assert!(
+            Opt::from_iter_safe(vec!["inferno-flamegraph", "--collapse-recursion=all"]).is_err()
+        );
+    }
 }
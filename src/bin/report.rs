@@ -0,0 +1,99 @@
+use std::io;
+use std::path::PathBuf;
+
+use env_logger::Env;
+use inferno::report::{self, Options, SortBy};
+use lazy_static::lazy_static;
+use structopt::StructOpt;
+
+lazy_static! {
+    static ref DEFAULT_TOP: String = format!("{}", report::DEFAULT_TOP);
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "inferno-report",
+    author = "",
+    after_help = "\
+Prints a table of the functions that the most samples were taken in, for a
+quick look at where the time goes without opening a flame graph.
+
+  $ cat stacks.folded | inferno-report
+
+Self samples were taken in the function itself, and total samples in the
+function or anything it calls. Given the output of inferno-diff-folded, the
+report also shows how much these changed between the two profiles:
+
+  $ inferno-diff-folded folded1 folded2 | inferno-report --sort delta"
+)]
+struct Opt {
+    // *************** //
+    // *** OPTIONS *** //
+    // *************** //
+    /// Number of functions to list, or 0 to list all of them
+    #[structopt(
+        short = "n",
+        long = "top",
+        value_name = "UINT",
+        raw(default_value = "&DEFAULT_TOP")
+    )]
+    top: usize,
+
+    /// What to rank functions by
+    #[structopt(
+        long = "sort",
+        value_name = "ORDER",
+        default_value = "self",
+        raw(possible_values = r#"&["self", "total", "delta"]"#)
+    )]
+    sort_by: SortBy,
+
+    // ************* //
+    // *** FLAGS *** //
+    // ************* //
+    /// Silence all log output
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Verbose logging mode (-v, -vv, -vvv)
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
+    verbose: usize,
+
+    // ************ //
+    // *** ARGS *** //
+    // ************ //
+    /// Folded stack files. With no PATH, or PATH is -, read STDIN.
+    #[structopt(value_name = "PATH", parse(from_os_str))]
+    infiles: Vec<PathBuf>,
+}
+
+impl Opt {
+    fn into_parts(self) -> (Vec<PathBuf>, Options) {
+        (
+            self.infiles,
+            Options {
+                top: self.top,
+                sort_by: self.sort_by,
+            },
+        )
+    }
+}
+
+fn main() -> io::Result<()> {
+    let opt = Opt::from_args();
+
+    // Initialize logger
+    if !opt.quiet {
+        env_logger::Builder::from_env(Env::default().default_filter_or(match opt.verbose {
+            0 => "warn",
+            1 => "info",
+            2 => "debug",
+            _ => "trace",
+        }))
+        .default_format_timestamp(false)
+        .init();
+    }
+
+    let (infiles, options) = opt.into_parts();
+    report::from_files(&options, &infiles, io::stdout().lock())
+}
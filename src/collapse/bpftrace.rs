@@ -0,0 +1,579 @@
+use std::borrow::Cow;
+use std::io::{self, BufRead};
+
+use log::warn;
+use symbolic_demangle::demangle;
+
+use crate::collapse::common::{self, CollapseParallel, Occurrences};
+use crate::folded::Recursion;
+use crate::rules::Rules;
+
+/// Addresses at or above this value live in the upper half of the address space, which is where
+/// both x86_64 and aarch64 Linux map the kernel.
+const KERNEL_ADDRESS_START: u64 = 0xffff_8000_0000_0000;
+
+mod logging {
+    use log::warn;
+
+    pub(super) fn weird_key_line(line: &str) {
+        warn!("Weird map key line: {}", line);
+    }
+
+    pub(super) fn weird_count(count: &str) {
+        warn!("Map value is not a count: {}", count);
+    }
+}
+
+/// `bpftrace` folder configuration options.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Annotate kernel functions with a `_[k]` suffix.
+    ///
+    /// A key with a single stack is only known to be a kernel stack if its frames say so, with
+    /// kernel addresses or `[kernel.kallsyms]` modules. Set [`assume_kernel`] for the symbolized
+    /// stacks of maps like `@[kstack]`.
+    ///
+    /// Default is `false`.
+    ///
+    ///   [`assume_kernel`]: #structfield.assume_kernel
+    pub annotate_kernel: bool,
+
+    /// Take keys with a single stack, whose frames don't say whether it came from the kernel, to
+    /// be kernel stacks, like those of `@[kstack]`.
+    ///
+    /// Default is `false`, which takes them to be user stacks.
+    pub assume_kernel: bool,
+
+    /// Fold recursive calls into one frame per function, as described by [`Recursion`].
+    ///
+    /// Default is `None`, which keeps them.
+    ///
+    ///   [`Recursion`]: ../../folded/enum.Recursion.html
+    pub collapse_recursion: Option<Recursion>,
+
+    /// [`Rules`] to filter and rewrite the stacks with, which are applied before any recursion is
+    /// collapsed.
+    ///
+    /// Default is no rules.
+    ///
+    ///   [`Rules`]: ../../rules/struct.Rules.html
+    pub rules: Rules,
+
+    /// Demangle function names.
+    ///
+    /// Default is `false`.
+    pub demangle: bool,
+
+    /// Include raw addresses (e.g., `0xbfff0836`) where symbols can't be found.
+    ///
+    /// Default is `false`.
+    pub include_addrs: bool,
+
+    /// The number of threads to use.
+    ///
+    /// Default is the number of logical cores on your machine.
+    pub nthreads: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            annotate_kernel: false,
+            assume_kernel: false,
+            collapse_recursion: None,
+            rules: Rules::new(),
+            demangle: false,
+            include_addrs: false,
+            nthreads: *common::DEFAULT_NTHREADS,
+        }
+    }
+}
+
+/// A single element of a map key, like `comm` or `ustack` in `@[comm, ustack]`.
+#[derive(Clone, Debug)]
+enum KeyElement {
+    /// A plain value, such as a process name or a thread id.
+    Scalar(String),
+
+    /// A stack, with frames in the order bpftrace prints them (leaf first).
+    Stack {
+        frames: Vec<String>,
+        /// Whether the frames we've seen say anything about this being a kernel stack.
+        kernel: Option<bool>,
+    },
+}
+
+/// A stack collapser for map dumps printed by [`bpftrace`](https://github.com/iovisor/bpftrace).
+///
+/// The folder understands maps keyed by `ustack` and/or `kstack`, optionally combined with other
+/// values such as `comm` or `tid`, for example the output of
+///
+/// ```text
+/// bpftrace -e 'profile:hz:99 { @[ustack, kstack] = count(); }'
+/// ```
+///
+/// Scalar key elements become the root frames of the folded stack, followed by the user stack
+/// and finally the kernel stack. bpftrace does not say which stacks in a key came from the
+/// kernel, so the folder looks at the frames (kernel addresses, `[kernel.kallsyms]` modules when
+/// using `perf`-style stack output) and otherwise assumes the `@[ustack, kstack]` order, i.e.,
+/// that a trailing second stack is the kernel stack. A lone stack with symbolized frames, like
+/// those of `@[kstack]`, is taken to be a user stack unless [`Options::assume_kernel`] is set.
+///
+/// To construct one, either use `bpftrace::Folder::default()` or create an [`Options`] and use
+/// `bpftrace::Folder::from(options)`.
+pub struct Folder {
+    /// Elements of the map key in this entry thus far.
+    elements: Vec<KeyElement>,
+
+    /// All lines until the closing `]: <count>` line are part of a map key.
+    in_key: bool,
+
+    /// The number of stacks per job to send to the threadpool.
+    nstacks_per_job: usize,
+
+    /// Frame lines extend the last stack element until the next piece of key text.
+    stack_open: bool,
+
+    opt: Options,
+}
+
+impl From<Options> for Folder {
+    fn from(mut opt: Options) -> Self {
+        if opt.nthreads == 0 {
+            opt.nthreads = 1;
+        }
+        Self {
+            elements: Vec::default(),
+            in_key: false,
+            nstacks_per_job: common::DEFAULT_NSTACKS_PER_JOB,
+            stack_open: false,
+            opt,
+        }
+    }
+}
+
+impl Default for Folder {
+    fn default() -> Self {
+        Options::default().into()
+    }
+}
+
+impl CollapseParallel for Folder {
+    fn pre_process<R>(&mut self, _: &mut R, occurrences: &mut Occurrences) -> io::Result<()>
+    where
+        R: io::BufRead,
+    {
+        // Lines outside of map keys (like `Attaching 1 probe...`) are ignored while collapsing,
+        // so there is no header to skip.
+        occurrences.set_collapse_recursion(self.opt.collapse_recursion);
+        occurrences.set_rules(&self.opt.rules);
+        Ok(())
+    }
+
+    fn collapse_single_threaded<R>(
+        &mut self,
+        mut reader: R,
+        occurrences: &mut Occurrences,
+    ) -> io::Result<()>
+    where
+        R: io::BufRead,
+    {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                break;
+            }
+            self.on_line(line.trim_end(), occurrences);
+        }
+
+        if self.in_key {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Input data ends in the middle of a map key.",
+            ));
+        }
+        Ok(())
+    }
+
+    fn is_applicable(&mut self, input: &str) -> Option<bool> {
+        let mut input = input.as_bytes();
+        let mut line = String::new();
+        let mut in_key = false;
+        let mut found_stack_line = false;
+        loop {
+            line.clear();
+            if let Ok(n) = input.read_line(&mut line) {
+                if n == 0 {
+                    break;
+                }
+            } else {
+                return Some(false);
+            }
+
+            // skip the preamble, and the `^C` echoed when bpftrace was interrupted
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with("Attaching ") || line == "^C" {
+                continue;
+            }
+
+            if !in_key {
+                if !line.starts_with('@') || !line.contains('[') {
+                    return Some(false);
+                }
+                if let Some((_, count)) = key_end(line) {
+                    // single-line keys don't contain stacks, but they're still bpftrace
+                    if count.parse::<usize>().is_err() {
+                        return Some(false);
+                    }
+                    continue;
+                }
+                in_key = true;
+            } else if line.starts_with(char::is_whitespace) {
+                found_stack_line = true;
+            } else if let Some((_, count)) = key_end(line) {
+                return Some(found_stack_line && count.parse::<usize>().is_ok());
+            } else if !line.starts_with(',') {
+                return Some(false);
+            }
+        }
+        None
+    }
+
+    fn would_end_stack(&mut self, line: &[u8]) -> bool {
+        // A map entry ends with `]: <count>`. Frames are always indented, so any line that is not
+        // indented and ends that way closes the current key.
+        match std::str::from_utf8(line) {
+            Ok(line) => {
+                !line.starts_with(char::is_whitespace)
+                    && key_end(line.trim_end())
+                        .map(|(_, count)| count.parse::<usize>().is_ok())
+                        .unwrap_or(false)
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn clone_and_reset_stack_context(&self) -> Self {
+        Self {
+            elements: Vec::default(),
+            in_key: false,
+            nstacks_per_job: self.nstacks_per_job,
+            stack_open: false,
+            opt: self.opt.clone(),
+        }
+    }
+
+    fn nstacks_per_job(&self) -> usize {
+        self.nstacks_per_job
+    }
+
+    fn set_nstacks_per_job(&mut self, n: usize) {
+        self.nstacks_per_job = n;
+    }
+
+    fn nthreads(&self) -> usize {
+        self.opt.nthreads
+    }
+
+    fn set_nthreads(&mut self, n: usize) {
+        self.opt.nthreads = n;
+    }
+}
+
+impl Folder {
+    // we have a line of a map dump, like:
+    //
+    //     Attaching 1 probe...
+    //     @[
+    //         native_safe_halt+6
+    //         default_idle+14
+    //     , bash]: 12
+    //     @[sshd,
+    //         7f3a0d2e4f40 __poll+73 (/usr/lib/libc-2.31.so)
+    //     ]: 1
+    //     @[bash, 1234]: 5
+    fn on_line(&mut self, line: &str, occurrences: &mut Occurrences) {
+        if line.is_empty() {
+            return;
+        }
+
+        if !self.in_key {
+            if !line.starts_with('@') {
+                // some other output, like `Attaching 1 probe...`
+                return;
+            }
+            match line.find('[') {
+                Some(open) => {
+                    self.in_key = true;
+                    self.on_key_text(&line[open + 1..], occurrences);
+                }
+                None => {
+                    // a map without keys, like `@total: 42`
+                }
+            }
+        } else if line.starts_with(char::is_whitespace) {
+            self.on_frame_line(line.trim_start());
+        } else {
+            self.on_key_text(line, occurrences);
+        }
+    }
+
+    // Handles the non-frame parts of a key: scalars, the `,` between key elements, and the
+    // closing `]: <count>`.
+    fn on_key_text(&mut self, text: &str, occurrences: &mut Occurrences) {
+        // any key text ends the stack we were in, if any
+        self.stack_open = false;
+
+        let (text, count) = match key_end(text) {
+            Some((text, count)) => (text, Some(count)),
+            None => (text, None),
+        };
+
+        for piece in text.split(',') {
+            let piece = piece.trim();
+            if !piece.is_empty() {
+                self.elements
+                    .push(KeyElement::Scalar(piece.replace(';', ":")));
+            }
+        }
+
+        if let Some(count) = count {
+            match count.parse::<u64>() {
+                Ok(count) => self.on_key_end(count, occurrences),
+                Err(_) => logging::weird_count(count),
+            }
+            self.elements.clear();
+            self.in_key = false;
+        } else if text.contains(']') {
+            logging::weird_key_line(text);
+        }
+    }
+
+    // we have a frame line, in either of bpftrace's stack modes:
+    //
+    //     native_safe_halt+6
+    //     0x7f3a0d2e4f40
+    //     ffffffff81c2e4c6 native_safe_halt+6 ([kernel.kallsyms])
+    //     7f3a0d2e4f40 __poll+73 (/usr/lib/libc-2.31.so)
+    fn on_frame_line(&mut self, line: &str) {
+        let (frame, kernel) = self.parse_frame(line);
+        if self.stack_open {
+            if let Some(KeyElement::Stack { frames, kernel: k }) = self.elements.last_mut() {
+                frames.push(frame);
+                if k.is_none() {
+                    *k = kernel;
+                }
+                return;
+            }
+        }
+        self.stack_open = true;
+        self.elements.push(KeyElement::Stack {
+            frames: vec![frame],
+            kernel,
+        });
+    }
+
+    fn parse_frame(&self, line: &str) -> (String, Option<bool>) {
+        let mut words = line.splitn(2, ' ');
+        let first = words.next().unwrap_or("");
+        let rest = words.next().map(str::trim);
+
+        let (addr, mut rawfunc, module) = match rest {
+            // perf-style output: `<address> <symbol> (<module>)`
+            Some(rest) if parse_address(first.trim_start_matches("0x")).is_some() => {
+                let (sym, module) = match rest.rfind(" (") {
+                    Some(i) if rest.ends_with(')') => {
+                        (rest[..i].trim(), Some(&rest[i + 2..rest.len() - 1]))
+                    }
+                    _ if rest.starts_with('(') && rest.ends_with(')') => {
+                        ("[unknown]", Some(&rest[1..rest.len() - 1]))
+                    }
+                    _ => (rest, None),
+                };
+                (Some(first), sym, module)
+            }
+            // a bare, unresolved address
+            None if first.starts_with("0x") && parse_address(&first[2..]).is_some() => {
+                (Some(first), "[unknown]", None)
+            }
+            // default output: `<symbol>+<offset>`
+            _ => (None, line, None),
+        };
+
+        let kernel = match (
+            module,
+            addr.and_then(|a| parse_address(a.trim_start_matches("0x"))),
+        ) {
+            (Some(module), _) if module != "[unknown]" => {
+                Some(module.starts_with('[') || module.ends_with("vmlinux"))
+            }
+            (_, Some(addr)) => Some(addr >= KERNEL_ADDRESS_START),
+            _ => None,
+        };
+
+        // Strip off symbol offsets
+        if let Some(offset) = rawfunc.rfind('+') {
+            let end = rawfunc[offset + 1..].trim_start_matches("0x");
+            if !end.is_empty() && end.chars().all(|c| c.is_ascii_hexdigit()) {
+                rawfunc = &rawfunc[..offset];
+            }
+        }
+
+        let func: Cow<'_, str> = if rawfunc == "[unknown]" {
+            match (module, addr, self.opt.include_addrs) {
+                (Some(module), Some(addr), true) if module != "[unknown]" => {
+                    Cow::Owned(format!("[{} <{}>]", basename(module), addr))
+                }
+                (Some(module), _, _) if module != "[unknown]" => {
+                    Cow::Owned(format!("[{}]", basename(module)))
+                }
+                (_, Some(addr), true) => Cow::Owned(format!("[unknown <{}>]", addr)),
+                _ => Cow::Borrowed(rawfunc),
+            }
+        } else if self.opt.demangle {
+            Cow::Owned(demangle(rawfunc).into_owned())
+        } else {
+            common::fix_partially_demangled_rust_symbol(rawfunc)
+        };
+
+        (func.replace(';', ":"), kernel)
+    }
+
+    fn on_key_end(&mut self, count: u64, occurrences: &mut Occurrences) {
+        let mut scalars = Vec::new();
+        let mut stacks = Vec::new();
+        for element in self.elements.drain(..) {
+            match element {
+                KeyElement::Scalar(s) => scalars.push(s),
+                KeyElement::Stack { frames, kernel } => stacks.push((frames, kernel)),
+            }
+        }
+
+        // Work out which stacks came from the kernel. If only one of two stacks told us what it
+        // is, the other one must be the opposite. Otherwise fall back to `@[ustack, kstack]`, or
+        // to `@[kstack]` for a lone stack if the options say so.
+        if stacks.len() == 1 {
+            if stacks[0].1.is_none() && self.opt.assume_kernel {
+                stacks[0].1 = Some(true);
+            }
+        } else if stacks.len() == 2 {
+            match (stacks[0].1, stacks[1].1) {
+                (Some(k), None) => stacks[1].1 = Some(!k),
+                (None, Some(k)) => stacks[0].1 = Some(!k),
+                (None, None) => {
+                    stacks[0].1 = Some(false);
+                    stacks[1].1 = Some(true);
+                }
+                _ => {}
+            }
+        }
+        // user stacks go below kernel stacks
+        stacks.sort_by_key(|(_, kernel)| kernel.unwrap_or(false));
+
+        let mut stack_str = String::with_capacity(
+            scalars.iter().map(|s| s.len() + 1).sum::<usize>()
+                + stacks
+                    .iter()
+                    .flat_map(|(frames, _)| frames.iter())
+                    .map(|f| f.len() + 5)
+                    .sum::<usize>(),
+        );
+        for s in scalars {
+            if !stack_str.is_empty() {
+                stack_str.push(';');
+            }
+            stack_str.push_str(&s);
+        }
+        for (frames, kernel) in stacks {
+            let annotate = self.opt.annotate_kernel && kernel == Some(true);
+            // bpftrace prints the leaf frame first
+            for frame in frames.iter().rev() {
+                if !stack_str.is_empty() {
+                    stack_str.push(';');
+                }
+                stack_str.push_str(frame);
+                if annotate {
+                    stack_str.push_str("_[k]");
+                }
+            }
+        }
+
+        if stack_str.is_empty() {
+            warn!("Skipping map entry with an empty key");
+            return;
+        }
+
+        occurrences.insert_or_add(stack_str, count);
+    }
+}
+
+// Splits `<key text>]: <count>` into the key text and the count.
+fn key_end(line: &str) -> Option<(&str, &str)> {
+    let end = line.rfind("]: ")?;
+    Some((&line[..end], line[end + 3..].trim()))
+}
+
+fn parse_address(hex: &str) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u64::from_str_radix(hex, 16).ok()
+}
+
+fn basename(module: &str) -> &str {
+    &module[module.rfind('/').map(|i| i + 1).unwrap_or(0)..]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use lazy_static::lazy_static;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::collapse::common;
+
+    lazy_static! {
+        static ref INPUT: Vec<PathBuf> = {
+            [
+                "./tests/data/collapse-bpftrace/comm-perf-mode.txt",
+                "./tests/data/collapse-bpftrace/kstack.txt",
+                "./tests/data/collapse-bpftrace/ustack-kstack.txt",
+            ]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>()
+        };
+    }
+
+    #[test]
+    fn test_collapse_multi_bpftrace() -> io::Result<()> {
+        let mut folder = Folder::default();
+        common::testing::test_collapse_multi(&mut folder, &INPUT)
+    }
+
+    #[test]
+    fn parse_frame_kernel_evidence() {
+        let folder = Folder::default();
+        assert_eq!(
+            folder.parse_frame("native_safe_halt+6"),
+            ("native_safe_halt".to_string(), None)
+        );
+        assert_eq!(
+            folder.parse_frame("ffffffff81c2e4c6 native_safe_halt+6 ([kernel.kallsyms])"),
+            ("native_safe_halt".to_string(), Some(true))
+        );
+        assert_eq!(
+            folder.parse_frame("7f3a0d2e4f40 __poll+73 (/usr/lib/libc-2.31.so)"),
+            ("__poll".to_string(), Some(false))
+        );
+        assert_eq!(
+            folder.parse_frame("0xffffffff81c2e4c6"),
+            ("[unknown]".to_string(), Some(true))
+        );
+        // a symbol that happens to be valid hex isn't an address
+        assert_eq!(folder.parse_frame("add+3"), ("add".to_string(), None));
+    }
+}
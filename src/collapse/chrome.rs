@@ -0,0 +1,281 @@
+use std::io;
+
+use fnv::FnvHashMap;
+use log::warn;
+use serde::Deserialize;
+
+use crate::collapse::common::Occurrences;
+use crate::collapse::Collapse;
+use crate::folded::{FoldedStacks, Recursion};
+use crate::rules::Rules;
+
+// The name V8 gives the node at the base of every profile.
+static ROOT_NAME: &str = "(root)";
+
+// The name used for functions that don't have one.
+static ANONYMOUS_NAME: &str = "(anonymous)";
+
+/// `chrome` folder configuration options.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Weight each stack by the time (in microseconds) until the next sample was taken, rather
+    /// than by the number of samples.
+    ///
+    /// Default is `false`.
+    pub weight_by_time: bool,
+
+    /// Tag each frame with the URL and line number of its function (e.g.,
+    /// `render (file:///app/src/view.js:42)`).
+    ///
+    /// Default is `false`.
+    pub include_location: bool,
+
+    /// Fold recursive calls into one frame per function, as described by [`Recursion`].
+    ///
+    /// Default is `None`, which keeps them.
+    ///
+    ///   [`Recursion`]: ../../folded/enum.Recursion.html
+    pub collapse_recursion: Option<Recursion>,
+
+    /// [`Rules`] to filter and rewrite the stacks with, which are applied before any recursion is
+    /// collapsed.
+    ///
+    /// Default is no rules.
+    ///
+    ///   [`Rules`]: ../../rules/struct.Rules.html
+    pub rules: Rules,
+}
+
+/// A stack collapser for CPU profiles in the JSON format used by V8, as saved by Chrome
+/// DevTools' JavaScript profiler and by `node --cpu-prof` (usually with a `.cpuprofile`
+/// extension).
+///
+/// Such a profile consists of a tree of call frames, and a list of samples that each refer to the
+/// node of the tree that was executing when the sample was taken. The resulting stacks are best
+/// plotted with the `js` palette.
+///
+/// To construct one, either use `chrome::Folder::default()` or create an [`Options`] and use
+/// `chrome::Folder::from(options)`.
+#[derive(Clone, Default)]
+pub struct Folder {
+    opt: Options,
+}
+
+impl From<Options> for Folder {
+    fn from(opt: Options) -> Self {
+        Folder { opt }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Profile {
+    nodes: Vec<Node>,
+    #[serde(default)]
+    start_time: Option<u64>,
+    #[serde(default)]
+    end_time: Option<u64>,
+    #[serde(default)]
+    samples: Option<Vec<u64>>,
+    #[serde(default)]
+    time_deltas: Option<Vec<i64>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Node {
+    id: u64,
+    call_frame: CallFrame,
+    #[serde(default)]
+    hit_count: u64,
+    #[serde(default)]
+    children: Vec<u64>,
+    #[serde(default)]
+    parent: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CallFrame {
+    function_name: String,
+    #[serde(default)]
+    url: String,
+    /// Zero-based, and -1 if unknown.
+    #[serde(default)]
+    line_number: i64,
+}
+
+impl Collapse for Folder {
+    fn collapse<R, W>(&mut self, reader: R, writer: W) -> io::Result<()>
+    where
+        R: io::BufRead,
+        W: io::Write,
+    {
+        self.collapse_to_occurrences(reader)?
+            .write_and_clear(writer)
+    }
+
+    fn collapse_to_stacks<R>(&mut self, reader: R) -> io::Result<FoldedStacks>
+    where
+        R: io::BufRead,
+    {
+        Ok(self.collapse_to_occurrences(reader)?.take_stacks())
+    }
+
+    /// Check for a JSON object with a list of nodes that have call frames.
+    fn is_applicable(&mut self, input: &str) -> Option<bool> {
+        let input = input.trim_start();
+        if input.is_empty() {
+            return None;
+        }
+        if !input.starts_with('{') {
+            return Some(false);
+        }
+        if input.contains("\"nodes\"") && input.contains("\"callFrame\"") {
+            Some(true)
+        } else {
+            None
+        }
+    }
+}
+
+impl Folder {
+    // Collapses the contents of the provided `reader` into the occurrences of each stack, which
+    // `collapse` writes out and `collapse_to_stacks` returns.
+    fn collapse_to_occurrences<R>(&mut self, mut reader: R) -> io::Result<Occurrences>
+    where
+        R: io::BufRead,
+    {
+        let mut input = String::new();
+        reader.read_to_string(&mut input)?;
+        let profile: Profile = serde_json::from_str(&input).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid V8 CPU profile: {}", e),
+            )
+        })?;
+
+        let stacks = self.stacks(&profile);
+        let mut occurrences = Occurrences::new(1);
+        occurrences.set_collapse_recursion(self.opt.collapse_recursion);
+        occurrences.set_rules(&self.opt.rules);
+        match profile.samples {
+            Some(ref samples) => {
+                let weights = if self.opt.weight_by_time {
+                    sample_durations(&profile, samples.len())
+                } else {
+                    None
+                };
+                for (i, id) in samples.iter().enumerate() {
+                    let count = weights.as_ref().map(|w| w[i]).unwrap_or(1);
+                    match stacks.get(id) {
+                        Some(stack) if !stack.is_empty() && count > 0 => {
+                            occurrences.insert_or_add(stack.clone(), count)
+                        }
+                        Some(_) => {}
+                        None => warn!("Sample refers to unknown node {}", id),
+                    }
+                }
+            }
+            None => {
+                // Older profiles only have per-node hit counts.
+                if self.opt.weight_by_time {
+                    warn!("Profile has no samples, so weighting by hit count instead of time");
+                }
+                for node in &profile.nodes {
+                    match stacks.get(&node.id) {
+                        Some(stack) if !stack.is_empty() && node.hit_count > 0 => {
+                            occurrences.insert_or_add(stack.clone(), node.hit_count)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        Ok(occurrences)
+    }
+
+    /// Returns the semicolon-separated stack for every node of the profile.
+    fn stacks(&self, profile: &Profile) -> FnvHashMap<u64, String> {
+        let mut parents = FnvHashMap::default();
+        for node in &profile.nodes {
+            if let Some(parent) = node.parent {
+                parents.insert(node.id, parent);
+            }
+            for &child in &node.children {
+                parents.insert(child, node.id);
+            }
+        }
+
+        let names: FnvHashMap<u64, String> = profile
+            .nodes
+            .iter()
+            .map(|node| (node.id, self.frame_name(&node.call_frame)))
+            .collect();
+
+        let mut stacks = FnvHashMap::default();
+        for node in &profile.nodes {
+            let mut frames = Vec::new();
+            let mut id = node.id;
+            loop {
+                match names.get(&id) {
+                    Some(name) if name != ROOT_NAME => frames.push(name.as_str()),
+                    _ => {}
+                }
+                id = match parents.get(&id) {
+                    // a malformed profile could have a cycle, which would keep us here forever
+                    Some(&parent) if frames.len() <= profile.nodes.len() => parent,
+                    _ => break,
+                };
+            }
+            frames.reverse();
+            stacks.insert(node.id, frames.join(";"));
+        }
+        stacks
+    }
+
+    fn frame_name(&self, frame: &CallFrame) -> String {
+        let mut name = if frame.function_name.is_empty() {
+            ANONYMOUS_NAME.to_string()
+        } else {
+            frame.function_name.replace(';', ":")
+        };
+        if self.opt.include_location && !frame.url.is_empty() {
+            name.push_str(" (");
+            name.push_str(&frame.url.replace(';', ":"));
+            if frame.line_number >= 0 {
+                name.push(':');
+                name.push_str(&(frame.line_number + 1).to_string());
+            }
+            name.push(')');
+        }
+        name
+    }
+}
+
+/// Works out how long (in microseconds) each sample lasted, which is the time until the next
+/// sample, or until the end of the profile for the last one.
+fn sample_durations(profile: &Profile, nsamples: usize) -> Option<Vec<u64>> {
+    let deltas = match profile.time_deltas {
+        Some(ref deltas) if deltas.len() == nsamples => deltas,
+        _ => {
+            warn!("Profile has no time deltas for its samples, so weighting by sample count");
+            return None;
+        }
+    };
+
+    let mut durations = Vec::with_capacity(nsamples);
+    for delta in deltas.iter().skip(1) {
+        durations.push((*delta).max(0) as u64);
+    }
+    let last = match (profile.start_time, profile.end_time) {
+        (Some(start), Some(end)) => {
+            let last_sample_time = deltas.iter().fold(start as i64, |t, d| t + d);
+            (end as i64 - last_sample_time).max(0) as u64
+        }
+        _ => 0,
+    };
+    durations.push(last);
+    Some(durations)
+}
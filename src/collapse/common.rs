@@ -1,6 +1,10 @@
 use std::borrow::Cow;
-use std::io;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
 use std::mem;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
 
 use chashmap::CHashMap;
@@ -8,16 +12,22 @@ use crossbeam::channel;
 use fnv::FnvHashMap;
 use lazy_static::lazy_static;
 
+use super::Collapse;
+use crate::flamegraph;
+use crate::folded::{self, FoldedStacks, Recursion};
+use crate::rules::Rules;
+
 const CAPACITY_HASHMAP: usize = 512;
 
 pub(crate) const CAPACITY_READER: usize = 128 * 1024;
 
-/// Internal parameter (not exposed to users) that determines how many stacks of
-/// input data make up a "chunk" (unit that is sent to the threadpool for
-/// processing). Chosen by benchmarking various values using the following tests:
+/// The default number of stacks of input data that make up a "chunk" (the unit that is sent to
+/// the threadpool for processing) in [`CollapseParallel`] implementations.
+///
+/// Chosen by benchmarking various values using the following tests:
 /// * cargo test bench_nstacks_dtrace --release -- --ignored --nocapture
 /// * cargo test bench_nstacks_perf --release -- --ignored --nocapture
-pub(crate) const DEFAULT_NSTACKS_PER_JOB: usize = 100;
+pub const DEFAULT_NSTACKS_PER_JOB: usize = 100;
 
 /// A guess at the number of bytes contained in any given stack of any given format.
 /// Used to calculate the initial capacity of the vector used for sending input
@@ -27,17 +37,124 @@ const NBYTES_PER_STACK_GUESS: usize = 1024;
 const RUST_HASH_LENGTH: usize = 17;
 
 lazy_static! {
-    #[doc(hidden)]
+    /// The default number of threads to collapse with, which is the number of CPUs.
     pub static ref DEFAULT_NTHREADS: usize = num_cpus::get();
 }
 
-/// Private trait for internal library authors.
+/// Trait for collapsers that want parallelism for free.
 ///
 /// If you implement this trait, your type will implement the public-facing
-/// `Collapse` trait as well. Implementing this trait gives you parallelism
+/// [`Collapse`] trait as well. Implementing this trait gives you parallelism
 /// for free as long as you adhere to the requirements described in the
-/// comments below.
-pub trait CollapsePrivate: Send + Sized {
+/// comments below: the input is split into chunks of whole stacks on the main
+/// thread, and each chunk is then folded by a pool of worker threads, each
+/// with its own copy of the collapser, into a shared [`Occurrences`]. This is
+/// how the `perf`, `dtrace`, and `bpftrace` collapsers work, and collapsers
+/// outside of this crate can work the same way.
+///
+/// # Example
+///
+/// A collapser for stacks that are written one frame per line, leaf first, with an empty line
+/// after each stack:
+///
+/// ```
+/// use std::io::{self, BufRead};
+///
+/// use inferno::collapse::common::{self, CollapseParallel, Occurrences};
+/// use inferno::collapse::Collapse;
+///
+/// struct Folder {
+///     stack: Vec<String>,
+///     nstacks_per_job: usize,
+///     nthreads: usize,
+/// }
+///
+/// impl Folder {
+///     fn end_stack(&mut self, occurrences: &mut Occurrences) {
+///         if !self.stack.is_empty() {
+///             self.stack.reverse();
+///             occurrences.insert_or_add(self.stack.join(";"), 1);
+///             self.stack.clear();
+///         }
+///     }
+/// }
+///
+/// impl CollapseParallel for Folder {
+///     fn pre_process<R>(&mut self, _: &mut R, _: &mut Occurrences) -> io::Result<()>
+///     where
+///         R: BufRead,
+///     {
+///         // There's no header to skip.
+///         Ok(())
+///     }
+///
+///     fn collapse_single_threaded<R>(
+///         &mut self,
+///         reader: R,
+///         occurrences: &mut Occurrences,
+///     ) -> io::Result<()>
+///     where
+///         R: BufRead,
+///     {
+///         for line in reader.lines() {
+///             let line = line?;
+///             match line.trim() {
+///                 "" => self.end_stack(occurrences),
+///                 frame => self.stack.push(frame.to_string()),
+///             }
+///         }
+///         // The input may not end with an empty line.
+///         self.end_stack(occurrences);
+///         Ok(())
+///     }
+///
+///     fn would_end_stack(&mut self, line: &[u8]) -> bool {
+///         line.iter().all(u8::is_ascii_whitespace)
+///     }
+///
+///     fn clone_and_reset_stack_context(&self) -> Self {
+///         Folder {
+///             stack: Vec::new(),
+///             nstacks_per_job: self.nstacks_per_job,
+///             nthreads: self.nthreads,
+///         }
+///     }
+///
+///     fn is_applicable(&mut self, _: &str) -> Option<bool> {
+///         None
+///     }
+///
+///     fn nstacks_per_job(&self) -> usize {
+///         self.nstacks_per_job
+///     }
+///
+///     fn set_nstacks_per_job(&mut self, n: usize) {
+///         self.nstacks_per_job = n;
+///     }
+///
+///     fn nthreads(&self) -> usize {
+///         self.nthreads
+///     }
+///
+///     fn set_nthreads(&mut self, n: usize) {
+///         self.nthreads = n;
+///     }
+/// }
+///
+/// let mut folder = Folder {
+///     stack: Vec::new(),
+///     nstacks_per_job: common::DEFAULT_NSTACKS_PER_JOB,
+///     nthreads: 4,
+/// };
+/// let input = "c\nb\na\n\nb\na\n\nc\nb\na\n";
+/// let mut output = Vec::new();
+/// Collapse::collapse(&mut folder, input.as_bytes(), &mut output)?;
+/// assert_eq!(String::from_utf8(output).unwrap(), "a;b 1\na;b;c 2\n");
+/// # Ok::<(), io::Error>(())
+/// ```
+///
+///   [`Collapse`]: ../trait.Collapse.html
+pub trait CollapseParallel: Send + Sized {
     // *********************************************************** //
     // ********************* REQUIRED METHODS ******************** //
     // *********************************************************** //
@@ -132,28 +249,58 @@ pub trait CollapsePrivate: Send + Sized {
     // ******************** PROVIDED METHODS ********************* //
     // *********************************************************** //
 
-    fn collapse<R, W>(&mut self, mut reader: R, writer: W) -> io::Result<()>
+    /// Collapses the contents of the provided `reader` and writes folded stack lines to the
+    /// provided `writer`, on as many threads as `nthreads` asks for.
+    ///
+    /// This is what [`Collapse::collapse`] does for implementors of this trait; there should be no
+    /// need to override it.
+    ///
+    ///   [`Collapse::collapse`]: ../trait.Collapse.html#tymethod.collapse
+    fn collapse<R, W>(&mut self, reader: R, writer: W) -> io::Result<()>
     where
         R: io::BufRead,
         W: io::Write,
     {
-        let mut occurrences = Occurrences::new(self.nthreads());
-
-        // Consume the header, if any, and do any other pre-processing
-        // that needs to occur.
-        self.pre_process(&mut reader, &mut occurrences)?;
+        fold(self, reader)?.write_and_clear(writer)
+    }
 
-        // Do collapsing.
-        if occurrences.is_concurrent() {
-            self.collapse_multi_threaded(reader, &mut occurrences)?;
-        } else {
-            self.collapse_single_threaded(reader, &mut occurrences)?;
-        }
+    /// Collapses the contents of the provided `reader` into [`FoldedStacks`], on as many threads
+    /// as `nthreads` asks for.
+    ///
+    /// This is what [`Collapse::collapse_to_stacks`] does for implementors of this trait; there
+    /// should be no need to override it.
+    ///
+    ///   [`FoldedStacks`]: ../../folded/struct.FoldedStacks.html
+    ///   [`Collapse::collapse_to_stacks`]: ../trait.Collapse.html#method.collapse_to_stacks
+    fn collapse_to_stacks<R>(&mut self, reader: R) -> io::Result<FoldedStacks>
+    where
+        R: io::BufRead,
+    {
+        Ok(fold(self, reader)?.take_stacks())
+    }
 
-        // Write results.
-        occurrences.write_and_clear(writer)
+    /// Collapses the contents of the provided `reader` into one [`Partition`] per process or
+    /// thread, as described by [`Split`], on as many threads as `nthreads` asks for.
+    ///
+    /// This is what [`Collapse::collapse_to_partitions`] does for implementors of this trait.
+    /// Collapsers that only name processes or threads in their root frames when asked to can
+    /// override it to always do so.
+    ///
+    ///   [`Partition`]: struct.Partition.html
+    ///   [`Split`]: enum.Split.html
+    ///   [`Collapse::collapse_to_partitions`]: ../trait.Collapse.html#method.collapse_to_partitions
+    fn collapse_to_partitions<R>(&mut self, reader: R, split: Split) -> io::Result<Vec<Partition>>
+    where
+        R: io::BufRead,
+    {
+        Ok(fold(self, reader)?.take_partitions(split))
     }
 
+    /// Folds the input that is left after `pre_process` on `nthreads` worker threads, which all
+    /// write to the given (concurrent) `occurrences`.
+    ///
+    /// Called by `collapse` when there is more than one thread to use; there should be no need to
+    /// call or override it directly.
     fn collapse_multi_threaded<R>(
         &mut self,
         mut reader: R,
@@ -324,74 +471,268 @@ pub trait CollapsePrivate: Send + Sized {
     }
 }
 
-/// Occurrences is a HashMap, which uses:
+/// The name [`CollapseParallel`] had while it was internal to inferno.
+#[doc(hidden)]
+pub use self::CollapseParallel as CollapsePrivate;
+
+// Counts the stacks in `reader` with the given collapser.
+pub(crate) fn fold<C, R>(collapser: &mut C, mut reader: R) -> io::Result<Occurrences>
+where
+    C: CollapseParallel,
+    R: io::BufRead,
+{
+    let mut occurrences = Occurrences::new(collapser.nthreads());
+
+    // Consume the header, if any, and do any other pre-processing
+    // that needs to occur.
+    collapser.pre_process(&mut reader, &mut occurrences)?;
+
+    // Do collapsing.
+    if occurrences.is_concurrent() {
+        collapser.collapse_multi_threaded(reader, &mut occurrences)?;
+    } else {
+        collapser.collapse_single_threaded(reader, &mut occurrences)?;
+    }
+
+    Ok(occurrences)
+}
+
+/// The number of times each folded stack occurred.
+///
+/// Clones of a multi-threaded `Occurrences` share their counts, so that every worker thread can
+/// count into the same map.
+#[derive(Clone, Debug)]
+pub struct Occurrences {
+    counts: Counts,
+    rules: Option<Arc<Rules>>,
+    recursion: Option<Recursion>,
+    timestamped: bool,
+    keyed: bool,
+}
+
+/// Counts is a HashMap, which uses:
 /// * Fnv if single-threaded
 /// * CHashMap if multi-threaded
+///
+/// or, for output that must keep the order of the samples (like flame charts), a list in which
+/// only adjacent identical stacks are merged.
 #[derive(Clone, Debug)]
-pub enum Occurrences {
-    SingleThreaded(FnvHashMap<String, usize>),
-    MultiThreaded(Arc<CHashMap<String, usize>>),
+enum Counts {
+    SingleThreaded(FnvHashMap<String, u64>),
+    MultiThreaded(Arc<CHashMap<String, u64>>),
+    Ordered(Vec<(String, u64)>),
 }
 
 impl Occurrences {
-    pub(crate) fn new(nthreads: usize) -> Self {
+    /// Creates an `Occurrences` for collapsing on `nthreads` threads, which is only concurrent
+    /// (and a little slower) if `nthreads` is more than one.
+    ///
+    /// # Panics
+    ///
+    /// If `nthreads` is zero.
+    pub fn new(nthreads: usize) -> Self {
         assert_ne!(nthreads, 0);
         if nthreads == 1 {
             let map = FnvHashMap::with_capacity_and_hasher(
                 CAPACITY_HASHMAP,
                 fnv::FnvBuildHasher::default(),
             );
-            Occurrences::SingleThreaded(map)
+            Occurrences::with_counts(Counts::SingleThreaded(map))
         } else {
             let map = CHashMap::with_capacity(CAPACITY_HASHMAP);
             let arc = Arc::new(map);
-            Occurrences::MultiThreaded(arc)
+            Occurrences::with_counts(Counts::MultiThreaded(arc))
+        }
+    }
+
+    /// Creates an `Occurrences` that writes its stacks out in the order they were inserted, and
+    /// only merges a stack with the one inserted right before it. It is never concurrent.
+    pub fn new_ordered() -> Self {
+        let mut occurrences =
+            Occurrences::with_counts(Counts::Ordered(Vec::with_capacity(CAPACITY_HASHMAP)));
+        occurrences.timestamped = true;
+        occurrences
+    }
+
+    fn with_counts(counts: Counts) -> Self {
+        Occurrences {
+            counts,
+            rules: None,
+            recursion: None,
+            timestamped: false,
+            keyed: false,
+        }
+    }
+
+    // Marks the stacks that are inserted from now on as starting with a key and a space, like the
+    // index of a window or the name of an event, which the rules and recursion folding leave
+    // alone.
+    pub(crate) fn set_keyed(&mut self) {
+        self.keyed = true;
+    }
+
+    /// Applies the given rules to the stacks that are inserted from now on, before any recursion
+    /// is folded. Stacks that the rules drop aren't inserted, and the counts of stacks that end up
+    /// identical are added up.
+    ///
+    /// Clones that were made before this call are not affected.
+    pub fn set_rules(&mut self, rules: &Rules) {
+        self.rules = if rules.is_empty() {
+            None
+        } else {
+            Some(Arc::new(rules.clone()))
+        };
+    }
+
+    /// Folds the recursive calls in the stacks that are inserted from now on, as described by
+    /// [`Recursion`], or stops folding them if `recursion` is `None`. The counts of stacks that
+    /// end up identical are added up.
+    ///
+    /// Clones that were made before this call are not affected.
+    ///
+    ///   [`Recursion`]: ../../folded/enum.Recursion.html
+    pub fn set_collapse_recursion(&mut self, recursion: Option<Recursion>) {
+        self.recursion = recursion;
+    }
+
+    // Applies the rules to the stack of `key` and folds its recursion, if asked to, leaving the
+    // timestamp that the stacks of flame charts (or the key that keyed stacks) start with alone.
+    // Returns `None` if the rules drop the stack.
+    fn transform(&self, key: String) -> Option<String> {
+        if self.rules.is_none() && self.recursion.is_none() {
+            return Some(key);
+        }
+        let stack = if self.keyed {
+            key.find(' ').map(|space| &key[space + 1..]).unwrap_or(&key)
+        } else if self.timestamped {
+            folded::split_timestamp(&key).1
+        } else {
+            &key
+        };
+        let start = key.len() - stack.len();
+        let mut stack = Cow::Borrowed(stack);
+        if let Some(ref rules) = self.rules {
+            stack = match rules.apply(&stack)? {
+                Cow::Borrowed(_) => stack,
+                Cow::Owned(applied) => Cow::Owned(applied),
+            };
+        }
+        if let Some(recursion) = self.recursion {
+            if let Cow::Owned(folded) = folded::collapse_recursion(&stack, recursion) {
+                stack = Cow::Owned(folded);
+            }
+        }
+        match stack {
+            Cow::Borrowed(_) => Some(key),
+            Cow::Owned(stack) => Some(format!("{}{}", &key[..start], stack)),
         }
     }
 
     /// Inserts a key-count pair into the map. If the map did not have this key
     /// present, `None` is returned. If the map did have this key present, the
     /// value is updated, and the old value is returned.
-    pub(crate) fn insert(&mut self, key: String, count: usize) -> Option<usize> {
-        use self::Occurrences::*;
-        match self {
+    ///
+    /// If the stack is dropped by the rules set with [`set_rules`], nothing is inserted and `None`
+    /// is returned.
+    ///
+    ///   [`set_rules`]: #method.set_rules
+    pub fn insert(&mut self, key: String, count: u64) -> Option<u64> {
+        use self::Counts::*;
+        let key = self.transform(key)?;
+        match &mut self.counts {
             SingleThreaded(map) => map.insert(key, count),
             MultiThreaded(arc) => arc.insert(key, count),
+            Ordered(list) => match list.last_mut() {
+                Some((last, value)) if *last == key => Some(mem::replace(value, count)),
+                _ => {
+                    list.push((key, count));
+                    None
+                }
+            },
         }
     }
 
     /// Inserts a key-count pair into the map if the key does not already exist.
     /// If the key does already exist, adds count to the current value of the
     /// existing key.
-    pub(crate) fn insert_or_add(&mut self, key: String, count: usize) {
-        use self::Occurrences::*;
-        match self {
+    pub fn insert_or_add(&mut self, key: String, count: u64) {
+        use self::Counts::*;
+        let key = match self.transform(key) {
+            Some(key) => key,
+            None => return,
+        };
+        match &mut self.counts {
             SingleThreaded(map) => *map.entry(key).or_insert(0) += count,
             MultiThreaded(arc) => arc.upsert(key, || count, |v| *v += count),
+            Ordered(list) => match list.last_mut() {
+                Some((last, value)) if *last == key => *value += count,
+                _ => list.push((key, count)),
+            },
         }
     }
 
-    pub(crate) fn is_concurrent(&self) -> bool {
-        use self::Occurrences::*;
-        match self {
-            SingleThreaded(_) => false,
+    /// Returns whether clones of this `Occurrences` share their counts, so that it can be written
+    /// to from several threads.
+    pub fn is_concurrent(&self) -> bool {
+        use self::Counts::*;
+        match self.counts {
+            SingleThreaded(_) | Ordered(_) => false,
             MultiThreaded(_) => true,
         }
     }
 
-    pub(crate) fn write_and_clear<W>(&mut self, mut writer: W) -> io::Result<()>
+    /// Writes out every stack with its count as a folded stack line, sorted by stack (unless
+    /// created with [`new_ordered`]), and clears the counts.
+    ///
+    /// # Panics
+    ///
+    /// If this `Occurrences` is concurrent and other clones of it are still around.
+    ///
+    ///   [`new_ordered`]: #method.new_ordered
+    pub fn write_and_clear<W>(&mut self, mut writer: W) -> io::Result<()>
     where
         W: io::Write,
     {
-        use self::Occurrences::*;
-        match self {
-            SingleThreaded(ref mut map) => {
-                let mut contents: Vec<_> = map.drain().collect();
-                contents.sort();
-                for (key, value) in contents {
-                    writeln!(writer, "{} {}", key, value)?;
-                }
-            }
+        for (key, value) in self.drain() {
+            writeln!(writer, "{} {}", key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Takes every stack with its count out of this `Occurrences`, in the same order that
+    /// [`write_and_clear`] would write them.
+    ///
+    /// # Panics
+    ///
+    /// If this `Occurrences` is concurrent and other clones of it are still around.
+    ///
+    ///   [`write_and_clear`]: #method.write_and_clear
+    pub fn take_stacks(&mut self) -> FoldedStacks {
+        let mut stacks = FoldedStacks::new();
+        for (key, value) in self.drain() {
+            stacks.push(key.split(';'), value);
+        }
+        stacks
+    }
+
+    /// Takes every stack with its count out of this `Occurrences` like [`take_stacks`], but split
+    /// up by the process or thread that their root frames name, as described by [`Split`].
+    ///
+    /// # Panics
+    ///
+    /// If this `Occurrences` is concurrent and other clones of it are still around.
+    ///
+    ///   [`take_stacks`]: #method.take_stacks
+    ///   [`Split`]: enum.Split.html
+    pub fn take_partitions(&mut self, split: Split) -> Vec<Partition> {
+        Partition::from_stacks(&self.take_stacks(), split)
+    }
+
+    // Takes out the stacks and their counts, sorted by stack unless they are ordered.
+    fn drain(&mut self) -> Vec<(String, u64)> {
+        use self::Counts::*;
+        let mut contents: Vec<_> = match &mut self.counts {
+            SingleThreaded(ref mut map) => map.drain().collect(),
             MultiThreaded(ref mut arc) => {
                 let map = match Arc::get_mut(arc) {
                     Some(map) => map,
@@ -402,14 +743,237 @@ impl Occurrences {
                     ),
                 };
                 let map = mem::replace(map, CHashMap::with_capacity(CAPACITY_HASHMAP));
-                let mut contents: Vec<_> = map.into_iter().collect();
-                contents.sort();
-                for (key, value) in contents {
-                    writeln!(writer, "{} {}", key, value)?;
+                map.into_iter().collect()
+            }
+            Ordered(ref mut list) => return mem::take(list),
+        };
+        contents.sort();
+        contents
+    }
+}
+
+/// How stacks are split up into [`Partition`]s, by the process or thread that their root frames
+/// name.
+///
+/// Root frames have to be named like those of the `perf` collapser with `include_pid` or
+/// `include_tid`, as `comm-pid` or `comm-pid/tid` (e.g., `java-1234/1240`). When splitting by
+/// thread, stacks whose root frames don't have a TID are split up by process, and stacks whose
+/// root frames don't have a PID either are split up by their whole root frame.
+///
+///   [`Partition`]: struct.Partition.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Split {
+    /// One partition per process.
+    Process,
+    /// One partition per thread.
+    Thread,
+}
+
+impl Split {
+    /// Returns the template that [`write_partitions`] names the files of partitions after when
+    /// it isn't given one: `{comm}-{pid}.folded`, or `{comm}-{pid}-{tid}.folded` by thread.
+    ///
+    ///   [`write_partitions`]: fn.write_partitions.html
+    pub fn default_template(self) -> &'static str {
+        match self {
+            Split::Process => "{comm}-{pid}.folded",
+            Split::Thread => "{comm}-{pid}-{tid}.folded",
+        }
+    }
+}
+
+impl FromStr for Split {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "process" => Ok(Split::Process),
+            "thread" => Ok(Split::Thread),
+            unknown => Err(format!("unknown split: {}", unknown)),
+        }
+    }
+}
+
+/// The stacks of one process or thread, as split up by [`Split`].
+///
+///   [`Split`]: enum.Split.html
+#[derive(Clone, Debug)]
+pub struct Partition {
+    /// The command name of the process or thread. If the threads of a process have different
+    /// names, this is the one with the most samples.
+    pub comm: String,
+    /// The PID of the process, if the root frames have one.
+    pub pid: Option<String>,
+    /// The TID of the thread, if the stacks were split up by thread and the root frames have one.
+    pub tid: Option<String>,
+    /// The stacks, with their root frames.
+    pub stacks: FoldedStacks,
+}
+
+impl Partition {
+    /// Splits up `stacks` by the process or thread that their root frames name, as described by
+    /// [`Split`]. Any timestamps that the stacks of flame charts start with are skipped.
+    ///
+    ///   [`Split`]: enum.Split.html
+    pub fn from_stacks(stacks: &FoldedStacks, split: Split) -> Vec<Partition> {
+        // Each partition, by PID or TID, along with the number of samples of each command name.
+        let mut partitions: BTreeMap<String, (Partition, BTreeMap<String, u64>)> = BTreeMap::new();
+        for stack in stacks {
+            let root = match stack.frames().next() {
+                Some(root) => folded::split_timestamp(root).1,
+                None => continue,
+            };
+            let (comm, pid, tid) = task(root);
+            let tid = if split == Split::Thread { tid } else { None };
+            let key = match (pid, tid) {
+                // Thread IDs are only unique within a process for some profilers, like dtrace.
+                (pid, Some(tid)) => format!("tid {}/{}", pid.unwrap_or("?"), tid),
+                (Some(pid), None) => format!("pid {}", pid),
+                (None, None) => format!("root {}", comm),
+            };
+
+            let (partition, comms) = partitions.entry(key).or_insert_with(|| {
+                let partition = Partition {
+                    comm: String::new(),
+                    pid: pid.map(str::to_string),
+                    tid: tid.map(str::to_string),
+                    stacks: FoldedStacks::new(),
+                };
+                (partition, BTreeMap::new())
+            });
+            match stack.original_count() {
+                Some(original) => {
+                    partition
+                        .stacks
+                        .push_differential(stack.frames(), original, stack.count())
                 }
+                None => partition.stacks.push(stack.frames(), stack.count()),
             }
+            *comms.entry(comm.to_string()).or_insert(0) += stack.count();
         }
-        Ok(())
+
+        partitions
+            .into_iter()
+            .map(|(_, (mut partition, comms))| {
+                // The first of the names with the most samples.
+                partition.comm = comms
+                    .into_iter()
+                    .max_by(|(a, a_count), (b, b_count)| a_count.cmp(b_count).then(b.cmp(a)))
+                    .map(|(comm, _)| comm)
+                    .unwrap_or_default();
+                partition
+            })
+            .collect()
+    }
+
+    /// Returns the path that `template` gives this partition, with `{comm}`, `{pid}`, and `{tid}`
+    /// replaced by its command name, PID, and TID (or `unknown`), like `out/java-1234.folded`
+    /// for `out/{comm}-{pid}.folded`. Slashes in them are replaced by underscores.
+    pub fn path(&self, template: &str) -> PathBuf {
+        let clean = |part: &str| part.replace(&['/', '\\'][..], "_");
+        let id = |id: &Option<String>| match id {
+            Some(id) => clean(id),
+            None => "unknown".to_string(),
+        };
+        PathBuf::from(
+            template
+                .replace("{comm}", &clean(&self.comm))
+                .replace("{pid}", &id(&self.pid))
+                .replace("{tid}", &id(&self.tid)),
+        )
+    }
+
+    /// Writes the stacks of this partition to the file that [`path`] gives it for `template`,
+    /// creating its directory if need be, and returns that path.
+    ///
+    /// With `svg`, also draws a flame graph of the stacks into a file next to it with an `.svg`
+    /// extension, subtitled with the command name (and TID) of the partition.
+    ///
+    ///   [`path`]: #method.path
+    pub fn write(&self, template: &str, svg: bool) -> io::Result<PathBuf> {
+        let path = self.path(template);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut writer = io::BufWriter::new(File::create(&path)?);
+        self.stacks.write_to(&mut writer)?;
+        writer.flush()?;
+
+        if svg {
+            let mut options = flamegraph::Options {
+                subtitle: Some(match self.tid {
+                    Some(ref tid) => format!("{} (TID {})", self.comm, tid),
+                    None => self.comm.clone(),
+                }),
+                ..Default::default()
+            };
+            let writer = io::BufWriter::new(File::create(path.with_extension("svg"))?);
+            flamegraph::from_folded_stacks(&mut options, &self.stacks, writer).map_err(
+                |e| match e {
+                    quick_xml::Error::Io(e) => e,
+                    e => io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)),
+                },
+            )?;
+        }
+        Ok(path)
+    }
+}
+
+/// Collapses the contents of the provided file (or of STDIN if `infile` is `None`) with `folder`
+/// into one [`Partition`] per process or thread, as described by [`Split`], and writes each of
+/// them to a file of its own with [`Partition::write`].
+///
+/// The files are named after `template`, or after [`Split::default_template`] if it is `None`.
+///
+///   [`Partition`]: struct.Partition.html
+///   [`Partition::write`]: struct.Partition.html#method.write
+///   [`Split`]: enum.Split.html
+///   [`Split::default_template`]: enum.Split.html#method.default_template
+pub fn write_partitions<C, P>(
+    folder: &mut C,
+    infile: Option<P>,
+    split: Split,
+    template: Option<&str>,
+    svg: bool,
+) -> io::Result<()>
+where
+    C: Collapse,
+    P: AsRef<Path>,
+{
+    let template = template.unwrap_or_else(|| split.default_template());
+    let partitions = match infile {
+        Some(ref path) => {
+            let file = File::open(path)?;
+            let reader = io::BufReader::with_capacity(CAPACITY_READER, file);
+            folder.collapse_to_partitions(reader, split)?
+        }
+        None => {
+            let stdio = io::stdin();
+            let stdio_guard = stdio.lock();
+            let reader = io::BufReader::with_capacity(CAPACITY_READER, stdio_guard);
+            folder.collapse_to_partitions(reader, split)?
+        }
+    };
+    for partition in partitions {
+        partition.write(template, svg)?;
+    }
+    Ok(())
+}
+
+// Splits a root frame named like `comm-pid/tid` or `comm-pid` into its command name, PID, and TID.
+// A PID of `?`, which is what `perf` collapses events without one to, counts as none.
+fn task(root: &str) -> (&str, Option<&str>, Option<&str>) {
+    let is_id = |id: &str| id == "?" || (!id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()));
+    let (process, tid) = match root.rfind('/') {
+        Some(slash) if is_id(&root[slash + 1..]) => (&root[..slash], Some(&root[slash + 1..])),
+        _ => (root, None),
+    };
+    match process.rfind('-') {
+        Some(dash) if is_id(&process[dash + 1..]) => {
+            let pid = &process[dash + 1..];
+            (&process[..dash], Some(pid).filter(|&pid| pid != "?"), tid)
+        }
+        _ => (root, None, None),
     }
 }
 
@@ -558,7 +1122,7 @@ pub(crate) mod testing {
 
     pub(crate) fn test_collapse_multi<C, P>(folder: &mut C, inputs: &[P]) -> io::Result<()>
     where
-        C: Collapse + CollapsePrivate,
+        C: Collapse + CollapseParallel,
         P: AsRef<Path>,
     {
         const MAX_THREADS: usize = 16;
@@ -589,7 +1153,7 @@ pub(crate) mod testing {
 
     pub(crate) fn bench_nstacks<C, P>(folder: &mut C, inputs: &[P]) -> io::Result<()>
     where
-        C: CollapsePrivate,
+        C: CollapseParallel,
         P: AsRef<Path>,
     {
         const MIN_LINES: usize = 2000;
@@ -618,7 +1182,7 @@ pub(crate) mod testing {
                 stdout: &mut io::StdoutLock,
             ) -> io::Result<Option<Self>>
             where
-                C: CollapsePrivate,
+                C: CollapseParallel,
             {
                 let default = folder.nstacks_per_job();
 
@@ -738,7 +1302,6 @@ pub(crate) mod testing {
 
         Ok(())
     }
-
 }
 
 #[cfg(test)]
@@ -775,6 +1338,41 @@ mod tests {
         }
     }
 
+    #[test]
+    fn splits_root_frames_into_tasks() {
+        assert_eq!(
+            super::task("java-1234/1240"),
+            ("java", Some("1234"), Some("1240"))
+        );
+        assert_eq!(super::task("my-app-99"), ("my-app", Some("99"), None));
+        assert_eq!(super::task("perf-?/5"), ("perf", None, Some("5")));
+        assert_eq!(super::task("main"), ("main", None, None));
+        assert_eq!(super::task("a/b-c"), ("a/b-c", None, None));
+    }
+
+    #[test]
+    fn partitions_by_thread_within_process() {
+        let stacks = super::FoldedStacks::parse(
+            "sshd-1/1;main 2\nbash-2/1;main 3\nbash-2/1;wait 1\nbash-2;main 4\nmain 5\n",
+        )
+        .unwrap();
+        let partitions = super::Partition::from_stacks(&stacks, super::Split::Thread);
+        let paths: Vec<_> = partitions
+            .iter()
+            .map(|p| p.path("{comm}-{pid}-{tid}").to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                "bash-2-unknown",
+                "main-unknown-unknown",
+                "sshd-1-1",
+                "bash-2-1"
+            ]
+        );
+        assert_eq!(partitions[3].stacks.total(), 4);
+    }
+
     #[test]
     fn fix_partially_demangled_rust_symbols() {
         t!(
@@ -4,11 +4,28 @@ use std::io::{self, prelude::*};
 
 use log::warn;
 
-use crate::collapse::common::{self, CollapsePrivate, Occurrences};
+use crate::collapse::common::{self, CollapseParallel, Occurrences};
+use crate::folded::Recursion;
+use crate::rules::Rules;
 
 /// `dtrace` folder configuration options.
 #[derive(Clone, Debug)]
 pub struct Options {
+    /// Fold recursive calls into one frame per function, as described by [`Recursion`].
+    ///
+    /// Default is `None`, which keeps them.
+    ///
+    ///   [`Recursion`]: ../../folded/enum.Recursion.html
+    pub collapse_recursion: Option<Recursion>,
+
+    /// [`Rules`] to filter and rewrite the stacks with, which are applied before any recursion is
+    /// collapsed.
+    ///
+    /// Default is no rules.
+    ///
+    ///   [`Rules`]: ../../rules/struct.Rules.html
+    pub rules: Rules,
+
     /// Demangle function names.
     ///
     /// Default is `false`.
@@ -28,6 +45,8 @@ pub struct Options {
 impl Default for Options {
     fn default() -> Self {
         Self {
+            collapse_recursion: None,
+            rules: Rules::new(),
             demangle: false,
             includeoffset: false,
             nthreads: *common::DEFAULT_NTHREADS,
@@ -39,6 +58,13 @@ impl Default for Options {
 ///
 /// To construct one, either use `dtrace::Folder::default()` or create an [`Options`] and use
 /// `dtrace::Folder::from(options)`.
+///
+/// Stacks that were aggregated along with the `execname` and `pid` (and `tid`) of their process,
+/// as with `@[execname, pid, ustack()] = count();`, start with a root frame named like
+/// `sshd-1234` (or `sshd-1234/2`), which is what [`Collapse::collapse_to_partitions`] splits them
+/// up by.
+///
+///   [`Collapse::collapse_to_partitions`]: ../trait.Collapse.html#method.collapse_to_partitions
 pub struct Folder {
     /// Vector for processing java stuff
     cache_inlines: Vec<String>,
@@ -52,6 +78,9 @@ pub struct Folder {
     /// Keep track of stack string size while we consume a stack
     stack_str_size: usize,
 
+    /// Root frame of the current stack, made from the aggregation keys that came before it.
+    root: Option<String>,
+
     opt: Options,
 }
 
@@ -65,6 +94,7 @@ impl From<Options> for Folder {
             nstacks_per_job: common::DEFAULT_NSTACKS_PER_JOB,
             stack: VecDeque::default(),
             stack_str_size: 0,
+            root: None,
             opt,
         }
     }
@@ -76,11 +106,14 @@ impl Default for Folder {
     }
 }
 
-impl CollapsePrivate for Folder {
-    fn pre_process<R>(&mut self, reader: &mut R, _: &mut Occurrences) -> io::Result<()>
+impl CollapseParallel for Folder {
+    fn pre_process<R>(&mut self, reader: &mut R, occurrences: &mut Occurrences) -> io::Result<()>
     where
         R: io::BufRead,
     {
+        occurrences.set_collapse_recursion(self.opt.collapse_recursion);
+        occurrences.set_rules(&self.opt.rules);
+
         // Consumer the header...
         let mut line = String::new();
         loop {
@@ -113,7 +146,7 @@ impl CollapsePrivate for Folder {
             let line = line.trim();
             if line.is_empty() {
                 continue;
-            } else if let Ok(count) = line.parse::<usize>() {
+            } else if let Ok(count) = line.parse::<u64>() {
                 self.on_stack_end(count, occurrences);
             } else {
                 self.on_stack_line(line);
@@ -124,7 +157,7 @@ impl CollapsePrivate for Folder {
         // did not terminate at the end of a stack; rather, it terminated in
         // the middle of a stack. In this case, we consider the input data
         // invalid and return an io::Error to the user.
-        if !self.stack.is_empty() || self.stack_str_size != 0 {
+        if !self.stack.is_empty() || self.stack_str_size != 0 || self.root.is_some() {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "Input data ends in the middle of a stack.",
@@ -158,6 +191,8 @@ impl CollapsePrivate for Folder {
                     || (line.starts_with("0x") && usize::from_str_radix(&line[2..], 16).is_ok())
                 {
                     found_stack_line = true;
+                } else if Self::key_root(line).is_some() {
+                    // The aggregation keys before a stack
                 } else {
                     // This is not a stack or count line
                     return Some(false);
@@ -239,6 +274,7 @@ impl CollapsePrivate for Folder {
             nstacks_per_job: self.nstacks_per_job,
             stack: VecDeque::default(),
             stack_str_size: 0,
+            root: None,
             opt: self.opt.clone(),
         }
     }
@@ -275,6 +311,34 @@ impl Folder {
         }
     }
 
+    // Turns the line of aggregation keys that dtrace prints before a stack that was aggregated
+    // along with them, like `sshd    1234` for `@[execname, pid, ustack()]` or `sshd  1234  2` for
+    // `@[execname, pid, tid, ustack()]`, into the root frame of the stack, like `sshd-1234` or
+    // `sshd-1234/2`. Returns `None` for stack lines.
+    fn key_root(line: &str) -> Option<String> {
+        if line.contains('`') || line.starts_with("0x") {
+            return None;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let nids = words
+            .iter()
+            .rev()
+            .take_while(|word| word.bytes().all(|b| b.is_ascii_digit()))
+            .count();
+        if nids == 0 || nids > 2 || nids == words.len() {
+            return None;
+        }
+        let ids = &words[words.len() - nids..];
+        let mut root = words[..words.len() - nids].join("_");
+        root.push('-');
+        root.push_str(ids[0]);
+        if let Some(tid) = ids.get(1) {
+            root.push('/');
+            root.push_str(tid);
+        }
+        Some(root)
+    }
+
     fn remove_offset(line: &str) -> (bool, bool, bool, &str) {
         let mut has_inlines = false;
         let mut could_be_cpp = false;
@@ -345,6 +409,14 @@ impl Folder {
     //     unix`sys_syscall+0x10e
     //       1
     fn on_stack_line(&mut self, line: &str) {
+        if self.stack.is_empty() && self.root.is_none() {
+            if let Some(root) = Self::key_root(line) {
+                self.stack_str_size += root.len() + 1;
+                self.root = Some(root);
+                return;
+            }
+        }
+
         let (has_inlines, could_be_cpp, has_semicolon, mut frame) = if self.opt.includeoffset {
             (true, true, true, line)
         } else {
@@ -388,13 +460,18 @@ impl Folder {
         }
     }
 
-    fn on_stack_end(&mut self, count: usize, occurrences: &mut Occurrences) {
+    fn on_stack_end(&mut self, count: u64, occurrences: &mut Occurrences) {
         // allocate a string that is long enough to hold the entire stack string
         let mut stack_str = String::with_capacity(self.stack_str_size);
 
         let mut first = true;
+        // add the root made from the aggregation keys (if any)
+        if let Some(root) = self.root.take() {
+            stack_str.push_str(&root);
+            first = false;
+        }
         // add the other stack entries (if any)
-        let last = self.stack.len() - 1;
+        let last = self.stack.len().saturating_sub(1);
         for (i, e) in self.stack.drain(..).enumerate() {
             if first {
                 first = false
@@ -546,6 +623,18 @@ mod tests {
         loop {
             let nstacks_per_job = rng.gen_range(1, 500 + 1);
             let options = Options {
+                collapse_recursion: match rng.gen_range(0, 3) {
+                    0 => None,
+                    1 => Some(Recursion::Direct),
+                    _ => Some(Recursion::Indirect),
+                },
+                rules: if rng.gen() {
+                    "frame '^\\[unknown\\]$' drop-frame\nframe '0x[0-9a-f]+' rename 0x..."
+                        .parse()
+                        .unwrap()
+                } else {
+                    Rules::new()
+                },
                 demangle: rng.gen(),
                 includeoffset: rng.gen(),
                 nthreads: rng.gen_range(2, 32 + 1),
@@ -586,4 +675,23 @@ mod tests {
         }
     }
 
+    #[test]
+    fn turns_aggregation_keys_into_roots() {
+        assert_eq!(
+            Folder::key_root("sshd    1234"),
+            Some("sshd-1234".to_string())
+        );
+        assert_eq!(
+            Folder::key_root("  java  4321     7"),
+            Some("java-4321/7".to_string())
+        );
+        assert_eq!(
+            Folder::key_root("Web Content  99"),
+            Some("Web_Content-99".to_string())
+        );
+        assert_eq!(Folder::key_root("  test`main"), None);
+        assert_eq!(Folder::key_root("  0xffff0001"), None);
+        assert_eq!(Folder::key_root("  12"), None);
+        assert_eq!(Folder::key_root("a 1 2 3"), None);
+    }
 }
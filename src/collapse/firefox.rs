@@ -0,0 +1,271 @@
+use std::io;
+
+use fnv::FnvHashMap;
+use indexmap::IndexMap;
+use log::warn;
+use serde::Deserialize;
+
+use crate::collapse::common::Occurrences;
+use crate::collapse::Collapse;
+use crate::folded::{FoldedStacks, Recursion};
+use crate::rules::Rules;
+
+/// `firefox` folder configuration options.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Only collapse the thread with this index.
+    ///
+    /// Default is `None`, which collapses all threads.
+    pub thread: Option<usize>,
+
+    /// Use the name of each thread (e.g., `GeckoMain`) as the root frame of its stacks.
+    ///
+    /// Default is `false`.
+    pub include_thread_name: bool,
+
+    /// Fold recursive calls into one frame per function, as described by [`Recursion`].
+    ///
+    /// Default is `None`, which keeps them.
+    ///
+    ///   [`Recursion`]: ../../folded/enum.Recursion.html
+    pub collapse_recursion: Option<Recursion>,
+
+    /// [`Rules`] to filter and rewrite the stacks with, which are applied before any recursion is
+    /// collapsed.
+    ///
+    /// Default is no rules.
+    ///
+    ///   [`Rules`]: ../../rules/struct.Rules.html
+    pub rules: Rules,
+}
+
+/// A stack collapser for profiles saved by the [Firefox Profiler] in its "processed" format.
+///
+/// Each sample is counted once, unless the profile carries sample weights. Weights in
+/// milliseconds (as used by tracing profiles) are reported in microseconds, and all other
+/// weights (like bytes) are reported as is.
+///
+/// To construct one, either use `firefox::Folder::default()` or create an [`Options`] and use
+/// `firefox::Folder::from(options)`.
+///
+///   [Firefox Profiler]: https://profiler.firefox.com/
+#[derive(Clone, Default)]
+pub struct Folder {
+    opt: Options,
+}
+
+impl From<Options> for Folder {
+    fn from(opt: Options) -> Self {
+        Folder { opt }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Profile {
+    threads: Vec<Thread>,
+    #[serde(default)]
+    shared: Option<Shared>,
+}
+
+// Newer versions of the format share the strings between threads.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Shared {
+    #[serde(default)]
+    string_array: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Thread {
+    #[serde(default)]
+    name: String,
+    samples: SamplesTable,
+    stack_table: StackTable,
+    frame_table: FrameTable,
+    func_table: FuncTable,
+    #[serde(default)]
+    string_array: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SamplesTable {
+    stack: Vec<Option<usize>>,
+    #[serde(default)]
+    weight: Option<Vec<Option<f64>>>,
+    #[serde(default)]
+    weight_type: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StackTable {
+    frame: Vec<usize>,
+    prefix: Vec<Option<usize>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FrameTable {
+    func: Vec<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FuncTable {
+    name: Vec<usize>,
+}
+
+impl Collapse for Folder {
+    fn collapse<R, W>(&mut self, reader: R, writer: W) -> io::Result<()>
+    where
+        R: io::BufRead,
+        W: io::Write,
+    {
+        self.collapse_to_occurrences(reader)?
+            .write_and_clear(writer)
+    }
+
+    fn collapse_to_stacks<R>(&mut self, reader: R) -> io::Result<FoldedStacks>
+    where
+        R: io::BufRead,
+    {
+        Ok(self.collapse_to_occurrences(reader)?.take_stacks())
+    }
+
+    /// Check for a JSON object with `meta` and `threads`.
+    fn is_applicable(&mut self, input: &str) -> Option<bool> {
+        let input = input.trim_start();
+        if input.is_empty() {
+            return None;
+        }
+        if !input.starts_with('{') {
+            return Some(false);
+        }
+        if input.contains("\"meta\"")
+            && input.contains("\"threads\"")
+            && input.contains("\"stackTable\"")
+        {
+            Some(true)
+        } else {
+            None
+        }
+    }
+}
+
+impl Folder {
+    // Collapses the contents of the provided `reader` into the occurrences of each stack, which
+    // `collapse` writes out and `collapse_to_stacks` returns.
+    fn collapse_to_occurrences<R>(&mut self, mut reader: R) -> io::Result<Occurrences>
+    where
+        R: io::BufRead,
+    {
+        let mut input = String::new();
+        reader.read_to_string(&mut input)?;
+        let profile: Profile = serde_json::from_str(&input).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid Firefox profile: {}", e),
+            )
+        })?;
+
+        if let Some(index) = self.opt.thread {
+            if index >= profile.threads.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Thread {} does not exist; the profile has {} threads",
+                        index,
+                        profile.threads.len()
+                    ),
+                ));
+            }
+        }
+
+        let shared_strings = profile
+            .shared
+            .as_ref()
+            .and_then(|shared| shared.string_array.as_ref());
+
+        // Weights may be fractional, so sum them up before rounding.
+        let mut weights: IndexMap<String, f64> = IndexMap::new();
+        for (i, thread) in profile.threads.iter().enumerate() {
+            if self.opt.thread.map(|index| index != i).unwrap_or(false) {
+                continue;
+            }
+            let strings = match thread.string_array.as_ref().or(shared_strings) {
+                Some(strings) => strings,
+                None => {
+                    warn!("Thread {} has no string table", i);
+                    continue;
+                }
+            };
+
+            let scale = match thread.samples.weight_type {
+                Some(ref ty) if ty == "tracing-ms" => 1_000.0,
+                _ => 1.0,
+            };
+
+            let mut stacks: FnvHashMap<usize, String> = FnvHashMap::default();
+            for (j, stack) in thread.samples.stack.iter().enumerate() {
+                // Samples without a stack were taken while the thread was idle.
+                let stack = match *stack {
+                    Some(stack) => stack,
+                    None => continue,
+                };
+                let weight = thread
+                    .samples
+                    .weight
+                    .as_ref()
+                    .and_then(|w| w.get(j).cloned())
+                    .unwrap_or(Some(1.0))
+                    .unwrap_or(1.0);
+                let stack = stacks
+                    .entry(stack)
+                    .or_insert_with(|| self.stack(thread, strings, stack))
+                    .clone();
+                *weights.entry(stack).or_insert(0.0) += weight * scale;
+            }
+        }
+
+        let mut occurrences = Occurrences::new(1);
+        occurrences.set_collapse_recursion(self.opt.collapse_recursion);
+        occurrences.set_rules(&self.opt.rules);
+        for (stack, weight) in weights {
+            let count = weight.round();
+            if !stack.is_empty() && count >= 1.0 {
+                occurrences.insert_or_add(stack, count as u64);
+            }
+        }
+        Ok(occurrences)
+    }
+
+    /// Builds the stack ending in the given entry of the thread's stack table, root first.
+    fn stack(&self, thread: &Thread, strings: &[String], mut index: usize) -> String {
+        let mut frames = Vec::new();
+        loop {
+            let name = thread
+                .stack_table
+                .frame
+                .get(index)
+                .and_then(|&frame| thread.frame_table.func.get(frame))
+                .and_then(|&func| thread.func_table.name.get(func))
+                .and_then(|&name| strings.get(name));
+            match name {
+                Some(name) => frames.push(name.replace(';', ":")),
+                None => {
+                    warn!("Stack {} refers to a frame that doesn't exist", index);
+                    frames.push("[unknown]".to_string());
+                }
+            }
+            index = match thread.stack_table.prefix.get(index) {
+                // a malformed profile could have a cycle, which would keep us here forever
+                Some(&Some(prefix)) if frames.len() <= thread.stack_table.frame.len() => prefix,
+                _ => break,
+            };
+        }
+        if self.opt.include_thread_name && !thread.name.is_empty() {
+            frames.push(thread.name.replace(';', ":"));
+        }
+        frames.reverse();
+        frames.join(";")
+    }
+}
@@ -3,7 +3,11 @@ use std::io::{self, Cursor};
 
 use log::{error, info};
 
-use crate::collapse::{self, dtrace, perf, sample, Collapse};
+use crate::collapse::{
+    self, bpftrace, chrome, dtrace, firefox, perf, pprof, sample, speedscope, xdebug, Collapse,
+};
+use crate::folded::{FoldedStacks, Recursion};
+use crate::rules::Rules;
 
 const LINES_PER_ITERATION: usize = 10;
 
@@ -14,12 +18,29 @@ pub struct Options {
     ///
     /// Default is the number of logical cores on your machine.
     pub nthreads: usize,
+
+    /// Fold recursive calls into one frame per function, as described by [`Recursion`].
+    ///
+    /// Default is `None`, which keeps them.
+    ///
+    ///   [`Recursion`]: ../../folded/enum.Recursion.html
+    pub collapse_recursion: Option<Recursion>,
+
+    /// [`Rules`] to filter and rewrite the stacks with, which are applied before any recursion is
+    /// collapsed.
+    ///
+    /// Default is no rules.
+    ///
+    ///   [`Rules`]: ../../rules/struct.Rules.html
+    pub rules: Rules,
 }
 
 impl Default for Options {
     fn default() -> Self {
         Self {
-            nthreads: *collapse::DEFAULT_NTHREADS,
+            nthreads: *collapse::common::DEFAULT_NTHREADS,
+            collapse_recursion: None,
+            rules: Rules::new(),
         }
     }
 }
@@ -47,26 +68,118 @@ impl Default for Folder {
 }
 
 impl Collapse for Folder {
-    fn collapse<R, W>(&mut self, mut reader: R, writer: W) -> io::Result<()>
+    fn collapse<R, W>(&mut self, reader: R, writer: W) -> io::Result<()>
+    where
+        R: io::BufRead,
+        W: io::Write,
+    {
+        self.guess(reader, Output::Lines(writer)).map(|_| ())
+    }
+
+    fn collapse_to_stacks<R>(&mut self, reader: R) -> io::Result<FoldedStacks>
+    where
+        R: io::BufRead,
+    {
+        let stacks = self.guess(reader, Output::<io::Sink>::Stacks)?;
+        Ok(stacks.unwrap_or_default())
+    }
+
+    fn is_applicable(&mut self, _line: &str) -> Option<bool> {
+        unreachable!()
+    }
+}
+
+// Where the collapser that is picked writes its output to: folded stack lines to a writer, or
+// stacks that are returned.
+enum Output<W> {
+    Lines(W),
+    Stacks,
+}
+
+impl<W: io::Write> Output<W> {
+    fn collapse<C, R>(self, collapser: &mut C, reader: R) -> io::Result<Option<FoldedStacks>>
+    where
+        C: Collapse,
+        R: io::BufRead,
+    {
+        match self {
+            Output::Lines(writer) => collapser.collapse(reader, writer).map(|()| None),
+            Output::Stacks => collapser.collapse_to_stacks(reader).map(Some),
+        }
+    }
+}
+
+impl Folder {
+    // Picks the collapser for the input, and collapses it to `output`. Returns the stacks if
+    // they were asked for and a collapser was found.
+    fn guess<R, W>(&mut self, mut reader: R, output: Output<W>) -> io::Result<Option<FoldedStacks>>
     where
         R: io::BufRead,
         W: io::Write,
     {
+        let collapse_recursion = self.opt.collapse_recursion;
         let mut dtrace = {
             let mut options = dtrace::Options::default();
             options.nthreads = self.opt.nthreads;
+            options.collapse_recursion = collapse_recursion;
+            options.rules = self.opt.rules.clone();
             dtrace::Folder::from(options)
         };
         let mut perf = {
             let mut options = perf::Options::default();
             options.nthreads = self.opt.nthreads;
+            options.collapse_recursion = collapse_recursion;
+            options.rules = self.opt.rules.clone();
             perf::Folder::from(options)
         };
-        let mut sample = sample::Folder::default();
+        let mut sample = sample::Folder::from(sample::Options {
+            collapse_recursion,
+            rules: self.opt.rules.clone(),
+            ..Default::default()
+        });
+        let mut bpftrace = {
+            let mut options = bpftrace::Options::default();
+            options.nthreads = self.opt.nthreads;
+            options.collapse_recursion = collapse_recursion;
+            options.rules = self.opt.rules.clone();
+            bpftrace::Folder::from(options)
+        };
+        let mut xdebug = xdebug::Folder::from(xdebug::Options {
+            collapse_recursion,
+            rules: self.opt.rules.clone(),
+            ..Default::default()
+        });
+        let mut chrome = chrome::Folder::from(chrome::Options {
+            collapse_recursion,
+            rules: self.opt.rules.clone(),
+            ..Default::default()
+        });
+        let mut speedscope = speedscope::Folder::from(speedscope::Options {
+            collapse_recursion,
+            rules: self.opt.rules.clone(),
+            ..Default::default()
+        });
+        let mut firefox = firefox::Folder::from(firefox::Options {
+            collapse_recursion,
+            rules: self.opt.rules.clone(),
+            ..Default::default()
+        });
+
+        // pprof profiles are binary, so they have to be recognized before we start reading the
+        // input as lines of text.
+        let mut pprof = pprof::Folder::from(pprof::Options {
+            collapse_recursion,
+            rules: self.opt.rules.clone(),
+            ..Default::default()
+        });
+        if crate::pprof::looks_like_profile(reader.fill_buf()?) {
+            info!("Using pprof collapser");
+            return output.collapse(&mut pprof, reader);
+        }
 
         // Each Collapse impl gets its own flag in this array.
         // It gets set to true when the impl has been ruled out.
-        let mut not_applicable = [false; 3];
+        let mut not_applicable = [false; 8];
 
         let mut buffer = String::new();
         loop {
@@ -89,7 +202,7 @@ impl Collapse for Folder {
                                 // We found a collapser that works! Let's use it.
                                 info!("Using {} collapser", stringify!($collapse));
                                 let cursor = Cursor::new(buffer).chain(reader);
-                                return $collapse.collapse(cursor, writer);
+                                return output.collapse(&mut $collapse, cursor);
                             }
                             None => (), // We're not yet sure if this collapser is appropriate
                         }
@@ -99,6 +212,11 @@ impl Collapse for Folder {
             try_collapse_impl!(perf, 0);
             try_collapse_impl!(dtrace, 1);
             try_collapse_impl!(sample, 2);
+            try_collapse_impl!(bpftrace, 3);
+            try_collapse_impl!(xdebug, 4);
+            try_collapse_impl!(chrome, 5);
+            try_collapse_impl!(speedscope, 6);
+            try_collapse_impl!(firefox, 7);
 
             if eof {
                 break;
@@ -107,10 +225,6 @@ impl Collapse for Folder {
 
         error!("No applicable collapse implementation found for input");
 
-        Ok(())
-    }
-
-    fn is_applicable(&mut self, _line: &str) -> Option<bool> {
-        unreachable!()
+        Ok(None)
     }
 }
@@ -1,3 +1,18 @@
+/// Stack collapsing for the output of [`bpftrace`](https://github.com/iovisor/bpftrace).
+///
+/// See the [crate-level documentation] for details.
+///
+///   [crate-level documentation]: ../../index.html
+pub mod bpftrace;
+
+/// Stack collapsing for CPU profiles from [Chrome DevTools](https://developers.google.com/web/tools/chrome-devtools/rendering-tools/js-execution)
+/// and `node --cpu-prof`.
+///
+/// See the [crate-level documentation] for details.
+///
+///   [crate-level documentation]: ../../index.html
+pub mod chrome;
+
 /// Stack collapsing for the output of [`dtrace`](https://www.joyent.com/dtrace).
 ///
 /// See the [crate-level documentation] for details.
@@ -5,6 +20,13 @@
 ///   [crate-level documentation]: ../../index.html
 pub mod dtrace;
 
+/// Stack collapsing for profiles saved by the [Firefox Profiler](https://profiler.firefox.com/).
+///
+/// See the [crate-level documentation] for details.
+///
+///   [crate-level documentation]: ../../index.html
+pub mod firefox;
+
 /// Attempts to use whichever Collapse implementation is appropriate for a given input
 pub mod guess;
 
@@ -15,6 +37,13 @@ pub mod guess;
 ///   [crate-level documentation]: ../../index.html
 pub mod perf;
 
+/// Stack collapsing for profiles in the [pprof](https://github.com/google/pprof) format.
+///
+/// See the [crate-level documentation] for details.
+///
+///   [crate-level documentation]: ../../index.html
+pub mod pprof;
+
 /// Stack collapsing for the output of [`sample`](https://gist.github.com/loderunner/36724cc9ee8db66db305#profiling-with-sample) on macOS.
 ///
 /// See the [crate-level documentation] for details.
@@ -22,18 +51,36 @@ pub mod perf;
 ///   [crate-level documentation]: ../../index.html
 pub mod sample;
 
-pub(crate) mod common;
+/// Stack collapsing for files in the [speedscope](https://www.speedscope.app/) format.
+///
+/// See the [crate-level documentation] for details.
+///
+///   [crate-level documentation]: ../../index.html
+pub mod speedscope;
 
-// DEFAULT_NTHREADS is public because we use it in the help text of the binaries,
-// but it doesn't need to be exposed to library users, hence #[doc(hidden)].
-#[doc(hidden)]
-pub use self::common::DEFAULT_NTHREADS;
+/// Stack collapsing for function traces written by [xdebug](https://xdebug.org/docs/trace).
+///
+/// See the [crate-level documentation] for details.
+///
+///   [crate-level documentation]: ../../index.html
+pub mod xdebug;
+
+/// Building blocks for collapsers, including the framework that lets collapsers (like those for
+/// `perf` and `dtrace`) fold their input on many threads. Collapsers outside of this crate can use
+/// it too.
+pub mod common;
+
+/// Finding the functions at the addresses that profilers couldn't find symbols for, in the debug
+/// information of their modules. Only available with the `symbolize` feature.
+#[cfg(feature = "symbolize")]
+pub mod symbolize;
 
 use std::fs::File;
 use std::io;
 use std::path::Path;
 
-use self::common::{CollapsePrivate, CAPACITY_READER};
+use self::common::{CollapseParallel, Partition, Split, CAPACITY_READER};
+use crate::folded::FoldedStacks;
 
 /// The abstract behavior of stack collapsing.
 ///
@@ -76,6 +123,50 @@ pub trait Collapse {
         }
     }
 
+    /// Collapses the contents of the provided `reader` into [`FoldedStacks`], for library users
+    /// who want to work with the stacks (or draw a flame graph from them with
+    /// [`flamegraph::from_folded_stacks`]) without going through folded stack lines.
+    ///
+    /// The stacks are the same, and in the same order, as the lines that [`collapse`] writes.
+    ///
+    /// The collapsers in this crate all build the stacks directly. The default implementation,
+    /// for collapsers that only implement [`collapse`], goes through text instead: it writes
+    /// folded stack lines with [`collapse`] and parses them back with [`FoldedStacks::parse`].
+    ///
+    ///   [`FoldedStacks`]: ../folded/struct.FoldedStacks.html
+    ///   [`FoldedStacks::parse`]: ../folded/struct.FoldedStacks.html#method.parse
+    ///   [`flamegraph::from_folded_stacks`]: ../flamegraph/fn.from_folded_stacks.html
+    ///   [`collapse`]: #tymethod.collapse
+    fn collapse_to_stacks<R>(&mut self, reader: R) -> io::Result<FoldedStacks>
+    where
+        R: io::BufRead,
+    {
+        let mut lines = Vec::new();
+        self.collapse(reader, &mut lines)?;
+        let lines =
+            String::from_utf8(lines).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(FoldedStacks::parse(&lines)?)
+    }
+
+    /// Collapses the contents of the provided `reader` into one [`Partition`] per process or
+    /// thread, as described by [`Split`], for writing out (or drawing) each of them on its own.
+    ///
+    /// The root frames of the stacks have to name their processes or threads, like those of the
+    /// `perf` collapser (which always does so here) and those of the `dtrace` collapser for
+    /// stacks that were aggregated with their `execname` and `pid`.
+    ///
+    ///   [`Partition`]: common/struct.Partition.html
+    ///   [`Split`]: common/enum.Split.html
+    fn collapse_to_partitions<R>(&mut self, reader: R, split: Split) -> io::Result<Vec<Partition>>
+    where
+        R: io::BufRead,
+    {
+        Ok(Partition::from_stacks(
+            &self.collapse_to_stacks(reader)?,
+            split,
+        ))
+    }
+
     /// Returns whether this implementation is appropriate for the given input.
     ///
     /// - `None` means "not sure -- need more input"
@@ -86,17 +177,31 @@ pub trait Collapse {
 
 impl<T> Collapse for T
 where
-    T: CollapsePrivate,
+    T: CollapseParallel,
 {
     fn collapse<R, W>(&mut self, reader: R, writer: W) -> io::Result<()>
     where
         R: io::BufRead,
         W: io::Write,
     {
-        <Self as CollapsePrivate>::collapse(self, reader, writer)
+        <Self as CollapseParallel>::collapse(self, reader, writer)
+    }
+
+    fn collapse_to_stacks<R>(&mut self, reader: R) -> io::Result<FoldedStacks>
+    where
+        R: io::BufRead,
+    {
+        <Self as CollapseParallel>::collapse_to_stacks(self, reader)
+    }
+
+    fn collapse_to_partitions<R>(&mut self, reader: R, split: Split) -> io::Result<Vec<Partition>>
+    where
+        R: io::BufRead,
+    {
+        <Self as CollapseParallel>::collapse_to_partitions(self, reader, split)
     }
 
     fn is_applicable(&mut self, input: &str) -> Option<bool> {
-        <Self as CollapsePrivate>::is_applicable(self, input)
+        <Self as CollapseParallel>::is_applicable(self, input)
     }
 }
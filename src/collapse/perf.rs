@@ -1,12 +1,18 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
 use std::io::{self, BufRead};
+use std::str::FromStr;
+use std::time::Duration;
 
+use fnv::FnvHashMap;
+use regex::Regex;
 use symbolic_demangle::demangle;
 
-use crate::collapse::common::{self, CollapsePrivate, Occurrences};
-
-const TIDY_GENERIC: bool = true;
-const TIDY_JAVA: bool = true;
+use crate::collapse::common::{self, CollapseParallel, Occurrences, Partition, Split};
+#[cfg(feature = "symbolize")]
+use crate::collapse::symbolize::{Function, Symbolizer};
+use crate::folded::{FoldedStacks, Recursion};
+use crate::rules::Rules;
 
 mod logging {
     use log::{info, warn};
@@ -22,6 +28,27 @@ mod logging {
     pub(super) fn weird_stack_line(line: &str) {
         warn!("Weird stack line: {}", line);
     }
+
+    pub(super) fn no_period(line: &str) {
+        warn!(
+            "Event line has no period, so events without one are counted as one: {}",
+            line
+        );
+    }
+
+    pub(super) fn no_timestamp(line: &str) {
+        warn!(
+            "Event line has no timestamp, so events without one are skipped: {}",
+            line
+        );
+    }
+
+    pub(super) fn no_event(line: &str) {
+        warn!(
+            "Event line has no event name, so events without one are skipped: {}",
+            line
+        );
+    }
 }
 
 /// `perf` folder configuration options.
@@ -37,22 +64,94 @@ pub struct Options {
     /// Default is `false`.
     pub annotate_kernel: bool,
 
+    /// Fold recursive calls into one frame per function, as described by [`Recursion`].
+    ///
+    /// Default is `None`, which keeps them.
+    ///
+    ///   [`Recursion`]: ../../folded/enum.Recursion.html
+    pub collapse_recursion: Option<Recursion>,
+
+    /// [`Rules`] to filter and rewrite the stacks with, which are applied before any recursion is
+    /// collapsed.
+    ///
+    /// Default is no rules.
+    ///
+    ///   [`Rules`]: ../../rules/struct.Rules.html
+    pub rules: Rules,
+
     /// Demangle function names.
     ///
     /// Default is `false`.
     pub demangle: bool,
 
     /// Only consider samples of the given event type (see `perf list`). If this option is
-    /// set to `None`, it will be set to the first encountered event type.
+    /// set to `None`, it will be set to the first encountered event type. To collapse the samples
+    /// of every event type, each on their own, use [`Folder::collapse_events`] instead.
     ///
     /// Default is `None`.
+    ///
+    ///   [`Folder::collapse_events`]: struct.Folder.html#method.collapse_events
     pub event_filter: Option<String>,
 
+    /// Write every sample on a line of its own, in the order the samples were taken and prefixed
+    /// with its timestamp (e.g., `4794564.109216 java;start_thread;run 1`), instead of counting
+    /// identical stacks. This is the input that [`flamegraph::Options::flame_chart`] expects.
+    ///
+    /// Only adjacent samples with the same timestamp and stack are merged, so this always
+    /// collapses on a single thread.
+    ///
+    /// Default is `false`.
+    ///
+    ///   [`flamegraph::Options::flame_chart`]: ../../flamegraph/struct.Options.html#structfield.flame_chart
+    pub flame_chart: bool,
+
+    /// Weight each stack by the period of its event, the number that `perf script` prints
+    /// before the event name (e.g., the `257597` of `72.176760: 257597 cycles:`), rather than
+    /// by the number of samples. This is the number of events that a sample stands for, like
+    /// the cycles since the previous sample, or the nanoseconds a thread was blocked for in
+    /// `perf sched` and off-CPU data. Events without a period count as one.
+    ///
+    /// In flame charts with timestamps, samples are still placed (and sized) by their
+    /// timestamps, and the periods only decide where gaps between samples are.
+    ///
+    /// Default is `false`.
+    pub weight_by_period: bool,
+
+    /// Turn scheduler events into off-CPU stacks, as described by [`OffCpu`], instead of
+    /// counting samples. All other events are skipped, as is `event_filter`.
+    ///
+    /// Since blocked threads have to be followed from one event to the next, this always
+    /// collapses on a single thread.
+    ///
+    /// Default is `None`.
+    ///
+    ///   [`OffCpu`]: enum.OffCpu.html
+    pub off_cpu: Option<OffCpu>,
+
+    /// Only consider the events from this time on. Events without a timestamp are skipped
+    /// whenever `start` or `end` is set.
+    ///
+    /// Default is `None`, which starts at the first event.
+    pub start: Option<Time>,
+
+    /// Only consider the events before this time.
+    ///
+    /// Default is `None`, which ends after the last event.
+    pub end: Option<Time>,
+
     /// Include raw addresses (e.g., `0xbfff0836`) where symbols can't be found.
     ///
     /// Default is `false`.
     pub include_addrs: bool,
 
+    /// Find the functions at the addresses that `perf` couldn't find symbols for (the frames of
+    /// `[unknown]` functions) in the debug information of their modules. Only available with the
+    /// `symbolize` feature.
+    ///
+    /// Default is `None`.
+    #[cfg(feature = "symbolize")]
+    pub symbolizer: Option<Symbolizer>,
+
     /// Include PID in the root frame. If disabled, the root frame is given the name of the
     /// profiled process.
     ///
@@ -68,6 +167,64 @@ pub struct Options {
     ///
     /// Default is the number of logical cores on your machine.
     pub nthreads: usize,
+
+    /// Only keep the events of the processes with these PIDs. Events without a PID, which
+    /// `perf script` only prints when asked to, are skipped when this is set.
+    ///
+    /// Default is empty, which keeps the events of all processes.
+    pub only_pids: Vec<u32>,
+
+    /// Skip the events of the processes with these PIDs.
+    ///
+    /// Default is empty.
+    pub exclude_pids: Vec<u32>,
+
+    /// Only keep the events of the threads with these TIDs.
+    ///
+    /// Default is empty, which keeps the events of all threads.
+    pub only_tids: Vec<u32>,
+
+    /// Skip the events of the threads with these TIDs.
+    ///
+    /// Default is empty.
+    pub exclude_tids: Vec<u32>,
+
+    /// Only keep the events on these CPUs, the number in brackets (like the `2` of `[002]`) that
+    /// `perf script` prints after the TID. Events without a CPU are skipped when this is set.
+    ///
+    /// Default is empty, which keeps the events on all CPUs.
+    pub only_cpus: Vec<u32>,
+
+    /// Skip the events on these CPUs.
+    ///
+    /// Default is empty.
+    pub exclude_cpus: Vec<u32>,
+
+    /// Only keep the events of processes whose whole command name (like `java` or
+    /// `V8 WorkerThread`) matches this regular expression, like the process scopes of
+    /// [`TidyStrategy::for_process`]. So `java|jsvc` keeps `java` and `jsvc`, but not `javac`.
+    ///
+    /// Default is `None`, which keeps the events of all command names.
+    ///
+    ///   [`TidyStrategy::for_process`]: struct.TidyStrategy.html#method.for_process
+    pub only_comm: Option<Regex>,
+
+    /// Skip the events of processes whose whole command name matches this regular expression.
+    ///
+    /// Default is `None`.
+    pub exclude_comm: Option<Regex>,
+
+    /// How to tidy up function names, as a list of [`TidyStrategy`]s that are applied to each
+    /// function in order.
+    ///
+    /// Default is [`TidyStrategy::defaults`], which tidies up all functions with
+    /// [`Tidy::Generic`], and those of processes named `java` with [`Tidy::Java`] as well.
+    ///
+    ///   [`TidyStrategy`]: struct.TidyStrategy.html
+    ///   [`TidyStrategy::defaults`]: struct.TidyStrategy.html#method.defaults
+    ///   [`Tidy::Generic`]: enum.Tidy.html#variant.Generic
+    ///   [`Tidy::Java`]: enum.Tidy.html#variant.Java
+    pub tidy: Vec<TidyStrategy>,
 }
 
 impl Default for Options {
@@ -75,20 +232,311 @@ impl Default for Options {
         Self {
             annotate_jit: false,
             annotate_kernel: false,
+            collapse_recursion: None,
+            rules: Rules::new(),
             demangle: false,
             event_filter: None,
+            flame_chart: false,
+            weight_by_period: false,
+            off_cpu: None,
+            start: None,
+            end: None,
             include_addrs: false,
+            #[cfg(feature = "symbolize")]
+            symbolizer: None,
             include_pid: false,
             include_tid: false,
             nthreads: *common::DEFAULT_NTHREADS,
+            only_pids: Vec::new(),
+            exclude_pids: Vec::new(),
+            only_tids: Vec::new(),
+            exclude_tids: Vec::new(),
+            only_cpus: Vec::new(),
+            exclude_cpus: Vec::new(),
+            only_comm: None,
+            exclude_comm: None,
+            tidy: TidyStrategy::defaults(),
+        }
+    }
+}
+
+/// How scheduler events are turned into off-CPU stacks, which show where threads were blocked
+/// rather than where they were running.
+///
+/// The input has to have the `sched:sched_switch` events of the profile, with their stacks and
+/// timestamps, like the output of:
+///
+/// ```console
+/// # perf record -e sched:sched_switch -e sched:sched_wakeup -a -g -- sleep 10
+/// $ perf script
+/// ```
+///
+/// When a thread is switched out, its stack is kept until it is switched back in, and then
+/// counted with the microseconds in between. If `sched:sched_stat_sleep`,
+/// `sched:sched_stat_blocked`, or `sched:sched_stat_iowait` events are recorded as well, the
+/// delay they report is counted instead, which leaves out the time that the thread then waited
+/// for a CPU. Threads that are still switched out at the end of the input aren't counted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OffCpu {
+    /// The stacks that threads blocked in, weighted by the microseconds they were blocked for.
+    Blocked,
+    /// Like `Blocked`, but with the stack of the thread that woke the blocked thread up (as
+    /// recorded by its `sched:sched_wakeup` or `sched:sched_waking` event) on top, after a `--`
+    /// frame and from its leaf down to its process name. This is the input for wakeup flame
+    /// graphs, drawn with [`MultiPalette::Wakeup`].
+    ///
+    ///   [`MultiPalette::Wakeup`]: ../../flamegraph/color/enum.MultiPalette.html#variant.Wakeup
+    Wakers,
+}
+
+/// A point in time in `perf script` output, for [`Options::start`] and [`Options::end`].
+///
+/// Parses from a timestamp as `perf script` prints it, like `4794564.109216`, or from a number of
+/// seconds after a `+`, like `+2.5`, for a time relative to the first event.
+///
+///   [`Options::start`]: struct.Options.html#structfield.start
+///   [`Options::end`]: struct.Options.html#structfield.end
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Time {
+    /// A timestamp as `perf script` prints it, which is usually the time since boot.
+    Absolute(Duration),
+    /// The time since the first event of the input.
+    SinceFirstEvent(Duration),
+}
+
+impl Time {
+    // The timestamp in nanoseconds, given that of the first event.
+    fn nanos(self, first_event: u64) -> u64 {
+        match self {
+            Time::Absolute(time) => duration_nanos(time),
+            Time::SinceFirstEvent(time) => first_event + duration_nanos(time),
+        }
+    }
+}
+
+impl FromStr for Time {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (relative, time) = match s.chars().next() {
+            Some('+') => (true, &s[1..]),
+            _ => (false, s),
+        };
+        let nanos = if time.chars().all(|c| c.is_ascii_digit() || c == '.') {
+            parse_nanos(time)
+        } else {
+            None
+        };
+        match nanos {
+            Some(nanos) if relative => Ok(Time::SinceFirstEvent(Duration::from_nanos(nanos))),
+            Some(nanos) => Ok(Time::Absolute(Duration::from_nanos(nanos))),
+            None => Err(format!("invalid time: {}", s)),
+        }
+    }
+}
+
+/// The stacks of one window of time, as collapsed by [`Folder::collapse_windows`].
+///
+///   [`Folder::collapse_windows`]: struct.Folder.html#method.collapse_windows
+#[derive(Clone, Debug)]
+pub struct Window {
+    /// When the window starts, as a `perf script` timestamp.
+    pub start: Duration,
+    /// When the window ends, as a `perf script` timestamp. Events at this time belong to the next
+    /// window.
+    pub end: Duration,
+    /// The folded stacks of the events in the window.
+    pub stacks: FoldedStacks,
+}
+
+/// A way of tidying up the function names in `perf script` output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tidy {
+    /// Replace semicolons with colons, and remove argument lists, like
+    /// `parse(char const*, int)` becoming `parse`. Go methods like `net/http.(*Client).Do` and
+    /// C++ anonymous namespaces are left alone.
+    Generic,
+    /// Remove the `L` in front of Java class names, so that (along with [`Tidy::Generic`])
+    /// `Ljava/lang/Thread;.run()V` becomes `java/lang/Thread:.run`.
+    ///
+    ///   [`Tidy::Generic`]: #variant.Generic
+    Java,
+    /// Elide C++ template arguments, so that `std::vector<int, std::allocator<int> >::push_back`
+    /// becomes `std::vector<...>::push_back`. Put this before [`Tidy::Generic`], or parentheses
+    /// in template arguments, like those of `std::function<void (int)>`, will cut names short.
+    ///
+    ///   [`Tidy::Generic`]: #variant.Generic
+    CppTemplates,
+    /// Remove the hash at the end of Rust symbols, along with any `.llvm.` suffix after it, so
+    /// that `server::handle::h3f0c6a8e1d9b2c47.llvm.8312` becomes `server::handle`. Trailing
+    /// hashes on their own are already removed when symbols are demangled, but not those that
+    /// are followed by a suffix, or those of inlined functions.
+    RustHash,
+    /// Remove the path of Go packages, so that `github.com/user/project/store.(*DB).Get` becomes
+    /// `store.(*DB).Get`.
+    GoPackages,
+}
+
+impl Tidy {
+    fn apply(self, func: String) -> String {
+        match self {
+            Tidy::Generic => tidy_generic(func),
+            Tidy::Java => tidy_java(func),
+            Tidy::CppTemplates => tidy_cpp_templates(func),
+            Tidy::RustHash => tidy_rust_hash(func),
+            Tidy::GoPackages => tidy_go_packages(func),
+        }
+    }
+}
+
+impl FromStr for Tidy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "generic" => Ok(Tidy::Generic),
+            "java" => Ok(Tidy::Java),
+            "cpp-templates" => Ok(Tidy::CppTemplates),
+            "rust-hash" => Ok(Tidy::RustHash),
+            "go-packages" => Ok(Tidy::GoPackages),
+            unknown => Err(format!("unknown tidy strategy: {}", unknown)),
+        }
+    }
+}
+
+impl fmt::Display for Tidy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Tidy::Generic => "generic",
+            Tidy::Java => "java",
+            Tidy::CppTemplates => "cpp-templates",
+            Tidy::RustHash => "rust-hash",
+            Tidy::GoPackages => "go-packages",
+        })
+    }
+}
+
+/// The functions that a [`TidyStrategy`] applies to.
+///
+/// The regular expressions have to match the whole name, so `java` matches `java` but not
+/// `javac`.
+///
+///   [`TidyStrategy`]: struct.TidyStrategy.html
+#[derive(Clone, Debug)]
+pub enum TidyScope {
+    /// All functions.
+    All,
+    /// The functions of processes whose command name matches the regular expression, like `java`.
+    /// This is the name at the root of the stacks (with spaces replaced by underscores), but
+    /// without the PID or TID that `include_pid` or `include_tid` add to it.
+    Process(Regex),
+    /// The functions in modules whose path matches the regular expression, like `/tmp/perf-.*`
+    /// for the functions of a JIT.
+    Module(Regex),
+}
+
+/// A way of tidying up function names, and the functions to do so for.
+#[derive(Clone, Debug)]
+pub struct TidyStrategy {
+    /// How to tidy up the function names.
+    pub tidy: Tidy,
+
+    /// Which functions to tidy up.
+    pub scope: TidyScope,
+}
+
+impl TidyStrategy {
+    /// Tidies up all functions with `tidy`.
+    pub fn new(tidy: Tidy) -> Self {
+        TidyStrategy {
+            tidy,
+            scope: TidyScope::All,
         }
     }
+
+    /// Tidies up the functions of processes whose whole name matches `process`.
+    pub fn for_process(tidy: Tidy, process: &str) -> Result<Self, regex::Error> {
+        Ok(TidyStrategy {
+            tidy,
+            scope: TidyScope::Process(whole_match(process)?),
+        })
+    }
+
+    /// Tidies up the functions in modules whose whole path matches `module`.
+    pub fn for_module(tidy: Tidy, module: &str) -> Result<Self, regex::Error> {
+        Ok(TidyStrategy {
+            tidy,
+            scope: TidyScope::Module(whole_match(module)?),
+        })
+    }
+
+    /// The strategies that `perf::Options` uses by default, which tidy up names the way
+    /// `stackcollapse-perf.pl` does: [`Tidy::Generic`] for all functions, and [`Tidy::Java`]
+    /// for processes named `java`.
+    ///
+    ///   [`Tidy::Generic`]: enum.Tidy.html#variant.Generic
+    ///   [`Tidy::Java`]: enum.Tidy.html#variant.Java
+    pub fn defaults() -> Vec<Self> {
+        vec![
+            TidyStrategy::new(Tidy::Generic),
+            TidyStrategy::for_process(Tidy::Java, "java").unwrap(),
+        ]
+    }
+
+    fn applies_to_process(&self, process: &str) -> bool {
+        match self.scope {
+            TidyScope::Process(ref regex) => regex.is_match(process),
+            _ => true,
+        }
+    }
+
+    fn applies_to_module(&self, module: &str) -> bool {
+        match self.scope {
+            TidyScope::Module(ref regex) => regex.is_match(module),
+            _ => true,
+        }
+    }
+}
+
+/// Parses strategies like `java`, `java:process=java|jsvc`, or `java:module=/tmp/perf-.*`.
+impl FromStr for TidyStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let tidy = parts.next().unwrap_or_default().parse()?;
+        let scope = match parts.next() {
+            None => return Ok(TidyStrategy::new(tidy)),
+            Some(scope) => scope,
+        };
+        let mut parts = scope.splitn(2, '=');
+        let strategy = match (parts.next(), parts.next()) {
+            (Some("process"), Some(process)) => TidyStrategy::for_process(tidy, process),
+            (Some("module"), Some(module)) => TidyStrategy::for_module(tidy, module),
+            _ => {
+                return Err(format!(
+                    "unknown tidy scope (expected process= or module=): {}",
+                    scope
+                ))
+            }
+        };
+        strategy.map_err(|e| e.to_string())
+    }
+}
+
+fn whole_match(regex: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{})$", regex))
 }
 
 /// A stack collapser for the output of `perf script`.
 ///
 /// To construct one, either use `perf::Folder::default()` or create an [`Options`] and use
 /// `perf::Folder::from(options)`.
+///
+/// When collapsing into partitions with [`Collapse::collapse_to_partitions`], the root frames
+/// always include the PIDs (and the TIDs, when splitting by thread) to split by.
+///
+///   [`Collapse::collapse_to_partitions`]: ../trait.Collapse.html#method.collapse_to_partitions
 pub struct Folder {
     // State...
     /// General String cache that can be used while processing lines. Currently only used to keep
@@ -105,6 +553,10 @@ pub struct Folder {
     ///   different event types, such as instructions and cycles, would produce misleading results.
     event_filter: Option<String>,
 
+    /// Whether the current event is filtered out by its process, thread, CPU, or comm. Only used
+    /// with `off_cpu`, since other events that are filtered out skip their stack.
+    filtered_out: bool,
+
     /// All lines until the next empty line are stack lines.
     in_event: bool,
 
@@ -116,30 +568,83 @@ pub struct Folder {
     /// Called pname after original stackcollapse-perf source.
     pname: String,
 
+    /// Period of the current event, which its stack is counted with when weighting by period.
+    period: u64,
+
     /// Skip all stack lines in this event.
     skip_stack: bool,
 
+    /// Whether each of the tidy strategies applies to the current process, by index.
+    tidy_process: Vec<bool>,
+
+    /// Scheduler bookkeeping for off-CPU stacks. Only used with `off_cpu`.
+    sched: Sched,
+
     /// Function entries on the stack in this entry thus far.
     stack: VecDeque<String>,
 
+    /// Timestamp of the current event. Only kept when producing flame chart output, or the index
+    /// of the window of the current event when collapsing windows, or the name of the current
+    /// event when collapsing by event.
+    timestamp: String,
+
+    /// Timestamp of the first event, in nanoseconds. Only kept when `start`, `end`, or windows
+    /// need it.
+    first_timestamp: Option<u64>,
+
+    /// Length of the windows being collapsed, in nanoseconds.
+    window: Option<u64>,
+
+    /// How the stacks being collapsed are split up into partitions.
+    split: Option<Split>,
+
+    /// Whether the stacks being collapsed are kept apart by their event, rather than filtered by
+    /// `event_filter`.
+    by_event: bool,
+
+    /// Whether an event without a period has been warned about.
+    warned_no_period: bool,
+
+    /// Whether an event without a timestamp has been warned about.
+    warned_no_timestamp: bool,
+
+    /// Whether an event without an event name has been warned about.
+    warned_no_event: bool,
+
     // Options...
     opt: Options,
 }
 
 impl From<Options> for Folder {
     fn from(mut opt: Options) -> Self {
-        if opt.nthreads == 0 {
+        if opt.nthreads == 0 || opt.flame_chart || opt.off_cpu.is_some() {
             opt.nthreads = 1;
         }
         opt.include_pid = opt.include_pid || opt.include_tid;
+        // Command names are matched as a whole, like the process scopes of tidy strategies.
+        let whole = |regex: Regex| whole_match(regex.as_str()).unwrap_or(regex);
+        opt.only_comm = opt.only_comm.map(whole);
+        opt.exclude_comm = opt.exclude_comm.map(whole);
         Self {
             cache_line: Vec::default(),
             event_filter: opt.event_filter.clone(),
+            filtered_out: false,
             in_event: false,
             nstacks_per_job: common::DEFAULT_NSTACKS_PER_JOB,
             pname: String::default(),
+            period: 1,
             skip_stack: false,
+            sched: Sched::default(),
             stack: VecDeque::default(),
+            tidy_process: Vec::default(),
+            timestamp: String::default(),
+            first_timestamp: None,
+            window: None,
+            split: None,
+            by_event: false,
+            warned_no_period: false,
+            warned_no_timestamp: false,
+            warned_no_event: false,
             opt,
         }
     }
@@ -151,26 +656,45 @@ impl Default for Folder {
     }
 }
 
-impl CollapsePrivate for Folder {
+impl CollapseParallel for Folder {
     fn pre_process<R>(&mut self, reader: &mut R, occurrences: &mut Occurrences) -> io::Result<()>
     where
         R: io::BufRead,
     {
-        // If user has provided an event filter, do nothing...
-        if self.event_filter.is_some() {
+        // Flame charts need the samples in their original order.
+        if self.opt.flame_chart {
+            *occurrences = Occurrences::new_ordered();
+        } else if self.window.is_some() || self.by_event {
+            // Stacks start with the index of their window, or the name of their event.
+            occurrences.set_keyed();
+        }
+        occurrences.set_collapse_recursion(self.opt.collapse_recursion);
+        occurrences.set_rules(&self.opt.rules);
+        self.first_timestamp = None;
+
+        // If user has provided an event filter, or all scheduler events (or all events) are
+        // needed, do nothing...
+        if self.opt.off_cpu.is_some()
+            || ((self.event_filter.is_some() || self.by_event) && !self.needs_first_timestamp())
+        {
             return Ok(());
         }
 
         // Otherwise, we don't know what the event filter should be; so process
         // the first stack to figure it out (the worker threads need this
         // information to get started). Only read one stack, however, as we would
-        // like the remaining stacks to be processed on the worker threads.
+        // like the remaining stacks to be processed on the worker threads. The
+        // workers may also need the timestamp of the first event, so keep reading
+        // until there is one.
         let mut line_buffer = String::new();
-        self.process_single_stack(&mut line_buffer, reader, occurrences)?;
+        let mut eof = self.process_single_stack(&mut line_buffer, reader, occurrences)?;
+        while !eof && self.first_timestamp.is_none() && self.needs_first_timestamp() {
+            eof = self.process_single_stack(&mut line_buffer, reader, occurrences)?;
+        }
 
         // If we didn't find an event filter, there is something wrong with
         // our processing code.
-        assert!(self.event_filter.is_some());
+        assert!(self.event_filter.is_some() || self.by_event);
 
         Ok(())
     }
@@ -194,6 +718,17 @@ impl CollapsePrivate for Folder {
         Ok(())
     }
 
+    fn collapse_to_partitions<R>(&mut self, reader: R, split: Split) -> io::Result<Vec<Partition>>
+    where
+        R: io::BufRead,
+    {
+        // The root frames have to name the processes or threads to split by.
+        self.split = Some(split);
+        let occurrences = common::fold(self, reader);
+        self.split = None;
+        Ok(occurrences?.take_partitions(split))
+    }
+
     fn is_applicable(&mut self, input: &str) -> Option<bool> {
         // Check if the input has an event line followed by a stack line.
 
@@ -243,11 +778,23 @@ impl CollapsePrivate for Folder {
         Self {
             cache_line: self.cache_line.clone(),
             event_filter: self.event_filter.clone(),
+            filtered_out: false,
             in_event: false,
             nstacks_per_job: self.nstacks_per_job,
             pname: String::new(),
+            period: 1,
             skip_stack: false,
+            sched: Sched::default(),
             stack: VecDeque::default(),
+            tidy_process: Vec::new(),
+            timestamp: String::new(),
+            first_timestamp: self.first_timestamp,
+            window: self.window,
+            split: self.split,
+            by_event: self.by_event,
+            warned_no_period: self.warned_no_period,
+            warned_no_timestamp: self.warned_no_timestamp,
+            warned_no_event: self.warned_no_event,
             opt: self.opt.clone(),
         }
     }
@@ -270,6 +817,112 @@ impl CollapsePrivate for Folder {
 }
 
 impl Folder {
+    /// Collapses the contents of the provided `reader` into [`FoldedStacks`] like
+    /// [`Collapse::collapse_to_stacks`], but with a separate set of stacks for each window of time
+    /// of the given `length`. The first window starts at [`Options::start`], or at the first event
+    /// that isn't filtered out, and the windows without any stacks are left out.
+    ///
+    /// Events without a timestamp are skipped.
+    ///
+    /// # Errors
+    ///
+    /// If `length` is zero, or if this folder writes flame charts, which are ordered by time
+    /// already.
+    ///
+    ///   [`FoldedStacks`]: ../../folded/struct.FoldedStacks.html
+    ///   [`Collapse::collapse_to_stacks`]: ../trait.Collapse.html#method.collapse_to_stacks
+    ///   [`Options::start`]: struct.Options.html#structfield.start
+    pub fn collapse_windows<R>(&mut self, reader: R, length: Duration) -> io::Result<Vec<Window>>
+    where
+        R: io::BufRead,
+    {
+        let length = duration_nanos(length);
+        if length == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "windows must be longer than zero",
+            ));
+        }
+        if self.opt.flame_chart {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "flame charts can't be split into windows",
+            ));
+        }
+
+        self.window = Some(length);
+        let stacks = <Self as CollapseParallel>::collapse_to_stacks(self, reader);
+        self.window = None;
+        let stacks = stacks?;
+        let windows: BTreeMap<u64, FoldedStacks> = split_keys(&stacks)
+            .into_iter()
+            .filter_map(|(index, stacks)| Some((index.parse().ok()?, stacks)))
+            .collect();
+
+        let first = match self.first_timestamp {
+            Some(first) => first,
+            None => return Ok(Vec::new()),
+        };
+        let origin = self
+            .opt
+            .start
+            .map(|start| start.nanos(first))
+            .unwrap_or(first);
+        Ok(windows
+            .into_iter()
+            .map(|(index, stacks)| {
+                let start = origin + index * length;
+                Window {
+                    start: Duration::from_nanos(start),
+                    end: Duration::from_nanos(start + length),
+                    stacks,
+                }
+            })
+            .collect())
+    }
+
+    /// Collapses the contents of the provided `reader` into [`FoldedStacks`] like
+    /// [`Collapse::collapse_to_stacks`], but with a separate set of stacks for each type of event,
+    /// like `cycles` or `instructions`, by the name of the event. Rather than only collapsing the
+    /// events of [`Options::event_filter`], or of the first type of event in the input, this
+    /// collapses the events of every type in one pass.
+    ///
+    /// Events without an event name are skipped.
+    ///
+    /// # Errors
+    ///
+    /// If this folder writes flame charts, or off-CPU stacks, which are made from scheduler events
+    /// of several types.
+    ///
+    ///   [`FoldedStacks`]: ../../folded/struct.FoldedStacks.html
+    ///   [`Collapse::collapse_to_stacks`]: ../trait.Collapse.html#method.collapse_to_stacks
+    ///   [`Options::event_filter`]: struct.Options.html#structfield.event_filter
+    pub fn collapse_events<R>(&mut self, reader: R) -> io::Result<BTreeMap<String, FoldedStacks>>
+    where
+        R: io::BufRead,
+    {
+        if self.opt.flame_chart {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "flame charts can't be split by event",
+            ));
+        }
+        if self.opt.off_cpu.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "off-CPU stacks can't be split by event",
+            ));
+        }
+
+        self.by_event = true;
+        let stacks = <Self as CollapseParallel>::collapse_to_stacks(self, reader);
+        self.by_event = false;
+        Ok(split_keys(&stacks?)
+            .into_iter()
+            .map(|(event, stacks)| (event.to_string(), stacks))
+            .collect())
+    }
+
     /// Processes a stack. On success, returns `true` if at end of data; `false` otherwise.
     fn process_single_stack<R>(
         &mut self,
@@ -350,7 +1003,35 @@ impl Folder {
         self.in_event = true;
 
         if let Some((comm, pid, tid)) = Self::event_line_parts(line) {
-            if let Some(event) = line.rsplitn(2, ' ').next() {
+            // Times since the first event are since the first event of the input, whether or not
+            // it is filtered out.
+            if self.first_timestamp.is_none()
+                && (self.opt.start.is_some() || self.opt.end.is_some() || self.window.is_some())
+            {
+                self.first_timestamp = Self::event_timestamp(line).and_then(parse_nanos);
+            }
+
+            if self.opt.off_cpu.is_some() {
+                if !self.sched.on_event_line(line, tid) {
+                    self.skip_stack = true;
+                    return;
+                }
+            } else if self.by_event {
+                // Tracepoints print their fields after the event name, like
+                // `sched:sched_switch: prev_comm=...`, so the name isn't always the last word.
+                self.timestamp.clear();
+                match event_trace(line) {
+                    Some((_, event, _)) if !event.is_empty() => self.timestamp.push_str(event),
+                    _ => {
+                        if !self.warned_no_event {
+                            logging::no_event(line);
+                            self.warned_no_event = true;
+                        }
+                        self.skip_stack = true;
+                        return;
+                    }
+                }
+            } else if let Some(event) = line.rsplitn(2, ' ').next() {
                 if event.ends_with(':') {
                     let event = &event[..(event.len() - 1)];
 
@@ -369,23 +1050,184 @@ impl Folder {
                 }
             }
 
+            if !self.keeps_task(line, comm, pid, tid) {
+                // Off-CPU stacks come from the events of other threads, so those of filtered out
+                // threads still have to be followed.
+                if self.opt.off_cpu.is_none() {
+                    self.skip_stack = true;
+                    return;
+                }
+                self.filtered_out = true;
+            }
+
+            if (self.opt.start.is_some() || self.opt.end.is_some() || self.window.is_some())
+                && !self.on_event_time(line)
+            {
+                self.skip_stack = true;
+                return;
+            }
+
+            if self.opt.flame_chart {
+                self.timestamp.clear();
+                if let Some(timestamp) = Self::event_timestamp(line) {
+                    self.timestamp.push_str(timestamp);
+                }
+            }
+
+            if self.opt.weight_by_period {
+                self.period = match Self::event_period(line) {
+                    Some(period) => period,
+                    None => {
+                        if !self.warned_no_period {
+                            logging::no_period(line);
+                            self.warned_no_period = true;
+                        }
+                        1
+                    }
+                };
+            }
+
             // XXX: re-use existing memory in pname if possible
             self.pname = comm.replace(' ', "_");
-            if self.opt.include_tid {
+            let comm_len = self.pname.len();
+            if self.opt.include_tid || self.split == Some(Split::Thread) {
                 self.pname.push_str("-");
                 self.pname.push_str(pid);
                 self.pname.push_str("/");
                 self.pname.push_str(tid);
-            } else if self.opt.include_pid {
+            } else if self.opt.include_pid || self.split.is_some() {
                 self.pname.push_str("-");
                 self.pname.push_str(pid);
             }
+
+            // Tidy strategies are scoped to the command name, without any PID or TID, so that
+            // which functions they tidy up doesn't depend on how the root frames are labelled.
+            let comm = &self.pname[..comm_len];
+            self.tidy_process.clear();
+            self.tidy_process
+                .extend(self.opt.tidy.iter().map(|t| t.applies_to_process(comm)));
         } else {
             logging::weird_event_line(line);
             self.in_event = false;
         }
     }
 
+    // Whether the process, thread, CPU, and command name of an event pass the filters of the
+    // options.
+    fn keeps_task(&self, line: &str, comm: &str, pid: &str, tid: &str) -> bool {
+        let opt = &self.opt;
+        let keeps_id = |only: &[u32], exclude: &[u32], id: Option<u32>| match id {
+            Some(id) => (only.is_empty() || only.contains(&id)) && !exclude.contains(&id),
+            None => only.is_empty(),
+        };
+        if (!opt.only_pids.is_empty() || !opt.exclude_pids.is_empty())
+            && !keeps_id(&opt.only_pids, &opt.exclude_pids, pid.parse().ok())
+        {
+            return false;
+        }
+        if (!opt.only_tids.is_empty() || !opt.exclude_tids.is_empty())
+            && !keeps_id(&opt.only_tids, &opt.exclude_tids, tid.parse().ok())
+        {
+            return false;
+        }
+        if (!opt.only_cpus.is_empty() || !opt.exclude_cpus.is_empty())
+            && !keeps_id(
+                &opt.only_cpus,
+                &opt.exclude_cpus,
+                Self::event_cpu(line, tid),
+            )
+        {
+            return false;
+        }
+        if let Some(ref only) = opt.only_comm {
+            if !only.is_match(comm) {
+                return false;
+            }
+        }
+        if let Some(ref exclude) = opt.exclude_comm {
+            if exclude.is_match(comm) {
+                return false;
+            }
+        }
+        true
+    }
+
+    // The CPU is the bracketed number right after the TID, like the `2` of `[002]`.
+    fn event_cpu(line: &str, tid: &str) -> Option<u32> {
+        let tid_end = tid.as_ptr() as usize - line.as_ptr() as usize + tid.len();
+        let rest = line[tid_end..].trim_start();
+        if !rest.starts_with('[') {
+            return None;
+        }
+        let end = rest.find(']')?;
+        rest[1..end].parse().ok()
+    }
+
+    // Whether the timestamp of the first event is needed to place the others in time.
+    fn needs_first_timestamp(&self) -> bool {
+        let relative = |time| matches!(time, Some(Time::SinceFirstEvent(_)));
+        relative(self.opt.start)
+            || relative(self.opt.end)
+            || (self.window.is_some() && self.opt.start.is_none())
+    }
+
+    // Checks that the event is between `start` and `end`, and keeps the index of its window as
+    // its timestamp when collapsing windows. Returns `false` if the event should be skipped.
+    fn on_event_time(&mut self, line: &str) -> bool {
+        let time = match Self::event_timestamp(line).and_then(parse_nanos) {
+            Some(time) => time,
+            None => {
+                if !self.warned_no_timestamp {
+                    logging::no_timestamp(line);
+                    self.warned_no_timestamp = true;
+                }
+                return false;
+            }
+        };
+        let first = *self.first_timestamp.get_or_insert(time);
+
+        let start = self.opt.start.map(|start| start.nanos(first));
+        if let Some(start) = start {
+            if time < start {
+                return false;
+            }
+        }
+        if let Some(end) = self.opt.end {
+            if time >= end.nanos(first) {
+                return false;
+            }
+        }
+
+        if let Some(length) = self.window {
+            let index = time.saturating_sub(start.unwrap_or(first)) / length;
+            self.timestamp.clear();
+            self.timestamp.push_str(&index.to_string());
+        }
+        true
+    }
+
+    // The timestamp is the first word after the PID/TID that is a number followed by a colon,
+    // like `4794564.109216:` (the optional CPU, like `[002]`, comes before it).
+    fn event_timestamp(line: &str) -> Option<&str> {
+        line.split_whitespace()
+            .filter(|word| word.len() > 1 && word.ends_with(':'))
+            .map(|word| &word[..word.len() - 1])
+            .find(|word| Self::is_timestamp(word))
+    }
+
+    // The period is the number right after the timestamp, like the `257597` of
+    // `72.176760: 257597 cycles:uppp:`, which older versions of perf don't print.
+    fn event_period(line: &str) -> Option<u64> {
+        let mut words = line.split_whitespace();
+        words.find(|word| word.ends_with(':') && Self::is_timestamp(&word[..word.len() - 1]))?;
+        words.next()?.parse().ok()
+    }
+
+    fn is_timestamp(word: &str) -> bool {
+        word.chars().all(|c| c.is_ascii_digit() || c == '.')
+            && word.chars().filter(|&c| c == '.').count() == 1
+    }
+
     fn stack_line_parts(line: &str) -> Option<(&str, &str, &str)> {
         let mut line = line.trim_start().splitn(2, ' ');
         let pc = line.next()?.trim_end();
@@ -428,6 +1270,9 @@ impl Folder {
         }
 
         if let Some((pc, mut rawfunc, module)) = Self::stack_line_parts(line) {
+            #[cfg_attr(not(feature = "symbolize"), allow(unused_variables))]
+            let (module, module_offset) = split_module_offset(module);
+
             // Strip off symbol offsets
             if let Some(offset) = rawfunc.rfind("+0x") {
                 let end = &rawfunc[(offset + 3)..];
@@ -443,6 +1288,17 @@ impl Folder {
                 return;
             }
 
+            #[cfg(feature = "symbolize")]
+            {
+                if let Some(functions) = self.symbolize(rawfunc, pc, module, module_offset) {
+                    for function in functions {
+                        self.push_function(module, function.name, function.location);
+                    }
+                    self.push_cache_line();
+                    return;
+                }
+            }
+
             let rawfunc = if self.opt.demangle {
                 demangle(rawfunc)
             } else {
@@ -455,49 +1311,79 @@ impl Folder {
             // rest are annotated with "_[i]" to mark them as inlined.
             // See https://github.com/brendangregg/FlameGraph/pull/89.
             for func in rawfunc.split("->") {
-                let mut func = with_module_fallback(module, func, pc, self.opt.include_addrs);
-                if TIDY_GENERIC {
-                    func = tidy_generic(func);
-                }
-
-                if TIDY_JAVA && self.pname == "java" {
-                    func = tidy_java(func);
-                }
+                let func = with_module_fallback(module, func, pc, self.opt.include_addrs);
+                self.push_function(module, func, None);
+            }
+            self.push_cache_line();
+        } else {
+            logging::weird_stack_line(line);
+        }
+    }
 
-                // Annotations
-                //
-                // detect inlined when self.cache_line has funcs
-                // detect kernel from the module name; eg, frames to parse include:
-                //
-                //     ffffffff8103ce3b native_safe_halt ([kernel.kallsyms])
-                //     8c3453 tcp_sendmsg (/lib/modules/4.3.0-rc1-virtual/build/vmlinux)
-                //     7d8 ipv4_conntrack_local+0x7f8f80b8 ([nf_conntrack_ipv4])
-                //
-                // detect jit from the module name; eg:
-                //
-                //     7f722d142778 Ljava/io/PrintStream;::print (/tmp/perf-19982.map)
-                if !self.cache_line.is_empty() {
-                    func.push_str("_[i]"); // inlined
-                } else if self.opt.annotate_kernel
-                    && (module.starts_with('[') || module.ends_with("vmlinux"))
-                    && module != "[unknown]"
-                {
-                    func.push_str("_[k]"); // kernel
-                } else if self.opt.annotate_jit
-                    && module.starts_with("/tmp/perf-")
-                    && module.ends_with(".map")
-                {
-                    func.push_str("_[j]"); // jitted
-                }
+    // Finds the functions at the address of a frame that perf couldn't find a symbol for.
+    #[cfg(feature = "symbolize")]
+    fn symbolize(
+        &self,
+        rawfunc: &str,
+        pc: &str,
+        module: &str,
+        module_offset: Option<u64>,
+    ) -> Option<Vec<Function>> {
+        if rawfunc != "[unknown]" {
+            return None;
+        }
+        let symbolizer = self.opt.symbolizer.as_ref()?;
+        let pc = u64::from_str_radix(pc, 16).ok()?;
+        symbolizer.symbolize(module, pc, module_offset)
+    }
 
-                self.cache_line.push(func);
+    // Tidies up and annotates a function of the current stack line, and adds it to the functions
+    // of the line, along with its source location (if any).
+    fn push_function(&mut self, module: &str, mut func: String, location: Option<String>) {
+        for (strategy, &applies) in self.opt.tidy.iter().zip(&self.tidy_process) {
+            if applies && strategy.applies_to_module(module) {
+                func = strategy.tidy.apply(func);
             }
+        }
+        if let Some(location) = location {
+            func.push_str(" (");
+            func.push_str(&location);
+            func.push(')');
+        }
 
-            while let Some(func) = self.cache_line.pop() {
-                self.stack.push_front(func);
-            }
-        } else {
-            logging::weird_stack_line(line);
+        // Annotations
+        //
+        // detect inlined when self.cache_line has funcs
+        // detect kernel from the module name; eg, frames to parse include:
+        //
+        //     ffffffff8103ce3b native_safe_halt ([kernel.kallsyms])
+        //     8c3453 tcp_sendmsg (/lib/modules/4.3.0-rc1-virtual/build/vmlinux)
+        //     7d8 ipv4_conntrack_local+0x7f8f80b8 ([nf_conntrack_ipv4])
+        //
+        // detect jit from the module name; eg:
+        //
+        //     7f722d142778 Ljava/io/PrintStream;::print (/tmp/perf-19982.map)
+        if !self.cache_line.is_empty() {
+            func.push_str("_[i]"); // inlined
+        } else if self.opt.annotate_kernel
+            && (module.starts_with('[') || module.ends_with("vmlinux"))
+            && module != "[unknown]"
+        {
+            func.push_str("_[k]"); // kernel
+        } else if self.opt.annotate_jit
+            && module.starts_with("/tmp/perf-")
+            && module.ends_with(".map")
+        {
+            func.push_str("_[j]"); // jitted
+        }
+
+        self.cache_line.push(func);
+    }
+
+    // Adds the functions of the current stack line to the stack, from the outermost one.
+    fn push_cache_line(&mut self) {
+        while let Some(func) = self.cache_line.pop() {
+            self.stack.push_front(func);
         }
     }
 
@@ -506,10 +1392,20 @@ impl Folder {
         if !self.skip_stack {
             // allocate a string that is long enough to hold the entire stack string
             let mut stack_str = String::with_capacity(
-                self.pname.len() + self.stack.iter().fold(0, |a, s| a + s.len() + 1),
+                self.timestamp.len()
+                    + 1
+                    + self.pname.len()
+                    + self.stack.iter().fold(0, |a, s| a + s.len() + 1),
             );
 
+            // add the timestamp, if we're keeping those
+            if !self.timestamp.is_empty() {
+                stack_str.push_str(&self.timestamp);
+                stack_str.push(' ');
+            }
+
             // add the comm name
+            let stack_start = stack_str.len();
             stack_str.push_str(&self.pname);
             // add the other stack entries (if any)
             for e in self.stack.drain(..) {
@@ -518,14 +1414,261 @@ impl Folder {
             }
 
             // count it!
-            occurrences.insert_or_add(stack_str, 1);
+            if self.opt.off_cpu.is_some() {
+                self.sched.after_event(
+                    stack_str,
+                    stack_start,
+                    self.opt.off_cpu == Some(OffCpu::Wakers),
+                    !self.filtered_out,
+                    occurrences,
+                );
+            } else {
+                occurrences.insert_or_add(stack_str, self.period);
+            }
         }
 
         // reset for the next event
+        self.filtered_out = false;
         self.in_event = false;
         self.skip_stack = false;
         self.stack.clear();
+        self.timestamp.clear();
+        self.period = 1;
+    }
+}
+
+/// A scheduler event that off-CPU stacks are made from.
+#[derive(Clone, Debug)]
+enum SchedEvent {
+    /// The thread of the event was switched out, and the one with this ID in.
+    Switch { next_tid: String },
+    /// The thread with this ID was woken up, by the thread of the event.
+    Wakeup { tid: String },
+    /// The thread with this ID was blocked for this many nanoseconds.
+    Delay { tid: String, delay: u64 },
+}
+
+/// A thread that has been switched out.
+#[derive(Clone, Debug)]
+struct Blocked {
+    /// The stack it was switched out in, possibly prefixed with a timestamp.
+    stack: String,
+    /// When it was switched out, in nanoseconds.
+    since: u64,
+    /// How long it was blocked for, in nanoseconds, if a `sched_stat_*` event said so.
+    delay: Option<u64>,
+}
+
+/// Follows threads from being switched out to being switched back in, for off-CPU stacks.
+#[derive(Clone, Debug, Default)]
+struct Sched {
+    /// The scheduler event of the current event line.
+    event: Option<SchedEvent>,
+    /// The thread ID of the current event line.
+    tid: String,
+    /// The time of the current event line, in nanoseconds.
+    time: u64,
+    /// The threads that are switched out, by thread ID.
+    blocked: FnvHashMap<String, Blocked>,
+    /// The stacks (from the leaf down) of the threads that woke up switched out threads, by the
+    /// ID of the woken thread.
+    wakers: FnvHashMap<String, String>,
+}
+
+impl Sched {
+    // Picks the scheduler event out of an event line, like:
+    //
+    //     app 3101 [001] 2001.000300: sched:sched_switch: prev_comm=app prev_pid=3101 prev_prio=120 prev_state=S ==> next_comm=swapper/1 next_pid=0 next_prio=120
+    //     app 3101 [001] 2001.000300: sched:sched_switch: app:3101 [120] S ==> swapper/1:0 [120]
+    //     worker 3102 [000] 2001.000900: sched:sched_wakeup: comm=app pid=3101 prio=120 target_cpu=001
+    //     worker 3102 [000] 2001.000900: sched:sched_wakeup: app:3101 [120] success=1 CPU:001
+    //     worker 3102 [000] 2001.000900: sched:sched_stat_sleep: comm=app pid=3101 delay=600123 [ns]
+    //
+    // Returns `false` if the event isn't one of those.
+    fn on_event_line(&mut self, line: &str, tid: &str) -> bool {
+        self.event = None;
+        let (timestamp, event, trace) = match event_trace(line) {
+            Some(parts) => parts,
+            None => return false,
+        };
+        self.event = match event {
+            "sched:sched_switch" => trace
+                .find("==>")
+                .map(|arrow| &trace[arrow + 3..])
+                .and_then(|next| trace_field(next, "next_pid").or_else(|| task_tid(next)))
+                .map(|next_tid| SchedEvent::Switch {
+                    next_tid: next_tid.to_string(),
+                }),
+            "sched:sched_wakeup" | "sched:sched_wakeup_new" | "sched:sched_waking" => {
+                trace_field(trace, "pid")
+                    .or_else(|| task_tid(trace))
+                    .map(|tid| SchedEvent::Wakeup {
+                        tid: tid.to_string(),
+                    })
+            }
+            "sched:sched_stat_sleep" | "sched:sched_stat_blocked" | "sched:sched_stat_iowait" => {
+                match (trace_field(trace, "pid"), trace_field(trace, "delay")) {
+                    (Some(tid), Some(delay)) => delay.parse().ok().map(|delay| SchedEvent::Delay {
+                        tid: tid.to_string(),
+                        delay,
+                    }),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        self.tid.clear();
+        self.tid.push_str(tid);
+        self.time = match parse_nanos(timestamp) {
+            Some(time) => time,
+            None => return false,
+        };
+        self.event.is_some()
+    }
+
+    // Handles the stack of the current event, which is that of the thread that is switched out
+    // or that wakes another one up. `stack[stack_start..]` leaves out any timestamp, and `keep`
+    // is false if the thread of the event is filtered out.
+    fn after_event(
+        &mut self,
+        stack: String,
+        stack_start: usize,
+        wakers: bool,
+        keep: bool,
+        occurrences: &mut Occurrences,
+    ) {
+        match self.event.take() {
+            Some(SchedEvent::Switch { next_tid }) => {
+                let waker = self.wakers.remove(&next_tid);
+                if let Some(blocked) = self.blocked.remove(&next_tid) {
+                    let delay = blocked
+                        .delay
+                        .unwrap_or_else(|| self.time.saturating_sub(blocked.since));
+                    let mut blocked_stack = blocked.stack;
+                    if let (true, Some(waker)) = (wakers, waker) {
+                        blocked_stack.push_str(";--;");
+                        blocked_stack.push_str(&waker);
+                    }
+                    let micros = delay / 1000;
+                    if micros > 0 {
+                        occurrences.insert_or_add(blocked_stack, micros);
+                    }
+                }
+                // The idle task isn't blocked when it's switched out, and the threads that are
+                // filtered out aren't kept track of.
+                if keep && self.tid != "0" {
+                    self.wakers.remove(&self.tid);
+                    self.blocked.insert(
+                        self.tid.clone(),
+                        Blocked {
+                            stack,
+                            since: self.time,
+                            delay: None,
+                        },
+                    );
+                }
+            }
+            Some(SchedEvent::Wakeup { tid }) if wakers => {
+                let waker: Vec<&str> = stack[stack_start..].rsplit(';').collect();
+                self.wakers.insert(tid, waker.join(";"));
+            }
+            Some(SchedEvent::Delay { tid, delay }) => {
+                if let Some(blocked) = self.blocked.get_mut(&tid) {
+                    blocked.delay = Some(delay);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Splits an event line into its timestamp, its event name, and what its tracepoint printed, like
+// `2001.000300`, `sched:sched_switch`, and `prev_comm=app prev_pid=3101 ...`.
+fn event_trace(line: &str) -> Option<(&str, &str, &str)> {
+    let timestamp = Folder::event_timestamp(line)?;
+    let timestamp_end = timestamp.as_ptr() as usize - line.as_ptr() as usize + timestamp.len();
+    let mut rest = line[timestamp_end + 1..].trim_start();
+
+    // skip the period, if there is one
+    if let Some(end) = rest.find(' ') {
+        if rest[..end].chars().all(|c| c.is_ascii_digit()) {
+            rest = rest[end..].trim_start();
+        }
+    }
+
+    match rest.find(": ") {
+        Some(end) => Some((timestamp, &rest[..end], rest[end + 2..].trim())),
+        None => Some((timestamp, rest.trim_end().trim_end_matches(':'), "")),
+    }
+}
+
+// Finds the value of a `name=value` field in what a tracepoint printed.
+fn trace_field<'a>(trace: &'a str, name: &str) -> Option<&'a str> {
+    trace.split_whitespace().find_map(|field| {
+        let mut parts = field.splitn(2, '=');
+        if parts.next() == Some(name) {
+            parts.next()
+        } else {
+            None
+        }
+    })
+}
+
+// Finds the thread ID of a task that perf printed as `comm:tid [prio]`.
+fn task_tid(task: &str) -> Option<&str> {
+    let task = task.trim_start().split(" [").next()?;
+    let tid = task.rsplit(':').next()?;
+    if !tid.is_empty() && tid.chars().all(|c| c.is_ascii_digit()) {
+        Some(tid)
+    } else {
+        None
+    }
+}
+
+// Splits up keyed stacks, which start with a key and a space (like the index of a window), by
+// their keys.
+fn split_keys(stacks: &FoldedStacks) -> BTreeMap<&str, FoldedStacks> {
+    let mut keyed: BTreeMap<&str, FoldedStacks> = BTreeMap::new();
+    for stack in stacks {
+        let mut frames = stack.frames();
+        let root = frames.next().unwrap_or_default();
+        if let Some(space) = root.find(' ') {
+            keyed.entry(&root[..space]).or_default().push(
+                Some(&root[space + 1..]).into_iter().chain(frames),
+                stack.count(),
+            );
+        }
+    }
+    keyed
+}
+
+fn duration_nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + u64::from(duration.subsec_nanos())
+}
+
+// Parses a timestamp in seconds, like `2001.000300`, into nanoseconds.
+fn parse_nanos(timestamp: &str) -> Option<u64> {
+    let mut parts = timestamp.splitn(2, '.');
+    let secs: u64 = parts.next()?.parse().ok()?;
+    let fraction = parts.next().unwrap_or("");
+    let digits = &fraction[..fraction.len().min(9)];
+    let nanos: u64 = if digits.is_empty() {
+        0
+    } else {
+        digits.parse().ok()?
+    };
+    Some(secs * 1_000_000_000 + nanos * 10u64.pow(9 - digits.len() as u32))
+}
+
+// Splits the offset of the address of a stack line in its module, which `perf script -F +dsoff`
+// adds after the module (like `/usr/lib/libfoo.so+0x194d0`), off the module.
+fn split_module_offset(module: &str) -> (&str, Option<u64>) {
+    if let Some(plus) = module.rfind("+0x") {
+        if let Ok(offset) = u64::from_str_radix(&module[plus + 3..], 16) {
+            return (&module[..plus], Some(offset));
+        }
     }
+    (module, None)
 }
 
 // massage function name to be nicer
@@ -615,6 +1758,91 @@ fn tidy_java(mut func: String) -> String {
     func
 }
 
+fn tidy_cpp_templates(func: String) -> String {
+    // converts the following:
+    //     std::vector<int, std::allocator<int> >::push_back(int const&)
+    //     std::function<void (int)>::operator()(int) const
+    //     operator<<(std::ostream&, foo const&)
+    // into:
+    //     std::vector<...>::push_back(int const&)
+    //     std::function<...>::operator()(int) const
+    //     operator<<(std::ostream&, foo const&)
+    if !func.contains('<') {
+        return func;
+    }
+
+    let mut res = String::with_capacity(func.len());
+    let mut depth = 0;
+    let mut rest = &func[..];
+    while let Some(c) = rest.chars().next() {
+        if depth == 0 && (rest.starts_with("operator<") || rest.starts_with("operator>")) {
+            // the < and > of comparison and shift operators don't start or end arguments
+            let end = rest["operator".len()..]
+                .find(|c| c != '<' && c != '>' && c != '=')
+                .map_or(rest.len(), |i| i + "operator".len());
+            res.push_str(&rest[..end]);
+            rest = &rest[end..];
+            continue;
+        }
+        match c {
+            '<' => {
+                if depth == 0 {
+                    res.push_str("<...");
+                }
+                depth += 1;
+            }
+            '>' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    res.push('>');
+                }
+            }
+            c if depth == 0 => res.push(c),
+            _ => {}
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+    res
+}
+
+fn tidy_rust_hash(mut func: String) -> String {
+    // converts the following:
+    //     core::ptr::drop_in_place::h3f0c6a8e1d9b2c47
+    //     server::handle::h0123456789abcdef.llvm.8312
+    // into:
+    //     core::ptr::drop_in_place
+    //     server::handle
+    if let Some(hash) = func.rfind("::h") {
+        let rest = &func[hash + 3..];
+        let (digits, suffix) = rest.split_at(rest.find('.').unwrap_or(rest.len()));
+        if digits.len() == 16
+            && digits.chars().all(|c| c.is_ascii_hexdigit())
+            && (suffix.is_empty() || suffix.starts_with(".llvm."))
+        {
+            func.truncate(hash);
+        }
+    }
+
+    func
+}
+
+fn tidy_go_packages(func: String) -> String {
+    // converts the following:
+    //     github.com/user/project/store.(*DB).Get
+    //     net/http.HandlerFunc.ServeHTTP
+    // into:
+    //     store.(*DB).Get
+    //     http.HandlerFunc.ServeHTTP
+    //
+    // Only slashes before the first parenthesis are part of the package path; the ones after
+    // it, like those in the type parameters of a generic receiver, are left alone.
+    let name_end = func.find('(').unwrap_or(func.len());
+    match func[..name_end].rfind('/') {
+        Some(slash) => func[slash + 1..].to_string(),
+        None => func,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs;
@@ -657,6 +1885,140 @@ mod tests {
         };
     }
 
+    #[test]
+    fn tidies_names() {
+        let tidy = |tidy: Tidy, func: &str| tidy.apply(func.to_string());
+        assert_eq!(
+            tidy(
+                Tidy::CppTemplates,
+                "std::vector<int, std::allocator<int> >::push_back(int const&)"
+            ),
+            "std::vector<...>::push_back(int const&)"
+        );
+        assert_eq!(
+            tidy(
+                Tidy::CppTemplates,
+                "std::function<void (int)>::operator()(int) const"
+            ),
+            "std::function<...>::operator()(int) const"
+        );
+        assert_eq!(
+            tidy(Tidy::CppTemplates, "operator<< <char>(std::ostream&, char)"),
+            "operator<< <...>(std::ostream&, char)"
+        );
+        assert_eq!(
+            tidy(
+                Tidy::RustHash,
+                "core::ptr::drop_in_place::h3f0c6a8e1d9b2c47"
+            ),
+            "core::ptr::drop_in_place"
+        );
+        assert_eq!(
+            tidy(
+                Tidy::RustHash,
+                "server::handle::h0123456789abcdef.llvm.8312"
+            ),
+            "server::handle"
+        );
+        assert_eq!(tidy(Tidy::RustHash, "foo::hello"), "foo::hello");
+        assert_eq!(
+            tidy(Tidy::GoPackages, "github.com/user/project/store.(*DB).Get"),
+            "store.(*DB).Get"
+        );
+        assert_eq!(tidy(Tidy::GoPackages, "main.main.func1"), "main.main.func1");
+    }
+
+    #[test]
+    fn parses_tidy_strategies() {
+        let strategy: TidyStrategy = "java:process=java|jsvc".parse().unwrap();
+        assert_eq!(strategy.tidy, Tidy::Java);
+        assert!(strategy.applies_to_process("jsvc"));
+        assert!(!strategy.applies_to_process("jsvc2"));
+
+        let strategy: TidyStrategy = "rust-hash:module=.*/target/.*".parse().unwrap();
+        assert!(strategy.applies_to_process("anything"));
+        assert!(strategy.applies_to_module("/src/target/release/app"));
+        assert!(!strategy.applies_to_module("/usr/lib/libc.so.6"));
+
+        assert!("cpp".parse::<TidyStrategy>().is_err());
+        assert!("java:pid=1".parse::<TidyStrategy>().is_err());
+        assert!("java:process=(".parse::<TidyStrategy>().is_err());
+    }
+
+    #[test]
+    fn finds_event_cpus() {
+        let cpu = |line| {
+            let (_, _, tid) = Folder::event_line_parts(line).unwrap();
+            Folder::event_cpu(line, tid)
+        };
+        assert_eq!(cpu("java 12688 [002] 6544038.708352: cpu-clock:"), Some(2));
+        assert_eq!(
+            cpu("V8 WorkerThread 24636/25607 [011] 94564.109216: cycles:"),
+            Some(11)
+        );
+        assert_eq!(cpu("java 25607 4794564.109216: cycles:"), None);
+    }
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(
+            "4794564.109216".parse(),
+            Ok(Time::Absolute(Duration::new(4_794_564, 109_216_000)))
+        );
+        assert_eq!(
+            "+2.5".parse(),
+            Ok(Time::SinceFirstEvent(Duration::from_millis(2_500)))
+        );
+        assert_eq!(
+            "+3".parse(),
+            Ok(Time::SinceFirstEvent(Duration::from_secs(3)))
+        );
+        for invalid in &["", "+", "-1", "1.5s", "1e3"] {
+            assert!(invalid.parse::<Time>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parses_scheduler_events() {
+        let line = "app  3101 [001]  2001.000100: sched:sched_switch: prev_comm=app prev_pid=3101 \
+                    prev_prio=120 prev_state=S ==> next_comm=swapper/1 next_pid=0 next_prio=120";
+        let (timestamp, event, trace) = event_trace(line).unwrap();
+        assert_eq!(timestamp, "2001.000100");
+        assert_eq!(event, "sched:sched_switch");
+        assert_eq!(trace_field(trace, "next_pid"), Some("0"));
+
+        let line = "worker  3102 [000]  2001.004150: sched:sched_wakeup: app:3101 [120] success=1";
+        let (_, event, trace) = event_trace(line).unwrap();
+        assert_eq!(event, "sched:sched_wakeup");
+        assert_eq!(task_tid(trace), Some("3101"));
+        assert_eq!(task_tid(" swapper/1:0 [120] R"), Some("0"));
+        assert_eq!(task_tid("app [120]"), None);
+
+        let line = "app 3101 2001.005200:     250000 cpu-clock: ";
+        assert_eq!(event_trace(line), Some(("2001.005200", "cpu-clock", "")));
+
+        assert_eq!(parse_nanos("2001.000100"), Some(2_001_000_100_000));
+        assert_eq!(parse_nanos("12"), Some(12_000_000_000));
+        assert_eq!(parse_nanos("1.2x"), None);
+    }
+
+    #[test]
+    fn splits_module_offsets() {
+        assert_eq!(
+            split_module_offset("/usr/lib/libfoo.so+0x194d0"),
+            ("/usr/lib/libfoo.so", Some(0x194d0))
+        );
+        assert_eq!(
+            split_module_offset("/usr/lib/libfoo.so"),
+            ("/usr/lib/libfoo.so", None)
+        );
+        assert_eq!(split_module_offset("[unknown]"), ("[unknown]", None));
+        assert_eq!(
+            split_module_offset("/opt/c++/x+0xzz"),
+            ("/opt/c++/x+0xzz", None)
+        );
+    }
+
     #[test]
     fn test_collapse_multi_perf() -> io::Result<()> {
         let mut folder = Folder::default();
@@ -705,12 +2067,49 @@ mod tests {
             let options = Options {
                 annotate_jit: rng.gen(),
                 annotate_kernel: rng.gen(),
+                collapse_recursion: match rng.gen_range(0, 3) {
+                    0 => None,
+                    1 => Some(Recursion::Direct),
+                    _ => Some(Recursion::Indirect),
+                },
+                rules: if rng.gen() {
+                    "frame '^\\[unknown\\]$' drop-frame\nframe '0x[0-9a-f]+' rename 0x..."
+                        .parse()
+                        .unwrap()
+                } else {
+                    Rules::new()
+                },
                 demangle: rng.gen(),
                 event_filter: None,
+                flame_chart: false,
+                weight_by_period: rng.gen(),
+                off_cpu: None,
+                start: None,
+                end: None,
                 include_addrs: rng.gen(),
+                #[cfg(feature = "symbolize")]
+                symbolizer: None,
                 include_pid: rng.gen(),
                 include_tid: rng.gen(),
                 nthreads: rng.gen_range(2, 32 + 1),
+                only_pids: Vec::new(),
+                exclude_pids: Vec::new(),
+                only_tids: Vec::new(),
+                exclude_tids: Vec::new(),
+                only_cpus: Vec::new(),
+                exclude_cpus: Vec::new(),
+                only_comm: None,
+                exclude_comm: None,
+                tidy: if rng.gen() {
+                    TidyStrategy::defaults()
+                } else {
+                    vec![
+                        TidyStrategy::new(Tidy::CppTemplates),
+                        TidyStrategy::new(Tidy::Generic),
+                        TidyStrategy::for_module(Tidy::Java, "/tmp/perf-.*").unwrap(),
+                        TidyStrategy::new(Tidy::RustHash),
+                    ]
+                },
             };
 
             for (path, input) in inputs.iter() {
@@ -747,5 +2146,4 @@ mod tests {
             }
         }
     }
-
 }
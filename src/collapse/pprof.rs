@@ -0,0 +1,370 @@
+use std::io;
+
+use fnv::FnvHashMap;
+use log::warn;
+
+use crate::collapse::common::Occurrences;
+use crate::collapse::Collapse;
+use crate::folded::{FoldedStacks, Recursion};
+use crate::pprof::{self, Function, Location, Mapping, Profile};
+use crate::rules::Rules;
+
+/// `pprof` folder configuration options.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// The index of the sample type (e.g., `samples` or `cpu` for CPU profiles, or
+    /// `alloc_space` or `inuse_objects` for heap profiles) whose values are used as the count of
+    /// each stack.
+    ///
+    /// Default is `None`, which uses the profile's default sample type if it has one, and the
+    /// last sample type otherwise (which is what `go tool pprof` does).
+    pub sample_type: Option<usize>,
+
+    /// Include raw addresses (e.g., `0xbfff0836`) where symbols can't be found.
+    ///
+    /// Default is `false`.
+    pub include_addrs: bool,
+
+    /// Fold recursive calls into one frame per function, as described by [`Recursion`].
+    ///
+    /// Default is `None`, which keeps them.
+    ///
+    ///   [`Recursion`]: ../../folded/enum.Recursion.html
+    pub collapse_recursion: Option<Recursion>,
+
+    /// [`Rules`] to filter and rewrite the stacks with, which are applied before any recursion is
+    /// collapsed.
+    ///
+    /// Default is no rules.
+    ///
+    ///   [`Rules`]: ../../rules/struct.Rules.html
+    pub rules: Rules,
+}
+
+/// A stack collapser for profiles in the [pprof] protocol buffer format, as written by Go's
+/// `runtime/pprof` and `net/http/pprof` packages, among others.
+///
+/// Profiles may be gzip compressed (which they usually are) or not.
+///
+/// To construct one, either use `pprof::Folder::default()` or create an [`Options`] and use
+/// `pprof::Folder::from(options)`.
+///
+///   [pprof]: https://github.com/google/pprof/blob/master/proto/profile.proto
+#[derive(Clone, Default)]
+pub struct Folder {
+    opt: Options,
+}
+
+impl From<Options> for Folder {
+    fn from(opt: Options) -> Self {
+        Folder { opt }
+    }
+}
+
+impl Collapse for Folder {
+    fn collapse<R, W>(&mut self, reader: R, writer: W) -> io::Result<()>
+    where
+        R: io::BufRead,
+        W: io::Write,
+    {
+        self.collapse_to_occurrences(reader)?
+            .write_and_clear(writer)
+    }
+
+    fn collapse_to_stacks<R>(&mut self, reader: R) -> io::Result<FoldedStacks>
+    where
+        R: io::BufRead,
+    {
+        Ok(self.collapse_to_occurrences(reader)?.take_stacks())
+    }
+
+    /// Check for a gzip header, or for what an uncompressed profile starts with.
+    ///
+    /// Note that profiles are binary, so callers that want to detect them should look at the raw
+    /// bytes of the input instead, since it usually won't be valid UTF-8.
+    fn is_applicable(&mut self, input: &str) -> Option<bool> {
+        if input.is_empty() {
+            None
+        } else {
+            Some(pprof::looks_like_profile(input.as_bytes()))
+        }
+    }
+}
+
+impl Folder {
+    // Collapses the contents of the provided `reader` into the occurrences of each stack, which
+    // `collapse` writes out and `collapse_to_stacks` returns.
+    fn collapse_to_occurrences<R>(&mut self, mut reader: R) -> io::Result<Occurrences>
+    where
+        R: io::BufRead,
+    {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let profile = Profile::decode(&bytes)?;
+        let value_index = self.value_index(&profile)?;
+
+        let locations: FnvHashMap<u64, &Location> =
+            profile.location.iter().map(|l| (l.id, l)).collect();
+        let functions: FnvHashMap<u64, &Function> =
+            profile.function.iter().map(|f| (f.id, f)).collect();
+        let mappings: FnvHashMap<u64, &Mapping> =
+            profile.mapping.iter().map(|m| (m.id, m)).collect();
+
+        // Many samples share locations, so only work out the frames for each location once.
+        let mut frames: FnvHashMap<u64, Vec<String>> = FnvHashMap::default();
+
+        let mut occurrences = Occurrences::new(1);
+        occurrences.set_collapse_recursion(self.opt.collapse_recursion);
+        occurrences.set_rules(&self.opt.rules);
+        let mut stack = String::new();
+        for sample in &profile.sample {
+            let count = match sample.value.get(value_index) {
+                Some(&count) if count > 0 => count as u64,
+                Some(_) => continue,
+                None => {
+                    warn!(
+                        "Sample has {} values, but sample type {} was selected",
+                        sample.value.len(),
+                        value_index
+                    );
+                    continue;
+                }
+            };
+
+            stack.clear();
+            // Locations are listed leaf first.
+            for id in sample.location_id.iter().rev() {
+                let location_frames =
+                    frames
+                        .entry(*id)
+                        .or_insert_with(|| match locations.get(id) {
+                            Some(location) => location_frames(
+                                &profile, location, &functions, &mappings, &self.opt,
+                            ),
+                            None => {
+                                warn!("Sample refers to unknown location {}", id);
+                                vec!["[unknown]".to_string()]
+                            }
+                        });
+                for frame in location_frames.iter() {
+                    if !stack.is_empty() {
+                        stack.push(';');
+                    }
+                    stack.push_str(frame);
+                }
+            }
+
+            if !stack.is_empty() {
+                occurrences.insert_or_add(stack.clone(), count);
+            }
+        }
+
+        Ok(occurrences)
+    }
+
+    fn value_index(&self, profile: &Profile) -> io::Result<usize> {
+        if let Some(index) = self.opt.sample_type {
+            if index < profile.sample_type.len() {
+                return Ok(index);
+            }
+            let available: Vec<String> = profile
+                .sample_type
+                .iter()
+                .enumerate()
+                .map(|(i, st)| {
+                    format!(
+                        "{}: {}/{}",
+                        i,
+                        profile.string(st.ty),
+                        profile.string(st.unit)
+                    )
+                })
+                .collect();
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Sample type {} does not exist; the profile has: {}",
+                    index,
+                    available.join(", ")
+                ),
+            ));
+        }
+
+        if profile.default_sample_type != 0 {
+            let default = profile.string(profile.default_sample_type);
+            if let Some(index) = profile
+                .sample_type
+                .iter()
+                .position(|st| profile.string(st.ty) == default)
+            {
+                return Ok(index);
+            }
+        }
+        Ok(profile.sample_type.len().saturating_sub(1))
+    }
+}
+
+/// Returns the frames for a location, caller first.
+fn location_frames(
+    profile: &Profile,
+    location: &Location,
+    functions: &FnvHashMap<u64, &Function>,
+    mappings: &FnvHashMap<u64, &Mapping>,
+    opt: &Options,
+) -> Vec<String> {
+    // If there are several lines, the earlier ones were inlined into the later ones.
+    let names: Vec<String> = location
+        .line
+        .iter()
+        .rev()
+        .filter_map(|line| functions.get(&line.function_id))
+        .map(|function| {
+            let name = match profile.string(function.name) {
+                "" => profile.string(function.system_name),
+                name => name,
+            };
+            name.replace(';', ":")
+        })
+        .filter(|name| !name.is_empty())
+        .collect();
+    if !names.is_empty() {
+        return names;
+    }
+
+    // An unsymbolized location; try to use part of the mapping's file name instead.
+    let module = mappings
+        .get(&location.mapping_id)
+        .map(|mapping| profile.string(mapping.filename))
+        .unwrap_or("");
+    let module = &module[module.rfind('/').map(|i| i + 1).unwrap_or(0)..];
+    let frame = match (module, opt.include_addrs) {
+        ("", false) => "[unknown]".to_string(),
+        ("", true) => format!("[unknown <{:x}>]", location.address),
+        (module, false) => format!("[{}]", module.replace(';', ":")),
+        (module, true) => format!("[{} <{:x}>]", module.replace(';', ":"), location.address),
+    };
+    vec![frame]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pprof::{Line, Sample, ValueType};
+
+    fn profile() -> Profile {
+        let strings = &[
+            "",
+            "samples",
+            "count",
+            "cpu",
+            "nanoseconds",
+            "main",
+            "foo",
+            "bar",
+            "/bin/app",
+        ];
+        Profile {
+            sample_type: vec![ValueType { ty: 1, unit: 2 }, ValueType { ty: 3, unit: 4 }],
+            sample: vec![
+                Sample {
+                    location_id: vec![2, 1],
+                    value: vec![1, 10],
+                },
+                Sample {
+                    location_id: vec![3, 1],
+                    value: vec![2, 20],
+                },
+                Sample {
+                    location_id: vec![2, 1],
+                    value: vec![3, 30],
+                },
+            ],
+            mapping: vec![Mapping { id: 1, filename: 8 }],
+            location: vec![
+                Location {
+                    id: 1,
+                    mapping_id: 1,
+                    address: 0x1000,
+                    line: vec![Line {
+                        function_id: 1,
+                        line: 3,
+                    }],
+                },
+                Location {
+                    id: 2,
+                    mapping_id: 1,
+                    address: 0x2000,
+                    // bar was inlined into foo
+                    line: vec![
+                        Line {
+                            function_id: 3,
+                            line: 7,
+                        },
+                        Line {
+                            function_id: 2,
+                            line: 5,
+                        },
+                    ],
+                },
+                Location {
+                    id: 3,
+                    mapping_id: 1,
+                    address: 0x3000,
+                    line: vec![],
+                },
+            ],
+            function: vec![
+                Function {
+                    id: 1,
+                    name: 5,
+                    ..Default::default()
+                },
+                Function {
+                    id: 2,
+                    name: 6,
+                    ..Default::default()
+                },
+                Function {
+                    id: 3,
+                    name: 7,
+                    ..Default::default()
+                },
+            ],
+            string_table: strings.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn value_index_defaults_to_last_sample_type() {
+        let mut profile = profile();
+        assert_eq!(Folder::default().value_index(&profile).unwrap(), 1);
+        profile.default_sample_type = 1;
+        assert_eq!(Folder::default().value_index(&profile).unwrap(), 0);
+        let mut folder = Folder::from(Options {
+            sample_type: Some(2),
+            ..Default::default()
+        });
+        let err = folder.value_index(&profile).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("0: samples/count, 1: cpu/nanoseconds"));
+        folder.opt.sample_type = Some(0);
+        assert_eq!(folder.value_index(&profile).unwrap(), 0);
+    }
+
+    #[test]
+    fn location_frames_order_and_fallback() {
+        let profile = profile();
+        let functions = profile.function.iter().map(|f| (f.id, f)).collect();
+        let mappings = profile.mapping.iter().map(|m| (m.id, m)).collect();
+        let mut opt = Options::default();
+        let frames = |l: usize, opt: &Options| {
+            location_frames(&profile, &profile.location[l], &functions, &mappings, opt)
+        };
+        assert_eq!(frames(1, &opt), vec!["foo", "bar"]);
+        assert_eq!(frames(2, &opt), vec!["[app]"]);
+        opt.include_addrs = true;
+        assert_eq!(frames(2, &opt), vec!["[app <3000>]"]);
+    }
+}
@@ -4,6 +4,8 @@ use log::{error, warn};
 
 use crate::collapse::common::{self, Occurrences};
 use crate::collapse::Collapse;
+use crate::folded::{FoldedStacks, Recursion};
+use crate::rules::Rules;
 
 // The set of symbols to ignore for 'waiting' threads, for ease of use.
 // This will hide waiting threads from the view, making it easier to
@@ -35,11 +37,30 @@ pub struct Options {
     ///
     /// Default is `false`.
     pub no_modules: bool,
+
+    /// Fold recursive calls into one frame per function, as described by [`Recursion`].
+    ///
+    /// Default is `None`, which keeps them.
+    ///
+    ///   [`Recursion`]: ../../folded/enum.Recursion.html
+    pub collapse_recursion: Option<Recursion>,
+
+    /// [`Rules`] to filter and rewrite the stacks with, which are applied before any recursion is
+    /// collapsed.
+    ///
+    /// Default is no rules.
+    ///
+    ///   [`Rules`]: ../../rules/struct.Rules.html
+    pub rules: Rules,
 }
 
 impl Default for Options {
     fn default() -> Self {
-        Self { no_modules: false }
+        Self {
+            no_modules: false,
+            collapse_recursion: None,
+            rules: Rules::new(),
+        }
     }
 }
 
@@ -50,7 +71,7 @@ impl Default for Options {
 #[derive(Clone)]
 pub struct Folder {
     /// Number of samples for the current stack frame.
-    current_samples: usize,
+    current_samples: u64,
 
     /// Function on the stack in this entry thus far.
     stack: Vec<String>,
@@ -69,10 +90,63 @@ impl Default for Folder {
 }
 
 impl Collapse for Folder {
-    fn collapse<R, W>(&mut self, mut reader: R, writer: W) -> io::Result<()>
+    fn collapse<R, W>(&mut self, reader: R, writer: W) -> io::Result<()>
     where
         R: io::BufRead,
         W: io::Write,
+    {
+        self.collapse_to_occurrences(reader)?
+            .write_and_clear(writer)
+    }
+
+    fn collapse_to_stacks<R>(&mut self, reader: R) -> io::Result<FoldedStacks>
+    where
+        R: io::BufRead,
+    {
+        Ok(self.collapse_to_occurrences(reader)?.take_stacks())
+    }
+
+    /// Check for start and end lines of a call graph.
+    fn is_applicable(&mut self, input: &str) -> Option<bool> {
+        let mut found_start = false;
+        let mut input = input.as_bytes();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if let Ok(n) = input.read_line(&mut line) {
+                if n == 0 {
+                    break;
+                }
+            } else {
+                return Some(false);
+            }
+
+            if line.starts_with(START_LINE) {
+                found_start = true;
+                continue;
+            } else if line.starts_with(END_LINE) {
+                return Some(found_start);
+            }
+        }
+        None
+    }
+}
+
+impl From<Options> for Folder {
+    fn from(opt: Options) -> Self {
+        Folder {
+            opt,
+            ..Default::default()
+        }
+    }
+}
+
+impl Folder {
+    // Collapses the contents of the provided `reader` into the occurrences of each stack, which
+    // `collapse` writes out and `collapse_to_stacks` returns.
+    fn collapse_to_occurrences<R>(&mut self, mut reader: R) -> io::Result<Occurrences>
+    where
+        R: io::BufRead,
     {
         // Consume the header...
         let mut line = String::new();
@@ -80,7 +154,7 @@ impl Collapse for Folder {
             line.clear();
             if reader.read_line(&mut line)? == 0 {
                 warn!("File ended before start of call graph");
-                return Ok(());
+                return Ok(Occurrences::new(1));
             };
             if line.starts_with(START_LINE) {
                 break;
@@ -89,6 +163,8 @@ impl Collapse for Folder {
 
         // Process the data...
         let mut occurrences = Occurrences::new(1);
+        occurrences.set_collapse_recursion(self.opt.collapse_recursion);
+        occurrences.set_rules(&self.opt.rules);
         loop {
             line.clear();
             if reader.read_line(&mut line)? == 0 {
@@ -110,51 +186,12 @@ impl Collapse for Folder {
             }
         }
 
-        // Write the results...
-        occurrences.write_and_clear(writer)?;
-
         // Reset the state...
         self.current_samples = 0;
         self.stack.clear();
-        Ok(())
+        Ok(occurrences)
     }
 
-    /// Check for start and end lines of a call graph.
-    fn is_applicable(&mut self, input: &str) -> Option<bool> {
-        let mut found_start = false;
-        let mut input = input.as_bytes();
-        let mut line = String::new();
-        loop {
-            line.clear();
-            if let Ok(n) = input.read_line(&mut line) {
-                if n == 0 {
-                    break;
-                }
-            } else {
-                return Some(false);
-            }
-
-            if line.starts_with(START_LINE) {
-                found_start = true;
-                continue;
-            } else if line.starts_with(END_LINE) {
-                return Some(found_start);
-            }
-        }
-        None
-    }
-}
-
-impl From<Options> for Folder {
-    fn from(opt: Options) -> Self {
-        Folder {
-            opt,
-            ..Default::default()
-        }
-    }
-}
-
-impl Folder {
     fn line_parts<'a>(&self, line: &'a str) -> Option<(&'a str, &'a str, &'a str)> {
         let mut line = line.trim_start().splitn(2, ' ');
         let time = line.next()?.trim_end();
@@ -221,7 +258,7 @@ impl Folder {
             }
 
             if let Some((samples, func, module)) = self.line_parts(&line[4 + indent_chars..]) {
-                if let Ok(samples) = samples.parse::<usize>() {
+                if let Ok(samples) = samples.parse::<u64>() {
                     // The sample counts of the direct children of a non-leaf entry will always
                     // add up to that node's sample count so we only need to keep track of the
                     // sample count at the top of the stack.
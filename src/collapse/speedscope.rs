@@ -0,0 +1,302 @@
+use std::io;
+
+use indexmap::IndexMap;
+use log::warn;
+use serde::Deserialize;
+
+use crate::collapse::common::Occurrences;
+use crate::collapse::Collapse;
+use crate::folded::{FoldedStacks, Recursion};
+use crate::rules::Rules;
+
+// Every speedscope file refers to this schema.
+static SCHEMA: &str = "https://www.speedscope.app/file-format-schema.json";
+
+/// `speedscope` folder configuration options.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Only collapse the profile with this index. Speedscope files often contain one profile per
+    /// thread.
+    ///
+    /// Default is `None`, which collapses all profiles.
+    pub profile: Option<usize>,
+
+    /// Use the name of each profile as the root frame of its stacks.
+    ///
+    /// Default is `false`.
+    pub include_profile_name: bool,
+
+    /// Fold recursive calls into one frame per function, as described by [`Recursion`].
+    ///
+    /// Default is `None`, which keeps them.
+    ///
+    ///   [`Recursion`]: ../../folded/enum.Recursion.html
+    pub collapse_recursion: Option<Recursion>,
+
+    /// [`Rules`] to filter and rewrite the stacks with, which are applied before any recursion is
+    /// collapsed.
+    ///
+    /// Default is no rules.
+    ///
+    ///   [`Rules`]: ../../rules/struct.Rules.html
+    pub rules: Rules,
+}
+
+/// A stack collapser for files in the [speedscope] JSON format.
+///
+/// Both "sampled" and "evented" profiles are supported. Sampled profiles are weighted by their
+/// sample weights, and evented profiles by the time each stack was open. Profiles measured in
+/// time are reported in microseconds, and all other units (like bytes) are reported as is.
+///
+/// To construct one, either use `speedscope::Folder::default()` or create an [`Options`] and
+/// use `speedscope::Folder::from(options)`.
+///
+///   [speedscope]: https://www.speedscope.app/
+#[derive(Clone, Default)]
+pub struct Folder {
+    opt: Options,
+}
+
+impl From<Options> for Folder {
+    fn from(opt: Options) -> Self {
+        Folder { opt }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct File {
+    shared: Shared,
+    profiles: Vec<Profile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Shared {
+    frames: Vec<Frame>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Frame {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Profile {
+    Sampled {
+        #[serde(default)]
+        name: String,
+        unit: String,
+        samples: Vec<Vec<usize>>,
+        weights: Vec<f64>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Evented {
+        #[serde(default)]
+        name: String,
+        unit: String,
+        end_value: f64,
+        events: Vec<Event>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct Event {
+    #[serde(rename = "type")]
+    ty: EventType,
+    at: f64,
+    frame: usize,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+enum EventType {
+    #[serde(rename = "O")]
+    Open,
+    #[serde(rename = "C")]
+    Close,
+}
+
+impl Collapse for Folder {
+    fn collapse<R, W>(&mut self, reader: R, writer: W) -> io::Result<()>
+    where
+        R: io::BufRead,
+        W: io::Write,
+    {
+        self.collapse_to_occurrences(reader)?
+            .write_and_clear(writer)
+    }
+
+    fn collapse_to_stacks<R>(&mut self, reader: R) -> io::Result<FoldedStacks>
+    where
+        R: io::BufRead,
+    {
+        Ok(self.collapse_to_occurrences(reader)?.take_stacks())
+    }
+
+    /// Check for the speedscope schema.
+    fn is_applicable(&mut self, input: &str) -> Option<bool> {
+        let input = input.trim_start();
+        if input.is_empty() {
+            return None;
+        }
+        if !input.starts_with('{') {
+            return Some(false);
+        }
+        if input.contains(SCHEMA) {
+            Some(true)
+        } else {
+            None
+        }
+    }
+}
+
+impl Folder {
+    // Collapses the contents of the provided `reader` into the occurrences of each stack, which
+    // `collapse` writes out and `collapse_to_stacks` returns.
+    fn collapse_to_occurrences<R>(&mut self, mut reader: R) -> io::Result<Occurrences>
+    where
+        R: io::BufRead,
+    {
+        let mut input = String::new();
+        reader.read_to_string(&mut input)?;
+        let file: File = serde_json::from_str(&input).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid speedscope file: {}", e),
+            )
+        })?;
+
+        if let Some(index) = self.opt.profile {
+            if index >= file.profiles.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Profile {} does not exist; the file has {} profiles",
+                        index,
+                        file.profiles.len()
+                    ),
+                ));
+            }
+        }
+
+        // Weights may be fractional, so sum them up before rounding.
+        let mut weights: IndexMap<String, f64> = IndexMap::new();
+        for (i, profile) in file.profiles.iter().enumerate() {
+            if self.opt.profile.map(|index| index != i).unwrap_or(false) {
+                continue;
+            }
+            match *profile {
+                Profile::Sampled {
+                    ref name,
+                    ref unit,
+                    ref samples,
+                    weights: ref sample_weights,
+                } => {
+                    let scale = unit_scale(unit);
+                    if samples.len() != sample_weights.len() {
+                        warn!(
+                            "Profile {} has {} samples but {} weights",
+                            i,
+                            samples.len(),
+                            sample_weights.len()
+                        );
+                    }
+                    for (sample, weight) in samples.iter().zip(sample_weights) {
+                        let stack = self.stack(&file.shared.frames, name, sample);
+                        *weights.entry(stack).or_insert(0.0) += weight * scale;
+                    }
+                }
+                Profile::Evented {
+                    ref name,
+                    ref unit,
+                    end_value,
+                    ref events,
+                } => {
+                    let scale = unit_scale(unit);
+                    let mut open: Vec<usize> = Vec::new();
+                    let mut last = None;
+                    for event in events {
+                        if let Some(last) = last {
+                            if !open.is_empty() && event.at > last {
+                                let stack = self.stack(&file.shared.frames, name, &open);
+                                *weights.entry(stack).or_insert(0.0) += (event.at - last) * scale;
+                            }
+                        }
+                        last = Some(event.at);
+
+                        match event.ty {
+                            EventType::Open => open.push(event.frame),
+                            EventType::Close => close_frame(&mut open, event.frame),
+                        }
+                    }
+                    // Anything still open ends with the profile.
+                    if let Some(last) = last {
+                        if !open.is_empty() && end_value > last {
+                            let stack = self.stack(&file.shared.frames, name, &open);
+                            *weights.entry(stack).or_insert(0.0) += (end_value - last) * scale;
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut occurrences = Occurrences::new(1);
+        occurrences.set_collapse_recursion(self.opt.collapse_recursion);
+        occurrences.set_rules(&self.opt.rules);
+        for (stack, weight) in weights {
+            let count = weight.round();
+            if !stack.is_empty() && count >= 1.0 {
+                occurrences.insert_or_add(stack, count as u64);
+            }
+        }
+        Ok(occurrences)
+    }
+
+    /// Builds a stack from frame indices, root first.
+    fn stack(&self, frames: &[Frame], profile_name: &str, indices: &[usize]) -> String {
+        let mut stack = String::new();
+        if self.opt.include_profile_name && !profile_name.is_empty() {
+            stack.push_str(&profile_name.replace(';', ":"));
+        }
+        for &i in indices {
+            if !stack.is_empty() {
+                stack.push(';');
+            }
+            match frames.get(i) {
+                Some(frame) => stack.push_str(&frame.name.replace(';', ":")),
+                None => {
+                    warn!("Reference to unknown frame {}", i);
+                    stack.push_str("[unknown]");
+                }
+            }
+        }
+        stack
+    }
+}
+
+/// Closes the most recently opened instance of `frame`, along with any frames opened after it.
+fn close_frame(open: &mut Vec<usize>, frame: usize) {
+    match open.iter().rposition(|&f| f == frame) {
+        Some(pos) => {
+            if pos + 1 != open.len() {
+                warn!(
+                    "Frame {} closed while frames opened after it were still open",
+                    frame
+                );
+            }
+            open.truncate(pos);
+        }
+        None => warn!("Frame {} closed without being opened", frame),
+    }
+}
+
+/// How to scale values in the given unit into what we report: microseconds for times, and
+/// anything else as is.
+fn unit_scale(unit: &str) -> f64 {
+    match unit {
+        "nanoseconds" => 0.001,
+        "microseconds" => 1.0,
+        "milliseconds" => 1_000.0,
+        "seconds" => 1_000_000.0,
+        _ => 1.0,
+    }
+}
@@ -0,0 +1,356 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use addr2line::Loader;
+use fnv::FnvHashMap;
+use log::{debug, warn};
+use object::{Object, ObjectSegment, ReadCache};
+use symbolic_demangle::demangle;
+
+/// Finds the functions at addresses that a profiler couldn't find symbols for, like those of
+/// stripped binaries in containers, in the debug information of their modules.
+///
+/// The debug information of a module, like `/usr/lib/libfoo.so`, is looked for in each of the
+/// given directories in turn:
+///
+/// - in a `perf` build-id cache (like `~/.debug`), as `usr/lib/libfoo.so/<build-id>/debug` or
+///   `usr/lib/libfoo.so/<build-id>/elf`, with the build-id of the module if it exists locally,
+/// - in a debug file directory (like `/usr/lib/debug`), as `.build-id/ab/cdef….debug` if the
+///   module exists locally, or as `usr/lib/libfoo.so.debug` or `usr/lib/libfoo.so`,
+/// - or directly in the directory, as `libfoo.so.debug` or `libfoo.so`.
+///
+/// If none of those exist, the module itself is used, if it exists locally.
+///
+/// A `Symbolizer` can be cloned cheaply, and clones share the modules they have loaded and the
+/// addresses they have looked up, so that every worker thread of a collapser only loads each
+/// module once.
+#[derive(Clone)]
+pub struct Symbolizer {
+    debug_dirs: Vec<PathBuf>,
+    inlines: bool,
+    lines: bool,
+    /// The modules that have been looked up, by path, or `None` if they couldn't be loaded.
+    modules: Arc<Mutex<Modules>>,
+}
+
+type Modules = FnvHashMap<String, Option<Arc<Mutex<Module>>>>;
+
+impl fmt::Debug for Symbolizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Symbolizer")
+            .field("debug_dirs", &self.debug_dirs)
+            .field("inlines", &self.inlines)
+            .field("lines", &self.lines)
+            .finish()
+    }
+}
+
+/// A function found by a [`Symbolizer`].
+///
+///   [`Symbolizer`]: struct.Symbolizer.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Function {
+    /// The demangled name of the function.
+    pub name: String,
+    /// The file and line in the function, like `src/parse.c:42`, if the `Symbolizer` was asked
+    /// for lines and the debug information has them.
+    pub location: Option<String>,
+}
+
+impl Symbolizer {
+    /// Creates a `Symbolizer` that looks for debug information in the given directories, in
+    /// order, and finds the outermost function at each address, without its file and line.
+    pub fn new<I, P>(debug_dirs: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        Symbolizer {
+            debug_dirs: debug_dirs.into_iter().map(Into::into).collect(),
+            inlines: false,
+            lines: false,
+            modules: Arc::default(),
+        }
+    }
+
+    /// Also finds the functions that were inlined at each address.
+    pub fn with_inlines(mut self) -> Self {
+        self.inlines = true;
+        self
+    }
+
+    /// Also finds the file and line of each function.
+    pub fn with_lines(mut self) -> Self {
+        self.lines = true;
+        self
+    }
+
+    /// Returns the functions at the address `pc` in `module`, from the outermost one to the one
+    /// that was inlined the deepest, or `None` if they can't be found.
+    ///
+    /// `pc` is the address that `perf script` prints, and `offset` is the offset of that address
+    /// in the module, which `perf script -F +dsoff` prints after the module (like
+    /// `/usr/lib/libfoo.so+0x194d0`). Without an offset, only the addresses of modules that are
+    /// always loaded at the same address (like executables that aren't position-independent)
+    /// can be found.
+    pub fn symbolize(&self, module: &str, pc: u64, offset: Option<u64>) -> Option<Vec<Function>> {
+        // Kernel modules, JIT maps, and the like have no debug files.
+        if !module.starts_with('/') || module.starts_with("/tmp/perf-") {
+            return None;
+        }
+        let module = {
+            let mut modules = self.modules.lock().unwrap();
+            modules
+                .entry(module.to_string())
+                .or_insert_with(|| self.load(module).map(|m| Arc::new(Mutex::new(m))))
+                .clone()?
+        };
+        let mut module = module.lock().unwrap();
+        let address = module.address(pc, offset)?;
+        if let Some(functions) = module.functions.get(&address) {
+            return functions.clone();
+        }
+        let functions = module.find(address, self.inlines, self.lines);
+        module.functions.insert(address, functions.clone());
+        functions
+    }
+
+    fn load(&self, module: &str) -> Option<Module> {
+        let original = Path::new(module);
+        let original = if original.is_file() {
+            Some(original)
+        } else {
+            None
+        };
+        let build_id = original.and_then(build_id);
+        let path = match self.find_debug_file(module, build_id.as_deref()) {
+            Some(path) => path,
+            None => {
+                debug!("No debug information found for {}", module);
+                return None;
+            }
+        };
+        // Separate debug files don't keep the file offsets of segments, so those of the module
+        // itself are used if it's around.
+        let segments = match original.and_then(segments) {
+            Some(segments) => segments,
+            None => segments(&path)?,
+        };
+        match Loader::new(&path) {
+            Ok(loader) => {
+                debug!("Loaded debug information for {} from {:?}", module, path);
+                Some(Module {
+                    loader,
+                    segments,
+                    functions: FnvHashMap::default(),
+                })
+            }
+            Err(e) => {
+                warn!("Couldn't load debug information from {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    fn find_debug_file(&self, module: &str, build_id: Option<&str>) -> Option<PathBuf> {
+        let relative = module.trim_start_matches('/');
+        let file_name = Path::new(module).file_name()?.to_str()?;
+        for dir in &self.debug_dirs {
+            let cache = dir.join(relative);
+            if cache.is_dir() {
+                // A build-id cache has a directory per build of the module.
+                let builds = match build_id {
+                    Some(build_id) => vec![cache.join(build_id)],
+                    None => {
+                        let mut builds: Vec<_> = fs::read_dir(&cache)
+                            .ok()?
+                            .filter_map(|entry| Some(entry.ok()?.path()))
+                            .collect();
+                        builds.sort();
+                        builds
+                    }
+                };
+                for build in builds {
+                    if let Some(path) = first_file(&[build.join("debug"), build.join("elf")]) {
+                        return Some(path);
+                    }
+                }
+            }
+
+            let mut candidates = Vec::new();
+            if let Some(build_id) = build_id {
+                if build_id.len() > 2 {
+                    let path = dir.join(".build-id").join(&build_id[..2]);
+                    candidates.push(path.join(format!("{}.debug", &build_id[2..])));
+                }
+            }
+            candidates.push(dir.join(format!("{}.debug", relative)));
+            candidates.push(cache);
+            candidates.push(dir.join(format!("{}.debug", file_name)));
+            candidates.push(dir.join(file_name));
+            if let Some(path) = first_file(&candidates) {
+                return Some(path);
+            }
+        }
+        first_file(&[PathBuf::from(module)])
+    }
+}
+
+/// A module with its debug information.
+struct Module {
+    loader: Loader,
+    segments: Vec<Segment>,
+    /// The functions at each address that has been looked up.
+    functions: FnvHashMap<u64, Option<Vec<Function>>>,
+}
+
+/// A loadable segment of a module.
+#[derive(Clone, Copy, Debug)]
+struct Segment {
+    address: u64,
+    offset: u64,
+    size: u64,
+}
+
+impl Module {
+    // Returns the address in the module's debug information of `pc`, or of `offset` in the module.
+    fn address(&self, pc: u64, offset: Option<u64>) -> Option<u64> {
+        match offset {
+            Some(offset) => Some(
+                self.segments
+                    .iter()
+                    .find(|s| offset >= s.offset && offset - s.offset < s.size)
+                    .map(|s| offset - s.offset + s.address)
+                    .unwrap_or(offset),
+            ),
+            None => self
+                .segments
+                .iter()
+                .find(|s| pc >= s.address && pc - s.address < s.size)
+                .map(|_| pc),
+        }
+    }
+
+    fn find(&self, address: u64, inlines: bool, lines: bool) -> Option<Vec<Function>> {
+        let mut functions = Vec::new();
+        if let Ok(mut frames) = self.loader.find_frames(address) {
+            while let Ok(Some(frame)) = frames.next() {
+                let name = match frame.function {
+                    Some(ref function) => match function.raw_name() {
+                        Ok(name) => demangle(&name).into_owned(),
+                        Err(_) => continue,
+                    },
+                    None => continue,
+                };
+                let location = if lines {
+                    frame.location.and_then(|location| {
+                        let file = location.file?;
+                        Some(match location.line {
+                            Some(line) => format!("{}:{}", file, line),
+                            None => file.to_string(),
+                        })
+                    })
+                } else {
+                    None
+                };
+                functions.push(Function { name, location });
+            }
+        }
+        if functions.is_empty() {
+            // Without DWARF, there may still be a symbol table.
+            let name = self.loader.find_symbol(address)?;
+            functions.push(Function {
+                name: demangle(name).into_owned(),
+                location: None,
+            });
+        }
+
+        // The frames come innermost first.
+        functions.reverse();
+        if !inlines {
+            functions.truncate(1);
+        }
+        for function in &mut functions {
+            // Semicolons separate frames in folded stacks.
+            function.name = function.name.replace(';', ":");
+            if let Some(ref mut location) = function.location {
+                *location = location.replace(';', ":");
+            }
+        }
+        Some(functions)
+    }
+}
+
+fn first_file(candidates: &[PathBuf]) -> Option<PathBuf> {
+    candidates.iter().find(|path| path.is_file()).cloned()
+}
+
+// Reads the build-id of the ELF file at `path`, in hex.
+fn build_id(path: &Path) -> Option<String> {
+    let cache = ReadCache::new(File::open(path).ok()?);
+    let file = object::File::parse(&cache).ok()?;
+    let build_id = file.build_id().ok()??;
+    Some(build_id.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+// Reads the loadable segments of the object file at `path`.
+fn segments(path: &Path) -> Option<Vec<Segment>> {
+    let cache = ReadCache::new(File::open(path).ok()?);
+    let file = object::File::parse(&cache).ok()?;
+    let first = file.segments().map(|s| s.address()).min().unwrap_or(0);
+    Some(
+        file.segments()
+            .map(|segment| {
+                let (offset, size) = segment.file_range();
+                if size == 0 {
+                    // A separate debug file, which only has the addresses of the segments. The
+                    // offset is guessed to be as far from the start of the file as the segment
+                    // is from the first one, which holds for the code of most binaries.
+                    Segment {
+                        address: segment.address(),
+                        offset: segment.address() - first,
+                        size: segment.size(),
+                    }
+                } else {
+                    Segment {
+                        address: segment.address(),
+                        offset,
+                        size,
+                    }
+                }
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_addresses_of_offsets() {
+        let segment = |address, offset, size| Segment {
+            address,
+            offset,
+            size,
+        };
+        let module = Module {
+            loader: Loader::new("./tests/data/collapse-perf/symbolize/usr/lib/libdemo.so.debug")
+                .unwrap(),
+            segments: vec![
+                segment(0x40_0000, 0, 0x1000),
+                segment(0x40_2000, 0x1000, 0x800),
+            ],
+            functions: FnvHashMap::default(),
+        };
+        assert_eq!(
+            module.address(0x7f00_0000_1234, Some(0x1234)),
+            Some(0x40_2234)
+        );
+        assert_eq!(module.address(0x7f00_0000_1234, Some(0x2234)), Some(0x2234));
+        assert_eq!(module.address(0x40_0010, None), Some(0x40_0010));
+        assert_eq!(module.address(0x7f00_0000_1234, None), None);
+    }
+}
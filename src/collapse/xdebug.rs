@@ -0,0 +1,353 @@
+use std::io;
+use std::str::FromStr;
+
+use log::{error, warn};
+
+use crate::collapse::common::Occurrences;
+use crate::collapse::Collapse;
+use crate::folded::{FoldedStacks, Recursion};
+use crate::rules::Rules;
+
+// The first line of a computerized (`trace_format=1`) trace file.
+static VERSION_LINE: &str = "Version: ";
+
+// The second line of a computerized trace file.
+static FILE_FORMAT_LINE: &str = "File format: ";
+
+// Records start after this line.
+static START_LINE: &str = "TRACE START";
+
+// We know we're done when we get to this line.
+static END_LINE: &str = "TRACE END";
+
+// xdebug reports times in seconds with microsecond precision, but folded stacks need integer
+// counts, so times are reported in microseconds.
+const MICROS_PER_SEC: f64 = 1_000_000.0;
+
+/// What to count for each stack.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Weight {
+    /// Count the number of calls that ended in each stack.
+    Calls,
+
+    /// Count the time (in microseconds) spent in each stack's leaf function, including the time
+    /// spent in the functions it called.
+    ///
+    /// Note that a parent's frame in the resulting flame graph is then wider than the sum of
+    /// its children, since each child's time is counted both for the child and for the parent.
+    InclusiveTime,
+
+    /// Count the time (in microseconds) spent in each stack's leaf function itself, excluding
+    /// the time spent in the functions it called.
+    #[default]
+    ExclusiveTime,
+}
+
+impl FromStr for Weight {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "calls" => Ok(Weight::Calls),
+            "inclusive" => Ok(Weight::InclusiveTime),
+            "exclusive" => Ok(Weight::ExclusiveTime),
+            unknown => Err(format!("unknown weight: {}", unknown)),
+        }
+    }
+}
+
+/// `xdebug` folder configuration options.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// What each stack is weighted by.
+    ///
+    /// Default is `Weight::ExclusiveTime`.
+    pub weight: Weight,
+
+    /// Fold recursive calls into one frame per function, as described by [`Recursion`].
+    ///
+    /// Default is `None`, which keeps them.
+    ///
+    ///   [`Recursion`]: ../../folded/enum.Recursion.html
+    pub collapse_recursion: Option<Recursion>,
+
+    /// [`Rules`] to filter and rewrite the stacks with, which are applied before any recursion is
+    /// collapsed.
+    ///
+    /// Default is no rules.
+    ///
+    ///   [`Rules`]: ../../rules/struct.Rules.html
+    pub rules: Rules,
+}
+
+/// A function call that has been entered, but not exited yet.
+#[derive(Clone, Debug)]
+struct Call {
+    /// The function number xdebug assigned to this call.
+    number: String,
+
+    /// The frame name to use for this call.
+    name: String,
+
+    /// Time index (in microseconds) at which the call was entered.
+    start: u64,
+
+    /// Time (in microseconds) spent in calls made from this one.
+    children: u64,
+}
+
+/// A stack collapser for function traces written by [xdebug](https://xdebug.org/docs/trace)
+/// with `xdebug.trace_format=1`.
+///
+/// Call stacks are reconstructed from the function entry and exit records, and each stack is
+/// weighted according to [`Options::weight`].
+///
+/// To construct one, either use `xdebug::Folder::default()` or create an [`Options`] and use
+/// `xdebug::Folder::from(options)`.
+#[derive(Clone, Default)]
+pub struct Folder {
+    /// Calls that are currently on the stack.
+    stack: Vec<Call>,
+
+    /// The last time index seen. Used to close calls that never exited.
+    last_time: u64,
+
+    opt: Options,
+}
+
+impl From<Options> for Folder {
+    fn from(opt: Options) -> Self {
+        Folder {
+            opt,
+            ..Default::default()
+        }
+    }
+}
+
+impl Collapse for Folder {
+    fn collapse<R, W>(&mut self, reader: R, writer: W) -> io::Result<()>
+    where
+        R: io::BufRead,
+        W: io::Write,
+    {
+        self.collapse_to_occurrences(reader)?
+            .write_and_clear(writer)
+    }
+
+    fn collapse_to_stacks<R>(&mut self, reader: R) -> io::Result<FoldedStacks>
+    where
+        R: io::BufRead,
+    {
+        Ok(self.collapse_to_occurrences(reader)?.take_stacks())
+    }
+
+    /// Check for the header of a computerized trace.
+    fn is_applicable(&mut self, input: &str) -> Option<bool> {
+        let mut lines = input.lines().filter(|l| !l.trim().is_empty());
+        match lines.next() {
+            Some(line) if line.starts_with(VERSION_LINE) => {}
+            Some(_) => return Some(false),
+            None => return None,
+        }
+        match lines.next() {
+            Some(line) if line.starts_with(FILE_FORMAT_LINE) => {}
+            Some(_) => return Some(false),
+            None => return None,
+        }
+        match lines.next() {
+            Some(line) if line.starts_with(START_LINE) => {}
+            Some(_) => return Some(false),
+            None => return None,
+        }
+        // the human readable format doesn't use tab separated records
+        lines
+            .next()
+            .map(|line| line.starts_with(END_LINE) || line.split('\t').count() >= 5)
+    }
+}
+
+impl Folder {
+    // Collapses the contents of the provided `reader` into the occurrences of each stack, which
+    // `collapse` writes out and `collapse_to_stacks` returns.
+    fn collapse_to_occurrences<R>(&mut self, mut reader: R) -> io::Result<Occurrences>
+    where
+        R: io::BufRead,
+    {
+        // Consume the header...
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                warn!("File ended before start of trace");
+                return Ok(Occurrences::new(1));
+            };
+            if line.starts_with(START_LINE) {
+                break;
+            }
+        }
+
+        // Process the data...
+        let mut occurrences = Occurrences::new(1);
+        occurrences.set_collapse_recursion(self.opt.collapse_recursion);
+        occurrences.set_rules(&self.opt.rules);
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                warn!("File ended before end of trace");
+                break;
+            }
+            let line = line.trim_end_matches(&['\n', '\r'][..]);
+            if line.starts_with(END_LINE) {
+                break;
+            } else if !line.is_empty() {
+                self.on_line(line, &mut occurrences);
+            }
+        }
+
+        // Calls that never returned (e.g., because the script called `exit()`) end with the
+        // trace.
+        while !self.stack.is_empty() {
+            self.on_exit(None, self.last_time, &mut occurrences);
+        }
+
+        // Reset the state...
+        self.last_time = 0;
+        Ok(occurrences)
+    }
+
+    // we have a record line, with tab separated fields like:
+    //
+    //     level  fn#  0  time      memory  name    user?  include  file  line  nparams  params...
+    //     2      3    0  0.000275  393528  foo     1               a.php 3     0
+    //     level  fn#  1  time      memory
+    //     2      3    1  0.000310  393528
+    //     level  fn#  R                    return value
+    //     2      3    R                    42
+    //                    time      memory
+    //                    0.000410  8192
+    fn on_line(&mut self, line: &str, occurrences: &mut Occurrences) {
+        let mut fields = line.split('\t');
+        let level = fields.next().unwrap_or("");
+        let number = fields.next().unwrap_or("");
+        let kind = fields.next().unwrap_or("");
+        let time = fields.next().unwrap_or("");
+
+        if level.is_empty() && number.is_empty() {
+            // The summary line at the end of the trace with the total time and memory.
+            if let Some(time) = parse_time(time) {
+                self.last_time = time;
+            }
+            return;
+        }
+
+        if kind == "R" {
+            // return values don't tell us anything about time or stacks
+            return;
+        }
+
+        let time = match parse_time(time) {
+            Some(time) => time,
+            None => {
+                error!("Invalid time index in line:\n{}", line);
+                return;
+            }
+        };
+        self.last_time = time;
+
+        match kind {
+            "0" => {
+                let _memory = fields.next();
+                let name = match fields.next() {
+                    Some(name) if !name.is_empty() => name,
+                    _ => {
+                        error!("Missing function name in line:\n{}", line);
+                        return;
+                    }
+                };
+                let _user_defined = fields.next();
+                let include = fields.next().unwrap_or("");
+
+                if let Ok(level) = level.parse::<usize>() {
+                    if level != self.stack.len() + 1 {
+                        warn!(
+                            "Function entry at level {} while stack depth is {}:\n{}",
+                            level,
+                            self.stack.len(),
+                            line
+                        );
+                    }
+                }
+
+                let mut name = name.replace(';', ":");
+                if !include.is_empty() {
+                    // e.g. `require_once(/var/www/vendor/autoload.php)`
+                    name.push('(');
+                    name.push_str(&include.replace(';', ":"));
+                    name.push(')');
+                }
+                self.stack.push(Call {
+                    number: number.to_string(),
+                    name,
+                    start: time,
+                    children: 0,
+                });
+            }
+            "1" => self.on_exit(Some(number), time, occurrences),
+            _ => error!("Unknown record type {:?} in line:\n{}", kind, line),
+        }
+    }
+
+    fn on_exit(&mut self, number: Option<&str>, time: u64, occurrences: &mut Occurrences) {
+        if let Some(number) = number {
+            match self.stack.iter().rposition(|call| call.number == number) {
+                Some(i) => {
+                    // Any calls above the one exiting never got an exit record; close those too.
+                    while self.stack.len() > i + 1 {
+                        if let Some(call) = self.stack.last() {
+                            warn!("Function {} exited without an exit record", call.name);
+                        }
+                        self.on_exit(None, time, occurrences);
+                    }
+                }
+                None => {
+                    error!("Exit record for function {} that was never entered", number);
+                    return;
+                }
+            }
+        }
+
+        let mut key = String::new();
+        for (i, call) in self.stack.iter().enumerate() {
+            if i > 0 {
+                key.push(';');
+            }
+            key.push_str(&call.name);
+        }
+
+        let call = match self.stack.pop() {
+            Some(call) => call,
+            None => return,
+        };
+        let inclusive = time.saturating_sub(call.start);
+        if let Some(parent) = self.stack.last_mut() {
+            parent.children += inclusive;
+        }
+
+        let count = match self.opt.weight {
+            Weight::Calls => 1,
+            Weight::InclusiveTime => inclusive,
+            Weight::ExclusiveTime => inclusive.saturating_sub(call.children),
+        };
+        if count > 0 {
+            occurrences.insert_or_add(key, count);
+        }
+    }
+}
+
+fn parse_time(time: &str) -> Option<u64> {
+    let time = time.trim().parse::<f64>().ok()?;
+    if time < 0.0 {
+        return None;
+    }
+    Some((time * MICROS_PER_SEC).round() as u64)
+}
@@ -1,12 +1,21 @@
+use std::borrow::Cow;
 use std::fs::File;
 use std::io::{self, prelude::*};
 use std::path::Path;
 
 use fnv::FnvHashMap;
+use lazy_static::lazy_static;
 use log::warn;
 
+use crate::rules::Rules;
+
 const READER_CAPACITY: usize = 128 * 1024;
 
+lazy_static! {
+    // What `strip_hex` does, as a rule.
+    static ref STRIP_HEX: Rules = "frame '0x[0-9a-fA-F]+' rename '0x...'".parse().unwrap();
+}
+
 #[derive(Debug, Clone, Copy, Default)]
 struct Counts {
     first: usize,
@@ -16,7 +25,7 @@ struct Counts {
 /// Configure the generated output.
 ///
 /// All options default to off.
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct Options {
     /// Normalize the first profile count to match the second.
     ///
@@ -27,7 +36,22 @@ pub struct Options {
     pub normalize: bool,
 
     /// Strip hex numbers (addresses) of the form "0x45ef2173" and replace with "0x...".
+    ///
+    /// This is the same as the rule `frame '0x[0-9a-fA-F]+' rename '0x...'`, applied before
+    /// [`rules`].
+    ///
+    ///   [`rules`]: #structfield.rules
     pub strip_hex: bool,
+
+    /// Rules that filter and rewrite the stacks of both profiles before they are compared, so
+    /// that stacks that only differ in, say, addresses or generic parameters are merged.
+    ///
+    /// Stacks that the rules drop are left out of both profiles, and of their totals.
+    ///
+    /// See [`Rules`] for the rules that there are.
+    ///
+    ///   [`Rules`]: ../rules/struct.Rules.html
+    pub rules: Rules,
 }
 
 /// Produce an output that can be used to generate a differential flame graph.
@@ -47,8 +71,8 @@ where
     W: Write,
 {
     let mut stack_counts = FnvHashMap::default();
-    let total1 = parse_stack_counts(opt, &mut stack_counts, before, true)?;
-    let total2 = parse_stack_counts(opt, &mut stack_counts, after, false)?;
+    let total1 = parse_stack_counts(&opt, &mut stack_counts, before, true)?;
+    let total2 = parse_stack_counts(&opt, &mut stack_counts, after, false)?;
     if opt.normalize && total1 != total2 {
         for counts in stack_counts.values_mut() {
             counts.first = (counts.first as f64 * total2 as f64 / total1 as f64) as usize;
@@ -81,7 +105,7 @@ where
 
 // Populate stack_counts based on lines from the reader and returns the sum of the sample counts.
 fn parse_stack_counts<R>(
-    opt: Options,
+    opt: &Options,
     stack_counts: &mut FnvHashMap<String, Counts>,
     mut reader: R,
     is_first: bool,
@@ -99,9 +123,11 @@ where
             break;
         }
 
-        if let Some((stack, count)) =
-            parse_line(&line, opt.strip_hex, &mut stripped_fractional_samples)
-        {
+        if let Some((stack, count)) = parse_line(&line, opt, &mut stripped_fractional_samples) {
+            let stack = match stack {
+                Some(stack) => stack,
+                None => continue,
+            };
             let mut counts = stack_counts.entry(stack).or_default();
             if is_first {
                 counts.first += count;
@@ -129,12 +155,13 @@ where
     Ok(())
 }
 
-// Parse stack and sample count from line.
+// Parse stack and sample count from line, and apply the rules of `opt` to the stack. The stack is
+// `None` if the rules dropped it.
 fn parse_line(
     line: &str,
-    strip_hex: bool,
+    opt: &Options,
     stripped_fractional_samples: &mut bool,
-) -> Option<(String, usize)> {
+) -> Option<(Option<String>, usize)> {
     let samplesi = line.rfind(' ')?;
     let mut samples = line[samplesi + 1..].trim_end();
 
@@ -160,28 +187,12 @@ fn parse_line(
     }
 
     let nsamples = samples.parse::<usize>().ok()?;
-    let stack = line[..samplesi].trim_end();
-    if strip_hex {
-        Some((strip_hex_address(stack), nsamples))
-    } else {
-        Some((stack.to_string(), nsamples))
-    }
-}
-
-// Replace all hex strings like "0x45ef2173" with "0x...".
-fn strip_hex_address(mut stack: &str) -> String {
-    let mut stripped = String::with_capacity(stack.len());
-    while let Some(idx) = stack.find("0x") {
-        stripped.push_str(&stack[..idx + 2]);
-        let ndigits = stack[idx + 2..]
-            .chars()
-            .take_while(|c| c.is_digit(16))
-            .count();
-        if ndigits > 0 {
-            stripped.push_str("...");
-        }
-        stack = &stack[idx + 2 + ndigits..];
+    let mut stack = line[..samplesi].trim_end();
+    let stripped;
+    if opt.strip_hex {
+        // The rule only renames frames, so it never drops the stack.
+        stripped = STRIP_HEX.apply(stack).unwrap_or_default();
+        stack = &stripped;
     }
-    stripped.push_str(stack);
-    stripped
+    Some((opt.rules.apply(stack).map(Cow::into_owned), nsamples))
 }
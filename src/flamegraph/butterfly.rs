@@ -0,0 +1,169 @@
+use std::io;
+
+use log::warn;
+use regex::Regex;
+use str_stack::StrStack;
+
+use super::merge::{self, ParsedLine, TimedFrame};
+use super::{deannotate, Options};
+
+/// Merges the stacks that go through a function matching `function` into the frames of a
+/// butterfly graph, with the callers of the function above it and its callees below it.
+///
+/// `function` is a regular expression that has to match the whole (deannotated) name of a
+/// function. In each stack, the first matching function is the one the stack is split at, so
+/// recursive calls end up among the callees. The callers, with their stacks reversed, and the
+/// callees are merged separately, and both have the matching functions at their root, which
+/// therefore line up.
+///
+/// `lines` are the parsed folded stack lines, of which `ignored` couldn't be parsed. The frames are
+/// returned as if for an inverted flame graph, with the depth of each frame being the row it is
+/// drawn in, counting from the top. Frames narrower than `opt.min_width` have already been left
+/// out. Otherwise, the return value is the same as that of [`merge::frames`].
+pub(super) fn frames<'r>(
+    opt: &Options<'_>,
+    function: &str,
+    lines: &[ParsedLine<'_>],
+    ignored: usize,
+    stacks: &'r mut StrStack,
+) -> quick_xml::Result<(Vec<TimedFrame<'r>>, u64, usize, u64)> {
+    let regex = Regex::new(&format!("^(?:{})$", function))
+        .map_err(|e| quick_xml::Error::Io(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+
+    let mut counts = Vec::new();
+    let mut stack = String::new();
+    for parsed in lines {
+        let frames: Vec<&str> = parsed.stack.split(';').collect();
+        let focus = match frames.iter().position(|f| regex.is_match(deannotate(f))) {
+            Some(focus) => focus,
+            None => continue,
+        };
+
+        // Callers and callees alternate in `stacks`.
+        stack.clear();
+        for (i, frame) in frames[..=focus].iter().rev().enumerate() {
+            if i != 0 {
+                stack.push(';');
+            }
+            stack.push_str(frame);
+        }
+        stacks.push(&stack);
+        stacks.push(&frames[focus..].join(";"));
+        counts.push((parsed.original_samples, parsed.samples));
+    }
+    if stacks.is_empty() {
+        warn!("No stacks go through a function matching {}", function);
+    }
+
+    let stacks: &'r StrStack = stacks;
+    let parsed = |skip| {
+        let mut parsed: Vec<_> = stacks
+            .iter()
+            .skip(skip)
+            .step_by(2)
+            .zip(&counts)
+            .map(|(stack, &(original_samples, samples))| ParsedLine {
+                stack,
+                original_samples,
+                samples,
+            })
+            .collect();
+        parsed.sort_by(|a, b| a.stack.cmp(b.stack));
+        parsed
+    };
+    let (mut callers, time, _, callers_delta_max) = merge::parsed_frames(&parsed(0))?;
+    let (mut callees, _, _, callees_delta_max) = merge::parsed_frames(&parsed(1))?;
+
+    let minwidth_time = opt.min_width * time as f64 / 100.0;
+    let wide_enough =
+        |frame: &TimedFrame<'_>| (frame.end_time - frame.start_time) as f64 >= minwidth_time;
+    // The root is left out, and the matching functions are drawn from the callees only.
+    callers.retain(|frame| frame.location.depth > 1 && wide_enough(frame));
+    callees.retain(|frame| frame.location.depth > 0 && wide_enough(frame));
+
+    let callers_depth = callers
+        .iter()
+        .map(|frame| frame.location.depth)
+        .max()
+        .unwrap_or(1);
+    for frame in &mut callers {
+        frame.location.depth = callers_depth - frame.location.depth;
+    }
+    for frame in &mut callees {
+        frame.location.depth = callers_depth - 1 + (frame.location.depth - 1);
+    }
+    callers.append(&mut callees);
+
+    Ok((
+        callers,
+        time,
+        ignored,
+        std::cmp::max(callers_delta_max, callees_delta_max),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(function: &str, lines: &[&str]) -> Vec<String> {
+        let mut stacks = StrStack::new();
+        let (lines, ignored) = merge::parse_lines(lines.iter().cloned());
+        let (frames, _, _, _) =
+            frames(&Options::default(), function, &lines, ignored, &mut stacks).unwrap();
+        let mut rows: Vec<_> = frames
+            .iter()
+            .map(|frame| {
+                (
+                    frame.location.depth,
+                    frame.start_time,
+                    frame.location.function,
+                    frame.end_time,
+                )
+            })
+            .collect();
+        rows.sort_unstable();
+        rows.into_iter()
+            .map(|(row, start, function, end)| format!("{}: {} {}..{}", row, function, start, end))
+            .collect()
+    }
+
+    #[test]
+    fn callers_above_callees_below() {
+        let lines = [
+            "main;a;memcpy;copy 2",
+            "main;b;memcpy 1",
+            "main;b 5",
+            "main;a;memcpy;memcpy 1",
+        ];
+        assert_eq!(
+            rows("memcpy", &lines),
+            vec![
+                // callers, from the outermost
+                "0: main 0..3",
+                "0: main 3..4",
+                "1: a 0..3",
+                "1: b 3..4",
+                // the function itself
+                "2: memcpy 0..4",
+                // callees, with the recursive call
+                "3: copy 1..3",
+                "3: memcpy 3..4",
+            ]
+        );
+    }
+
+    #[test]
+    fn matches_whole_deannotated_names() {
+        let lines = ["main;memcpy_[k] 1", "main;memcpy_erms 2", "main;xmemcpy 4"];
+        let rows = rows("memcpy|memmove", &lines);
+        assert_eq!(rows, vec!["0: main 0..1", "1: memcpy_[k] 0..1"]);
+    }
+
+    #[test]
+    fn invalid_regex_is_an_error() {
+        let mut stacks = StrStack::new();
+        let (lines, ignored) = merge::parse_lines(vec!["main 1"]);
+        assert!(frames(&Options::default(), "(", &lines, ignored, &mut stacks).is_err());
+    }
+}
@@ -150,7 +150,7 @@ macro_rules! u8_from_hex_iter {
     };
 }
 
-fn parse_flat_bgcolor(s: &str) -> Option<Color> {
+pub(super) fn parse_flat_bgcolor(s: &str) -> Option<Color> {
     if !s.starts_with('#') || (s.len() != 7) {
         None
     } else {
@@ -341,7 +341,7 @@ pub(super) fn color(palette: Palette, hash: bool, name: &str, thread_rng: &mut T
     rgb_components_for_palette(palette, name, v1, v2, v3)
 }
 
-pub(super) fn color_scale(value: isize, max: usize) -> Color {
+pub(super) fn color_scale(value: i64, max: u64) -> Color {
     if value == 0 {
         Color {
             r: 255,
@@ -351,12 +351,12 @@ pub(super) fn color_scale(value: isize, max: usize) -> Color {
     } else if value > 0 {
         // A positive value indicates _more_ samples,
         // and hence more time spent, so we give it a red hue.
-        let c = (210 * (max as isize - value) / max as isize) as u8;
+        let c = (210 * (i128::from(max) - i128::from(value)) / i128::from(max)) as u8;
         Color { r: 255, g: c, b: c }
     } else {
         // A negative value indicates _fewer_ samples,
         // or a speed-up, so we give it a green hue.
-        let c = (210 * (max as isize + value) / max as isize) as u8;
+        let c = (210 * (i128::from(max) + i128::from(value)) / i128::from(max)) as u8;
         Color { r: c, g: c, b: 255 }
     }
 }
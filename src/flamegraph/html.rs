@@ -0,0 +1,305 @@
+use std::borrow::Cow;
+use std::io::{self, Write};
+
+use fnv::FnvHashMap;
+use log::warn;
+use serde::Serialize;
+use str_stack::StrStack;
+
+use super::merge::TimedFrame;
+use super::{color, deannotate, frame_color, merged_frames, Direction, Options, Stacks};
+
+// Everything before and after the embedded frame data.
+static TEMPLATE: &str = include_str!("flamegraph.html");
+static DATA_MARKER: &str = "/*INFERNO_DATA*/";
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Data<'a> {
+    title: &'a str,
+    subtitle: &'a Option<String>,
+    notes: &'a str,
+    count_name: &'a str,
+    name_type: &'a str,
+    factor: f64,
+    inverted: bool,
+    differential: bool,
+    total: u64,
+    image_width: Option<usize>,
+    frame_height: usize,
+    font_type: &'a str,
+    font_size: usize,
+    background: (Cow<'a, str>, Cow<'a, str>),
+    search_color: String,
+    /// Distinct function names, as they should be shown.
+    names: Vec<&'a str>,
+    /// Distinct fill colors, as CSS colors.
+    colors: Vec<String>,
+    frames: Vec<FrameRecord>,
+}
+
+/// A frame, serialized as a compact array of
+/// `[name, depth, start, end, color, parent, delta]`, where `name` and `color` index into the
+/// `names` and `colors` tables, and `parent` is the index of the calling frame (`null` for the
+/// root).
+#[derive(Debug, Serialize, PartialEq)]
+struct FrameRecord(usize, usize, u64, u64, usize, Option<usize>, Option<i64>);
+
+/// Writes a standalone HTML page that draws the flame graph on a canvas.
+///
+/// The page embeds the merged frames as JSON, with each frame's color picked exactly as for the
+/// SVG output, and has no external dependencies. Unlike the SVG, frames narrower than
+/// `opt.min_width` are kept, since the page can zoom in on them.
+pub(super) fn write<'a, I, W>(
+    opt: &mut Options<'_>,
+    stacks: Stacks<'a, I>,
+    mut writer: W,
+) -> quick_xml::Result<()>
+where
+    I: IntoIterator<Item = &'a str>,
+    W: Write,
+{
+    let mut reversed = StrStack::new();
+    let (frames, time, ignored, delta_max) = merged_frames(opt, stacks, &mut reversed)?;
+    if ignored != 0 {
+        warn!("Ignored {} lines with invalid format", ignored);
+    }
+    if time == 0 {
+        return Err(quick_xml::Error::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "No stack counts found",
+        )));
+    }
+
+    let frames = records(opt, frames, delta_max);
+    let (bgcolor1, bgcolor2) = color::bgcolor_for(opt.bgcolors, opt.colors);
+    let data = Data {
+        title: &opt.title,
+        subtitle: &opt.subtitle,
+        notes: &opt.notes,
+        count_name: &opt.count_name,
+        name_type: &opt.name_type,
+        factor: opt.factor,
+        inverted: opt.direction == Direction::Inverted,
+        differential: frames.records.iter().any(|frame| frame.6.is_some()),
+        total: time,
+        image_width: opt.image_width,
+        frame_height: opt.frame_height,
+        font_type: &opt.font_type,
+        font_size: opt.font_size,
+        background: (bgcolor1, bgcolor2),
+        search_color: opt.search_color.to_string(),
+        names: frames.names,
+        colors: frames.colors,
+        frames: frames.records,
+    };
+
+    let json = serde_json::to_string(&data).map_err(io::Error::from)?;
+    // `<` only ever appears inside JSON strings, where it can be escaped so that no function name
+    // can close the surrounding script element.
+    let json = json.replace('<', "\\u003c");
+
+    let marker = TEMPLATE
+        .find(DATA_MARKER)
+        .expect("the HTML template has a data marker");
+    writer.write_all(&TEMPLATE.as_bytes()[..marker])?;
+    writer.write_all(json.as_bytes())?;
+    writer.write_all(&TEMPLATE.as_bytes()[marker + DATA_MARKER.len()..])?;
+    Ok(())
+}
+
+struct Records<'a> {
+    names: Vec<&'a str>,
+    colors: Vec<String>,
+    records: Vec<FrameRecord>,
+}
+
+/// Orders the frames by depth and start time, links every frame to its caller, and interns
+/// their names and colors.
+fn records<'a>(
+    opt: &mut Options<'_>,
+    mut frames: Vec<TimedFrame<'a>>,
+    delta_max: u64,
+) -> Records<'a> {
+    // Frames without any samples can't be drawn, and would make callers ambiguous.
+    frames.retain(|frame| frame.end_time > frame.start_time);
+    frames.sort_unstable_by_key(|frame| (frame.location.depth, frame.start_time));
+
+    let mut thread_rng = rand::thread_rng();
+    let mut name_indices: FnvHashMap<&str, usize> = FnvHashMap::default();
+    let mut color_indices: FnvHashMap<String, usize> = FnvHashMap::default();
+    let mut names = Vec::new();
+    let mut colors = Vec::new();
+    let mut records = Vec::with_capacity(frames.len());
+
+    // Where the frames of the current depth, and of the one before it, start.
+    let mut depth = 0;
+    let mut depth_start = 0;
+    let mut parent_depth_start = 0;
+    for (i, frame) in frames.iter().enumerate() {
+        if frame.location.depth != depth {
+            depth = frame.location.depth;
+            parent_depth_start = depth_start;
+            depth_start = i;
+        }
+
+        let parent = if depth == 0 {
+            None
+        } else {
+            let callers = &frames[parent_depth_start..depth_start];
+            let caller = match callers.binary_search_by_key(&frame.start_time, |f| f.start_time) {
+                Ok(caller) => Some(caller),
+                Err(0) => None,
+                Err(next) => Some(next - 1),
+            };
+            caller
+                .filter(|&caller| callers[caller].end_time >= frame.end_time)
+                .map(|caller| parent_depth_start + caller)
+        };
+
+        let name = if frame.location.function.is_empty() && depth == 0 {
+            "all"
+        } else {
+            deannotate(frame.location.function)
+        };
+        let name = *name_indices.entry(name).or_insert_with(|| {
+            names.push(name);
+            names.len() - 1
+        });
+
+        let color = frame_color(opt, frame, delta_max, &mut thread_rng);
+        let color = format!("rgb({},{},{})", color.r, color.g, color.b);
+        let color = match color_indices.get(&color) {
+            Some(&index) => index,
+            None => {
+                colors.push(color.clone());
+                color_indices.insert(color, colors.len() - 1);
+                colors.len() - 1
+            }
+        };
+
+        let delta = frame.delta.map(|delta| {
+            if opt.negate_differentials {
+                -delta
+            } else {
+                delta
+            }
+        });
+
+        records.push(FrameRecord(
+            name,
+            depth,
+            frame.start_time,
+            frame.end_time,
+            color,
+            parent,
+            delta,
+        ));
+    }
+
+    Records {
+        names,
+        colors,
+        records,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn html(opt: &mut Options<'_>, lines: &[&str]) -> String {
+        let mut buf = Vec::new();
+        write(opt, Stacks::Lines(lines.iter().cloned()), &mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    fn data(html: &str) -> Value {
+        let marker = r#"<script type="application/json" id="inferno-data">"#;
+        let start = html.find(marker).unwrap() + marker.len();
+        let end = html[start..].find("</script>").unwrap() + start;
+        serde_json::from_str(&html[start..end]).unwrap()
+    }
+
+    #[test]
+    fn links_frames_to_callers() {
+        let mut opt = Options::default();
+        let data = data(&html(
+            &mut opt,
+            &["main;foo;bar 3", "main;baz 1", "main;foo 2"],
+        ));
+        assert_eq!(data["total"], 6);
+        assert_eq!(
+            data["names"],
+            serde_json::json!(["all", "main", "baz", "foo", "bar"])
+        );
+        let frames: Vec<(u64, u64, u64, u64, Option<u64>)> = data["frames"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| {
+                (
+                    f[0].as_u64().unwrap(),
+                    f[1].as_u64().unwrap(),
+                    f[2].as_u64().unwrap(),
+                    f[3].as_u64().unwrap(),
+                    f[5].as_u64(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            frames,
+            vec![
+                (0, 0, 0, 6, None),
+                (1, 1, 0, 6, Some(0)),
+                (2, 2, 0, 1, Some(1)),
+                (3, 2, 1, 6, Some(1)),
+                (4, 3, 3, 6, Some(3)),
+            ]
+        );
+        assert_eq!(data["differential"], false);
+    }
+
+    #[test]
+    fn escapes_script_tags() {
+        let mut opt = Options {
+            title: "</script><script>alert(1)</script>".to_string(),
+            ..Default::default()
+        };
+        let html = html(&mut opt, &["main;</script> 1"]);
+        assert!(!html.contains("alert(1)</script>"));
+        let data = data(&html);
+        assert_eq!(data["title"], "</script><script>alert(1)</script>");
+        assert_eq!(data["names"][2], "</script>");
+    }
+
+    #[test]
+    fn differential_deltas() {
+        let mut opt = Options {
+            negate_differentials: true,
+            ..Default::default()
+        };
+        let data = data(&html(&mut opt, &["main;foo 3 5", "main;bar 4 1"]));
+        assert_eq!(data["differential"], true);
+        let deltas: Vec<i64> = data["frames"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f[6].as_i64().unwrap())
+            .collect();
+        // all, main, bar, foo
+        assert_eq!(deltas, vec![0, 0, 3, -2]);
+    }
+
+    #[test]
+    fn no_stacks_is_an_error() {
+        let mut buf = Vec::new();
+        assert!(write(
+            &mut Options::default(),
+            Stacks::Lines(vec!["nothing here"]),
+            &mut buf
+        )
+        .is_err());
+    }
+}
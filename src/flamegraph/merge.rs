@@ -4,6 +4,8 @@ use std::iter;
 
 use log::warn;
 
+use crate::folded::split_timestamp;
+
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub(super) struct Frame<'a> {
     pub(super) function: &'a str,
@@ -13,15 +15,15 @@ pub(super) struct Frame<'a> {
 #[derive(Debug, PartialEq)]
 pub(super) struct TimedFrame<'a> {
     pub(super) location: Frame<'a>,
-    pub(super) start_time: usize,
-    pub(super) end_time: usize,
-    pub(super) delta: Option<isize>,
+    pub(super) start_time: u64,
+    pub(super) end_time: u64,
+    pub(super) delta: Option<i64>,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub(super) struct FrameTime {
-    pub(super) start_time: usize,
-    pub(super) delta: Option<isize>,
+    pub(super) start_time: u64,
+    pub(super) delta: Option<i64>,
 }
 
 fn flow<'a, LI, TI>(
@@ -29,8 +31,8 @@ fn flow<'a, LI, TI>(
     frames: &mut Vec<TimedFrame<'a>>,
     last: LI,
     this: TI,
-    time: usize,
-    delta: Option<isize>,
+    time: u64,
+    delta: Option<i64>,
 ) where
     LI: IntoIterator<Item = &'a str>,
     TI: IntoIterator<Item = &'a str>,
@@ -106,19 +108,13 @@ fn flow<'a, LI, TI>(
     }
 }
 
-pub(super) fn frames<'a, I>(
-    lines: I,
-) -> quick_xml::Result<(Vec<TimedFrame<'a>>, usize, usize, usize)>
+pub(super) fn frames<'a, I>(lines: I) -> quick_xml::Result<(Vec<TimedFrame<'a>>, u64, usize, u64)>
 where
     I: IntoIterator<Item = &'a str>,
 {
-    let mut time = 0;
     let mut ignored = 0;
-    let mut last = "";
-    let mut tmp = Default::default();
-    let mut frames = Default::default();
+    let mut stacks = Vec::new();
     let mut delta = None;
-    let mut delta_max = 1;
     let mut stripped_fractional_samples = false;
     let mut prev_line = None;
     for line in lines {
@@ -129,10 +125,7 @@ where
 
         if let Some(prev_line) = prev_line {
             if prev_line > line {
-                return Err(quick_xml::Error::Io(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "unsorted input lines detected",
-                )));
+                return Err(unsorted());
             }
         }
 
@@ -146,8 +139,7 @@ where
                 if let Some(original_samples) =
                     parse_nsamples(&mut line, &mut stripped_fractional_samples)
                 {
-                    delta = Some(samples as isize - original_samples as isize);
-                    delta_max = std::cmp::max(delta.unwrap().abs() as usize, delta_max);
+                    delta = Some(samples as i64 - original_samples as i64);
                 }
                 samples
             } else {
@@ -159,7 +151,60 @@ where
             ignored += 1;
             continue;
         }
-        let stack = line;
+        stacks.push((line, nsamples, delta));
+        prev_line = Some(line);
+    }
+
+    let (frames, time, delta_max) = merge_stacks(stacks);
+    Ok((frames, time, ignored, delta_max))
+}
+
+/// Merges stacks that have already been parsed (like those of [`FoldedStacks`]) into frames, just
+/// like [`frames`] does for lines. Unlike lines, no stacks are ever ignored.
+///
+/// The stacks have to be sorted by their frames.
+///
+///   [`FoldedStacks`]: ../../folded/struct.FoldedStacks.html
+pub(super) fn parsed_frames<'a>(
+    stacks: &[ParsedLine<'a>],
+) -> quick_xml::Result<(Vec<TimedFrame<'a>>, u64, usize, u64)> {
+    if stacks.windows(2).any(|pair| pair[0].stack > pair[1].stack) {
+        return Err(unsorted());
+    }
+    let (frames, time, delta_max) = merge_stacks(stacks.iter().map(|line| {
+        let delta = line
+            .original_samples
+            .map(|original| line.samples as i64 - original as i64);
+        (line.stack, line.samples, delta)
+    }));
+    Ok((frames, time, 0, delta_max))
+}
+
+fn unsorted() -> quick_xml::Error {
+    quick_xml::Error::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "unsorted input lines detected",
+    ))
+}
+
+// Merges sorted stacks, each given as its semicolon-separated frames, its number of samples, and
+// (for differentials) the change in that number, into frames. Also returns the total number of
+// samples and the largest change.
+fn merge_stacks<'a, I>(stacks: I) -> (Vec<TimedFrame<'a>>, u64, u64)
+where
+    I: IntoIterator<Item = (&'a str, u64, Option<i64>)>,
+{
+    let mut time = 0;
+    let mut last = "";
+    let mut tmp = Default::default();
+    let mut frames = Default::default();
+    let mut delta = None;
+    let mut delta_max = 1;
+    for (stack, nsamples, stack_delta) in stacks {
+        delta = stack_delta;
+        if let Some(delta) = delta {
+            delta_max = std::cmp::max(delta.unsigned_abs(), delta_max);
+        }
 
         // inject empty first-level stack frame to capture "all"
         let this = iter::once("").chain(stack.split(';'));
@@ -181,7 +226,6 @@ where
 
         last = stack;
         time += nsamples;
-        prev_line = Some(line);
     }
 
     if !last.is_empty() {
@@ -196,11 +240,213 @@ where
         );
     }
 
-    Ok((frames, time, ignored, delta_max))
+    (frames, time, delta_max)
+}
+
+/// Merges parsed folded stack lines into the frames of a flame chart, in which the x-axis is time.
+///
+/// Unlike [`frames`], the lines are taken in the order they are given, and a frame is only merged
+/// with the one before it if the two are adjacent in time. If the lines are prefixed with
+/// timestamps in seconds (as written by `collapse::perf` for flame charts), each sample is placed
+/// at its timestamp, in microseconds since the first one, and lasts until the next sample, unless
+/// that comes more than twice the typical sampling interval later (like when the profiled program
+/// was idle). Otherwise, the samples are laid out one after another, each as wide as its count.
+///
+/// `ignored` is the number of lines that were ignored while parsing them. Returns the same things
+/// as [`frames`], and never fails.
+pub(super) fn chart_frames<'a>(
+    lines: &[ParsedLine<'a>],
+    mut ignored: usize,
+    reverse: bool,
+) -> (Vec<TimedFrame<'a>>, u64, usize, u64) {
+    if lines.iter().any(|line| line.original_samples.is_some()) {
+        warn!("Flame charts can't show differentials, so only the second sample count is used");
+    }
+
+    let timed = lines
+        .iter()
+        .any(|line| split_timestamp(line.stack).0.is_some());
+    let mut samples = Vec::with_capacity(lines.len());
+    let mut first_timestamp = None;
+    let mut went_backwards = false;
+    for line in lines {
+        let (timestamp, stack) = split_timestamp(line.stack);
+        let start = match (timed, timestamp) {
+            (false, _) => samples.last().map(|&(_, end, _, _)| end).unwrap_or(0),
+            (true, Some(timestamp)) => {
+                let first = *first_timestamp.get_or_insert(timestamp);
+                let start = ((timestamp - first) * 1_000_000.0).round().max(0.0) as u64;
+                match samples.last() {
+                    Some(&(previous, _, _, _)) if start < previous => {
+                        went_backwards = true;
+                        previous
+                    }
+                    _ => start,
+                }
+            }
+            (true, None) => {
+                ignored += 1;
+                continue;
+            }
+        };
+        // The end of timed samples is worked out below, once all the timestamps are known.
+        samples.push((start, start + line.samples, line.samples, stack));
+    }
+    if went_backwards {
+        warn!("Some samples are out of order, so they have been moved to the sample before them");
+    }
+
+    if timed {
+        let mut intervals: Vec<u64> = samples
+            .windows(2)
+            .map(|pair| pair[1].0 - pair[0].0)
+            .filter(|&interval| interval > 0)
+            .collect();
+        intervals.sort_unstable();
+        let interval = intervals.get(intervals.len() / 2).cloned().unwrap_or(1);
+        for i in 0..samples.len() {
+            let (start, _, count, _) = samples[i];
+            let duration = interval * count;
+            samples[i].1 = match samples.get(i + 1) {
+                Some(&(next, _, _, _)) if next - start <= 2 * duration => next,
+                _ => start + duration,
+            };
+        }
+    }
+
+    let split = |stack: &'a str| {
+        let mut frames: Vec<&'a str> = iter::once("").chain(stack.split(';')).collect();
+        if reverse {
+            frames[1..].reverse();
+        }
+        frames
+    };
+
+    let mut tmp = Default::default();
+    let mut frames = Vec::new();
+    let mut last: Option<(Vec<&str>, u64)> = None;
+    for &(start, end, _, stack) in &samples {
+        let this = split(stack);
+        match last {
+            None => flow(
+                &mut tmp,
+                &mut frames,
+                None,
+                this.iter().cloned(),
+                start,
+                None,
+            ),
+            Some((ref last, last_end)) if start > last_end => {
+                // There's a gap, so nothing carries over.
+                flow(
+                    &mut tmp,
+                    &mut frames,
+                    last.iter().cloned(),
+                    None,
+                    last_end,
+                    None,
+                );
+                flow(
+                    &mut tmp,
+                    &mut frames,
+                    None,
+                    this.iter().cloned(),
+                    start,
+                    None,
+                );
+            }
+            Some((ref last, _)) => flow(
+                &mut tmp,
+                &mut frames,
+                last.iter().cloned(),
+                this.iter().cloned(),
+                start,
+                None,
+            ),
+        }
+        last = Some((this, end));
+    }
+
+    let time = match last {
+        Some((last, last_end)) => {
+            flow(
+                &mut tmp,
+                &mut frames,
+                last.iter().cloned(),
+                None,
+                last_end,
+                None,
+            );
+            last_end
+        }
+        None => 0,
+    };
+
+    // Gaps split up the "all" frame, which should span the whole chart.
+    frames.retain(|frame| frame.location.depth != 0);
+    if time != 0 {
+        frames.push(TimedFrame {
+            location: Frame {
+                function: "",
+                depth: 0,
+            },
+            start_time: 0,
+            end_time: time,
+            delta: None,
+        });
+    }
+
+    (frames, time, ignored, 1)
+}
+
+/// A folded stack line with its sample count(s) parsed off.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct ParsedLine<'a> {
+    pub(super) stack: &'a str,
+    /// The first of two sample counts, if the line is a differential one.
+    pub(super) original_samples: Option<u64>,
+    pub(super) samples: u64,
+}
+
+/// Parses folded stack lines the same way [`frames`] does, for outputs that need the stacks
+/// themselves rather than merged frames.
+///
+/// Also returns the number of lines that were ignored because they had an invalid format.
+pub(super) fn parse_lines<'a, I>(lines: I) -> (Vec<ParsedLine<'a>>, usize)
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut parsed = Vec::new();
+    let mut ignored = 0;
+    let mut stripped_fractional_samples = false;
+    for line in lines {
+        let mut line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let samples = match parse_nsamples(&mut line, &mut stripped_fractional_samples) {
+            Some(samples) => samples,
+            None => {
+                ignored += 1;
+                continue;
+            }
+        };
+        let original_samples = parse_nsamples(&mut line, &mut stripped_fractional_samples);
+        if line.is_empty() {
+            ignored += 1;
+            continue;
+        }
+        parsed.push(ParsedLine {
+            stack: line,
+            original_samples,
+            samples,
+        });
+    }
+    (parsed, ignored)
 }
 
 // Parse and remove the number of samples from the end of a line.
-fn parse_nsamples(line: &mut &str, stripped_fractional_samples: &mut bool) -> Option<usize> {
+fn parse_nsamples(line: &mut &str, stripped_fractional_samples: &mut bool) -> Option<u64> {
     if let Some((samplesi, doti)) = rfind_samples(line) {
         let mut samples = &line[samplesi..];
         // Strip fractional part (if any);
@@ -223,7 +469,7 @@ fn parse_nsamples(line: &mut &str, stripped_fractional_samples: &mut bool) -> Op
             );
         }
         samples = &samples[..doti];
-        let nsamples = samples.parse::<usize>().ok()?;
+        let nsamples = samples.parse::<u64>().ok()?;
         // remove nsamples part we just parsed from line
         *line = line[..samplesi].trim_end();
         Some(nsamples)
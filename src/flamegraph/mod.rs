@@ -5,10 +5,18 @@ macro_rules! args {
 }
 
 mod attrs;
+mod butterfly;
 pub mod color;
+mod html;
 mod merge;
+#[cfg(feature = "png")]
+mod png;
+mod pprof;
+mod speedscope;
 mod svg;
+pub mod tree;
 
+use std::borrow::Cow;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::{self, BufReader};
@@ -20,6 +28,7 @@ use log::{error, warn};
 use num_format::Locale;
 use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
 use quick_xml::Writer;
+use rand::rngs::ThreadRng;
 use str_stack::StrStack;
 
 use self::attrs::FrameAttrs;
@@ -27,6 +36,9 @@ pub use self::attrs::FuncFrameAttrsMap;
 pub use self::color::Palette;
 use self::color::{Color, SearchColor};
 use self::svg::{Dimension, StyleOptions};
+pub use self::tree::FlameTree;
+use crate::folded::{self, FoldedStacks, Recursion};
+use crate::rules::Rules;
 
 const XPAD: usize = 10; // pad left and right
 const FRAMEPAD: usize = 1; // vertical padding for frames
@@ -119,6 +131,11 @@ pub struct Options<'a> {
     /// Whether to plot a plot that grows top-to-bottom or bottom-up (the default).
     pub direction: Direction,
 
+    /// The format to write the output in.
+    ///
+    /// Defaults to an SVG flame graph.
+    pub format: Format,
+
     /// The search color for flame graph.
     ///
     /// [Default value](defaults::SEARCH_COLOR).
@@ -215,6 +232,43 @@ pub struct Options<'a> {
     /// option will be ignored.
     pub reverse_stack_order: bool,
 
+    /// Produce a flame chart rather than a flame graph.
+    ///
+    /// In a flame chart, the x-axis is time: the lines are plotted in the order they are given
+    /// rather than sorted, and a frame is only merged with the one next to it. Lines may be
+    /// prefixed with a timestamp in seconds, like those written by `collapse::perf` with its
+    /// `flame_chart` option, in which case frame widths are in microseconds, and gaps between
+    /// samples (like when the profiled program was idle) are left empty. Differentials aren't
+    /// supported, and `no_sort` has no effect.
+    ///
+    /// Only the SVG, HTML, and PNG [`Format`]s can plot flame charts.
+    pub flame_chart: bool,
+
+    /// Draw a butterfly graph of the functions whose names match this regular expression.
+    ///
+    /// A butterfly graph merges all the stacks that go through the matching functions, no matter
+    /// where they are called from. The functions are drawn in the middle, with the callers that
+    /// led to them growing upwards (like in an inverted flame graph) and the functions they call
+    /// growing downwards. The expression has to match the whole name of a function, with any
+    /// annotation like `_[k]` removed, so a plain function name works as well.
+    ///
+    /// `direction` and `reverse_stack_order` have no effect on butterfly graphs, and flame charts
+    /// can't be drawn as one. Only the SVG and PNG [`Format`]s can plot butterfly graphs.
+    pub butterfly: Option<String>,
+
+    /// Fold recursive calls into one frame per function, as described by [`Recursion`], before
+    /// the stacks are merged, so that deep recursion neither makes the plot taller nor splits the
+    /// time of a function across many depths. This works for every [`Format`].
+    ///
+    ///   [`Recursion`]: ../folded/enum.Recursion.html
+    pub collapse_recursion: Option<Recursion>,
+
+    /// [`Rules`] to filter and rewrite the stacks with before they are merged, which are applied
+    /// before any recursion is collapsed. This works for every [`Format`].
+    ///
+    ///   [`Rules`]: ../rules/struct.Rules.html
+    pub rules: Rules,
+
     /// Don't include static JavaScript in flame graph.
     /// This is only meant to be used in tests.
     #[doc(hidden)]
@@ -255,10 +309,15 @@ impl<'a> Default for Options<'a> {
             palette_map: Default::default(),
             func_frameattrs: Default::default(),
             direction: Default::default(),
+            format: Default::default(),
             negate_differentials: Default::default(),
             pretty_xml: Default::default(),
             no_sort: Default::default(),
             reverse_stack_order: Default::default(),
+            flame_chart: Default::default(),
+            butterfly: Default::default(),
+            collapse_recursion: Default::default(),
+            rules: Default::default(),
             no_javascript: Default::default(),
         }
     }
@@ -284,6 +343,71 @@ impl Default for Direction {
     }
 }
 
+/// The output format of [`from_lines`] and friends.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Format {
+    /// An interactive SVG flame graph.
+    Svg,
+
+    /// A gzipped [pprof] profile, for use with `go tool pprof` and other pprof-based tooling.
+    ///
+    /// Each distinct frame becomes a single function and location in the profile. Differential
+    /// input (with two sample counts per line) produces a profile with two sample types, the
+    /// second of which is the default. Options that only concern plotting are ignored.
+    ///
+    /// [pprof]: https://github.com/google/pprof/blob/master/proto/profile.proto
+    Pprof,
+
+    /// A [speedscope] JSON file with a single "sampled" profile named after the `title`.
+    ///
+    /// Sample weights are scaled by the `factor`, and for differential input only the second
+    /// sample count is used.
+    ///
+    /// [speedscope]: https://www.speedscope.app/
+    Speedscope,
+
+    /// A standalone HTML page that draws the flame graph on a canvas.
+    ///
+    /// The page can zoom in on frames, search them by regular expression, and navigate them with
+    /// the keyboard, and has a sortable table of the functions with the most samples along with
+    /// the callers and callees of each. Frames are colored as in the SVG, but `func_frameattrs`
+    /// are ignored, and frames narrower than `min_width` are kept so that they can be zoomed in
+    /// on.
+    Html,
+
+    /// A PNG image of the flame graph, for places where SVG or JavaScript isn't an option.
+    ///
+    /// Frames and their labels are laid out and colored exactly as in the SVG, but without any
+    /// interactivity, and text is drawn in an embedded font (DejaVu Sans) rather than in
+    /// `font_type`. Only available with the `png` feature.
+    #[cfg(feature = "png")]
+    Png,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::Svg
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "svg" => Ok(Format::Svg),
+            "pprof" => Ok(Format::Pprof),
+            "speedscope" => Ok(Format::Speedscope),
+            "html" => Ok(Format::Html),
+            #[cfg(feature = "png")]
+            "png" => Ok(Format::Png),
+            #[cfg(not(feature = "png"))]
+            "png" => Err("PNG output requires the `png` feature".to_string()),
+            unknown => Err(format!("unknown format: {}", unknown)),
+        }
+    }
+}
+
 struct Rectangle {
     x1_pct: f64,
     y1: usize,
@@ -312,55 +436,115 @@ impl Rectangle {
 /// flame graph uses the difference between the two sample counts to show how the sample counts for
 /// each stack has changed between the first and second profiling.
 ///
-/// The resulting flame graph will be written out to `writer` in SVG format.
+/// The resulting flame graph will be written out to `writer` in SVG format, unless another
+/// [`Format`] is selected in `opt`. If `opt.flame_chart` is set, a flame chart is produced
+/// instead, which keeps the lines in the order they are given.
 ///
 /// [differential flame graph]: http://www.brendangregg.com/blog/2014-11-09/differential-flame-graphs.html
-#[allow(clippy::cognitive_complexity)]
 pub fn from_lines<'a, I, W>(opt: &mut Options<'_>, lines: I, writer: W) -> quick_xml::Result<()>
 where
     I: IntoIterator<Item = &'a str>,
     W: Write,
 {
-    let mut reversed = StrStack::new();
-    let (mut frames, time, ignored, delta_max) = if opt.reverse_stack_order {
-        if opt.no_sort {
-            warn!(
-                "Input lines are always sorted when `reverse_stack_order` is `true`. \
-                 The `no_sort` option is being ignored."
-            );
-        }
-        // Reverse order of stacks and sort.
-        let mut stack = String::new();
+    check_butterfly(opt)?;
+    if !opt.rules.is_empty() || opt.collapse_recursion.is_some() {
+        let mut transformed = StrStack::new();
         for line in lines {
-            stack.clear();
-            let samples_idx = merge::rfind_samples(line)
-                .map(|(i, _)| i)
-                .unwrap_or_else(|| line.len());
-            let samples_idx = merge::rfind_samples(&line[..samples_idx - 1])
-                .map(|(i, _)| i)
-                .unwrap_or(samples_idx);
-            for (i, func) in line[..samples_idx].trim().split(';').rev().enumerate() {
-                if i != 0 {
-                    stack.push(';');
-                }
-                stack.push_str(func);
+            if let Some(line) = transform_line(opt, line) {
+                transformed.push(&line);
             }
-            stack.push(' ');
-            stack.push_str(&line[samples_idx..]);
-            reversed.push(&stack);
         }
-        let mut reversed: Vec<&str> = reversed.iter().collect();
-        reversed.sort_unstable();
-        merge::frames(reversed)?
-    } else if opt.no_sort {
-        // Lines don't need sorting.
-        merge::frames(lines)?
+        write_stacks(opt, Stacks::Lines(&transformed), writer)
     } else {
-        // Sort lines by default.
-        let mut lines: Vec<&str> = lines.into_iter().collect();
-        lines.sort_unstable();
-        merge::frames(lines)?
-    };
+        write_stacks(opt, Stacks::Lines(lines), writer)
+    }
+}
+
+// Fails if `opt` asks for a butterfly graph in a form that can't show one.
+fn check_butterfly(opt: &Options<'_>) -> quick_xml::Result<()> {
+    if opt.butterfly.is_some() {
+        let unsupported = if opt.flame_chart {
+            Some("Flame charts can't be drawn as butterfly graphs")
+        } else {
+            match opt.format {
+                Format::Pprof | Format::Speedscope | Format::Html => {
+                    Some("Butterfly graphs can only be drawn as SVG or PNG")
+                }
+                _ => None,
+            }
+        };
+        if let Some(message) = unsupported {
+            return Err(quick_xml::Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                message,
+            )));
+        }
+    }
+    Ok(())
+}
+
+// The stacks that a flame graph is drawn from.
+enum Stacks<'a, I> {
+    // Folded stack lines, which are parsed as they are merged.
+    Lines(I),
+    // Stacks that have already been parsed, like those of `FoldedStacks`.
+    Parsed(Vec<merge::ParsedLine<'a>>),
+}
+
+impl<'a, I> Stacks<'a, I>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    // Parses the stacks, if they haven't been already, and sorts them unless `opt` says they're
+    // in order. Also returns the number of lines that couldn't be parsed.
+    fn parse(self, opt: &Options<'_>) -> (Vec<merge::ParsedLine<'a>>, usize) {
+        let sort = !opt.no_sort || opt.reverse_stack_order;
+        match self {
+            Stacks::Lines(lines) => {
+                let mut lines: Vec<&str> = lines.into_iter().collect();
+                if sort {
+                    lines.sort_unstable();
+                }
+                merge::parse_lines(lines)
+            }
+            Stacks::Parsed(mut stacks) => {
+                if sort {
+                    stacks.sort_by(|a, b| a.stack.cmp(b.stack));
+                }
+                (stacks, 0)
+            }
+        }
+    }
+}
+
+// Writes out the stacks in the format that `opt` asks for.
+#[allow(clippy::cognitive_complexity)]
+fn write_stacks<'a, I, W>(
+    opt: &mut Options<'_>,
+    stacks: Stacks<'a, I>,
+    writer: W,
+) -> quick_xml::Result<()>
+where
+    I: IntoIterator<Item = &'a str>,
+    W: Write,
+{
+    match opt.format {
+        Format::Svg => {}
+        Format::Pprof => {
+            let (stacks, ignored) = stacks.parse(opt);
+            return pprof::write(opt, &stacks, ignored, writer).map_err(quick_xml::Error::Io);
+        }
+        Format::Speedscope => {
+            let (stacks, ignored) = stacks.parse(opt);
+            return speedscope::write(opt, &stacks, ignored, writer).map_err(quick_xml::Error::Io);
+        }
+        Format::Html => return html::write(opt, stacks, writer),
+        #[cfg(feature = "png")]
+        Format::Png => return png::write(opt, stacks, writer),
+    }
+
+    let mut reversed = StrStack::new();
+    let (mut frames, time, ignored, delta_max) = merged_frames(opt, stacks, &mut reversed)?;
 
     if ignored != 0 {
         warn!("Ignored {} lines with invalid format", ignored);
@@ -401,21 +585,9 @@ where
     let image_width = opt.image_width.unwrap_or(DEFAULT_IMAGE_WIDTH) as f64;
     let timemax = time;
     let widthpertime_pct = 100.0 / timemax as f64;
-    let minwidth_time = opt.min_width / widthpertime_pct;
-
-    // prune blocks that are too narrow
-    let mut depthmax = 0;
-    frames.retain(|frame| {
-        if ((frame.end_time - frame.start_time) as f64) < minwidth_time {
-            false
-        } else {
-            depthmax = std::cmp::max(depthmax, frame.location.depth);
-            true
-        }
-    });
+    let imageheight = prune_frames(opt, &mut frames, time);
 
     // draw canvas, and embed interactive JavaScript program
-    let imageheight = ((depthmax + 1) * opt.frame_height) + opt.ypad1() + opt.ypad2();
     svg::write_header(&mut svg, imageheight, &opt)?;
 
     let (bgcolor1, bgcolor2) = color::bgcolor_for(opt.bgcolors, opt.colors);
@@ -453,29 +625,7 @@ where
     // draw frames
     let mut samples_txt_buffer = num_format::Buffer::default();
     for frame in frames {
-        let x1_pct = frame.start_time as f64 * widthpertime_pct;
-        let x2_pct = frame.end_time as f64 * widthpertime_pct;
-
-        let (y1, y2) = match opt.direction {
-            Direction::Straight => {
-                let y1 = imageheight - opt.ypad2() - (frame.location.depth + 1) * opt.frame_height
-                    + FRAMEPAD;
-                let y2 = imageheight - opt.ypad2() - frame.location.depth * opt.frame_height;
-                (y1, y2)
-            }
-            Direction::Inverted => {
-                let y1 = opt.ypad1() + frame.location.depth * opt.frame_height;
-                let y2 = opt.ypad1() + (frame.location.depth + 1) * opt.frame_height - FRAMEPAD;
-                (y1, y2)
-            }
-        };
-
-        let rect = Rectangle {
-            x1_pct,
-            y1,
-            x2_pct,
-            y2,
-        };
+        let rect = frame_rectangle(opt, &frame, imageheight, widthpertime_pct);
 
         // The rounding here can differ from the Perl version when the fractional part is `0.5`.
         // The Perl version does `my $samples = sprintf "%.0f", ($etime - $stime) * $factor;`,
@@ -483,7 +633,7 @@ where
         //     `sprintf "%.0f", 1.5` produces "2"
         //     `sprintf "%.0f", 2.5` produces "2"
         //     `sprintf "%.0f", 3.5` produces "4"
-        let samples = ((frame.end_time - frame.start_time) as f64 * opt.factor).round() as usize;
+        let samples = ((frame.end_time - frame.start_time) as f64 * opt.factor).round() as u64;
 
         // add thousands separators to `samples`
         let _ = samples_txt_buffer.write_formatted(&samples, &Locale::en);
@@ -548,56 +698,15 @@ where
         svg.write_event(Event::End(BytesEnd::borrowed(b"title")))?;
 
         // select the color of the rectangle
-        let color = if frame.location.function == "--" {
-            color::VDGREY
-        } else if frame.location.function == "-" {
-            color::DGREY
-        } else if let Some(mut delta) = frame.delta {
-            if opt.negate_differentials {
-                delta = -delta;
-            }
-            color::color_scale(delta, delta_max)
-        } else if let Some(ref mut palette_map) = opt.palette_map {
-            let colors = opt.colors;
-            let hash = opt.hash;
-            palette_map.find_color_for(&frame.location.function, |name| {
-                color::color(colors, hash, name, &mut thread_rng)
-            })
-        } else {
-            color::color(
-                opt.colors,
-                opt.hash,
-                frame.location.function,
-                &mut thread_rng,
-            )
-        };
+        let color = frame_color(opt, &frame, delta_max, &mut thread_rng);
         filled_rectangle(&mut svg, &mut buffer, &rect, color, &mut cache_rect)?;
 
-        let fitchars = (rect.width_pct() as f64
-            / (100.0 * opt.font_size as f64 * opt.font_width / image_width))
-            .trunc() as usize;
-        let text: svg::TextArgument<'_> = if fitchars >= 3 {
-            // room for one char plus two dots
-            let f = deannotate(&frame.location.function);
-
-            // TODO: use Unicode grapheme clusters instead
-            if f.len() < fitchars {
-                // no need to truncate
-                f.into()
-            } else {
-                // need to truncate :'(
-                use std::fmt::Write;
-                let mut w = buffer.writer();
-                for c in f.chars().take(fitchars - 2) {
-                    w.write_char(c).expect("writing to buffer shouldn't fail");
-                }
-                w.write_str("..").expect("writing to buffer shouldn't fail");
-                w.finish().into()
-            }
-        } else {
-            // don't show the function name
-            "".into()
-        };
+        let text: svg::TextArgument<'_> =
+            match frame_label(opt, &rect, image_width, frame.location.function) {
+                Label::Full(f) => f.into(),
+                Label::Truncated(f) => write!(buffer, "{}..", f).into(),
+                Label::Hidden => "".into(),
+            };
 
         // write the text
         svg::write_str(
@@ -626,6 +735,279 @@ where
     Ok(())
 }
 
+/// Removes the frames that are narrower than `opt.min_width`, and returns the height of the image
+/// that the remaining frames are drawn in.
+fn prune_frames(opt: &Options<'_>, frames: &mut Vec<merge::TimedFrame<'_>>, time: u64) -> usize {
+    let widthpertime_pct = 100.0 / time as f64;
+    let minwidth_time = opt.min_width / widthpertime_pct;
+
+    let mut depthmax = 0;
+    frames.retain(|frame| {
+        if ((frame.end_time - frame.start_time) as f64) < minwidth_time {
+            false
+        } else {
+            depthmax = std::cmp::max(depthmax, frame.location.depth);
+            true
+        }
+    });
+
+    ((depthmax + 1) * opt.frame_height) + opt.ypad1() + opt.ypad2()
+}
+
+/// Where a frame is drawn: horizontally as a percentage of the width of the frames container, and
+/// vertically in pixels.
+fn frame_rectangle(
+    opt: &Options<'_>,
+    frame: &merge::TimedFrame<'_>,
+    imageheight: usize,
+    widthpertime_pct: f64,
+) -> Rectangle {
+    let x1_pct = frame.start_time as f64 * widthpertime_pct;
+    let x2_pct = frame.end_time as f64 * widthpertime_pct;
+
+    let (y1, y2) = match opt.direction {
+        // Butterfly graphs are laid out from the top, one row per depth.
+        Direction::Straight if opt.butterfly.is_none() => {
+            let y1 = imageheight - opt.ypad2() - (frame.location.depth + 1) * opt.frame_height
+                + FRAMEPAD;
+            let y2 = imageheight - opt.ypad2() - frame.location.depth * opt.frame_height;
+            (y1, y2)
+        }
+        _ => {
+            let y1 = opt.ypad1() + frame.location.depth * opt.frame_height;
+            let y2 = opt.ypad1() + (frame.location.depth + 1) * opt.frame_height - FRAMEPAD;
+            (y1, y2)
+        }
+    };
+
+    Rectangle {
+        x1_pct,
+        y1,
+        x2_pct,
+        y2,
+    }
+}
+
+/// How much of a function's name is shown in its frame.
+enum Label<'a> {
+    /// All of it.
+    Full(&'a str),
+    /// Only this much, followed by `..`.
+    Truncated(&'a str),
+    /// None of it, since the frame is too narrow.
+    Hidden,
+}
+
+/// Fits the (deannotated) name of a function into its frame, assuming every character is
+/// `opt.font_width` times the font size wide.
+fn frame_label<'a>(
+    opt: &Options<'_>,
+    rect: &Rectangle,
+    image_width: f64,
+    function: &'a str,
+) -> Label<'a> {
+    let fitchars = (rect.width_pct() as f64
+        / (100.0 * opt.font_size as f64 * opt.font_width / image_width))
+        .trunc() as usize;
+    if fitchars < 3 {
+        // there isn't room for one char plus two dots
+        return Label::Hidden;
+    }
+
+    let f = deannotate(function);
+    // TODO: use Unicode grapheme clusters instead
+    if f.len() < fitchars {
+        Label::Full(f)
+    } else {
+        match f.char_indices().nth(fitchars - 2) {
+            Some((end, _)) => Label::Truncated(&f[..end]),
+            None => Label::Truncated(f),
+        }
+    }
+}
+
+/// Merges the (possibly reversed and sorted) folded stack lines into frames, or into the frames of
+/// a flame chart, along with the total time, the number of ignored lines, and the largest
+/// differential delta.
+///
+/// Reversed stacks are stored in `reversed`, which the returned frames may borrow from.
+fn merged_frames<'a, 'r, I>(
+    opt: &Options<'_>,
+    stacks: Stacks<'a, I>,
+    reversed: &'r mut StrStack,
+) -> quick_xml::Result<(Vec<merge::TimedFrame<'r>>, u64, usize, u64)>
+where
+    I: IntoIterator<Item = &'a str>,
+    'a: 'r,
+{
+    if opt.reverse_stack_order && opt.no_sort && opt.butterfly.is_none() && !opt.flame_chart {
+        warn!(
+            "Input lines are always sorted when `reverse_stack_order` is `true`. \
+             The `no_sort` option is being ignored."
+        );
+    }
+    let lines = match stacks {
+        Stacks::Lines(lines) => lines,
+        Stacks::Parsed(stacks) => return merged_parsed_frames(opt, stacks, reversed),
+    };
+
+    if let Some(ref function) = opt.butterfly {
+        let (lines, ignored) = merge::parse_lines(lines);
+        butterfly::frames(opt, function, &lines, ignored, reversed)
+    } else if opt.flame_chart {
+        let (lines, ignored) = merge::parse_lines(lines);
+        Ok(merge::chart_frames(
+            &lines,
+            ignored,
+            opt.reverse_stack_order,
+        ))
+    } else if opt.reverse_stack_order {
+        // Reverse order of stacks and sort.
+        let mut stack = String::new();
+        for line in lines {
+            stack.clear();
+            let samples_idx = merge::rfind_samples(line)
+                .map(|(i, _)| i)
+                .unwrap_or_else(|| line.len());
+            let samples_idx = merge::rfind_samples(&line[..samples_idx - 1])
+                .map(|(i, _)| i)
+                .unwrap_or(samples_idx);
+            for (i, func) in line[..samples_idx].trim().split(';').rev().enumerate() {
+                if i != 0 {
+                    stack.push(';');
+                }
+                stack.push_str(func);
+            }
+            stack.push(' ');
+            stack.push_str(&line[samples_idx..]);
+            reversed.push(&stack);
+        }
+        let reversed: &'r StrStack = reversed;
+        let mut reversed: Vec<&str> = reversed.iter().collect();
+        reversed.sort_unstable();
+        merge::frames(reversed)
+    } else if opt.no_sort {
+        // Lines don't need sorting.
+        merge::frames(lines)
+    } else {
+        // Sort lines by default.
+        let mut lines: Vec<&str> = lines.into_iter().collect();
+        lines.sort_unstable();
+        merge::frames(lines)
+    }
+}
+
+// Like `merged_frames`, for stacks that have already been parsed.
+fn merged_parsed_frames<'a, 'r>(
+    opt: &Options<'_>,
+    mut stacks: Vec<merge::ParsedLine<'a>>,
+    reversed: &'r mut StrStack,
+) -> quick_xml::Result<(Vec<merge::TimedFrame<'r>>, u64, usize, u64)>
+where
+    'a: 'r,
+{
+    if let Some(ref function) = opt.butterfly {
+        return butterfly::frames(opt, function, &stacks, 0, reversed);
+    } else if opt.flame_chart {
+        return Ok(merge::chart_frames(&stacks, 0, opt.reverse_stack_order));
+    }
+
+    if opt.reverse_stack_order {
+        let mut stack = String::new();
+        for line in &stacks {
+            stack.clear();
+            for (i, func) in line.stack.split(';').rev().enumerate() {
+                if i != 0 {
+                    stack.push(';');
+                }
+                stack.push_str(func);
+            }
+            reversed.push(&stack);
+        }
+        let reversed: &'r StrStack = reversed;
+        let mut reversed: Vec<merge::ParsedLine<'r>> = reversed
+            .iter()
+            .zip(stacks)
+            .map(|(stack, line)| merge::ParsedLine { stack, ..line })
+            .collect();
+        reversed.sort_by(|a, b| a.stack.cmp(b.stack));
+        merge::parsed_frames(&reversed)
+    } else {
+        if !opt.no_sort {
+            stacks.sort_by(|a, b| a.stack.cmp(b.stack));
+        }
+        merge::parsed_frames(&stacks)
+    }
+}
+
+// Applies the rules to the stack of a folded stack line and folds its recursion, keeping the
+// sample count(s) and, for flame charts, the timestamp. Returns `None` if the rules drop the
+// stack. Lines without a sample count are left for merging to ignore.
+fn transform_line<'l>(opt: &Options<'_>, line: &'l str) -> Option<Cow<'l, str>> {
+    let end = match merge::rfind_samples(line) {
+        Some((samples_idx, _)) => samples_idx - 1,
+        None => return Some(Cow::Borrowed(line)),
+    };
+    let end = merge::rfind_samples(&line[..end])
+        .map(|(samples_idx, _)| samples_idx - 1)
+        .unwrap_or(end);
+    let stack = if opt.flame_chart {
+        folded::split_timestamp(&line[..end]).1
+    } else {
+        &line[..end]
+    };
+    let start = end - stack.len();
+    match transform_stack(opt, stack)? {
+        Cow::Borrowed(_) => Some(Cow::Borrowed(line)),
+        Cow::Owned(stack) => Some(Cow::Owned(format!(
+            "{}{}{}",
+            &line[..start],
+            stack,
+            &line[end..]
+        ))),
+    }
+}
+
+// Applies the rules to a stack and folds its recursion. Returns `None` if the rules drop the
+// stack.
+fn transform_stack<'s>(opt: &Options<'_>, stack: &'s str) -> Option<Cow<'s, str>> {
+    let mut stack = opt.rules.apply(stack)?;
+    if let Some(recursion) = opt.collapse_recursion {
+        if let Cow::Owned(folded) = folded::collapse_recursion(&stack, recursion) {
+            stack = Cow::Owned(folded);
+        }
+    }
+    Some(stack)
+}
+
+/// Picks the fill color of a frame: grey for the separators that collapsers insert, a red or
+/// blue shade for differentials, and otherwise the palette's (or the palette map's) color.
+fn frame_color(
+    opt: &mut Options<'_>,
+    frame: &merge::TimedFrame<'_>,
+    delta_max: u64,
+    thread_rng: &mut ThreadRng,
+) -> Color {
+    if frame.location.function == "--" {
+        color::VDGREY
+    } else if frame.location.function == "-" {
+        color::DGREY
+    } else if let Some(mut delta) = frame.delta {
+        if opt.negate_differentials {
+            delta = -delta;
+        }
+        color::color_scale(delta, delta_max)
+    } else if let Some(ref mut palette_map) = opt.palette_map {
+        let colors = opt.colors;
+        let hash = opt.hash;
+        palette_map.find_color_for(&frame.location.function, |name| {
+            color::color(colors, hash, name, thread_rng)
+        })
+    } else {
+        color::color(opt.colors, opt.hash, frame.location.function, thread_rng)
+    }
+}
+
 /// Writes atributes to the container, container could be g or a
 fn write_container_attributes(event: &mut Event<'_>, frame_attributes: &FrameAttrs) {
     if let Event::Start(ref mut c) = event {
@@ -645,7 +1027,9 @@ fn write_container_attributes(event: &mut Event<'_>, frame_attributes: &FrameAtt
 ///
 /// See [`from_sorted_lines`] for the expected format of each line.
 ///
-/// The resulting flame graph will be written out to `writer` in SVG format.
+/// The resulting flame graph will be written out to `writer` in SVG format, unless another
+/// [`Format`] is selected in `opt`. If `opt.flame_chart` is set, a flame chart is produced
+/// instead, which keeps the lines in the order they are given.
 pub fn from_reader<R, W>(opt: &mut Options<'_>, reader: R, writer: W) -> quick_xml::Result<()>
 where
     R: Read,
@@ -658,7 +1042,9 @@ where
 ///
 /// See [`from_sorted_lines`] for the expected format of each line.
 ///
-/// The resulting flame graph will be written out to `writer` in SVG format.
+/// The resulting flame graph will be written out to `writer` in SVG format, unless another
+/// [`Format`] is selected in `opt`. If `opt.flame_chart` is set, a flame chart is produced
+/// instead, which keeps the lines in the order they are given.
 pub fn from_readers<R, W>(opt: &mut Options<'_>, readers: R, writer: W) -> quick_xml::Result<()>
 where
     R: IntoIterator,
@@ -674,6 +1060,71 @@ where
     from_lines(opt, input.lines(), writer)
 }
 
+/// Produce a flame graph from [`FoldedStacks`], such as those returned by
+/// [`Collapse::collapse_to_stacks`].
+///
+/// Stacks with an original count make for a [differential flame graph], just like lines with two
+/// sample counts do in [`from_lines`]. Since the stacks have been parsed already, they are never
+/// turned back into lines, so no frame is ever mistaken for a sample count.
+///
+/// The resulting flame graph will be written out to `writer` in SVG format, unless another
+/// [`Format`] is selected in `opt`. If `opt.flame_chart` is set, a flame chart is produced
+/// instead, which keeps the stacks in the order they are given.
+///
+/// [`FoldedStacks`]: ../folded/struct.FoldedStacks.html
+/// [`Collapse::collapse_to_stacks`]: ../collapse/trait.Collapse.html#method.collapse_to_stacks
+/// [differential flame graph]: http://www.brendangregg.com/blog/2014-11-09/differential-flame-graphs.html
+pub fn from_folded_stacks<W>(
+    opt: &mut Options<'_>,
+    stacks: &FoldedStacks,
+    writer: W,
+) -> quick_xml::Result<()>
+where
+    W: Write,
+{
+    check_butterfly(opt)?;
+
+    // The frames of each stack are joined as they are in lines, but the counts are kept apart.
+    let mut joined = StrStack::new();
+    let mut counts = Vec::with_capacity(stacks.len());
+    let mut line = String::new();
+    for stack in stacks {
+        line.clear();
+        for (i, frame) in stack.frames().enumerate() {
+            if i != 0 {
+                line.push(';');
+            }
+            line.push_str(frame);
+        }
+        if line.is_empty() {
+            continue;
+        }
+        let stack_line = if opt.flame_chart {
+            // Keep the timestamp that flame chart stacks may start with out of the rules' way.
+            let (_, rest) = folded::split_timestamp(&line);
+            let start = line.len() - rest.len();
+            transform_stack(opt, rest).map(|rest| format!("{}{}", &line[..start], rest))
+        } else {
+            transform_stack(opt, &line).map(Cow::into_owned)
+        };
+        if let Some(stack_line) = stack_line {
+            joined.push(&stack_line);
+            counts.push((stack.original_count(), stack.count()));
+        }
+    }
+
+    let parsed = joined
+        .iter()
+        .zip(counts)
+        .map(|(stack, (original_samples, samples))| merge::ParsedLine {
+            stack,
+            original_samples,
+            samples,
+        })
+        .collect();
+    write_stacks(opt, Stacks::<iter::Empty<&str>>::Parsed(parsed), writer)
+}
+
 /// Produce a flame graph from files that contain folded stack lines
 /// and write the result to provided `writer`.
 ///
@@ -711,7 +1162,7 @@ pub fn from_files<W: Write>(
     }
 }
 
-fn deannotate(f: &str) -> &str {
+pub(crate) fn deannotate(f: &str) -> &str {
     if f.ends_with(']') {
         if let Some(ai) = f.rfind("_[") {
             if f[ai..].len() == 4 && "kwij".contains(&f[ai + 2..ai + 3]) {
@@ -0,0 +1,330 @@
+use std::io::{self, Write};
+
+use ab_glyph::{point, Font, FontRef, Glyph, PxScale, ScaleFont};
+use log::warn;
+use str_stack::StrStack;
+
+use super::color::{self, Color};
+use super::{
+    frame_color, frame_label, frame_rectangle, merged_frames, prune_frames, Label, Options, Stacks,
+    DEFAULT_IMAGE_WIDTH, XPAD,
+};
+
+// The SVG leaves the choice of font to the viewer, but here we have to bring our own.
+static FONT: &[u8] = include_bytes!("fonts/DejaVuSans.ttf");
+
+const BLACK: Color = Color { r: 0, g: 0, b: 0 };
+
+/// Rasterizes the flame graph into a PNG image.
+///
+/// The frames and their (truncated) labels are laid out just as in the SVG, and the title,
+/// subtitle, and background gradient are drawn too, but everything that only makes sense
+/// interactively (like the search button) is left out.
+pub(super) fn write<'a, I, W>(
+    opt: &mut Options<'_>,
+    stacks: Stacks<'a, I>,
+    writer: W,
+) -> quick_xml::Result<()>
+where
+    I: IntoIterator<Item = &'a str>,
+    W: Write,
+{
+    let mut reversed = StrStack::new();
+    let (mut frames, time, ignored, delta_max) = merged_frames(opt, stacks, &mut reversed)?;
+    if ignored != 0 {
+        warn!("Ignored {} lines with invalid format", ignored);
+    }
+    if time == 0 {
+        return Err(quick_xml::Error::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "No stack counts found",
+        )));
+    }
+
+    let image_width = opt.image_width.unwrap_or(DEFAULT_IMAGE_WIDTH);
+    let imageheight = prune_frames(opt, &mut frames, time);
+    let widthpertime_pct = 100.0 / time as f64;
+
+    let font = FontRef::try_from_slice(FONT).expect("the embedded font is valid");
+    let mut canvas = Canvas::new(image_width, imageheight);
+
+    let (bgcolor1, bgcolor2) = color::bgcolor_for(opt.bgcolors, opt.colors);
+    canvas.fill_gradient(
+        color::parse_flat_bgcolor(&bgcolor1).unwrap_or(BLACK),
+        color::parse_flat_bgcolor(&bgcolor2).unwrap_or(BLACK),
+    );
+
+    let center = image_width as f64 / 2.0;
+    let title = Text::new(&font, (opt.font_size + 5) as f32, &opt.title);
+    title.draw(
+        &mut canvas,
+        center - title.width / 2.0,
+        (opt.font_size * 2) as f64,
+        image_width as f64,
+    );
+    if let Some(ref subtitle) = opt.subtitle {
+        let subtitle = Text::new(&font, opt.font_size as f32, subtitle);
+        subtitle.draw(
+            &mut canvas,
+            center - subtitle.width / 2.0,
+            (opt.font_size * 4) as f64,
+            image_width as f64,
+        );
+    }
+
+    // Frames are positioned relative to the frames container, which is inset by `XPAD`.
+    let container_width = (image_width - XPAD - XPAD) as f64;
+    let x = |pct: f64| XPAD as f64 + pct * container_width / 100.0;
+
+    let mut thread_rng = rand::thread_rng();
+    let mut label = String::new();
+    for frame in &frames {
+        let rect = frame_rectangle(opt, frame, imageheight, widthpertime_pct);
+        let color = frame_color(opt, frame, delta_max, &mut thread_rng);
+        canvas.fill_rect(x(rect.x1_pct), rect.y1, x(rect.x2_pct), rect.y2, color);
+
+        label.clear();
+        match frame_label(opt, &rect, image_width as f64, frame.location.function) {
+            Label::Full(f) => label.push_str(f),
+            Label::Truncated(f) => {
+                label.push_str(f);
+                label.push_str("..");
+            }
+            Label::Hidden => continue,
+        }
+        let text = Text::new(&font, opt.font_size as f32, &label);
+        text.draw(
+            &mut canvas,
+            x(rect.x1_pct + 100.0 * 3.0 / image_width as f64),
+            3.0 + (rect.y1 + rect.y2) as f64 / 2.0,
+            x(rect.x2_pct),
+        );
+    }
+
+    canvas.write_png(writer).map_err(quick_xml::Error::Io)
+}
+
+/// An RGB image that shapes are blended onto.
+struct Canvas {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    fn new(width: usize, height: usize) -> Self {
+        Canvas {
+            width,
+            height,
+            pixels: vec![255; width * height * 3],
+        }
+    }
+
+    /// Blends `color` into the given pixel with the given opacity.
+    fn blend(&mut self, x: usize, y: usize, color: Color, alpha: f64) {
+        if x >= self.width || y >= self.height || alpha <= 0.0 {
+            return;
+        }
+        let alpha = alpha.min(1.0);
+        let i = (y * self.width + x) * 3;
+        for (channel, &value) in self.pixels[i..i + 3]
+            .iter_mut()
+            .zip(&[color.r, color.g, color.b])
+        {
+            *channel = (*channel as f64 * (1.0 - alpha) + value as f64 * alpha).round() as u8;
+        }
+    }
+
+    /// Fills the whole canvas with a vertical gradient, with stops at 5% and 95% of its height
+    /// like the SVG's.
+    fn fill_gradient(&mut self, top: Color, bottom: Color) {
+        let mix = |a: u8, b: u8, t: f64| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+        for y in 0..self.height {
+            let t = ((y as f64 + 0.5) / self.height as f64 - 0.05) / 0.9;
+            let t = t.clamp(0.0, 1.0);
+            let color = Color {
+                r: mix(top.r, bottom.r, t),
+                g: mix(top.g, bottom.g, t),
+                b: mix(top.b, bottom.b, t),
+            };
+            for x in 0..self.width {
+                self.blend(x, y, color, 1.0);
+            }
+        }
+    }
+
+    /// Fills the rows from `y1` up to `y2`, between the (fractional) columns `x1` and `x2`.
+    /// Pixels that are only partly covered are blended in proportionally.
+    fn fill_rect(&mut self, x1: f64, y1: usize, x2: f64, y2: usize, color: Color) {
+        let first = x1.floor().max(0.0) as usize;
+        let last = x2.ceil().max(0.0) as usize;
+        for y in y1..y2 {
+            for x in first..last {
+                let coverage = (x2.min(x as f64 + 1.0) - x1.max(x as f64)).max(0.0);
+                self.blend(x, y, color, coverage);
+            }
+        }
+    }
+
+    fn write_png<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(writer, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(io_error)?;
+        writer.write_image_data(&self.pixels).map_err(io_error)?;
+        writer.finish().map_err(io_error)
+    }
+}
+
+/// A line of text laid out in the embedded font, starting at the origin.
+struct Text<'f> {
+    font: &'f FontRef<'static>,
+    glyphs: Vec<Glyph>,
+    width: f64,
+}
+
+impl<'f> Text<'f> {
+    /// Lays out `text` at a font size of `size` pixels (the size of an em, as in CSS).
+    fn new(font: &'f FontRef<'static>, size: f32, text: &str) -> Self {
+        let units_per_em = font
+            .units_per_em()
+            .unwrap_or_else(|| font.height_unscaled());
+        let scale = PxScale::from(size * font.height_unscaled() / units_per_em);
+        let scaled = font.as_scaled(scale);
+
+        let mut glyphs = Vec::new();
+        let mut caret = 0.0;
+        let mut previous = None;
+        for c in text.chars() {
+            let id = scaled.glyph_id(c);
+            if let Some(previous) = previous {
+                caret += scaled.kern(previous, id);
+            }
+            glyphs.push(id.with_scale_and_position(scale, point(caret, 0.0)));
+            caret += scaled.h_advance(id);
+            previous = Some(id);
+        }
+
+        Text {
+            font,
+            glyphs,
+            width: caret as f64,
+        }
+    }
+
+    /// Draws the text in black, starting at `x` with its baseline at `y`, and cut off at `right`.
+    fn draw(&self, canvas: &mut Canvas, x: f64, y: f64, right: f64) {
+        for glyph in &self.glyphs {
+            let mut glyph = glyph.clone();
+            glyph.position.x += x as f32;
+            glyph.position.y += y as f32;
+            if let Some(outline) = self.font.outline_glyph(glyph) {
+                let bounds = outline.px_bounds();
+                outline.draw(|gx, gy, coverage| {
+                    let px = bounds.min.x as i64 + i64::from(gx);
+                    let py = bounds.min.y as i64 + i64::from(gy);
+                    if px >= 0 && py >= 0 && (px as f64) < right {
+                        canvas.blend(px as usize, py as usize, BLACK, f64::from(coverage));
+                    }
+                });
+            }
+        }
+    }
+}
+
+fn io_error(error: png::EncodingError) -> io::Error {
+    match error {
+        png::EncodingError::IoError(error) => error,
+        error => io::Error::new(io::ErrorKind::InvalidData, error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flamegraph::color::BackgroundColor;
+
+    fn png(opt: &mut Options<'_>, lines: &[&str]) -> (png::OutputInfo, Vec<u8>) {
+        let mut buf = Vec::new();
+        write(opt, Stacks::Lines(lines.iter().cloned()), &mut buf).unwrap();
+        let mut reader = png::Decoder::new(&buf[..]).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        (info, pixels)
+    }
+
+    fn pixel(info: &png::OutputInfo, pixels: &[u8], x: usize, y: usize) -> Color {
+        let i = (y * info.width as usize + x) * 3;
+        Color {
+            r: pixels[i],
+            g: pixels[i + 1],
+            b: pixels[i + 2],
+        }
+    }
+
+    #[test]
+    fn draws_frames_where_the_svg_does() {
+        let mut opt = Options {
+            image_width: Some(220),
+            bgcolors: Some(BackgroundColor::Flat(Color { r: 1, g: 2, b: 3 })),
+            ..Default::default()
+        };
+        let (info, pixels) = png(&mut opt, &["main;foo 1", "main;bar 3"]);
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        assert_eq!(info.width, 220);
+        // three levels of frames, plus the padding above and below them
+        assert_eq!(info.height as usize, 3 * 16 + opt.ypad1() + opt.ypad2());
+
+        let background = Color { r: 1, g: 2, b: 3 };
+        assert_eq!(
+            pixel(&info, &pixels, 0, info.height as usize - 1),
+            background
+        );
+        // The frames container spans x = 10..210, so `bar` spans 10..160 and `foo` 160..210.
+        // `all` is at the bottom, and `bar` two frames above it.
+        let bar_y = info.height as usize - opt.ypad2() - 3 * 16 + 2;
+        assert_ne!(pixel(&info, &pixels, 150, bar_y), background);
+        assert_ne!(pixel(&info, &pixels, 170, bar_y), background);
+        assert_eq!(pixel(&info, &pixels, 5, bar_y), background);
+        assert_eq!(pixel(&info, &pixels, 215, bar_y), background);
+    }
+
+    #[test]
+    fn inverted_frames_start_at_the_top() {
+        let mut opt = Options {
+            image_width: Some(100),
+            direction: super::super::Direction::Inverted,
+            bgcolors: Some(BackgroundColor::Flat(Color {
+                r: 255,
+                g: 255,
+                b: 255,
+            })),
+            ..Default::default()
+        };
+        let (info, pixels) = png(&mut opt, &["main 1"]);
+        let white = Color {
+            r: 255,
+            g: 255,
+            b: 255,
+        };
+        // `all` is right below the title, and `main` right below that
+        let below_title = opt.ypad1() + 2;
+        assert_ne!(pixel(&info, &pixels, 50, below_title), white);
+        assert_ne!(pixel(&info, &pixels, 50, below_title + 16), white);
+        assert_eq!(
+            pixel(&info, &pixels, 50, info.height as usize - opt.ypad2() + 2),
+            white
+        );
+    }
+
+    #[test]
+    fn no_stacks_is_an_error() {
+        let mut buf = Vec::new();
+        assert!(write(
+            &mut Options::default(),
+            Stacks::Lines(vec!["nothing here"]),
+            &mut buf
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,228 @@
+use std::io::{self, Write};
+
+use fnv::FnvHashMap;
+use log::warn;
+
+use super::merge::ParsedLine;
+use super::Options;
+use crate::pprof::{Function, Line, Location, Profile, Sample, ValueType};
+
+/// Builds a pprof profile's string table, in which every string appears exactly once.
+struct StringTable {
+    strings: Vec<String>,
+    indices: FnvHashMap<String, i64>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        // The first entry of the string table must always be the empty string.
+        let mut table = StringTable {
+            strings: Vec::new(),
+            indices: FnvHashMap::default(),
+        };
+        table.intern("");
+        table
+    }
+
+    fn intern(&mut self, s: &str) -> i64 {
+        if let Some(&index) = self.indices.get(s) {
+            return index;
+        }
+        let index = self.strings.len() as i64;
+        self.strings.push(s.to_string());
+        self.indices.insert(s.to_string(), index);
+        index
+    }
+}
+
+/// Converts folded stack lines into a gzipped pprof profile.
+///
+/// Each distinct frame name becomes one function and one location, and each line becomes a
+/// sample. If the lines have two sample counts, the profile gets two sample types: the first
+/// count as `<count_name>_before`, and the second as `<count_name>`, which is also made the
+/// default sample type.
+///
+/// `ignored` is the number of lines that couldn't be parsed.
+pub(super) fn write<W>(
+    opt: &Options<'_>,
+    stacks: &[ParsedLine<'_>],
+    ignored: usize,
+    writer: W,
+) -> io::Result<()>
+where
+    W: Write,
+{
+    let differential = stacks.iter().any(|s| s.original_samples.is_some());
+
+    if ignored != 0 {
+        warn!("Ignored {} lines with invalid format", ignored);
+    }
+    if stacks.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "No stack counts found",
+        ));
+    }
+
+    let mut strings = StringTable::new();
+    let count_unit = strings.intern("count");
+    let count_name = strings.intern(&opt.count_name);
+    let mut sample_type = Vec::with_capacity(2);
+    if differential {
+        sample_type.push(ValueType {
+            ty: strings.intern(&format!("{}_before", opt.count_name)),
+            unit: count_unit,
+        });
+    }
+    sample_type.push(ValueType {
+        ty: count_name,
+        unit: count_unit,
+    });
+
+    let mut location_ids: FnvHashMap<&str, u64> = FnvHashMap::default();
+    let mut location = Vec::new();
+    let mut function = Vec::new();
+    let mut sample = Vec::with_capacity(stacks.len());
+    for line in stacks {
+        let mut ids: Vec<u64> = line
+            .stack
+            .split(';')
+            .map(|frame| {
+                *location_ids.entry(frame).or_insert_with(|| {
+                    // Frames have no addresses, so every function gets a location of its own,
+                    // and they might as well share ids.
+                    let id = function.len() as u64 + 1;
+                    let name = strings.intern(frame);
+                    function.push(Function {
+                        id,
+                        name,
+                        system_name: name,
+                        ..Default::default()
+                    });
+                    location.push(Location {
+                        id,
+                        line: vec![Line {
+                            function_id: id,
+                            line: 0,
+                        }],
+                        ..Default::default()
+                    });
+                    id
+                })
+            })
+            .collect();
+        // pprof lists locations leaf first.
+        if !opt.reverse_stack_order {
+            ids.reverse();
+        }
+
+        let mut value = Vec::with_capacity(2);
+        if differential {
+            value.push(line.original_samples.unwrap_or(0) as i64);
+        }
+        value.push(line.samples as i64);
+        sample.push(Sample {
+            location_id: ids,
+            value,
+        });
+    }
+
+    let profile = Profile {
+        period_type: sample_type.last().cloned(),
+        period: 1,
+        sample_type,
+        sample,
+        location,
+        function,
+        string_table: strings.strings,
+        default_sample_type: count_name,
+        ..Default::default()
+    };
+    profile.write_gzipped(writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Stacks;
+    use super::*;
+
+    fn profile(opt: &Options<'_>, lines: &[&str]) -> Profile {
+        let mut buf = Vec::new();
+        let (stacks, ignored) = Stacks::Lines(lines.iter().cloned()).parse(opt);
+        write(opt, &stacks, ignored, &mut buf).unwrap();
+        Profile::decode(&buf).unwrap()
+    }
+
+    fn stacks(profile: &Profile) -> Vec<(String, Vec<i64>)> {
+        profile
+            .sample
+            .iter()
+            .map(|sample| {
+                let frames: Vec<&str> = sample
+                    .location_id
+                    .iter()
+                    .rev()
+                    .map(|&id| {
+                        let location = &profile.location[id as usize - 1];
+                        let function = &profile.function[location.line[0].function_id as usize - 1];
+                        profile.string(function.name)
+                    })
+                    .collect();
+                (frames.join(";"), sample.value.clone())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn deduplicates_functions_and_locations() {
+        let profile = profile(
+            &Options::default(),
+            &["main;foo;bar 3", "main;foo 2", "main;baz;bar 1", "garbage"],
+        );
+        assert_eq!(profile.function.len(), 4);
+        assert_eq!(profile.location.len(), 4);
+        assert_eq!(profile.sample_type.len(), 1);
+        assert_eq!(profile.string(profile.sample_type[0].ty), "samples");
+        assert_eq!(profile.string(profile.default_sample_type), "samples");
+        assert_eq!(
+            stacks(&profile),
+            vec![
+                ("main;baz;bar".to_string(), vec![1]),
+                ("main;foo".to_string(), vec![2]),
+                ("main;foo;bar".to_string(), vec![3]),
+            ]
+        );
+    }
+
+    #[test]
+    fn differential_and_reversed() {
+        let opt = Options {
+            reverse_stack_order: true,
+            count_name: "ticks".to_string(),
+            ..Default::default()
+        };
+        let profile = profile(&opt, &["main;foo 3 5", "main;bar 0 1"]);
+        let types: Vec<&str> = profile
+            .sample_type
+            .iter()
+            .map(|st| profile.string(st.ty))
+            .collect();
+        assert_eq!(types, vec!["ticks_before", "ticks"]);
+        assert_eq!(profile.string(profile.default_sample_type), "ticks");
+        assert_eq!(
+            stacks(&profile),
+            vec![
+                ("bar;main".to_string(), vec![0, 1]),
+                ("foo;main".to_string(), vec![3, 5]),
+            ]
+        );
+    }
+
+    #[test]
+    fn no_stacks_is_an_error() {
+        let mut buf = Vec::new();
+        let (stacks, ignored) = Stacks::Lines(vec!["nothing here"]).parse(&Options::default());
+        let err = write(&Options::default(), &stacks, ignored, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,163 @@
+use std::io::{self, Write};
+
+use fnv::FnvHashMap;
+use log::warn;
+use serde::Serialize;
+
+use super::merge::ParsedLine;
+use super::Options;
+
+static SCHEMA: &str = "https://www.speedscope.app/file-format-schema.json";
+
+static EXPORTER: &str = concat!("inferno ", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct File<'a> {
+    #[serde(rename = "$schema")]
+    schema: &'static str,
+    shared: Shared<'a>,
+    profiles: Vec<SampledProfile<'a>>,
+    name: &'a str,
+    active_profile_index: usize,
+    exporter: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct Shared<'a> {
+    frames: Vec<Frame<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct Frame<'a> {
+    name: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SampledProfile<'a> {
+    #[serde(rename = "type")]
+    ty: &'static str,
+    name: &'a str,
+    unit: &'static str,
+    start_value: f64,
+    end_value: f64,
+    /// Indices into the shared frame table, root first.
+    samples: Vec<Vec<usize>>,
+    weights: Vec<f64>,
+}
+
+/// Converts folded stack lines into a [speedscope] file with a single "sampled" profile.
+///
+/// Every distinct frame name appears once in the file's shared frame table, and each line
+/// becomes a sample weighted by its (second, if differential) sample count times `opt.factor`.
+///
+/// `ignored` is the number of lines that couldn't be parsed.
+///
+/// [speedscope]: https://github.com/jlfwong/speedscope/wiki/Importing-from-custom-sources
+pub(super) fn write<W>(
+    opt: &Options<'_>,
+    stacks: &[ParsedLine<'_>],
+    ignored: usize,
+    writer: W,
+) -> io::Result<()>
+where
+    W: Write,
+{
+    if ignored != 0 {
+        warn!("Ignored {} lines with invalid format", ignored);
+    }
+    if stacks.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "No stack counts found",
+        ));
+    }
+
+    let mut frame_indices: FnvHashMap<&str, usize> = FnvHashMap::default();
+    let mut frames = Vec::new();
+    let mut samples = Vec::with_capacity(stacks.len());
+    let mut weights = Vec::with_capacity(stacks.len());
+    let mut total = 0.0;
+    for line in stacks {
+        let mut sample: Vec<usize> = line
+            .stack
+            .split(';')
+            .map(|name| {
+                *frame_indices.entry(name).or_insert_with(|| {
+                    frames.push(Frame { name });
+                    frames.len() - 1
+                })
+            })
+            .collect();
+        if opt.reverse_stack_order {
+            sample.reverse();
+        }
+        let weight = line.samples as f64 * opt.factor;
+        total += weight;
+        samples.push(sample);
+        weights.push(weight);
+    }
+
+    let file = File {
+        schema: SCHEMA,
+        shared: Shared { frames },
+        profiles: vec![SampledProfile {
+            ty: "sampled",
+            name: &opt.title,
+            unit: "none",
+            start_value: 0.0,
+            end_value: total,
+            samples,
+            weights,
+        }],
+        name: &opt.title,
+        active_profile_index: 0,
+        exporter: EXPORTER,
+    };
+    serde_json::to_writer(writer, &file).map_err(io::Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Stacks;
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn speedscope(opt: &Options<'_>, lines: &[&str]) -> Value {
+        let mut buf = Vec::new();
+        let (stacks, ignored) = Stacks::Lines(lines.iter().cloned()).parse(opt);
+        write(opt, &stacks, ignored, &mut buf).unwrap();
+        serde_json::from_slice(&buf).unwrap()
+    }
+
+    #[test]
+    fn shares_frames() {
+        let file = speedscope(&Options::default(), &["main;foo 2", "main;bar;foo 1"]);
+        assert_eq!(file["$schema"], SCHEMA);
+        assert_eq!(
+            file["shared"]["frames"],
+            json!([{"name": "main"}, {"name": "bar"}, {"name": "foo"}])
+        );
+        let profile = &file["profiles"][0];
+        assert_eq!(profile["type"], "sampled");
+        assert_eq!(profile["name"], "Flame Graph");
+        assert_eq!(profile["samples"], json!([[0, 1, 2], [0, 2]]));
+        assert_eq!(profile["weights"], json!([1.0, 2.0]));
+        assert_eq!(profile["endValue"], 3.0);
+    }
+
+    #[test]
+    fn reversed_differential_with_factor() {
+        let opt = Options {
+            reverse_stack_order: true,
+            factor: 0.5,
+            ..Default::default()
+        };
+        let file = speedscope(&opt, &["main;foo 2 4", "bad line", "main;bar 1 3"]);
+        let profile = &file["profiles"][0];
+        assert_eq!(profile["samples"], json!([[1, 0], [2, 0]]));
+        assert_eq!(profile["weights"], json!([1.5, 2.0]));
+        assert_eq!(profile["endValue"], 3.5);
+    }
+}
@@ -145,7 +145,7 @@ var fluiddrawing = {};",
         opt.font_size,
         opt.font_width,
         super::XPAD,
-        opt.direction == Direction::Inverted,
+        opt.direction == Direction::Inverted || opt.butterfly.is_some(),
         opt.search_color,
         opt.image_width.is_none()
     ))))?;
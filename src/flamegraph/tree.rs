@@ -0,0 +1,441 @@
+//! The call tree behind a flame graph, for querying profiles programmatically.
+
+use std::cmp::Reverse;
+use std::collections::hash_map::Entry;
+use std::fmt;
+use std::mem;
+use std::str::FromStr;
+
+use fnv::FnvHashMap;
+use indexmap::IndexSet;
+
+use crate::folded::{FoldedStacks, ParseError};
+
+/// The call tree that a flame graph draws: folded stacks merged frame by frame, so that every
+/// node is a function called along a particular path, with the number of samples that include it.
+///
+/// Unlike a flame graph, the tree can be queried, which makes it handy for checking a profile in
+/// tests:
+///
+/// ```
+/// use inferno::flamegraph::FlameTree;
+///
+/// let tree: FlameTree = "main;parse;read 3\nmain;parse 1\nmain;eval;read 2\n".parse().unwrap();
+/// assert_eq!(tree.total(), 6);
+///
+/// let read = tree.top_functions(1)[0];
+/// assert_eq!((read.name, read.self_count), ("read", 5));
+/// assert_eq!(tree.paths_to("read"), vec![vec!["main", "eval", "read"], vec!["main", "parse", "read"]]);
+///
+/// let parse = tree.zoom("parse");
+/// assert_eq!(parse.to_string(), "parse 1\nparse;read 3\n");
+/// ```
+///
+/// Only the sample count of each stack is used, so for differentials, the tree is that of the
+/// second profile. The children of each node are sorted by name.
+#[derive(Clone, Debug)]
+pub struct FlameTree {
+    names: IndexSet<String>,
+    nodes: Vec<NodeData>,
+}
+
+#[derive(Clone, Debug)]
+struct NodeData {
+    name: usize,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    depth: usize,
+    total: u64,
+    self_count: u64,
+}
+
+// The root is always the first node, and its name the first name.
+const ROOT: usize = 0;
+
+impl FlameTree {
+    /// Returns the root of the tree, which stands for all of the stacks and has an empty name.
+    pub fn root(&self) -> Node<'_> {
+        self.node(ROOT)
+    }
+
+    fn node(&self, id: usize) -> Node<'_> {
+        Node { tree: self, id }
+    }
+
+    /// Returns the total number of samples.
+    pub fn total(&self) -> u64 {
+        self.nodes[ROOT].total
+    }
+
+    /// Returns every node for `function`, in the order they would be drawn in a flame graph
+    /// (depth first, from left to right).
+    pub fn find(&self, function: &str) -> Vec<Node<'_>> {
+        let name = match self.names.get_full(function) {
+            Some((name, _)) => name,
+            None => return Vec::new(),
+        };
+        self.preorder()
+            .filter(|&id| id != ROOT && self.nodes[id].name == name)
+            .map(|id| self.node(id))
+            .collect()
+    }
+
+    /// Returns every path (from the root's children down) that leads to `function`.
+    pub fn paths_to(&self, function: &str) -> Vec<Vec<&str>> {
+        self.find(function).iter().map(Node::path).collect()
+    }
+
+    /// Returns the `n` functions that most samples were taken in (as opposed to in the functions
+    /// they call), along with their self and total counts, from the most samples to the least.
+    ///
+    /// Functions are told apart by name only, so the counts of all the nodes for a function are
+    /// added up. Recursive calls aren't counted twice towards the total.
+    pub fn top_functions(&self, n: usize) -> Vec<FunctionCounts<'_>> {
+        let mut counts: FnvHashMap<usize, (u64, u64)> = FnvHashMap::default();
+        let mut on_path: FnvHashMap<usize, usize> = FnvHashMap::default();
+        for &child in &self.nodes[ROOT].children {
+            self.count_functions(child, &mut on_path, &mut counts);
+        }
+
+        let mut functions: Vec<_> = counts
+            .into_iter()
+            .map(|(name, (self_count, total))| FunctionCounts {
+                name: &self.names[name],
+                self_count,
+                total,
+            })
+            .collect();
+        functions.sort_unstable_by_key(|f| (Reverse(f.self_count), Reverse(f.total), f.name));
+        functions.truncate(n);
+        functions
+    }
+
+    // Adds the counts of the subtree at `id` to `counts`, where `on_path` has the number of times
+    // each function appears among the node's ancestors.
+    fn count_functions(
+        &self,
+        id: usize,
+        on_path: &mut FnvHashMap<usize, usize>,
+        counts: &mut FnvHashMap<usize, (u64, u64)>,
+    ) {
+        let node = &self.nodes[id];
+        let count = counts.entry(node.name).or_insert((0, 0));
+        count.0 += node.self_count;
+        let depth = on_path.entry(node.name).or_insert(0);
+        if *depth == 0 {
+            count.1 += node.total;
+        }
+        *depth += 1;
+
+        for &child in &node.children {
+            self.count_functions(child, on_path, counts);
+        }
+
+        *on_path.get_mut(&node.name).unwrap() -= 1;
+    }
+
+    /// Returns the tree below `node` (which must be a node of this tree), as the only child of
+    /// the root of a new tree.
+    pub fn subtree(&self, node: Node<'_>) -> FlameTree {
+        let mut stacks = FoldedStacks::new();
+        self.push_stacks(node.id, &mut stacks);
+        FlameTree::from(&stacks)
+    }
+
+    /// Returns the merged subtrees of every call to `function`, like zooming in on it in a flame
+    /// graph, but across all of its callers.
+    ///
+    /// Recursive calls are part of the subtree of the outermost call, so the new tree's root only
+    /// has a single child, `function` (or none, if there are no calls to it).
+    pub fn zoom(&self, function: &str) -> FlameTree {
+        let mut stacks = FoldedStacks::new();
+        for node in self.find(function) {
+            if !node.ancestors().any(|ancestor| ancestor.name() == function) {
+                self.push_stacks(node.id, &mut stacks);
+            }
+        }
+        FlameTree::from(&stacks)
+    }
+
+    // Adds the stacks of the subtree at `id` to `stacks`, starting at that node.
+    fn push_stacks(&self, id: usize, stacks: &mut FoldedStacks) {
+        let depth = self.nodes[id].depth;
+        let mut subtree = vec![id];
+        while let Some(id) = subtree.pop() {
+            let node = &self.nodes[id];
+            if node.self_count != 0 {
+                let path = self.node(id).path();
+                stacks.push(&path[depth.saturating_sub(1)..], node.self_count);
+            }
+            subtree.extend(node.children.iter().rev());
+        }
+    }
+
+    /// Turns the tree back into folded stacks, one for each node that samples were taken in, with
+    /// the stacks sorted frame by frame.
+    pub fn to_stacks(&self) -> FoldedStacks {
+        let mut stacks = FoldedStacks::new();
+        self.push_stacks(ROOT, &mut stacks);
+        stacks
+    }
+
+    fn preorder(&self) -> impl Iterator<Item = usize> + '_ {
+        let mut pending = vec![ROOT];
+        std::iter::from_fn(move || {
+            let id = pending.pop()?;
+            pending.extend(self.nodes[id].children.iter().rev());
+            Some(id)
+        })
+    }
+}
+
+impl<'a> From<&'a FoldedStacks> for FlameTree {
+    fn from(stacks: &'a FoldedStacks) -> Self {
+        let mut names = IndexSet::new();
+        names.insert(String::new());
+        let mut nodes = vec![NodeData {
+            name: 0,
+            parent: None,
+            children: Vec::new(),
+            depth: 0,
+            total: 0,
+            self_count: 0,
+        }];
+
+        let mut lookup: FnvHashMap<(usize, usize), usize> = FnvHashMap::default();
+        for stack in stacks {
+            let count = stack.count();
+            let mut id = ROOT;
+            nodes[ROOT].total += count;
+            for frame in stack.frames() {
+                let name = match names.get_full(frame) {
+                    Some((name, _)) => name,
+                    None => names.insert_full(frame.to_string()).0,
+                };
+                id = match lookup.entry((id, name)) {
+                    Entry::Occupied(entry) => *entry.get(),
+                    Entry::Vacant(entry) => {
+                        let child = nodes.len();
+                        nodes.push(NodeData {
+                            name,
+                            parent: Some(id),
+                            children: Vec::new(),
+                            depth: nodes[id].depth + 1,
+                            total: 0,
+                            self_count: 0,
+                        });
+                        nodes[id].children.push(child);
+                        *entry.insert(child)
+                    }
+                };
+                nodes[id].total += count;
+            }
+            nodes[id].self_count += count;
+        }
+
+        for id in 0..nodes.len() {
+            let mut children = mem::take(&mut nodes[id].children);
+            children.sort_unstable_by(|&a, &b| names[nodes[a].name].cmp(&names[nodes[b].name]));
+            nodes[id].children = children;
+        }
+
+        FlameTree { names, nodes }
+    }
+}
+
+impl FromStr for FlameTree {
+    type Err = ParseError;
+
+    /// Parses folded stack lines, as [`FoldedStacks::parse`] does, and merges them into a tree.
+    ///
+    ///   [`FoldedStacks::parse`]: ../../folded/struct.FoldedStacks.html#method.parse
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(FlameTree::from(&FoldedStacks::parse(s)?))
+    }
+}
+
+impl fmt::Display for FlameTree {
+    /// Formats the tree as folded stack lines, as returned by [`to_stacks`].
+    ///
+    ///   [`to_stacks`]: #method.to_stacks
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.to_stacks(), f)
+    }
+}
+
+/// A node of a [`FlameTree`]: a function, called along a particular path.
+///
+///   [`FlameTree`]: struct.FlameTree.html
+#[derive(Clone, Copy)]
+pub struct Node<'a> {
+    tree: &'a FlameTree,
+    id: usize,
+}
+
+impl<'a> Node<'a> {
+    fn data(&self) -> &'a NodeData {
+        &self.tree.nodes[self.id]
+    }
+
+    /// Returns the name of the node's function.
+    pub fn name(&self) -> &'a str {
+        &self.tree.names[self.data().name]
+    }
+
+    /// Returns the number of samples taken in this node or the nodes below it.
+    pub fn total(&self) -> u64 {
+        self.data().total
+    }
+
+    /// Returns the number of samples taken in this node itself, rather than in the nodes below it.
+    pub fn self_count(&self) -> u64 {
+        self.data().self_count
+    }
+
+    /// Returns how far the node is from the root, which is at depth 0.
+    pub fn depth(&self) -> usize {
+        self.data().depth
+    }
+
+    /// Returns the node's caller, unless this is the root.
+    pub fn parent(&self) -> Option<Node<'a>> {
+        self.data().parent.map(|id| self.tree.node(id))
+    }
+
+    /// Iterates over the node's callers, from its parent up to the root's child.
+    pub fn ancestors(&self) -> impl Iterator<Item = Node<'a>> {
+        std::iter::successors(self.parent(), Node::parent).filter(|node| node.id != ROOT)
+    }
+
+    /// Iterates over the functions called by this node, sorted by name.
+    pub fn children(&self) -> impl Iterator<Item = Node<'a>> {
+        let tree = self.tree;
+        self.data().children.iter().map(move |&id| tree.node(id))
+    }
+
+    /// Returns the child for `function`, if this node called it.
+    pub fn child(&self, function: &str) -> Option<Node<'a>> {
+        self.children().find(|child| child.name() == function)
+    }
+
+    /// Returns the names of the functions on the path to this node, from the root's child down to
+    /// this node (which is empty for the root).
+    pub fn path(&self) -> Vec<&'a str> {
+        let mut path: Vec<&str> = self.ancestors().map(|node| node.name()).collect();
+        path.reverse();
+        if self.id != ROOT {
+            path.push(self.name());
+        }
+        path
+    }
+}
+
+impl<'a> fmt::Debug for Node<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Node")
+            .field("path", &self.path())
+            .field("total", &self.total())
+            .field("self_count", &self.self_count())
+            .finish()
+    }
+}
+
+/// The sample counts of a function across a [`FlameTree`], as returned by
+/// [`FlameTree::top_functions`].
+///
+///   [`FlameTree`]: struct.FlameTree.html
+///   [`FlameTree::top_functions`]: struct.FlameTree.html#method.top_functions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FunctionCounts<'a> {
+    /// The name of the function.
+    pub name: &'a str,
+    /// The number of samples taken in the function itself.
+    pub self_count: u64,
+    /// The number of samples taken in the function or in the functions it calls.
+    pub total: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn tree(input: &str) -> FlameTree {
+        input.parse().unwrap()
+    }
+
+    #[test]
+    fn merges_stacks() {
+        let tree = tree("main;b;c 2\nmain;a 1\nmain;b 3\nmain;b;c 1\nother 4\n");
+        assert_eq!(tree.total(), 11);
+        let root = tree.root();
+        assert_eq!(root.name(), "");
+        assert_eq!(root.path(), Vec::<&str>::new());
+        let names: Vec<&str> = root
+            .child("main")
+            .unwrap()
+            .children()
+            .map(|n| n.name())
+            .collect();
+        assert_eq!(names, vec!["a", "b"]);
+
+        let c = root
+            .child("main")
+            .unwrap()
+            .child("b")
+            .unwrap()
+            .child("c")
+            .unwrap();
+        assert_eq!((c.total(), c.self_count(), c.depth()), (3, 3, 3));
+        assert_eq!(c.path(), vec!["main", "b", "c"]);
+        let b = c.parent().unwrap();
+        assert_eq!((b.total(), b.self_count()), (6, 3));
+        assert_eq!(
+            c.ancestors().map(|n| n.name()).collect::<Vec<_>>(),
+            vec!["b", "main"]
+        );
+
+        assert_eq!(
+            tree.to_string(),
+            "main;a 1\nmain;b 3\nmain;b;c 3\nother 4\n"
+        );
+    }
+
+    #[test]
+    fn counts_recursion_once() {
+        let tree = tree("main;f;g;f 2\nmain;f 1\nmain;g 3\n");
+        assert_eq!(
+            tree.top_functions(10),
+            vec![
+                FunctionCounts {
+                    name: "g",
+                    self_count: 3,
+                    total: 5
+                },
+                FunctionCounts {
+                    name: "f",
+                    self_count: 3,
+                    total: 3
+                },
+                FunctionCounts {
+                    name: "main",
+                    self_count: 0,
+                    total: 6
+                },
+            ]
+        );
+        assert_eq!(tree.top_functions(1).len(), 1);
+    }
+
+    #[test]
+    fn zooms_in() {
+        let tree = tree("main;f;g;f 2\nmain;f 1\nmain;g;f;h 3\n");
+        assert_eq!(tree.zoom("f").to_string(), "f 1\nf;g;f 2\nf;h 3\n");
+        assert_eq!(tree.zoom("nope").total(), 0);
+
+        let g = tree.find("g")[0];
+        assert_eq!(g.path(), vec!["main", "f", "g"]);
+        assert_eq!(tree.subtree(g).to_string(), "g;f 2\n");
+        assert_eq!(tree.subtree(tree.root()).to_string(), tree.to_string());
+    }
+}
@@ -0,0 +1,617 @@
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::str::FromStr;
+
+use fnv::FnvHashMap;
+use indexmap::IndexSet;
+
+/// A set of folded stacks: call stacks, each with the number of times it was sampled.
+///
+/// This is what collapsers produce and what flame graphs are drawn from, without the round trip
+/// through the text format that the command-line tools use (`main;foo;bar 42`). Frame names are
+/// interned, so stacks that share frames only store each name once.
+///
+/// Like the text format, the stacks are kept in the order they were added, and the same stack may
+/// appear more than once (see [`merge`](#method.merge) for combining them). A stack can also have
+/// a second, _original_, count, which makes it part of a [differential flame graph].
+///
+/// ```
+/// use inferno::folded::FoldedStacks;
+///
+/// let mut stacks: FoldedStacks = "main;foo 3\nmain;bar 1\n".parse().unwrap();
+/// stacks.push(vec!["main", "foo", "baz"], 2);
+/// stacks.retain(|stack| stack.frames().any(|frame| frame == "foo"));
+/// assert_eq!(stacks.to_string(), "main;foo 3\nmain;foo;baz 2\n");
+/// ```
+///
+///   [differential flame graph]: http://www.brendangregg.com/blog/2014-11-09/differential-flame-graphs.html
+#[derive(Clone, Debug, Default)]
+pub struct FoldedStacks {
+    names: IndexSet<String>,
+    frames: Vec<usize>,
+    stacks: Vec<Entry>,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    frames: Range<usize>,
+    count: u64,
+    original_count: Option<u64>,
+}
+
+impl FoldedStacks {
+    /// Creates an empty set of stacks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses stacks in the folded text format, one per line.
+    ///
+    /// Each line must have a semicolon-separated list of frames, root first, followed by a space
+    /// and a sample count. A differential line has two sample counts, the original one first.
+    /// Empty lines are skipped, but unlike [`flamegraph::from_lines`], which ignores lines it
+    /// can't make sense of, any other line that doesn't follow this format is an error.
+    ///
+    ///   [`flamegraph::from_lines`]: ../flamegraph/fn.from_lines.html
+    pub fn parse(input: &str) -> Result<Self, ParseError> {
+        let mut stacks = Self::new();
+        for (i, line) in input.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let error = |index: usize, kind| ParseError {
+                line: i + 1,
+                column: line[..index].chars().count() + 1,
+                kind,
+            };
+
+            let (rest, count) = match split_count(line) {
+                Some(split) => split,
+                None => return Err(error(line.len(), ParseErrorKind::MissingCount)),
+            };
+            let count = count
+                .parse()
+                .map_err(|_| error(rest.len() + 1, ParseErrorKind::InvalidCount))?;
+
+            // A stack can't end in a frame that's just a number, so this must be a differential.
+            let (stack, original_count) = match split_count(rest) {
+                Some((stack, original)) if original.bytes().all(|b| b.is_ascii_digit()) => {
+                    let original = original
+                        .parse()
+                        .map_err(|_| error(stack.len() + 1, ParseErrorKind::InvalidCount))?;
+                    (stack, Some(original))
+                }
+                _ => (rest, None),
+            };
+            if stack.trim().is_empty() {
+                return Err(error(0, ParseErrorKind::EmptyStack));
+            }
+
+            stacks.push_entry(stack.split(';'), count, original_count);
+        }
+        Ok(stacks)
+    }
+
+    /// Adds a stack, given its frames from the root to the leaf, that was sampled `count` times.
+    pub fn push<I, S>(&mut self, frames: I, count: u64)
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.push_entry(frames, count, None);
+    }
+
+    /// Adds a stack of a differential profile, which was sampled `original_count` times in the
+    /// first profile and `count` times in the second.
+    pub fn push_differential<I, S>(&mut self, frames: I, original_count: u64, count: u64)
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.push_entry(frames, count, Some(original_count));
+    }
+
+    fn push_entry<I, S>(&mut self, frames: I, count: u64, original_count: Option<u64>)
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let start = self.frames.len();
+        for frame in frames {
+            let frame = frame.as_ref();
+            let id = match self.names.get_full(frame) {
+                Some((id, _)) => id,
+                None => self.names.insert_full(frame.to_string()).0,
+            };
+            self.frames.push(id);
+        }
+        self.stacks.push(Entry {
+            frames: start..self.frames.len(),
+            count,
+            original_count,
+        });
+    }
+
+    /// Returns the number of stacks.
+    pub fn len(&self) -> usize {
+        self.stacks.len()
+    }
+
+    /// Returns whether there are no stacks.
+    pub fn is_empty(&self) -> bool {
+        self.stacks.is_empty()
+    }
+
+    /// Returns the sum of the (second, for differentials) sample counts of all stacks.
+    pub fn total(&self) -> u64 {
+        self.stacks.iter().map(|entry| entry.count).sum()
+    }
+
+    /// Returns whether any of the stacks has an original count.
+    pub fn is_differential(&self) -> bool {
+        self.stacks
+            .iter()
+            .any(|entry| entry.original_count.is_some())
+    }
+
+    /// Iterates over the stacks, in the order they were added.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            stacks: self,
+            entries: self.stacks.iter(),
+        }
+    }
+
+    fn stack<'a>(&'a self, entry: &'a Entry) -> Stack<'a> {
+        Stack {
+            names: &self.names,
+            frames: &self.frames[entry.frames.clone()],
+            entry,
+        }
+    }
+
+    /// Only keeps the stacks for which `keep` returns `true`.
+    pub fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&Stack<'_>) -> bool,
+    {
+        let (names, frames) = (&self.names, &self.frames);
+        self.stacks.retain(|entry| {
+            keep(&Stack {
+                names,
+                frames: &frames[entry.frames.clone()],
+                entry,
+            })
+        });
+    }
+
+    /// Adds the stacks of `other` to these stacks, and adds up the counts of identical stacks, so
+    /// that every stack only appears once.
+    ///
+    /// Stacks keep the position of their first appearance. When only some of the identical stacks
+    /// have an original count, the others count as zero.
+    pub fn merge(&mut self, other: &FoldedStacks) {
+        let mut merged = FoldedStacks::new();
+        let mut seen: FnvHashMap<Vec<usize>, usize> = FnvHashMap::default();
+        for stack in self.iter().chain(other.iter()) {
+            let ids: Vec<usize> = stack
+                .frames()
+                .map(|frame| match merged.names.get_full(frame) {
+                    Some((id, _)) => id,
+                    None => merged.names.insert_full(frame.to_string()).0,
+                })
+                .collect();
+            match seen.get(&ids) {
+                Some(&i) => {
+                    let entry = &mut merged.stacks[i];
+                    entry.count += stack.count();
+                    entry.original_count = match (entry.original_count, stack.original_count()) {
+                        (None, None) => None,
+                        (a, b) => Some(a.unwrap_or(0) + b.unwrap_or(0)),
+                    };
+                }
+                None => {
+                    let start = merged.frames.len();
+                    merged.frames.extend_from_slice(&ids);
+                    merged.stacks.push(Entry {
+                        frames: start..merged.frames.len(),
+                        count: stack.count(),
+                        original_count: stack.original_count(),
+                    });
+                    seen.insert(ids, merged.stacks.len() - 1);
+                }
+            }
+        }
+        *self = merged;
+    }
+
+    /// Folds the recursive calls in every stack, as described by [`Recursion`].
+    ///
+    /// Stacks that end up identical are not combined; use [`merge`](#method.merge) for that.
+    ///
+    ///   [`Recursion`]: enum.Recursion.html
+    pub fn collapse_recursion(&mut self, recursion: Recursion) {
+        for entry in &mut self.stacks {
+            let len = fold_recursion(&mut self.frames[entry.frames.clone()], recursion);
+            entry.frames.end = entry.frames.start + len;
+        }
+    }
+
+    /// Writes the stacks out in the folded text format, one per line.
+    pub fn write_to<W>(&self, mut writer: W) -> io::Result<()>
+    where
+        W: io::Write,
+    {
+        for stack in self {
+            writeln!(writer, "{}", stack)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for FoldedStacks {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for FoldedStacks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for stack in self {
+            writeln!(f, "{}", stack)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> IntoIterator for &'a FoldedStacks {
+    type Item = Stack<'a>;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// One of the stacks in [`FoldedStacks`].
+///
+/// Formats as a line of the folded text format (without a newline).
+#[derive(Clone, Copy, Debug)]
+pub struct Stack<'a> {
+    names: &'a IndexSet<String>,
+    frames: &'a [usize],
+    entry: &'a Entry,
+}
+
+impl<'a> Stack<'a> {
+    /// Iterates over the names of the frames of the stack, from the root to the leaf.
+    pub fn frames(&self) -> impl DoubleEndedIterator<Item = &'a str> + ExactSizeIterator + 'a {
+        let names = self.names;
+        self.frames.iter().map(move |&id| names[id].as_str())
+    }
+
+    /// Returns the number of times the stack was sampled (in the second profile, for
+    /// differentials).
+    pub fn count(&self) -> u64 {
+        self.entry.count
+    }
+
+    /// Returns the number of times the stack was sampled in the first profile, if this stack is
+    /// part of a differential.
+    pub fn original_count(&self) -> Option<u64> {
+        self.entry.original_count
+    }
+}
+
+impl<'a> fmt::Display for Stack<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, frame) in self.frames().enumerate() {
+            if i != 0 {
+                f.write_str(";")?;
+            }
+            f.write_str(frame)?;
+        }
+        if let Some(original_count) = self.original_count() {
+            write!(f, " {}", original_count)?;
+        }
+        write!(f, " {}", self.count())
+    }
+}
+
+/// An iterator over the stacks in [`FoldedStacks`].
+#[derive(Clone, Debug)]
+pub struct Iter<'a> {
+    stacks: &'a FoldedStacks,
+    entries: std::slice::Iter<'a, Entry>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Stack<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.entries.next()?;
+        Some(self.stacks.stack(entry))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl<'a> DoubleEndedIterator for Iter<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let entry = self.entries.next_back()?;
+        Some(self.stacks.stack(entry))
+    }
+}
+
+impl<'a> ExactSizeIterator for Iter<'a> {}
+
+/// An error in a line of folded stacks, returned by [`FoldedStacks::parse`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    line: usize,
+    column: usize,
+    kind: ParseErrorKind,
+}
+
+impl ParseError {
+    /// Returns the line the error is on, starting at 1.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Returns the column (in characters) the error is at, starting at 1.
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    /// Returns what is wrong with the line.
+    pub fn kind(&self) -> ParseErrorKind {
+        self.kind
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.kind
+        )
+    }
+}
+
+impl Error for ParseError {}
+
+impl From<ParseError> for io::Error {
+    fn from(error: ParseError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+/// What is wrong with a line of folded stacks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// The line doesn't end with a sample count.
+    MissingCount,
+    /// A sample count isn't a whole number, or is too large.
+    InvalidCount,
+    /// The line has sample counts, but no stack.
+    EmptyStack,
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ParseErrorKind::MissingCount => "missing sample count",
+            ParseErrorKind::InvalidCount => "invalid sample count",
+            ParseErrorKind::EmptyStack => "empty stack",
+        })
+    }
+}
+
+/// Which recursive calls [`collapse_recursion`] folds into one frame.
+///
+///   [`collapse_recursion`]: fn.collapse_recursion.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Recursion {
+    /// Functions that call themselves directly, so that `main;parse;parse;parse;lex` becomes
+    /// `main;parse;lex`.
+    Direct,
+    /// Also cycles of functions that call each other, so that `main;expr;term;expr;term;lex`
+    /// becomes `main;expr;term;lex`. When a function shows up again further up the stack, the
+    /// frames since its first call are dropped.
+    Indirect,
+}
+
+impl FromStr for Recursion {
+    type Err = String;
+
+    /// Parses `direct` or `cycles` (for [`Recursion::Indirect`]), as given to the
+    /// `--collapse-recursion` option of the command-line tools.
+    ///
+    ///   [`Recursion::Indirect`]: #variant.Indirect
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "direct" => Ok(Recursion::Direct),
+            "cycles" => Ok(Recursion::Indirect),
+            unknown => Err(format!("unknown recursion: {}", unknown)),
+        }
+    }
+}
+
+/// Folds the recursive calls in a semicolon-separated stack of frames into one frame per
+/// function, like FlameGraph's `stackcollapse-recursive.pl` does, so that recursion neither makes
+/// a flame graph taller nor splits a function's time across many depths.
+///
+/// The stack is returned as is if there is no recursion to fold.
+///
+/// ```
+/// use inferno::folded::{collapse_recursion, Recursion};
+///
+/// let stack = "main;eval;eval;apply;eval;print";
+/// assert_eq!(collapse_recursion(stack, Recursion::Direct), "main;eval;apply;eval;print");
+/// assert_eq!(collapse_recursion(stack, Recursion::Indirect), "main;eval;print");
+/// ```
+pub fn collapse_recursion(stack: &str, recursion: Recursion) -> Cow<'_, str> {
+    let mut frames: Vec<&str> = stack.split(';').collect();
+    let len = fold_recursion(&mut frames, recursion);
+    if len == frames.len() {
+        Cow::Borrowed(stack)
+    } else {
+        Cow::Owned(frames[..len].join(";"))
+    }
+}
+
+// Folds the recursion in `frames` in place, and returns the number of frames that are left at the
+// start of it.
+fn fold_recursion<T: PartialEq>(frames: &mut [T], recursion: Recursion) -> usize {
+    let mut len = 0;
+    for i in 0..frames.len() {
+        let keep = match recursion {
+            Recursion::Direct => len == 0 || frames[len - 1] != frames[i],
+            Recursion::Indirect => match frames[..len].iter().position(|f| *f == frames[i]) {
+                Some(first) => {
+                    len = first + 1;
+                    false
+                }
+                None => true,
+            },
+        };
+        if keep {
+            frames.swap(len, i);
+            len += 1;
+        }
+    }
+    len
+}
+
+// Splits the timestamp that flame chart stacks may start with off a stack.
+pub(crate) fn split_timestamp(stack: &str) -> (Option<f64>, &str) {
+    if let Some(space) = stack.find(' ') {
+        let (word, rest) = (&stack[..space], stack[space + 1..].trim_start());
+        if !rest.is_empty() && word.chars().all(|c| c.is_ascii_digit() || c == '.') {
+            if let Ok(timestamp) = word.parse() {
+                return (Some(timestamp), rest);
+            }
+        }
+    }
+    (None, stack)
+}
+
+// Splits the last space-separated word, which should be a sample count, off a line.
+fn split_count(line: &str) -> Option<(&str, &str)> {
+    let space = line.trim_end().rfind(' ')?;
+    Some((&line[..space], line[space + 1..].trim_end()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn error(input: &str) -> (usize, usize, ParseErrorKind) {
+        let error = FoldedStacks::parse(input).unwrap_err();
+        (error.line(), error.column(), error.kind())
+    }
+
+    #[test]
+    fn parses_and_writes() {
+        let input = "main;foo 3\n\nmain;foo bar;baz 1\r\nmain;foo 2 5\n";
+        let stacks = FoldedStacks::parse(input).unwrap();
+        assert_eq!(stacks.len(), 3);
+        assert_eq!(stacks.total(), 9);
+        assert!(stacks.is_differential());
+        let parsed: Vec<(Vec<&str>, Option<u64>, u64)> = stacks
+            .iter()
+            .map(|s| (s.frames().collect(), s.original_count(), s.count()))
+            .collect();
+        assert_eq!(
+            parsed,
+            vec![
+                (vec!["main", "foo"], None, 3),
+                (vec!["main", "foo bar", "baz"], None, 1),
+                (vec!["main", "foo"], Some(2), 5),
+            ]
+        );
+        // "main" and "foo" are only stored once
+        assert_eq!(stacks.names.len(), 4);
+        assert_eq!(
+            stacks.to_string(),
+            "main;foo 3\nmain;foo bar;baz 1\nmain;foo 2 5\n"
+        );
+    }
+
+    #[test]
+    fn reports_where_errors_are() {
+        assert_eq!(
+            error("main;foo 1\nmain;foo\n"),
+            (2, 9, ParseErrorKind::MissingCount)
+        );
+        assert_eq!(
+            error("main;föo 1.5\n"),
+            (1, 10, ParseErrorKind::InvalidCount)
+        );
+        assert_eq!(
+            error("main;foo 99999999999999999999 1\n"),
+            (1, 10, ParseErrorKind::InvalidCount)
+        );
+        assert_eq!(error("\n 1 2\n"), (2, 1, ParseErrorKind::EmptyStack));
+        assert_eq!(
+            FoldedStacks::parse("main x").unwrap_err().to_string(),
+            "line 1, column 6: invalid sample count"
+        );
+    }
+
+    #[test]
+    fn merges_identical_stacks() {
+        let mut stacks = FoldedStacks::parse("main;foo 1\nmain;bar 2\nmain;foo 3\n").unwrap();
+        stacks.merge(&FoldedStacks::parse("main;baz 1\nmain;bar 0 1\n").unwrap());
+        assert_eq!(stacks.to_string(), "main;foo 4\nmain;bar 0 3\nmain;baz 1\n");
+    }
+
+    #[test]
+    fn collapses_recursion() {
+        let stack = "main;a;a;b;a;a;c;b;d";
+        assert_eq!(
+            collapse_recursion(stack, Recursion::Direct),
+            "main;a;b;a;c;b;d"
+        );
+        assert_eq!(
+            collapse_recursion(stack, Recursion::Indirect),
+            "main;a;c;b;d"
+        );
+        assert!(matches!(
+            collapse_recursion("main;a;b", Recursion::Indirect),
+            Cow::Borrowed(_)
+        ));
+
+        let mut stacks = FoldedStacks::parse("main;f;f;g 1\nmain;f;g 2\nmain;f;g;f 3\n").unwrap();
+        stacks.collapse_recursion(Recursion::Direct);
+        assert_eq!(stacks.to_string(), "main;f;g 1\nmain;f;g 2\nmain;f;g;f 3\n");
+        stacks.collapse_recursion(Recursion::Indirect);
+        stacks.merge(&FoldedStacks::new());
+        assert_eq!(stacks.to_string(), "main;f;g 3\nmain;f 3\n");
+    }
+
+    #[test]
+    fn parses_recursion() {
+        assert_eq!("direct".parse(), Ok(Recursion::Direct));
+        assert_eq!("cycles".parse(), Ok(Recursion::Indirect));
+        assert!("indirect".parse::<Recursion>().is_err());
+    }
+
+    #[test]
+    fn retains_stacks() {
+        let mut stacks = FoldedStacks::parse("main;foo 1\nmain;bar 2\nmain;foo;bar 3\n").unwrap();
+        stacks.retain(|stack| stack.frames().last() == Some("bar"));
+        assert_eq!(stacks.to_string(), "main;bar 2\nmain;foo;bar 3\n");
+        assert_eq!(stacks.iter().next_back().unwrap().count(), 3);
+    }
+}
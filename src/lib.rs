@@ -29,9 +29,15 @@
 //! Since profiling tools produce stack traces in a myriad of different formats, and the flame
 //! graph plotter expects input in a particular folded stack trace format, each profiler needs a
 //! separate collapse implementation. While the original Perl implementation supports _lots_ of
-//! profilers, Inferno currently only supports three: the widely used [`perf`] tool (specifically
-//! the output from `perf script`), [DTrace], and [sample]. Support for xdebug is [hopefully coming
-//! soon], and [`bpftrace`] should get [native support] before too long.
+//! profilers, Inferno currently only supports nine: the widely used [`perf`] tool (specifically
+//! the output from `perf script`), [DTrace], [sample], [`bpftrace`], [xdebug], [pprof], V8's CPU
+//! profiles from [Chrome DevTools] and Node.js, [speedscope] files, and profiles saved by the
+//! [Firefox Profiler].
+//!
+//! Collapsers for other profilers can be written outside of Inferno by implementing the
+//! [`Collapse`](collapse::Collapse) trait, or, to fold the input on many threads like the `perf`
+//! and `dtrace` collapsers do, the
+//! [`CollapseParallel`](collapse::common::CollapseParallel) trait.
 //!
 //! Inferno supports profiles from applications written in any language, but we'll walk through an
 //! example with a Rust program. To profile a Rust application, you would first set
@@ -51,6 +57,76 @@
 //! $ perf script | inferno-collapse-perf > stacks.folded
 //! ```
 //!
+//! Like `stackcollapse-perf.pl`, `inferno-collapse-perf` tidies up function names by removing
+//! their argument lists, and the `L` in front of class names for processes named `java`. With
+//! `--tidy`, you pick the strategies yourself, such as eliding C++ template arguments or Go
+//! package paths, along with the processes or modules to apply them to:
+//!
+//! ```console
+//! $ perf script | inferno-collapse-perf --tidy generic --tidy 'java:process=java|jsvc' > stacks.folded
+//! ```
+//!
+//! Each sample counts as one, unless you pass `--period` to weight samples by the period of their
+//! event instead, like the number of cycles or instructions that a sample stands for.
+//!
+//! Only the events of one type are collapsed, the first one in the input unless `--event-filter`
+//! names another. When you record several at once, `--all-events` collapses each of them in one
+//! pass, and writes their stacks to a file per event, like `cycles.folded`:
+//!
+//! ```console
+//! # perf record -e cycles,instructions,cache-misses --call-graph dwarf target/release/mybin
+//! $ perf script | inferno-collapse-perf --all-events --period --events-dir events
+//! ```
+//!
+//! To see where threads spend their time blocked rather than running, record the scheduler's
+//! context switches and pass `--off-cpu`, which weights the stack each thread switched out with
+//! by the microseconds until it was switched back in. `--off-cpu-wakers` also appends the stack
+//! of the thread that woke it up, for the `wakeup` palette:
+//!
+//! ```console
+//! # perf record -e sched:sched_switch -e sched:sched_wakeup --call-graph dwarf -a -- sleep 10
+//! $ perf script | inferno-collapse-perf --off-cpu-wakers | inferno-flamegraph --colors wakeup --countname us > wakeup.svg
+//! ```
+//!
+//! Profiles of a whole system (`perf record -a`) can be narrowed down to some processes, threads,
+//! CPUs, or command names with `--only-pid`, `--only-tid`, `--only-cpu`, and `--only-comm`, and
+//! the matching `--exclude-*` flags leave some out instead:
+//!
+//! ```console
+//! $ perf script | inferno-collapse-perf --only-comm 'java|jsvc' --exclude-cpu 0 > stacks.folded
+//! ```
+//!
+//! To zoom in on part of a profile, like a latency spike, `--start` and `--end` only keep the
+//! events in between, given as `perf script` timestamps or as seconds since the first event (like
+//! `+2.5`). `--window` splits the profile into windows of so many seconds instead, and writes the
+//! stacks of each window to a file of its own:
+//!
+//! ```console
+//! $ perf script | inferno-collapse-perf --start +2 --end +4 > spike.folded
+//! $ perf script | inferno-collapse-perf --window 1 --window-dir windows
+//! ```
+//!
+//! Rather than one flame graph for all processes, `--split` writes the stacks of each process or
+//! thread to a file of its own, named by the `--split-output` template, and `--split-svg` draws a
+//! flame graph of each as well:
+//!
+//! ```console
+//! $ perf script | inferno-collapse-perf --split process --split-output 'out/{comm}-{pid}.folded' --split-svg
+//! ```
+//!
+//! Frames that perf couldn't find a symbol for, like those of stripped binaries in containers,
+//! show up as `[unknown]`. With the `symbolize` feature, `--debug-dir` finds their functions in
+//! the debug information of their modules, in a directory of debug files like `/usr/lib/debug`
+//! or a perf build-id cache like `~/.debug`. `--inline` adds the functions inlined at each
+//! address, and `--srcline` tags each function with its file and line. Shared libraries are
+//! loaded at a different address in each process, so record the offset of each address in its
+//! module with `-F +dsoff`:
+//!
+//! ```console
+//! $ cargo install inferno --features symbolize
+//! $ perf script -F +dsoff | inferno-collapse-perf --debug-dir ~/.debug --inline > stacks.folded
+//! ```
+//!
 //! For more advanced uses, see Brendan Gregg's excellent [perf examples] page.
 //!
 //! ### DTrace (macOS)
@@ -62,9 +138,69 @@
 //! $ cat out.user_stacks | inferno-collapse-dtrace > stacks.folded
 //! ```
 //!
+//! To split the stacks up by process or thread with `--split`, like for `perf`, aggregate them
+//! along with the `execname` and `pid` (and `tid`) of their process:
+//!
+//! ```console
+//! # dtrace -x ustackframes=100 -n "profile-97 { @[execname, pid, ustack()] = count(); } tick-60s { exit(0); }"  -o out.user_stacks
+//! $ inferno-collapse-dtrace --split process --split-output 'out/{comm}-{pid}.folded' out.user_stacks
+//! ```
+//!
 //! For more advanced uses, see also upstream FlameGraph's [DTrace examples].
 //! You may also be interested in something like [NodeJS's ustack helper].
 //!
+//! ### bpftrace (Linux)
+//!
+//! ```console
+//! # bpftrace -e 'profile:hz:99 /pid == 1234/ { @[ustack, kstack] = count(); }' -o out.bpftrace
+//! $ cat out.bpftrace | inferno-collapse-bpftrace > stacks.folded
+//! ```
+//!
+//! For maps of kernel stacks alone, like `@[kstack]`, pass `--kstack` so that `--kernel` knows
+//! to annotate them.
+//!
+//! ### xdebug (PHP)
+//!
+//! Enable function traces in the computerized format with `xdebug.trace_format=1`, then:
+//!
+//! ```console
+//! $ inferno-collapse-xdebug --weight exclusive /tmp/trace.1234.xt > stacks.folded
+//! ```
+//!
+//! ### pprof (Go and others)
+//!
+//! Profiles in pprof's protocol buffer format, such as those written by Go's `runtime/pprof`, can
+//! be collapsed directly. For profiles with several sample types (like heap profiles), use
+//! `--sample-type` to pick the index of the one to count:
+//!
+//! ```console
+//! $ curl -o cpu.pb.gz http://localhost:6060/debug/pprof/profile?seconds=30
+//! $ inferno-collapse-pprof cpu.pb.gz > stacks.folded
+//! ```
+//!
+//! ### Chrome DevTools and Node.js
+//!
+//! CPU profiles saved from the JavaScript profiler in Chrome DevTools, or written by
+//! `node --cpu-prof`, can be collapsed by sample count or, with `--time`, by time spent:
+//!
+//! ```console
+//! $ node --cpu-prof app.js
+//! $ inferno-collapse-chrome --time CPU.*.cpuprofile > stacks.folded
+//! $ inferno-flamegraph --colors js stacks.folded > profile.svg
+//! ```
+//!
+//! ### speedscope and the Firefox Profiler
+//!
+//! Files in [speedscope]'s JSON format, which many profilers (like `rbspy` and `py-spy`) can
+//! write, and profiles uploaded from or saved by the [Firefox Profiler] can also be collapsed.
+//! Both usually hold one profile per thread; pass `--profile-names` or `--thread-names` to keep
+//! them apart in the flame graph, or `--profile`/`--thread` to pick just one:
+//!
+//! ```console
+//! $ inferno-collapse-speedscope --profile-names profile.speedscope.json > stacks.folded
+//! $ inferno-collapse-firefox --thread 0 profile.json > stacks.folded
+//! ```
+//!
 //! ## Producing a flame graph
 //!
 //! Once you have a folded stack file, you're ready to produce the flame graph SVG image. To do so,
@@ -77,6 +213,100 @@
 //!
 //! And then open `profile.svg` in your viewer of choice.
 //!
+//! For larger profiles, `--format html` produces a standalone web page instead, which draws the
+//! flame graph on a canvas and adds a searchable, sortable table of the hottest functions along
+//! with their callers and callees:
+//!
+//! ```console
+//! $ cat stacks.folded | inferno-flamegraph --format html > profile.html
+//! ```
+//!
+//! Where neither SVG nor JavaScript is welcome, like in emails or CI reports, `--format png`
+//! rasterizes the same flame graph into a PNG image. This requires building inferno with the
+//! `png` feature:
+//!
+//! ```console
+//! $ cargo install inferno --features png
+//! $ cat stacks.folded | inferno-flamegraph --format png > profile.png
+//! ```
+//!
+//! To explore the stacks with [pprof] or [speedscope] instead, pass `--format pprof` or
+//! `--format speedscope` to get a gzipped pprof profile or a speedscope JSON file rather than an
+//! SVG:
+//!
+//! ```console
+//! $ cat stacks.folded | inferno-flamegraph --format pprof > profile.pb.gz
+//! $ cat stacks.folded | inferno-flamegraph --format speedscope > profile.speedscope.json
+//! ```
+//!
+//! ## Reports
+//!
+//! For a quick look at where the time goes without opening a flame graph, `inferno-report` prints
+//! a table of the functions that the most samples were taken in, along with the samples taken in
+//! the functions they call. Given the output of `inferno-diff-folded` (see below), it also shows
+//! how much each function changed between the two profiles:
+//!
+//! ```console
+//! $ cat stacks.folded | inferno-report --top 10
+//! $ inferno-diff-folded folded1 folded2 | inferno-report --sort delta
+//! ```
+//!
+//! ## Flame charts
+//!
+//! A flame graph sorts and merges stacks, which hides how a program's behavior changes over time.
+//! A flame chart instead keeps the stacks in the order they were sampled, so that the x-axis
+//! shows the passage of time. `inferno-collapse-perf --flame-chart` prefixes each sample with its
+//! timestamp and leaves the samples in order, and `inferno-flamegraph --flame-chart` lays them out
+//! in time, leaving gaps where nothing was sampled:
+//!
+//! ```console
+//! $ perf script | inferno-collapse-perf --flame-chart | inferno-flamegraph --flame-chart > chart.svg
+//! ```
+//!
+//! Folded stacks without timestamps can also be drawn as a flame chart, in which case each line
+//! takes up as much of the x-axis as its sample count.
+//!
+//! ## Recursion
+//!
+//! Deeply recursive code, like a recursive descent parser, makes for tall flame graphs in which the
+//! time of a function is split across many depths. `--collapse-recursion`, which both the
+//! collapsers and `inferno-flamegraph` accept, merges the calls of a function that calls itself
+//! into one frame, so that `main;parse;parse;parse;lex` becomes `main;parse;lex`.
+//! `--collapse-recursion=cycles` also merges cycles of functions that call each other, so that
+//! `main;eval;apply;eval;apply;print` becomes `main;eval;apply;print`:
+//!
+//! ```console
+//! $ perf script | inferno-collapse-perf --collapse-recursion | inferno-flamegraph > flamegraph.svg
+//! ```
+//!
+//! ## Rules
+//!
+//! Folded stacks are often cleaned up with `grep` and `sed` before they are drawn, to leave out
+//! idle stacks, uninteresting frames like those of a runtime, or noise like addresses and generic
+//! parameters. `--rules`, which the collapsers, `inferno-flamegraph`, and `inferno-diff-folded`
+//! accept, does this with a file of regular-expression rules that drop, cut, rename, or truncate
+//! frames and stacks.
+//! See [`rules::Rules`](rules/struct.Rules.html) for the format:
+//!
+//! ```console
+//! $ cat cleanup.rules
+//! frame '^\[unknown\]$' drop-frame
+//! frame '0x[0-9a-f]+' rename '0x...'
+//! stack '^swapper;' drop-stack
+//! $ perf script | inferno-collapse-perf --rules cleanup.rules | inferno-flamegraph > flamegraph.svg
+//! ```
+//!
+//! ## Butterfly graphs
+//!
+//! A function that is called from many places, like `memcpy`, ends up split into many narrow
+//! frames across a flame graph. `inferno-flamegraph --butterfly` merges every call of the
+//! functions matching a regular expression into a single frame, with the callers of those
+//! functions drawn upside down above it and their callees drawn below it:
+//!
+//! ```console
+//! $ cat stacks.folded | inferno-flamegraph --butterfly 'memcpy|memmove' > memcpy.svg
+//! ```
+//!
 //! ## Differential flame graphs
 //!
 //! You can debug CPU performance regressions with the help of differential flame graphs.
@@ -99,6 +329,35 @@
 //! $ inferno-diff-folded folded2 folded1 | inferno-flamegraph --negate > diff1.svg
 //! ```
 //!
+//! # Library use
+//!
+//! Both stages are also available as a library. Rather than writing folded stack lines and
+//! parsing them again, a collapser can hand its stacks over as [`FoldedStacks`](folded::FoldedStacks)
+//! through [`Collapse::collapse_to_stacks`](collapse::Collapse::collapse_to_stacks), which can be
+//! filtered or merged before being drawn with [`flamegraph::from_folded_stacks`]:
+//!
+//! ```
+//! use inferno::collapse::{perf::Folder, Collapse};
+//! use inferno::flamegraph::{self, Options};
+//!
+//! let input = "\
+//! mybin 1234 9962.502413: 10101010 cpu-clock:
+//! \t    55d8e1a0b2c3 bar (/usr/bin/mybin)
+//! \t    55d8e1a0a1b2 main (/usr/bin/mybin)
+//!
+//! ";
+//! let mut stacks = Folder::default().collapse_to_stacks(input.as_bytes()).unwrap();
+//! assert_eq!(stacks.to_string(), "mybin;main;bar 1\n");
+//! stacks.retain(|stack| stack.frames().any(|frame| frame == "bar"));
+//!
+//! let mut svg = Vec::new();
+//! flamegraph::from_folded_stacks(&mut Options::default(), &stacks, &mut svg).unwrap();
+//! ```
+//!
+//! To check a profile in code rather than by looking at it, the stacks can also be merged into a
+//! [`FlameTree`](flamegraph::FlameTree), the call tree that a flame graph draws, which can be
+//! asked for things like the functions with the most samples or all the paths to a function.
+//!
 //! # Development
 //!
 //! This crate was initially developed through [a series of live coding sessions]. If you want to
@@ -113,8 +372,11 @@
 //!   [stack traces]: https://en.wikipedia.org/wiki/Stack_trace
 //!   [`perf`]: https://perf.wiki.kernel.org/index.php/Main_Page
 //!   [DTrace]: https://www.joyent.com/dtrace
-//!   [hopefully coming soon]: https://twitter.com/DanielLockyer/status/1094605231155900416
-//!   [native support]: https://github.com/jonhoo/inferno/issues/51#issuecomment-466732304
+//!   [xdebug]: https://xdebug.org/docs/trace
+//!   [pprof]: https://github.com/google/pprof
+//!   [speedscope]: https://www.speedscope.app/
+//!   [Firefox Profiler]: https://profiler.firefox.com/
+//!   [Chrome DevTools]: https://developers.google.com/web/tools/chrome-devtools/rendering-tools/js-execution
 //!   [`bpftrace`]: https://github.com/iovisor/bpftrace
 //!   [perf examples]: http://www.brendangregg.com/perf.html
 //!   [DTrace examples]: http://www.brendangregg.com/FlameGraphs/cpuflamegraphs.html#DTrace
@@ -149,3 +411,22 @@ pub mod differential;
 ///
 ///   [crate-level documentation]: ../index.html
 pub mod flamegraph;
+
+/// A typed representation of folded stack traces, with a parser and writer for their text format.
+pub mod folded;
+
+/// Text reports of where the samples in folded stack traces were taken.
+///
+/// See the [crate-level documentation] for details.
+///
+///   [crate-level documentation]: ../index.html
+pub mod report;
+
+/// Rules for filtering and rewriting folded stack traces.
+///
+/// See the [crate-level documentation] for details.
+///
+///   [crate-level documentation]: ../index.html
+pub mod rules;
+
+mod pprof;
@@ -0,0 +1,540 @@
+//! A minimal reader and writer for pprof's [`profile.proto`] format.
+//!
+//! Only the parts of the format that are needed to convert between profiles and folded stacks are
+//! supported. Unknown fields are skipped when decoding, as required by the protobuf spec.
+//!
+//!   [`profile.proto`]: https://github.com/google/pprof/blob/master/proto/profile.proto
+
+use std::io::{self, Read, Write};
+
+use libflate::gzip;
+
+/// The first two bytes of any gzip stream.
+pub(crate) const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// Protobuf wire types.
+const WIRE_VARINT: u64 = 0;
+const WIRE_64BIT: u64 = 1;
+const WIRE_LENGTH_DELIMITED: u64 = 2;
+const WIRE_32BIT: u64 = 5;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ValueType {
+    pub(crate) ty: i64,
+    pub(crate) unit: i64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Sample {
+    /// Leaf first.
+    pub(crate) location_id: Vec<u64>,
+    pub(crate) value: Vec<i64>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Mapping {
+    pub(crate) id: u64,
+    pub(crate) filename: i64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Line {
+    pub(crate) function_id: u64,
+    pub(crate) line: i64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Location {
+    pub(crate) id: u64,
+    pub(crate) mapping_id: u64,
+    pub(crate) address: u64,
+    /// If there is more than one line, all but the last one were inlined into the last one.
+    pub(crate) line: Vec<Line>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Function {
+    pub(crate) id: u64,
+    pub(crate) name: i64,
+    pub(crate) system_name: i64,
+    pub(crate) filename: i64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Profile {
+    pub(crate) sample_type: Vec<ValueType>,
+    pub(crate) sample: Vec<Sample>,
+    pub(crate) mapping: Vec<Mapping>,
+    pub(crate) location: Vec<Location>,
+    pub(crate) function: Vec<Function>,
+    pub(crate) string_table: Vec<String>,
+    pub(crate) period_type: Option<ValueType>,
+    pub(crate) period: i64,
+    pub(crate) default_sample_type: i64,
+}
+
+impl Profile {
+    /// Decodes a profile, which may or may not be gzip compressed.
+    pub(crate) fn decode(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.starts_with(&GZIP_MAGIC) {
+            let mut decoded = Vec::new();
+            gzip::Decoder::new(bytes)?.read_to_end(&mut decoded)?;
+            Self::decode_message(&decoded)
+        } else {
+            Self::decode_message(bytes)
+        }
+    }
+
+    fn decode_message(bytes: &[u8]) -> io::Result<Self> {
+        let mut profile = Profile::default();
+        let mut fields = Fields::new(bytes);
+        while let Some((field, value)) = fields.next()? {
+            match field {
+                1 => profile.sample_type.push(ValueType::decode(value.bytes()?)?),
+                2 => profile.sample.push(Sample::decode(value.bytes()?)?),
+                3 => profile.mapping.push(Mapping::decode(value.bytes()?)?),
+                4 => profile.location.push(Location::decode(value.bytes()?)?),
+                5 => profile.function.push(Function::decode(value.bytes()?)?),
+                6 => profile.string_table.push(
+                    String::from_utf8(value.bytes()?.to_vec())
+                        .map_err(|_| invalid("string table entry is not valid UTF-8"))?,
+                ),
+                11 => profile.period_type = Some(ValueType::decode(value.bytes()?)?),
+                12 => profile.period = value.int()?,
+                14 => profile.default_sample_type = value.int()?,
+                _ => {}
+            }
+        }
+        Ok(profile)
+    }
+
+    /// Encodes the profile and writes it to `writer`, gzip compressed like `go tool pprof` expects.
+    pub(crate) fn write_gzipped<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut encoder = gzip::Encoder::new(writer)?;
+        encoder.write_all(&self.encode())?;
+        encoder.finish().into_result()?;
+        Ok(())
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for st in &self.sample_type {
+            write_message(&mut buf, 1, &st.encode());
+        }
+        for sample in &self.sample {
+            write_message(&mut buf, 2, &sample.encode());
+        }
+        for mapping in &self.mapping {
+            write_message(&mut buf, 3, &mapping.encode());
+        }
+        for location in &self.location {
+            write_message(&mut buf, 4, &location.encode());
+        }
+        for function in &self.function {
+            write_message(&mut buf, 5, &function.encode());
+        }
+        for s in &self.string_table {
+            write_message(&mut buf, 6, s.as_bytes());
+        }
+        if let Some(ref period_type) = self.period_type {
+            write_message(&mut buf, 11, &period_type.encode());
+        }
+        write_int(&mut buf, 12, self.period);
+        write_int(&mut buf, 14, self.default_sample_type);
+        buf
+    }
+
+    /// Looks up an entry in the string table. Out of range indices map to the empty string.
+    pub(crate) fn string(&self, index: i64) -> &str {
+        if index < 0 {
+            return "";
+        }
+        self.string_table
+            .get(index as usize)
+            .map(String::as_str)
+            .unwrap_or("")
+    }
+}
+
+impl ValueType {
+    fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let mut vt = ValueType::default();
+        let mut fields = Fields::new(bytes);
+        while let Some((field, value)) = fields.next()? {
+            match field {
+                1 => vt.ty = value.int()?,
+                2 => vt.unit = value.int()?,
+                _ => {}
+            }
+        }
+        Ok(vt)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_int(&mut buf, 1, self.ty);
+        write_int(&mut buf, 2, self.unit);
+        buf
+    }
+}
+
+impl Sample {
+    fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let mut sample = Sample::default();
+        let mut fields = Fields::new(bytes);
+        while let Some((field, value)) = fields.next()? {
+            match field {
+                1 => value.repeated(|v| sample.location_id.push(v))?,
+                2 => value.repeated(|v| sample.value.push(v as i64))?,
+                _ => {}
+            }
+        }
+        Ok(sample)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_packed(&mut buf, 1, self.location_id.iter().cloned());
+        write_packed(&mut buf, 2, self.value.iter().map(|&v| v as u64));
+        buf
+    }
+}
+
+impl Mapping {
+    fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let mut mapping = Mapping::default();
+        let mut fields = Fields::new(bytes);
+        while let Some((field, value)) = fields.next()? {
+            match field {
+                1 => mapping.id = value.uint()?,
+                5 => mapping.filename = value.int()?,
+                _ => {}
+            }
+        }
+        Ok(mapping)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_uint(&mut buf, 1, self.id);
+        write_int(&mut buf, 5, self.filename);
+        buf
+    }
+}
+
+impl Location {
+    fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let mut location = Location::default();
+        let mut fields = Fields::new(bytes);
+        while let Some((field, value)) = fields.next()? {
+            match field {
+                1 => location.id = value.uint()?,
+                2 => location.mapping_id = value.uint()?,
+                3 => location.address = value.uint()?,
+                4 => location.line.push(Line::decode(value.bytes()?)?),
+                _ => {}
+            }
+        }
+        Ok(location)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_uint(&mut buf, 1, self.id);
+        write_uint(&mut buf, 2, self.mapping_id);
+        write_uint(&mut buf, 3, self.address);
+        for line in &self.line {
+            write_message(&mut buf, 4, &line.encode());
+        }
+        buf
+    }
+}
+
+impl Line {
+    fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let mut line = Line::default();
+        let mut fields = Fields::new(bytes);
+        while let Some((field, value)) = fields.next()? {
+            match field {
+                1 => line.function_id = value.uint()?,
+                2 => line.line = value.int()?,
+                _ => {}
+            }
+        }
+        Ok(line)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_uint(&mut buf, 1, self.function_id);
+        write_int(&mut buf, 2, self.line);
+        buf
+    }
+}
+
+impl Function {
+    fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let mut function = Function::default();
+        let mut fields = Fields::new(bytes);
+        while let Some((field, value)) = fields.next()? {
+            match field {
+                1 => function.id = value.uint()?,
+                2 => function.name = value.int()?,
+                3 => function.system_name = value.int()?,
+                4 => function.filename = value.int()?,
+                _ => {}
+            }
+        }
+        Ok(function)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_uint(&mut buf, 1, self.id);
+        write_int(&mut buf, 2, self.name);
+        write_int(&mut buf, 3, self.system_name);
+        write_int(&mut buf, 4, self.filename);
+        buf
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid pprof profile: {}", msg),
+    )
+}
+
+/// The value of a single field in a protobuf message.
+enum Value<'a> {
+    Varint(u64),
+    Fixed(u64),
+    Bytes(&'a [u8]),
+}
+
+impl<'a> Value<'a> {
+    fn uint(&self) -> io::Result<u64> {
+        match *self {
+            Value::Varint(v) | Value::Fixed(v) => Ok(v),
+            Value::Bytes(_) => Err(invalid("expected a number, found bytes")),
+        }
+    }
+
+    fn int(&self) -> io::Result<i64> {
+        self.uint().map(|v| v as i64)
+    }
+
+    fn bytes(&self) -> io::Result<&'a [u8]> {
+        match *self {
+            Value::Bytes(b) => Ok(b),
+            _ => Err(invalid("expected bytes, found a number")),
+        }
+    }
+
+    /// Calls `f` for each number in a repeated field, which may or may not be packed.
+    fn repeated<F>(&self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(u64),
+    {
+        match *self {
+            Value::Varint(v) | Value::Fixed(v) => f(v),
+            Value::Bytes(mut b) => {
+                while !b.is_empty() {
+                    f(read_varint(&mut b)?);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Iterates over the fields of an encoded protobuf message.
+struct Fields<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn next(&mut self) -> io::Result<Option<(u64, Value<'a>)>> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let key = read_varint(&mut self.buf)?;
+        let field = key >> 3;
+        let value = match key & 0x7 {
+            WIRE_VARINT => Value::Varint(read_varint(&mut self.buf)?),
+            WIRE_64BIT => Value::Fixed(read_fixed(&mut self.buf, 8)?),
+            WIRE_32BIT => Value::Fixed(read_fixed(&mut self.buf, 4)?),
+            WIRE_LENGTH_DELIMITED => {
+                let len = read_varint(&mut self.buf)? as usize;
+                if len > self.buf.len() {
+                    return Err(invalid("length-delimited field runs past end of message"));
+                }
+                let (bytes, rest) = self.buf.split_at(len);
+                self.buf = rest;
+                Value::Bytes(bytes)
+            }
+            _ => return Err(invalid("unsupported wire type")),
+        };
+        Ok(Some((field, value)))
+    }
+}
+
+fn read_varint(buf: &mut &[u8]) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf
+            .split_first()
+            .ok_or_else(|| invalid("truncated varint"))?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("varint is too long"))
+}
+
+fn read_fixed(buf: &mut &[u8], n: usize) -> io::Result<u64> {
+    if buf.len() < n {
+        return Err(invalid("truncated fixed-width field"));
+    }
+    let (bytes, rest) = buf.split_at(n);
+    *buf = rest;
+    Ok(bytes
+        .iter()
+        .rev()
+        .fold(0u64, |acc, &b| (acc << 8) | u64::from(b)))
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+// Fields with default values are left out, as proto3 does.
+fn write_uint(buf: &mut Vec<u8>, field: u64, value: u64) {
+    if value != 0 {
+        write_varint(buf, (field << 3) | WIRE_VARINT);
+        write_varint(buf, value);
+    }
+}
+
+fn write_int(buf: &mut Vec<u8>, field: u64, value: i64) {
+    write_uint(buf, field, value as u64)
+}
+
+fn write_message(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_varint(buf, (field << 3) | WIRE_LENGTH_DELIMITED);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_packed<I>(buf: &mut Vec<u8>, field: u64, values: I)
+where
+    I: IntoIterator<Item = u64>,
+{
+    let mut packed = Vec::new();
+    for value in values {
+        write_varint(&mut packed, value);
+    }
+    if !packed.is_empty() {
+        write_message(buf, field, &packed);
+    }
+}
+
+/// Returns whether `bytes` looks like the start of an (optionally gzipped) pprof profile.
+///
+/// Uncompressed profiles are recognised by their first field, which in practice is always a
+/// `sample_type` (field 1, length-delimited) wrapping a `ValueType` whose first field is its
+/// type (field 1, varint).
+pub(crate) fn looks_like_profile(bytes: &[u8]) -> bool {
+    if bytes.starts_with(&GZIP_MAGIC) {
+        return true;
+    }
+    let mut buf = bytes;
+    match read_varint(&mut buf) {
+        Ok(key) if key == (1 << 3) | WIRE_LENGTH_DELIMITED => {}
+        _ => return false,
+    }
+    match read_varint(&mut buf) {
+        Ok(len) if len > 0 && len < 64 => {}
+        _ => return false,
+    }
+    buf.first() == Some(&(1 << 3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varints() {
+        let mut buf: &[u8] = &[0x96, 0x01, 0x00, 0xff, 0xff, 0xff, 0xff, 0x0f];
+        assert_eq!(read_varint(&mut buf).unwrap(), 150);
+        assert_eq!(read_varint(&mut buf).unwrap(), 0);
+        assert_eq!(read_varint(&mut buf).unwrap(), 0xffff_ffff);
+        assert!(buf.is_empty());
+        assert!(read_varint(&mut &[0x80][..]).is_err());
+    }
+
+    #[test]
+    fn packed_and_unpacked_repeated_fields() {
+        // location_id = [1, 2] unpacked, value = [3, 300] packed
+        let bytes = [0x08, 0x01, 0x08, 0x02, 0x12, 0x03, 0x03, 0xac, 0x02];
+        let sample = Sample::decode(&bytes).unwrap();
+        assert_eq!(sample.location_id, vec![1, 2]);
+        assert_eq!(sample.value, vec![3, 300]);
+        assert_eq!(Sample::decode(&sample.encode()).unwrap(), sample);
+    }
+
+    #[test]
+    fn encode_decode_roundtrip() {
+        let profile = Profile {
+            sample_type: vec![ValueType { ty: 1, unit: 2 }],
+            sample: vec![Sample {
+                location_id: vec![2, 1],
+                value: vec![-1],
+            }],
+            location: vec![
+                Location {
+                    id: 1,
+                    line: vec![Line {
+                        function_id: 1,
+                        line: 0,
+                    }],
+                    ..Default::default()
+                },
+                Location {
+                    id: 2,
+                    address: 0xffff_ffff_ffff,
+                    ..Default::default()
+                },
+            ],
+            function: vec![Function {
+                id: 1,
+                name: 3,
+                ..Default::default()
+            }],
+            string_table: vec!["".into(), "samples".into(), "count".into(), "main".into()],
+            period_type: Some(ValueType { ty: 1, unit: 2 }),
+            period: 1,
+            default_sample_type: 1,
+            ..Default::default()
+        };
+        let encoded = profile.encode();
+        assert!(looks_like_profile(&encoded));
+        assert_eq!(Profile::decode(&encoded).unwrap(), profile);
+
+        let mut gzipped = Vec::new();
+        profile.write_gzipped(&mut gzipped).unwrap();
+        assert!(gzipped.starts_with(&GZIP_MAGIC));
+        assert_eq!(Profile::decode(&gzipped).unwrap(), profile);
+    }
+}
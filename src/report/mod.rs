@@ -0,0 +1,352 @@
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::iter;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use fnv::{FnvHashMap, FnvHashSet};
+use num_format::{Buffer, Locale};
+
+use crate::flamegraph::deannotate;
+use crate::folded::FoldedStacks;
+
+const READER_CAPACITY: usize = 128 * 1024;
+
+/// The number of functions that are listed by default.
+pub const DEFAULT_TOP: usize = 20;
+
+/// Configure the report.
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// The number of functions to list, or 0 to list all of them.
+    ///
+    /// [Default value](constant.DEFAULT_TOP.html).
+    pub top: usize,
+
+    /// What to rank the functions by.
+    ///
+    /// Defaults to [`SortBy::SelfSamples`].
+    ///
+    ///   [`SortBy::SelfSamples`]: enum.SortBy.html#variant.SelfSamples
+    pub sort_by: SortBy,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            top: DEFAULT_TOP,
+            sort_by: SortBy::SelfSamples,
+        }
+    }
+}
+
+/// What to rank the functions of a report by. Ties are broken by total samples, and then by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortBy {
+    /// The samples taken in the function itself.
+    SelfSamples,
+    /// The samples taken in the function or in anything it calls.
+    Total,
+    /// How much the self samples changed between the two profiles of a differential, in either
+    /// direction. The same as `SelfSamples` for other input.
+    Delta,
+}
+
+impl FromStr for SortBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "self" => Ok(SortBy::SelfSamples),
+            "total" => Ok(SortBy::Total),
+            "delta" => Ok(SortBy::Delta),
+            unknown => Err(format!("unknown sort order: {}", unknown)),
+        }
+    }
+}
+
+/// The samples of one function, across all the stacks it appears in.
+#[derive(Debug, Clone, Copy, Default)]
+struct Samples {
+    self_samples: u64,
+    total: u64,
+    /// The same, in the first profile of a differential.
+    original_self_samples: u64,
+    original_total: u64,
+}
+
+impl Samples {
+    fn self_delta(&self) -> i64 {
+        self.self_samples as i64 - self.original_self_samples as i64
+    }
+
+    fn total_delta(&self) -> i64 {
+        self.total as i64 - self.original_total as i64
+    }
+}
+
+/// Writes a table of the functions that the most samples were taken in, with their self samples
+/// (taken in the function itself) and total samples (taken in the function or anything it calls),
+/// and what percentage of all samples those are.
+///
+/// Functions are told apart by name, with annotations like `_[k]` removed, and recursive calls
+/// only count once towards a function's total. If any stack has an original count, the report
+/// is for a differential: the samples are those of the second profile, and extra columns show how
+/// much they changed since the first.
+pub fn from_stacks<W>(opt: &Options, stacks: &FoldedStacks, mut writer: W) -> io::Result<()>
+where
+    W: Write,
+{
+    let mut functions: FnvHashMap<&str, Samples> = FnvHashMap::default();
+    let mut seen = FnvHashSet::default();
+    let (mut total, mut original_total) = (0, 0);
+    for stack in stacks {
+        let count = stack.count();
+        let original = stack.original_count().unwrap_or(0);
+        total += count;
+        original_total += original;
+
+        seen.clear();
+        for frame in stack.frames() {
+            let function = deannotate(frame);
+            if seen.insert(function) {
+                let samples = functions.entry(function).or_default();
+                samples.total += count;
+                samples.original_total += original;
+            }
+        }
+        if let Some(leaf) = stack.frames().last() {
+            let samples = functions.get_mut(deannotate(leaf)).unwrap();
+            samples.self_samples += count;
+            samples.original_self_samples += original;
+        }
+    }
+    if total == 0 && original_total == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "No stack counts found",
+        ));
+    }
+
+    let mut functions: Vec<_> = functions.into_iter().collect();
+    functions.sort_unstable_by(|(a_name, a), (b_name, b)| {
+        let key = |s: &Samples| match opt.sort_by {
+            SortBy::SelfSamples => s.self_samples,
+            SortBy::Total => s.total,
+            SortBy::Delta => s.self_delta().unsigned_abs(),
+        };
+        key(b)
+            .cmp(&key(a))
+            .then(b.total.cmp(&a.total))
+            .then(a_name.cmp(b_name))
+    });
+    if opt.top != 0 {
+        functions.truncate(opt.top);
+    }
+
+    let differential = stacks.is_differential();
+    let percent = |samples: u64| {
+        if total == 0 {
+            "-".to_string()
+        } else {
+            format!("{:.2}%", samples as f64 * 100.0 / total as f64)
+        }
+    };
+
+    let mut header = vec!["Self", "Self%"];
+    if differential {
+        header.push("Δ Self");
+    }
+    header.extend_from_slice(&["Total", "Total%"]);
+    if differential {
+        header.push("Δ Total");
+    }
+    let rows: Vec<Vec<String>> = functions
+        .iter()
+        .map(|(_, samples)| {
+            let mut row = vec![count(samples.self_samples), percent(samples.self_samples)];
+            if differential {
+                row.push(delta(samples.self_delta()));
+            }
+            row.push(count(samples.total));
+            row.push(percent(samples.total));
+            if differential {
+                row.push(delta(samples.total_delta()));
+            }
+            row
+        })
+        .collect();
+
+    let widths: Vec<usize> = header
+        .iter()
+        .enumerate()
+        .map(|(i, title)| {
+            rows.iter()
+                .map(|row| row[i].chars().count())
+                .chain(iter::once(title.chars().count()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let write_row = |writer: &mut W, cells: &[&str], function: &str| -> io::Result<()> {
+        for (cell, &width) in cells.iter().zip(&widths) {
+            let padding = width - cell.chars().count();
+            write!(writer, "{:padding$}{}  ", "", cell, padding = padding)?;
+        }
+        writeln!(writer, "{}", function)
+    };
+
+    if differential {
+        writeln!(
+            writer,
+            "Total samples: {} ({} from {})",
+            count(total),
+            delta(total as i64 - original_total as i64),
+            count(original_total)
+        )?;
+    } else {
+        writeln!(writer, "Total samples: {}", count(total))?;
+    }
+    writeln!(writer)?;
+    write_row(&mut writer, &header, "Function")?;
+    for ((function, _), row) in functions.iter().zip(&rows) {
+        let cells: Vec<&str> = row.iter().map(String::as_str).collect();
+        write_row(&mut writer, &cells, function)?;
+    }
+    Ok(())
+}
+
+/// Writes a report for the folded stack lines in the given readers.
+///
+/// The lines are parsed strictly, as by [`FoldedStacks::parse`], and may have a second sample
+/// count for a differential report. See [`from_stacks`] for what the report shows.
+///
+///   [`FoldedStacks::parse`]: ../folded/struct.FoldedStacks.html#method.parse
+pub fn from_readers<R, W>(opt: &Options, readers: R, writer: W) -> io::Result<()>
+where
+    R: IntoIterator,
+    R::Item: Read,
+    W: Write,
+{
+    let mut input = String::new();
+    for mut reader in readers {
+        reader.read_to_string(&mut input)?;
+    }
+    let stacks = FoldedStacks::parse(&input)?;
+    from_stacks(opt, &stacks, writer)
+}
+
+/// Writes a report for the folded stack lines in the given files.
+///
+/// If `files` is empty, or just `-`, STDIN will be used as input. See [`from_stacks`] for what
+/// the report shows.
+pub fn from_files<W>(opt: &Options, files: &[PathBuf], writer: W) -> io::Result<()>
+where
+    W: Write,
+{
+    let stdin = io::stdin();
+    let mut stdin_added = false;
+    let mut readers: Vec<Box<dyn Read>> = Vec::with_capacity(files.len());
+    if files.is_empty() {
+        readers.push(Box::new(stdin.lock()));
+    }
+    for file in files {
+        if file.to_str() == Some("-") {
+            if !stdin_added {
+                readers.push(Box::new(stdin.lock()));
+                stdin_added = true;
+            }
+        } else {
+            let file = File::open(file)?;
+            readers.push(Box::new(io::BufReader::with_capacity(
+                READER_CAPACITY,
+                file,
+            )));
+        }
+    }
+    from_readers(opt, readers, writer)
+}
+
+fn count(samples: u64) -> String {
+    let mut buffer = Buffer::default();
+    buffer.write_formatted(&samples, &Locale::en);
+    buffer.to_string()
+}
+
+fn delta(delta: i64) -> String {
+    let mut buffer = Buffer::default();
+    buffer.write_formatted(&delta, &Locale::en);
+    if delta > 0 {
+        format!("+{}", buffer)
+    } else {
+        buffer.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn report(opt: &Options, input: &str) -> String {
+        let mut output = Vec::new();
+        from_readers(opt, iter::once(input.as_bytes()), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn ranks_functions() {
+        let input = "main;parse;read_[k] 3\nmain;parse 1\nmain;eval;read_[j] 2\nmain;f;g;f 4\n";
+        let expected = "\
+Total samples: 10
+
+Self   Self%  Total   Total%  Function
+   5  50.00%      5   50.00%  read
+   4  40.00%      4   40.00%  f
+   1  10.00%      4   40.00%  parse
+   0   0.00%     10  100.00%  main
+   0   0.00%      4   40.00%  g
+   0   0.00%      2   20.00%  eval
+";
+        assert_eq!(report(&Options::default(), input), expected);
+
+        let opt = Options {
+            top: 2,
+            sort_by: SortBy::Total,
+        };
+        let report = report(&opt, input);
+        let functions: Vec<&str> = report
+            .lines()
+            .skip(3)
+            .map(|line| line.rsplit(' ').next().unwrap())
+            .collect();
+        assert_eq!(functions, vec!["main", "read"]);
+    }
+
+    #[test]
+    fn shows_deltas() {
+        let input = "main;foo 10 4\nmain;bar 2 6\n";
+        let expected = "\
+Total samples: 10 (-2 from 12)
+
+Self   Self%  Δ Self  Total   Total%  Δ Total  Function
+   6  60.00%      +4      6   60.00%       +4  bar
+   4  40.00%      -6      4   40.00%       -6  foo
+   0   0.00%       0     10  100.00%       -2  main
+";
+        assert_eq!(report(&Options::default(), input), expected);
+
+        let opt = Options {
+            sort_by: SortBy::Delta,
+            ..Default::default()
+        };
+        assert!(report(&opt, input).lines().nth(3).unwrap().ends_with("foo"));
+    }
+
+    #[test]
+    fn no_stacks_is_an_error() {
+        let mut output = Vec::new();
+        let stacks = FoldedStacks::new();
+        assert!(from_stacks(&Options::default(), &stacks, &mut output).is_err());
+    }
+}
@@ -0,0 +1,413 @@
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use regex::Regex;
+
+/// An ordered list of rules that filter and rewrite folded stacks, like the `sed` and `grep`
+/// pipelines that folded stacks are often cleaned up with.
+///
+/// Each rule has a _target_, either `frame` or `stack`, a regular expression, and an _action_
+/// that is taken on the stacks the expression matches. A `frame` rule matches a stack if the
+/// expression matches any of its frames, and a `stack` rule if it matches the semicolon-separated
+/// stack as a whole (e.g., `main;foo;bar`, root first). Like with `grep`, the expression can match
+/// anywhere in a frame or stack unless it is anchored with `^` and `$`. The actions are:
+///
+///  - `drop-stack`: leave the stack out altogether.
+///  - `drop-frame`: leave the matching frames out of the stack.
+///  - `keep-above`: re-root the stack at the first (outermost) matching frame, leaving out its
+///    callers, which are drawn below it in a flame graph.
+///  - `keep-below`: cut the stack off after the first matching frame, leaving out its callees,
+///    which are drawn above it in a flame graph.
+///  - `rename REPLACEMENT`: replace every match of the expression in the matching frames (or in
+///    the stack, for `stack` rules), where `$1` or `${1}` is the first capture group, `$name` a
+///    named one, and `$$` a dollar sign.
+///  - `truncate DEPTH`: only keep the `DEPTH` outermost frames of the stack.
+///
+/// `drop-frame`, `keep-above`, and `keep-below` need a frame to act on, so they only work on
+/// `frame` rules. The rules are applied to each stack in order, and a stack that ends up with no
+/// frames is dropped.
+///
+/// In the text format, each line has a rule: the target, the expression, the action, and its
+/// argument (if any), separated by whitespace. The expression and the replacement can be put in
+/// single quotes if they contain whitespace, and are taken as is, without escapes. Empty lines
+/// and lines that start with `#` are skipped.
+///
+/// ```text
+/// # Frames that perf couldn't symbolize.
+/// frame '^\[unknown\]$' drop-frame
+/// # The tokio runtime, up to the task that it polls.
+/// frame '::poll$' keep-above
+/// # Java signatures, like the `java` tidy strategy of collapse::perf.
+/// frame '^L(.*/.*)$' rename '$1'
+/// # Addresses, like `strip_hex` in differential does.
+/// frame '0x[0-9a-fA-F]+' rename '0x...'
+/// # Generic parameters.
+/// frame '<.*>' rename '<T>'
+/// stack 'idle' drop-stack
+/// stack '.*' truncate 100
+/// ```
+///
+/// ```
+/// use inferno::rules::Rules;
+///
+/// let rules: Rules = "frame ^std:: drop-frame\nframe '^(\\w+)<.*>$' rename $1".parse().unwrap();
+/// assert_eq!(rules.apply("main;std::rt::lang_start;run<T>;work").unwrap(), "main;run;work");
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    target: Target,
+    regex: Regex,
+    action: Action,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Target {
+    Frame,
+    Stack,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Action {
+    DropStack,
+    DropFrame,
+    KeepAbove,
+    KeepBelow,
+    Rename(String),
+    Truncate(usize),
+}
+
+impl PartialEq for Rule {
+    fn eq(&self, other: &Self) -> bool {
+        self.target == other.target
+            && self.regex.as_str() == other.regex.as_str()
+            && self.action == other.action
+    }
+}
+
+impl Rules {
+    /// Creates an empty list of rules, which leaves every stack as it is.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses rules in the text format, one per line.
+    pub fn parse(input: &str) -> Result<Self, ParseError> {
+        let mut rules = Self::new();
+        for (i, line) in input.lines().enumerate() {
+            let error = |message: String| ParseError {
+                line: i + 1,
+                message,
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let words = split_words(line).map_err(error)?;
+            let mut words = words.iter().map(String::as_str);
+            let mut next = |what: &str| {
+                words
+                    .next()
+                    .ok_or_else(|| error(format!("missing {}", what)))
+            };
+            let target = match next("target")? {
+                "frame" => Target::Frame,
+                "stack" => Target::Stack,
+                target => return Err(error(format!("unknown target `{}`", target))),
+            };
+            let regex = Regex::new(next("regular expression")?)
+                .map_err(|e| error(format!("invalid regular expression: {}", e)))?;
+            let action = match next("action")? {
+                "drop-stack" => Action::DropStack,
+                "drop-frame" => Action::DropFrame,
+                "keep-above" => Action::KeepAbove,
+                "keep-below" => Action::KeepBelow,
+                "rename" => Action::Rename(next("replacement")?.to_string()),
+                "truncate" => {
+                    let depth = next("depth")?;
+                    Action::Truncate(
+                        depth
+                            .parse()
+                            .map_err(|_| error(format!("invalid depth `{}`", depth)))?,
+                    )
+                }
+                action => return Err(error(format!("unknown action `{}`", action))),
+            };
+            if let Ok(extra) = next("") {
+                return Err(error(format!("unexpected `{}` after the action", extra)));
+            }
+            match (target, &action) {
+                (Target::Stack, Action::DropFrame)
+                | (Target::Stack, Action::KeepAbove)
+                | (Target::Stack, Action::KeepBelow) => {
+                    return Err(error("this action only works on frame rules".to_string()));
+                }
+                _ => {}
+            }
+
+            rules.rules.push(Rule {
+                target,
+                regex,
+                action,
+            });
+        }
+        Ok(rules)
+    }
+
+    /// Reads and parses the rules in the given file.
+    ///
+    /// The errors include the path of the file, which makes this fit for parsing command-line
+    /// arguments.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let with_path =
+            |e: &dyn fmt::Display| format!("failed to load rules from {}: {}", path.display(), e);
+        let input =
+            fs::read_to_string(path).map_err(|e| io::Error::new(e.kind(), with_path(&e)))?;
+        Self::parse(&input).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, with_path(&e)))
+    }
+
+    /// Returns the number of rules.
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Returns whether there are no rules.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Applies the rules to a semicolon-separated stack of frames, root first.
+    ///
+    /// Returns `None` if the stack is dropped, and the stack as is if no rule changed it.
+    pub fn apply<'s>(&self, stack: &'s str) -> Option<Cow<'s, str>> {
+        let mut frames: Vec<Cow<'s, str>> = stack.split(';').map(Cow::Borrowed).collect();
+        let mut changed = false;
+        for rule in &self.rules {
+            let regex = &rule.regex;
+            match rule.target {
+                Target::Stack => {
+                    let joined = if changed {
+                        Cow::Owned(frames.join(";"))
+                    } else {
+                        Cow::Borrowed(stack)
+                    };
+                    if !regex.is_match(&joined) {
+                        continue;
+                    }
+                    match rule.action {
+                        Action::DropStack => return None,
+                        Action::Rename(ref replacement) => {
+                            let renamed = regex.replace_all(&joined, replacement.as_str());
+                            frames = renamed
+                                .split(';')
+                                .map(|frame| Cow::Owned(frame.to_string()))
+                                .collect();
+                        }
+                        Action::Truncate(depth) => frames.truncate(depth),
+                        Action::DropFrame | Action::KeepAbove | Action::KeepBelow => {
+                            unreachable!("frame actions are rejected for stack rules")
+                        }
+                    }
+                }
+                Target::Frame => {
+                    let first = match frames.iter().position(|frame| regex.is_match(frame)) {
+                        Some(first) => first,
+                        None => continue,
+                    };
+                    match rule.action {
+                        Action::DropStack => return None,
+                        Action::DropFrame => frames.retain(|frame| !regex.is_match(frame)),
+                        Action::KeepAbove => {
+                            frames.drain(..first);
+                        }
+                        Action::KeepBelow => frames.truncate(first + 1),
+                        Action::Rename(ref replacement) => {
+                            for frame in &mut frames[first..] {
+                                let renamed = match regex.replace_all(frame, replacement.as_str()) {
+                                    Cow::Owned(renamed) => renamed,
+                                    Cow::Borrowed(_) => continue,
+                                };
+                                *frame = Cow::Owned(renamed);
+                            }
+                        }
+                        Action::Truncate(depth) => frames.truncate(depth),
+                    }
+                }
+            }
+            changed = true;
+        }
+
+        if frames.is_empty() || (frames.len() == 1 && frames[0].is_empty()) {
+            None
+        } else if changed {
+            Some(Cow::Owned(frames.join(";")))
+        } else {
+            Some(Cow::Borrowed(stack))
+        }
+    }
+}
+
+impl FromStr for Rules {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+/// An error in the text format of [`Rules`].
+///
+///   [`Rules`]: struct.Rules.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    line: usize,
+    message: String,
+}
+
+impl ParseError {
+    /// The line the error is on, starting at 1.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for ParseError {}
+
+impl From<ParseError> for io::Error {
+    fn from(e: ParseError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+// Splits a line into whitespace-separated words, which may be put in single quotes.
+fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        let mut word = String::new();
+        match chars.peek() {
+            None => return Ok(words),
+            Some(c) if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            Some('\'') => {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("missing closing quote".to_string()),
+                    }
+                }
+            }
+            Some(_) => {
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+            }
+        }
+        words.push(word);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn apply(rules: &str, stack: &str) -> Option<String> {
+        let rules = Rules::parse(rules).unwrap();
+        rules.apply(stack).map(Cow::into_owned)
+    }
+
+    #[test]
+    fn applies_actions() {
+        let stack = "main;[unknown];run;tokio::poll;handle;parse<u8>;read";
+        assert_eq!(
+            apply("frame '^\\[unknown\\]$' drop-frame", stack).unwrap(),
+            "main;run;tokio::poll;handle;parse<u8>;read"
+        );
+        assert_eq!(apply("frame ^handle$ drop-stack", stack), None);
+        assert_eq!(
+            apply("frame ^tokio:: keep-above", stack).unwrap(),
+            "tokio::poll;handle;parse<u8>;read"
+        );
+        assert_eq!(
+            apply("frame ^handle$ keep-below", stack).unwrap(),
+            "main;[unknown];run;tokio::poll;handle"
+        );
+        assert_eq!(
+            apply("frame '^(\\w+)<.*>$' rename '${1}_generic'", stack).unwrap(),
+            "main;[unknown];run;tokio::poll;handle;parse_generic;read"
+        );
+        assert_eq!(
+            apply("stack 'run;tokio::poll' rename 'run'", stack).unwrap(),
+            "main;[unknown];run;handle;parse<u8>;read"
+        );
+        assert_eq!(
+            apply("stack . truncate 2", stack).unwrap(),
+            "main;[unknown]"
+        );
+        assert_eq!(apply("frame ^read$ drop-frame", "read"), None);
+    }
+
+    #[test]
+    fn applies_rules_in_order() {
+        let rules =
+            "# comment\n\nframe ^a$ rename b\n  frame ^b$ drop-frame\nstack ^main$ drop-stack\n";
+        assert_eq!(apply(rules, "main;a;c").unwrap(), "main;c");
+        assert_eq!(apply(rules, "main;a;b"), None);
+        assert!(matches!(
+            Rules::parse(rules).unwrap().apply("main;c"),
+            Some(Cow::Borrowed(_))
+        ));
+    }
+
+    #[test]
+    fn reports_errors() {
+        let error = |input: &str| Rules::parse(input).unwrap_err().to_string();
+        assert_eq!(
+            error("\nframes x drop-frame"),
+            "line 2: unknown target `frames`"
+        );
+        assert_eq!(error("frame"), "line 1: missing regular expression");
+        assert!(error("frame ( drop-frame").starts_with("line 1: invalid regular expression"));
+        assert_eq!(error("frame x rename"), "line 1: missing replacement");
+        assert_eq!(error("frame x truncate -1"), "line 1: invalid depth `-1`");
+        assert_eq!(error("frame x drop"), "line 1: unknown action `drop`");
+        assert_eq!(
+            error("frame x drop-frame y"),
+            "line 1: unexpected `y` after the action"
+        );
+        assert_eq!(
+            error("stack x keep-above"),
+            "line 1: this action only works on frame rules"
+        );
+        assert_eq!(
+            error("frame 'x drop-frame"),
+            "line 1: missing closing quote"
+        );
+    }
+}
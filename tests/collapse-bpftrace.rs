@@ -0,0 +1,196 @@
+mod common;
+
+use std::fs::File;
+use std::io::{self, BufReader, Cursor};
+use std::process::{Command, Stdio};
+
+use assert_cmd::cargo::CommandCargoExt;
+use inferno::collapse::bpftrace::{Folder, Options};
+use inferno::collapse::Collapse;
+use log::Level;
+use pretty_assertions::assert_eq;
+
+fn test_collapse_bpftrace(
+    test_file: &str,
+    expected_file: &str,
+    options: Options,
+) -> io::Result<()> {
+    for &n in &[1, 2] {
+        let mut options = options.clone();
+        options.nthreads = n;
+        common::test_collapse(Folder::from(options), test_file, expected_file, false)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn collapse_bpftrace_ustack_kstack() {
+    let test_file = "./tests/data/collapse-bpftrace/ustack-kstack.txt";
+    let result_file = "./tests/data/collapse-bpftrace/results/ustack-kstack.txt";
+    test_collapse_bpftrace(test_file, result_file, Options::default()).unwrap()
+}
+
+#[test]
+fn collapse_bpftrace_ustack_kstack_annotate_kernel() {
+    let test_file = "./tests/data/collapse-bpftrace/ustack-kstack.txt";
+    let result_file = "./tests/data/collapse-bpftrace/results/ustack-kstack-kernel.txt";
+    test_collapse_bpftrace(
+        test_file,
+        result_file,
+        Options {
+            annotate_kernel: true,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+#[test]
+fn collapse_bpftrace_comm_perf_mode() {
+    let test_file = "./tests/data/collapse-bpftrace/comm-perf-mode.txt";
+    let result_file = "./tests/data/collapse-bpftrace/results/comm-perf-mode.txt";
+    test_collapse_bpftrace(
+        test_file,
+        result_file,
+        Options {
+            annotate_kernel: true,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+#[test]
+fn collapse_bpftrace_comm_perf_mode_addrs_demangle() {
+    let test_file = "./tests/data/collapse-bpftrace/comm-perf-mode.txt";
+    let result_file = "./tests/data/collapse-bpftrace/results/comm-perf-mode-addrs-demangle.txt";
+    test_collapse_bpftrace(
+        test_file,
+        result_file,
+        Options {
+            include_addrs: true,
+            demangle: true,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+#[test]
+fn collapse_bpftrace_kstack_addresses() {
+    let test_file = "./tests/data/collapse-bpftrace/kstack.txt";
+    let result_file = "./tests/data/collapse-bpftrace/results/kstack.txt";
+    test_collapse_bpftrace(
+        test_file,
+        result_file,
+        Options {
+            annotate_kernel: true,
+            include_addrs: true,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+#[test]
+fn collapse_bpftrace_kstack_symbolized() {
+    // Nothing in these frames says that they are from the kernel.
+    let test_file = "./tests/data/collapse-bpftrace/kstack-symbolized.txt";
+    let result_file = "./tests/data/collapse-bpftrace/results/kstack-symbolized.txt";
+    test_collapse_bpftrace(
+        test_file,
+        result_file,
+        Options {
+            annotate_kernel: true,
+            assume_kernel: true,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+#[test]
+fn collapse_bpftrace_should_error_for_unterminated_key() {
+    let path = "./tests/data/collapse-bpftrace/unterminated.txt";
+    let mut collapser = Folder::from(Options {
+        nthreads: 1,
+        ..Default::default()
+    });
+    match collapser.collapse_file(Some(path), io::sink()) {
+        Ok(_) => panic!(
+            "Collapsing {:?} should have returned an error, but instead it returned Ok.",
+            path
+        ),
+        Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+    }
+}
+
+#[test]
+fn collapse_bpftrace_should_warn_for_non_count_values() {
+    common::test_collapse_logs(
+        Folder::from(Options {
+            nthreads: 2,
+            ..Default::default()
+        }),
+        "./tests/data/collapse-bpftrace/non-count-value.txt",
+        |captured_logs| {
+            let nwarnings = captured_logs
+                .iter()
+                .filter(|log| {
+                    log.body == "Map value is not a count: 12ms" && log.level == Level::Warn
+                })
+                .count();
+            assert_eq!(
+                nwarnings, 1,
+                "warning logged {} times, but should be logged exactly once",
+                nwarnings
+            );
+        },
+    );
+}
+
+#[test]
+fn collapse_bpftrace_cli() {
+    let input_file = "./tests/data/collapse-bpftrace/ustack-kstack.txt";
+    let expected_file = "./tests/data/collapse-bpftrace/results/ustack-kstack-kernel.txt";
+
+    // Test with file passed in
+    let output = Command::cargo_bin("inferno-collapse-bpftrace")
+        .unwrap()
+        .arg("--kernel")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    let expected = BufReader::new(File::open(expected_file).unwrap());
+    common::compare_results(Cursor::new(output.stdout), expected, expected_file, false);
+
+    // Test with STDIN
+    let mut child = Command::cargo_bin("inferno-collapse-bpftrace")
+        .unwrap()
+        .arg("--kernel")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to spawn child process");
+    let mut input = BufReader::new(File::open(input_file).unwrap());
+    let stdin = child.stdin.as_mut().expect("Failed to open stdin");
+    io::copy(&mut input, stdin).unwrap();
+    let output = child.wait_with_output().expect("Failed to read stdout");
+    let expected = BufReader::new(File::open(expected_file).unwrap());
+    common::compare_results(Cursor::new(output.stdout), expected, expected_file, false);
+}
+
+#[test]
+fn collapse_bpftrace_kstack_cli() {
+    let input_file = "./tests/data/collapse-bpftrace/kstack-symbolized.txt";
+    let expected_file = "./tests/data/collapse-bpftrace/results/kstack-symbolized.txt";
+    let output = Command::cargo_bin("inferno-collapse-bpftrace")
+        .unwrap()
+        .arg("--kernel")
+        .arg("--kstack")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    let expected = BufReader::new(File::open(expected_file).unwrap());
+    common::compare_results(Cursor::new(output.stdout), expected, expected_file, false);
+}
@@ -0,0 +1,123 @@
+mod common;
+
+use std::fs::File;
+use std::io::{self, BufReader, Cursor};
+use std::process::{Command, Stdio};
+
+use assert_cmd::cargo::CommandCargoExt;
+use inferno::collapse::chrome::{Folder, Options};
+use inferno::collapse::Collapse;
+use log::Level;
+use pretty_assertions::assert_eq;
+
+fn test_collapse_chrome(test_file: &str, expected_file: &str, options: Options) -> io::Result<()> {
+    common::test_collapse(Folder::from(options), test_file, expected_file, false)
+}
+
+#[test]
+fn collapse_chrome_default() {
+    let test_file = "./tests/data/collapse-chrome/profile.cpuprofile";
+    let result_file = "./tests/data/collapse-chrome/results/profile-default.txt";
+    test_collapse_chrome(test_file, result_file, Options::default()).unwrap()
+}
+
+#[test]
+fn collapse_chrome_time() {
+    let test_file = "./tests/data/collapse-chrome/profile.cpuprofile";
+    let result_file = "./tests/data/collapse-chrome/results/profile-time.txt";
+    test_collapse_chrome(
+        test_file,
+        result_file,
+        Options {
+            weight_by_time: true,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+#[test]
+fn collapse_chrome_location() {
+    let test_file = "./tests/data/collapse-chrome/profile.cpuprofile";
+    let result_file = "./tests/data/collapse-chrome/results/profile-location.txt";
+    test_collapse_chrome(
+        test_file,
+        result_file,
+        Options {
+            include_location: true,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+#[test]
+fn collapse_chrome_hit_counts() {
+    let test_file = "./tests/data/collapse-chrome/hitcounts.cpuprofile";
+    let result_file = "./tests/data/collapse-chrome/results/hitcounts.txt";
+    test_collapse_chrome(test_file, result_file, Options::default()).unwrap()
+}
+
+#[test]
+fn collapse_chrome_should_warn_about_missing_samples_when_weighting_by_time() {
+    common::test_collapse_logs(
+        Folder::from(Options {
+            weight_by_time: true,
+            ..Default::default()
+        }),
+        "./tests/data/collapse-chrome/hitcounts.cpuprofile",
+        |captured_logs| {
+            let nwarnings = captured_logs
+                .iter()
+                .filter(|log| {
+                    log.body == "Profile has no samples, so weighting by hit count instead of time"
+                        && log.level == Level::Warn
+                })
+                .count();
+            assert_eq!(
+                nwarnings, 1,
+                "warning logged {} times, but should be logged exactly once",
+                nwarnings
+            );
+        },
+    );
+}
+
+#[test]
+fn collapse_chrome_should_reject_invalid_json() {
+    let err = Folder::default()
+        .collapse(&b"{\"nodes\": [{\"id\": 1}"[..], io::sink())
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn collapse_chrome_cli() {
+    let input_file = "./tests/data/collapse-chrome/profile.cpuprofile";
+    let expected_file = "./tests/data/collapse-chrome/results/profile-time.txt";
+
+    // Test with file passed in
+    let output = Command::cargo_bin("inferno-collapse-chrome")
+        .unwrap()
+        .arg("--time")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    let expected = BufReader::new(File::open(expected_file).unwrap());
+    common::compare_results(Cursor::new(output.stdout), expected, expected_file, false);
+
+    // Test with STDIN
+    let mut child = Command::cargo_bin("inferno-collapse-chrome")
+        .unwrap()
+        .arg("--time")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to spawn child process");
+    let mut input = BufReader::new(File::open(input_file).unwrap());
+    let stdin = child.stdin.as_mut().expect("Failed to open stdin");
+    io::copy(&mut input, stdin).unwrap();
+    let output = child.wait_with_output().expect("Failed to read stdout");
+    let expected = BufReader::new(File::open(expected_file).unwrap());
+    common::compare_results(Cursor::new(output.stdout), expected, expected_file, false);
+}
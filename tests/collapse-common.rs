@@ -0,0 +1,214 @@
+use std::io::{self, BufRead};
+
+use inferno::collapse::common::{self, CollapseParallel, Occurrences};
+use inferno::collapse::Collapse;
+
+/// A collapser for stacks written one frame per line, leaf first, with an empty line after each
+/// stack. A frame called `!` is an error.
+struct Folder {
+    stack: Vec<String>,
+    nstacks_per_job: usize,
+    nthreads: usize,
+}
+
+impl Folder {
+    fn new(nthreads: usize, nstacks_per_job: usize) -> Self {
+        Folder {
+            stack: Vec::new(),
+            nstacks_per_job,
+            nthreads,
+        }
+    }
+
+    fn end_stack(&mut self, occurrences: &mut Occurrences) {
+        if !self.stack.is_empty() {
+            self.stack.reverse();
+            occurrences.insert_or_add(self.stack.join(";"), 1);
+            self.stack.clear();
+        }
+    }
+}
+
+impl CollapseParallel for Folder {
+    fn pre_process<R>(&mut self, reader: &mut R, _: &mut Occurrences) -> io::Result<()>
+    where
+        R: BufRead,
+    {
+        // skip the header
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        assert_eq!(header, "# stacks\n");
+        Ok(())
+    }
+
+    fn collapse_single_threaded<R>(
+        &mut self,
+        reader: R,
+        occurrences: &mut Occurrences,
+    ) -> io::Result<()>
+    where
+        R: BufRead,
+    {
+        for line in reader.lines() {
+            let line = line?;
+            match line.trim() {
+                "" => self.end_stack(occurrences),
+                "!" => {
+                    self.stack.clear();
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "bad frame"));
+                }
+                frame => self.stack.push(frame.to_string()),
+            }
+        }
+        self.end_stack(occurrences);
+        Ok(())
+    }
+
+    fn would_end_stack(&mut self, line: &[u8]) -> bool {
+        line.iter().all(u8::is_ascii_whitespace)
+    }
+
+    fn clone_and_reset_stack_context(&self) -> Self {
+        Folder::new(self.nthreads, self.nstacks_per_job)
+    }
+
+    fn is_applicable(&mut self, input: &str) -> Option<bool> {
+        Some(input.starts_with("# stacks"))
+    }
+
+    fn nstacks_per_job(&self) -> usize {
+        self.nstacks_per_job
+    }
+
+    fn set_nstacks_per_job(&mut self, n: usize) {
+        self.nstacks_per_job = n;
+    }
+
+    fn nthreads(&self) -> usize {
+        self.nthreads
+    }
+
+    fn set_nthreads(&mut self, n: usize) {
+        self.nthreads = n;
+    }
+}
+
+fn input(nstacks: usize) -> String {
+    let mut input = String::from("# stacks\n");
+    for i in 0..nstacks {
+        for depth in (0..(i % 5) + 1).rev() {
+            input.push_str(&format!("frame{}\n", (i + depth) % 7));
+        }
+        input.push('\n');
+    }
+    input
+}
+
+#[test]
+fn collapse_common_threads_agree() {
+    let input = input(1000);
+    let mut expected = Vec::new();
+    Collapse::collapse(
+        &mut Folder::new(1, common::DEFAULT_NSTACKS_PER_JOB),
+        input.as_bytes(),
+        &mut expected,
+    )
+    .unwrap();
+    let expected = String::from_utf8(expected).unwrap();
+    let total: usize = expected
+        .lines()
+        .map(|line| line.rsplit(' ').next().unwrap().parse::<usize>().unwrap())
+        .sum();
+    assert_eq!(total, 1000);
+
+    for &nthreads in &[2, 3, 8] {
+        for &nstacks_per_job in &[1, 7, common::DEFAULT_NSTACKS_PER_JOB] {
+            let mut folder = Folder::new(nthreads, nstacks_per_job);
+            let mut actual = Vec::new();
+            Collapse::collapse(&mut folder, input.as_bytes(), &mut actual).unwrap();
+            assert_eq!(
+                String::from_utf8(actual).unwrap(),
+                expected,
+                "{} threads with {} stacks per job",
+                nthreads,
+                nstacks_per_job
+            );
+        }
+    }
+}
+
+#[test]
+fn collapse_common_worker_errors_are_returned() {
+    let mut input = input(500);
+    input.push_str("!\n\n");
+    input.push_str(&self::input(500)["# stacks\n".len()..]);
+    for &nthreads in &[1, 4] {
+        let mut folder = Folder::new(nthreads, 10);
+        let error = Collapse::collapse(&mut folder, input.as_bytes(), io::sink()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
+
+#[test]
+fn collapse_common_occurrences() {
+    let mut occurrences = Occurrences::new(1);
+    assert!(!occurrences.is_concurrent());
+    occurrences.insert_or_add("main;b".to_string(), 1);
+    occurrences.insert_or_add("main;a".to_string(), 2);
+    assert_eq!(occurrences.insert("main;b".to_string(), 5), Some(1));
+    let mut output = Vec::new();
+    occurrences.write_and_clear(&mut output).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "main;a 2\nmain;b 5\n");
+
+    let mut output = Vec::new();
+    occurrences.write_and_clear(&mut output).unwrap();
+    assert!(output.is_empty());
+
+    let occurrences = Occurrences::new(4);
+    assert!(occurrences.is_concurrent());
+    let mut clone = occurrences.clone();
+    clone.insert_or_add("main".to_string(), 3);
+    drop(occurrences);
+    let mut output = Vec::new();
+    clone.write_and_clear(&mut output).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "main 3\n");
+}
+
+#[test]
+fn collapse_common_ordered_occurrences() {
+    let mut occurrences = Occurrences::new_ordered();
+    assert!(!occurrences.is_concurrent());
+    occurrences.insert_or_add("main;b".to_string(), 1);
+    occurrences.insert_or_add("main;b".to_string(), 1);
+    occurrences.insert_or_add("main;a".to_string(), 1);
+    occurrences.insert_or_add("main;b".to_string(), 1);
+    let mut output = Vec::new();
+    occurrences.write_and_clear(&mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "main;b 2\nmain;a 1\nmain;b 1\n"
+    );
+}
+
+#[test]
+fn collapse_common_take_stacks() {
+    for &nthreads in &[1, 4] {
+        let mut occurrences = Occurrences::new(nthreads);
+        occurrences.insert_or_add("main;b".to_string(), 1);
+        occurrences.insert_or_add("main;a".to_string(), 2);
+        occurrences.insert_or_add("main;b".to_string(), 3);
+        let stacks = occurrences.take_stacks();
+        assert_eq!(stacks.to_string(), "main;a 2\nmain;b 4\n");
+        assert_eq!(
+            stacks.iter().next().unwrap().frames().collect::<Vec<_>>(),
+            vec!["main", "a"]
+        );
+        assert!(occurrences.take_stacks().is_empty());
+    }
+
+    let input = input(100);
+    let mut expected = Vec::new();
+    Collapse::collapse(&mut Folder::new(1, 10), input.as_bytes(), &mut expected).unwrap();
+    let stacks = Collapse::collapse_to_stacks(&mut Folder::new(3, 10), input.as_bytes()).unwrap();
+    assert_eq!(stacks.to_string(), String::from_utf8(expected).unwrap());
+}
@@ -2,10 +2,13 @@ mod common;
 
 use std::fs::File;
 use std::io::{self, BufReader, Cursor};
+use std::path::Path;
 use std::process::{Command, Stdio};
 
 use assert_cmd::cargo::CommandCargoExt;
+use inferno::collapse::common::Split;
 use inferno::collapse::dtrace::{Folder, Options};
+use inferno::collapse::Collapse;
 use log::Level;
 use pretty_assertions::assert_eq;
 
@@ -156,6 +159,46 @@ fn collapse_dtrace_demangle_includeoffset() {
     .unwrap()
 }
 
+#[test]
+fn collapse_dtrace_aggregation_keys() {
+    let test_file = "./tests/data/collapse-dtrace/keys.txt";
+    let result_file = "./tests/data/collapse-dtrace/results/keys.txt";
+    test_collapse_dtrace(test_file, result_file, Options::default()).unwrap();
+}
+
+#[test]
+fn collapse_dtrace_split() {
+    let test_file = "./tests/data/collapse-dtrace/keys.txt";
+    let mut folder = Folder::default();
+    let reader = BufReader::new(File::open(test_file).unwrap());
+    let partitions = folder
+        .collapse_to_partitions(reader, Split::Thread)
+        .unwrap();
+    let paths: Vec<_> = partitions
+        .iter()
+        .map(|partition| partition.path("{comm}-{pid}-{tid}.folded"))
+        .collect();
+    assert_eq!(
+        paths,
+        vec![
+            Path::new("sshd-1234-1.folded"),
+            Path::new("sshd-1234-2.folded"),
+            Path::new("bash-4321-1.folded"),
+        ]
+    );
+    assert_eq!(partitions[0].stacks.total(), 17);
+
+    let reader = BufReader::new(File::open(test_file).unwrap());
+    let partitions = folder
+        .collapse_to_partitions(reader, Split::Process)
+        .unwrap();
+    let processes: Vec<_> = partitions
+        .iter()
+        .map(|partition| (partition.comm.as_str(), partition.stacks.total()))
+        .collect();
+    assert_eq!(processes, vec![("sshd", 20), ("bash", 7)]);
+}
+
 #[test]
 fn collapse_dtrace_cli() {
     let input_file = "./flamegraph/example-dtrace-stacks.txt";
@@ -0,0 +1,129 @@
+mod common;
+
+use std::fs::File;
+use std::io::{self, BufReader, Cursor};
+use std::process::{Command, Stdio};
+
+use assert_cmd::cargo::CommandCargoExt;
+use inferno::collapse::firefox::{Folder, Options};
+use inferno::collapse::Collapse;
+use log::Level;
+use pretty_assertions::assert_eq;
+
+fn test_collapse_firefox(test_file: &str, expected_file: &str, options: Options) -> io::Result<()> {
+    common::test_collapse(Folder::from(options), test_file, expected_file, false)
+}
+
+#[test]
+fn collapse_firefox_default() {
+    let test_file = "./tests/data/collapse-firefox/profile.json";
+    let result_file = "./tests/data/collapse-firefox/results/profile-default.txt";
+    test_collapse_firefox(test_file, result_file, Options::default()).unwrap()
+}
+
+#[test]
+fn collapse_firefox_thread_strings() {
+    let test_file = "./tests/data/collapse-firefox/profile-thread-strings.json";
+    let result_file = "./tests/data/collapse-firefox/results/profile-default.txt";
+    test_collapse_firefox(test_file, result_file, Options::default()).unwrap()
+}
+
+#[test]
+fn collapse_firefox_thread_names() {
+    let test_file = "./tests/data/collapse-firefox/profile.json";
+    let result_file = "./tests/data/collapse-firefox/results/profile-thread-names.txt";
+    test_collapse_firefox(
+        test_file,
+        result_file,
+        Options {
+            include_thread_name: true,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+#[test]
+fn collapse_firefox_single_thread() {
+    let test_file = "./tests/data/collapse-firefox/profile.json";
+    let result_file = "./tests/data/collapse-firefox/results/profile-thread-1.txt";
+    test_collapse_firefox(
+        test_file,
+        result_file,
+        Options {
+            thread: Some(1),
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+#[test]
+fn collapse_firefox_should_reject_unknown_thread() {
+    let err = Folder::from(Options {
+        thread: Some(5),
+        ..Default::default()
+    })
+    .collapse_file(
+        Some("./tests/data/collapse-firefox/profile.json"),
+        io::sink(),
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(
+        err.to_string(),
+        "Thread 5 does not exist; the profile has 2 threads"
+    );
+}
+
+#[test]
+fn collapse_firefox_should_warn_about_missing_strings() {
+    common::test_collapse_logs(
+        Folder::default(),
+        "./tests/data/collapse-firefox/no-strings.json",
+        |captured_logs| {
+            let nwarnings = captured_logs
+                .iter()
+                .filter(|log| {
+                    log.body == "Thread 0 has no string table" && log.level == Level::Warn
+                })
+                .count();
+            assert_eq!(
+                nwarnings, 1,
+                "warning logged {} times, but should be logged exactly once",
+                nwarnings
+            );
+        },
+    );
+}
+
+#[test]
+fn collapse_firefox_cli() {
+    let input_file = "./tests/data/collapse-firefox/profile.json";
+    let expected_file = "./tests/data/collapse-firefox/results/profile-thread-names.txt";
+
+    // Test with file passed in
+    let output = Command::cargo_bin("inferno-collapse-firefox")
+        .unwrap()
+        .arg("--thread-names")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    let expected = BufReader::new(File::open(expected_file).unwrap());
+    common::compare_results(Cursor::new(output.stdout), expected, expected_file, false);
+
+    // Test with STDIN
+    let mut child = Command::cargo_bin("inferno-collapse-firefox")
+        .unwrap()
+        .arg("--thread-names")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to spawn child process");
+    let mut input = BufReader::new(File::open(input_file).unwrap());
+    let stdin = child.stdin.as_mut().expect("Failed to open stdin");
+    io::copy(&mut input, stdin).unwrap();
+    let output = child.wait_with_output().expect("Failed to read stdout");
+    let expected = BufReader::new(File::open(expected_file).unwrap());
+    common::compare_results(Cursor::new(output.stdout), expected, expected_file, false);
+}
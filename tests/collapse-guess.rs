@@ -64,6 +64,62 @@ fn collapse_guess_perf_java_inline() {
     test_collapse_guess(test_file, result_file, true).unwrap()
 }
 
+#[test]
+fn collapse_guess_bpftrace() {
+    let test_file = "./tests/data/collapse-bpftrace/ustack-kstack.txt";
+    let result_file = "./tests/data/collapse-bpftrace/results/ustack-kstack.txt";
+    test_collapse_guess(test_file, result_file, false).unwrap()
+}
+
+#[test]
+fn collapse_guess_xdebug() {
+    let test_file = "./tests/data/collapse-xdebug/trace.xt";
+    let result_file = "./tests/data/collapse-xdebug/results/trace-exclusive.txt";
+    test_collapse_guess(test_file, result_file, false).unwrap()
+}
+
+#[test]
+fn collapse_guess_chrome() {
+    let test_file = "./tests/data/collapse-chrome/profile.cpuprofile";
+    let result_file = "./tests/data/collapse-chrome/results/profile-default.txt";
+    test_collapse_guess(test_file, result_file, false).unwrap()
+}
+
+#[test]
+fn collapse_guess_chrome_pretty_printed() {
+    let test_file = "./tests/data/collapse-chrome/hitcounts.cpuprofile";
+    let result_file = "./tests/data/collapse-chrome/results/hitcounts.txt";
+    test_collapse_guess(test_file, result_file, false).unwrap()
+}
+
+#[test]
+fn collapse_guess_speedscope() {
+    let test_file = "./tests/data/collapse-speedscope/evented.speedscope.json";
+    let result_file = "./tests/data/collapse-speedscope/results/evented-default.txt";
+    test_collapse_guess(test_file, result_file, false).unwrap()
+}
+
+#[test]
+fn collapse_guess_firefox() {
+    let test_file = "./tests/data/collapse-firefox/profile.json";
+    let result_file = "./tests/data/collapse-firefox/results/profile-default.txt";
+    test_collapse_guess(test_file, result_file, false).unwrap()
+}
+
+#[test]
+fn collapse_guess_pprof() {
+    let test_file = "./tests/data/collapse-pprof/cpu.pprof";
+    let result_file = "./tests/data/collapse-pprof/results/cpu-default.txt";
+    test_collapse_guess(test_file, result_file, false).unwrap()
+}
+
+#[test]
+fn collapse_guess_pprof_uncompressed() {
+    let test_file = "./tests/data/collapse-pprof/cpu-uncompressed.pb";
+    let result_file = "./tests/data/collapse-pprof/results/cpu-default.txt";
+    test_collapse_guess(test_file, result_file, false).unwrap()
+}
+
 #[test]
 fn collapse_guess_sample() {
     let test_file = "./tests/data/collapse-sample/sample.txt";
@@ -1,14 +1,21 @@
 mod common;
 
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{self, BufReader, Cursor};
 use std::path::Path;
 use std::process::{Command, Stdio};
+use std::time::Duration;
 
 use assert_cmd::cargo::CommandCargoExt;
-use inferno::collapse::perf::{Folder, Options};
+use inferno::collapse::common::Split;
+use inferno::collapse::perf::{Folder, OffCpu, Options, Tidy, TidyStrategy, Time};
+#[cfg(feature = "symbolize")]
+use inferno::collapse::symbolize::Symbolizer;
+use inferno::collapse::Collapse;
+use inferno::folded::Recursion;
 use log::Level;
 use pretty_assertions::assert_eq;
+use regex::Regex;
 
 use common::test_logger::CapturedLog;
 
@@ -299,6 +306,751 @@ fn collapse_perf_demangle() {
     .unwrap()
 }
 
+#[test]
+fn collapse_perf_flame_chart() {
+    let test_file = "./tests/data/collapse-perf/flame-chart.txt";
+    let result_file = "./tests/data/collapse-perf/results/flame-chart-collapsed.txt";
+    test_collapse_perf(
+        test_file,
+        result_file,
+        Options {
+            flame_chart: true,
+            ..Default::default()
+        },
+        false,
+    )
+    .unwrap()
+}
+
+#[test]
+fn collapse_perf_collapse_recursion() {
+    let test_file = "./tests/data/collapse-perf/recursion.txt";
+    for &(recursion, result_file) in &[
+        (
+            Recursion::Direct,
+            "./tests/data/collapse-perf/results/recursion-collapsed-direct.txt",
+        ),
+        (
+            Recursion::Indirect,
+            "./tests/data/collapse-perf/results/recursion-collapsed-indirect.txt",
+        ),
+    ] {
+        test_collapse_perf(
+            test_file,
+            result_file,
+            Options {
+                collapse_recursion: Some(recursion),
+                ..Default::default()
+            },
+            false,
+        )
+        .unwrap()
+    }
+}
+
+#[test]
+fn collapse_perf_flame_chart_collapse_recursion() {
+    let test_file = "./tests/data/collapse-perf/recursion.txt";
+    let result_file = "./tests/data/collapse-perf/results/recursion-flame-chart.txt";
+    test_collapse_perf(
+        test_file,
+        result_file,
+        Options {
+            flame_chart: true,
+            collapse_recursion: Some(Recursion::Direct),
+            ..Default::default()
+        },
+        false,
+    )
+    .unwrap()
+}
+
+#[test]
+fn collapse_perf_weight_by_period() {
+    let test_file = "./tests/data/collapse-perf/period.txt";
+    let result_file = "./tests/data/collapse-perf/results/period-collapsed.txt";
+    test_collapse_perf(
+        test_file,
+        result_file,
+        Options {
+            weight_by_period: true,
+            ..Default::default()
+        },
+        false,
+    )
+    .unwrap()
+}
+
+#[test]
+fn collapse_perf_should_warn_about_events_without_period() {
+    test_collapse_perf_logs_with_options(
+        "./tests/data/collapse-perf/period.txt",
+        |captured_logs| {
+            let nwarnings = captured_logs
+                .into_iter()
+                .filter(|log| {
+                    log.body.starts_with("Event line has no period") && log.level == Level::Warn
+                })
+                .count();
+            assert_eq!(
+                nwarnings, 1,
+                "no period warning logged {} times, but should be logged exactly once",
+                nwarnings
+            );
+        },
+        Options {
+            weight_by_period: true,
+            ..Default::default()
+        },
+    );
+}
+
+#[test]
+fn collapse_perf_off_cpu() {
+    let test_file = "./tests/data/collapse-perf/off-cpu.txt";
+    for &(off_cpu, result_file) in &[
+        (
+            OffCpu::Blocked,
+            "./tests/data/collapse-perf/results/off-cpu-collapsed.txt",
+        ),
+        (
+            OffCpu::Wakers,
+            "./tests/data/collapse-perf/results/off-cpu-collapsed-wakers.txt",
+        ),
+    ] {
+        test_collapse_perf(
+            test_file,
+            result_file,
+            Options {
+                off_cpu: Some(off_cpu),
+                ..Default::default()
+            },
+            false,
+        )
+        .unwrap()
+    }
+}
+
+#[test]
+fn collapse_perf_tidy() {
+    let test_file = "./tests/data/collapse-perf/tidy.txt";
+    for (tidy, result_file) in &[
+        (
+            TidyStrategy::defaults(),
+            "./tests/data/collapse-perf/results/tidy-collapsed-default.txt",
+        ),
+        (
+            vec![
+                TidyStrategy::new(Tidy::CppTemplates),
+                TidyStrategy::new(Tidy::Generic),
+                TidyStrategy::for_process(Tidy::Java, "java|jsvc").unwrap(),
+                TidyStrategy::for_module(Tidy::RustHash, ".*/target/.*").unwrap(),
+                TidyStrategy::for_process(Tidy::GoPackages, "api").unwrap(),
+            ],
+            "./tests/data/collapse-perf/results/tidy-collapsed-strategies.txt",
+        ),
+        (
+            Vec::new(),
+            "./tests/data/collapse-perf/results/tidy-collapsed-none.txt",
+        ),
+    ] {
+        test_collapse_perf(
+            test_file,
+            result_file,
+            Options {
+                tidy: tidy.clone(),
+                ..Default::default()
+            },
+            false,
+        )
+        .unwrap()
+    }
+}
+
+#[test]
+fn collapse_perf_tidy_tid() {
+    // Process scopes match the command name, whether or not the PID and TID are included.
+    let test_file = "./tests/data/collapse-perf/tidy.txt";
+    let result_file = "./tests/data/collapse-perf/results/tidy-collapsed-default-tid.txt";
+    test_collapse_perf(
+        test_file,
+        result_file,
+        Options {
+            include_tid: true,
+            ..Default::default()
+        },
+        false,
+    )
+    .unwrap()
+}
+
+#[test]
+fn collapse_perf_tidy_cli() {
+    let input_file = "./tests/data/collapse-perf/tidy.txt";
+    let expected_file = "./tests/data/collapse-perf/results/tidy-collapsed-strategies.txt";
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--tidy=cpp-templates")
+        .arg("--tidy=generic")
+        .arg("--tidy=java:process=java|jsvc")
+        .arg("--tidy=rust-hash:module=.*/target/.*")
+        .arg("--tidy=go-packages:process=api")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    let expected = BufReader::new(File::open(expected_file).unwrap());
+    common::compare_results(Cursor::new(output.stdout), expected, expected_file, false);
+
+    let expected_file = "./tests/data/collapse-perf/results/tidy-collapsed-none.txt";
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--no-tidy")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    let expected = BufReader::new(File::open(expected_file).unwrap());
+    common::compare_results(Cursor::new(output.stdout), expected, expected_file, false);
+
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--tidy=java:pid=1234")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn collapse_perf_time_range() {
+    let test_file = "./tests/data/collapse-perf/time.txt";
+    let result_file = "./tests/data/collapse-perf/results/time-collapsed-range.txt";
+    for &(start, end) in &[
+        (
+            Time::Absolute(Duration::from_millis(11_500)),
+            Time::Absolute(Duration::from_millis(12_950)),
+        ),
+        (
+            Time::SinceFirstEvent(Duration::from_millis(1_500)),
+            Time::SinceFirstEvent(Duration::from_millis(2_950)),
+        ),
+    ] {
+        test_collapse_perf(
+            test_file,
+            result_file,
+            Options {
+                start: Some(start),
+                end: Some(end),
+                ..Default::default()
+            },
+            false,
+        )
+        .unwrap()
+    }
+}
+
+#[test]
+fn collapse_perf_time_since_filtered_event() {
+    // Times are since the first event of the input, even if it is of another type.
+    let input = "\
+app  3101 [001]  10.000000: instructions: 
+	    55d4c1a0b2e0 parse+0x20 (/opt/app/bin/app)
+
+app  3101 [001]  11.000000: cycles: 
+	    55d4c1a0b2e0 parse+0x20 (/opt/app/bin/app)
+
+app  3101 [001]  12.500000: cycles: 
+	    55d4c1a0b400 compute+0x20 (/opt/app/bin/app)
+
+";
+    for &n in &[1, 2] {
+        let mut folder = Folder::from(Options {
+            nthreads: n,
+            event_filter: Some("cycles".to_string()),
+            start: Some(Time::SinceFirstEvent(Duration::from_secs(2))),
+            ..Default::default()
+        });
+        let stacks = folder.collapse_to_stacks(input.as_bytes()).unwrap();
+        assert_eq!(stacks.to_string(), "app;compute 1\n");
+    }
+}
+
+#[test]
+fn collapse_perf_windows() {
+    let test_file = "./tests/data/collapse-perf/time.txt";
+    for &n in &[1, 2] {
+        let mut folder = Folder::from(Options {
+            nthreads: n,
+            start: Some(Time::SinceFirstEvent(Duration::from_millis(500))),
+            ..Default::default()
+        });
+        let reader = BufReader::new(File::open(test_file).unwrap());
+        let windows = folder
+            .collapse_windows(reader, Duration::from_secs(1))
+            .unwrap();
+        let windows: Vec<_> = windows
+            .iter()
+            .map(|window| {
+                (
+                    window.start.as_millis(),
+                    window.end.as_millis(),
+                    window.stacks.to_string(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            windows,
+            vec![
+                (10_500, 11_500, "app;main;parse 1\n".to_string()),
+                (11_500, 12_500, "app;main;compute 2\n".to_string()),
+                (
+                    12_500,
+                    13_500,
+                    "app;main;compute 1\napp;main;write 1\n".to_string()
+                ),
+            ]
+        );
+    }
+
+    let mut folder = Folder::from(Options {
+        flame_chart: true,
+        ..Default::default()
+    });
+    let reader = BufReader::new(File::open(test_file).unwrap());
+    assert!(folder
+        .collapse_windows(reader, Duration::from_secs(1))
+        .is_err());
+}
+
+#[test]
+fn collapse_perf_windows_cli() {
+    let input_file = "./tests/data/collapse-perf/time.txt";
+    let dir = std::env::temp_dir().join(format!("inferno-windows-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--window=2")
+        .arg("--window-dir")
+        .arg(&dir)
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+
+    let mut files: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    files.sort();
+    assert_eq!(
+        files,
+        vec!["10.000000-12.000000.folded", "12.000000-14.000000.folded"]
+    );
+    let stacks = fs::read_to_string(dir.join(&files[1])).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(stacks, "app;main;compute 2\napp;main;write 1\n");
+}
+
+#[test]
+fn collapse_perf_all_events() {
+    let test_file = "./tests/data/collapse-perf/events.txt";
+    for &n in &[1, 2] {
+        let mut folder = Folder::from(Options {
+            nthreads: n,
+            weight_by_period: true,
+            event_filter: Some("cycles".to_string()),
+            ..Default::default()
+        });
+        let reader = BufReader::new(File::open(test_file).unwrap());
+        let events: Vec<_> = folder
+            .collapse_events(reader)
+            .unwrap()
+            .into_iter()
+            .map(|(event, stacks)| (event, stacks.to_string()))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (
+                    "cache-misses".to_string(),
+                    "app;main;eval 311\n".to_string()
+                ),
+                (
+                    "cpu/mem-loads/".to_string(),
+                    "app;main;parse 97\n".to_string()
+                ),
+                (
+                    "cycles".to_string(),
+                    "app;main;eval 512000\napp;main;parse 980004\n".to_string()
+                ),
+                (
+                    "instructions".to_string(),
+                    "app;main;eval 598112\napp;main;parse 620044\n".to_string()
+                ),
+            ]
+        );
+
+        // The event filter applies again afterwards.
+        let reader = BufReader::new(File::open(test_file).unwrap());
+        let stacks = folder.collapse_to_stacks(reader).unwrap();
+        assert_eq!(
+            stacks.to_string(),
+            "app;main;eval 512000\napp;main;parse 980004\n"
+        );
+    }
+
+    let mut folder = Folder::from(Options {
+        off_cpu: Some(OffCpu::Blocked),
+        ..Default::default()
+    });
+    let reader = BufReader::new(File::open(test_file).unwrap());
+    assert!(folder.collapse_events(reader).is_err());
+}
+
+#[test]
+fn collapse_perf_all_events_tracepoints() {
+    // Tracepoints print their fields after the event name.
+    let test_file = "./tests/data/collapse-perf/off-cpu.txt";
+    let mut folder = Folder::default();
+    let reader = BufReader::new(File::open(test_file).unwrap());
+    let events = folder.collapse_events(reader).unwrap();
+    let names: Vec<_> = events.keys().map(String::as_str).collect();
+    assert_eq!(
+        names,
+        vec![
+            "cpu-clock",
+            "sched:sched_stat_iowait",
+            "sched:sched_switch",
+            "sched:sched_wakeup",
+            "sched:sched_waking"
+        ]
+    );
+    assert_eq!(
+        events["sched:sched_stat_iowait"].to_string(),
+        "swapper;do_idle;try_to_wake_up 1\n"
+    );
+}
+
+#[test]
+fn collapse_perf_all_events_cli() {
+    let input_file = "./tests/data/collapse-perf/events.txt";
+    let dir = std::env::temp_dir().join(format!("inferno-events-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--all-events")
+        .arg("--events-dir")
+        .arg(&dir)
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+
+    let mut files: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    files.sort();
+    assert_eq!(
+        files,
+        vec![
+            "cache-misses.folded",
+            "cpu_mem-loads_.folded",
+            "cycles.folded",
+            "instructions.folded"
+        ]
+    );
+    let stacks = fs::read_to_string(dir.join("instructions.folded")).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(stacks, "app;main;eval 1\napp;main;parse 1\n");
+}
+
+#[test]
+fn collapse_perf_module_offsets() {
+    let test_file = "./tests/data/collapse-perf/symbolize.txt";
+    let result_file = "./tests/data/collapse-perf/results/symbolize-collapsed-none.txt";
+    test_collapse_perf(test_file, result_file, Options::default(), false).unwrap()
+}
+
+#[cfg(feature = "symbolize")]
+#[test]
+fn collapse_perf_symbolize() {
+    let test_file = "./tests/data/collapse-perf/symbolize.txt";
+    let debug_dir = "./tests/data/collapse-perf/symbolize";
+    let result_file = "./tests/data/collapse-perf/results/symbolize-collapsed.txt";
+    let options = Options {
+        symbolizer: Some(Symbolizer::new(vec![debug_dir])),
+        ..Default::default()
+    };
+    test_collapse_perf(test_file, result_file, options, false).unwrap();
+
+    let result_file = "./tests/data/collapse-perf/results/symbolize-collapsed-inline-srcline.txt";
+    let options = Options {
+        symbolizer: Some(Symbolizer::new(vec![debug_dir]).with_inlines().with_lines()),
+        ..Default::default()
+    };
+    test_collapse_perf(test_file, result_file, options, false).unwrap();
+}
+
+#[cfg(feature = "symbolize")]
+#[test]
+fn collapse_perf_symbolize_build_id_cache() {
+    let test_file = "./tests/data/collapse-perf/symbolize.txt";
+    let result_file = "./tests/data/collapse-perf/results/symbolize-collapsed.txt";
+    let cache = std::env::temp_dir().join(format!("inferno-build-ids-{}", std::process::id()));
+    let build = cache.join("usr/lib/libdemo.so/0123456789abcdef");
+    fs::create_dir_all(&build).unwrap();
+    fs::copy(
+        "./tests/data/collapse-perf/symbolize/usr/lib/libdemo.so.debug",
+        build.join("debug"),
+    )
+    .unwrap();
+    let options = Options {
+        symbolizer: Some(Symbolizer::new(vec![&cache])),
+        ..Default::default()
+    };
+    let result = test_collapse_perf(test_file, result_file, options, false);
+    fs::remove_dir_all(&cache).unwrap();
+    result.unwrap();
+}
+
+#[cfg(feature = "symbolize")]
+#[test]
+fn collapse_perf_symbolize_cli() {
+    let input_file = "./tests/data/collapse-perf/symbolize.txt";
+    let expected_file = "./tests/data/collapse-perf/results/symbolize-collapsed-inline-srcline.txt";
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--debug-dir")
+        .arg("./tests/data/nonexistent")
+        .arg("--debug-dir")
+        .arg("./tests/data/collapse-perf/symbolize")
+        .arg("--inline")
+        .arg("--srcline")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let expected = BufReader::new(File::open(expected_file).unwrap());
+    common::compare_results(Cursor::new(output.stdout), expected, expected_file, false);
+}
+
+#[test]
+fn collapse_perf_filter() {
+    let test_file = "./tests/data/collapse-perf/filter.txt";
+    test_collapse_perf(
+        test_file,
+        "./tests/data/collapse-perf/results/filter-collapsed-pid-tid.txt",
+        Options {
+            include_tid: true,
+            only_pids: vec![3101],
+            exclude_tids: vec![3105],
+            ..Default::default()
+        },
+        false,
+    )
+    .unwrap();
+    test_collapse_perf(
+        test_file,
+        "./tests/data/collapse-perf/results/filter-collapsed-cpu-comm.txt",
+        Options {
+            include_tid: true,
+            only_cpus: vec![1, 3],
+            exclude_comm: Some(Regex::new("^swapper$").unwrap()),
+            ..Default::default()
+        },
+        false,
+    )
+    .unwrap();
+
+    // The regular expressions have to match the whole command name.
+    let mut folder = Folder::from(Options {
+        only_comm: Some(Regex::new("db").unwrap()),
+        ..Default::default()
+    });
+    let mut output = Vec::new();
+    folder.collapse_file(Some(test_file), &mut output).unwrap();
+    assert!(output.is_empty());
+}
+
+#[test]
+fn collapse_perf_filter_cli() {
+    let input_file = "./tests/data/collapse-perf/filter.txt";
+    let expected_file = "./tests/data/collapse-perf/results/filter-collapsed-only-comm.txt";
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--only-comm")
+        .arg("db.*")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    let expected = BufReader::new(File::open(expected_file).unwrap());
+    common::compare_results(Cursor::new(output.stdout), expected, expected_file, false);
+
+    // The regular expression has to match the whole command name.
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--only-comm")
+        .arg("db")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn collapse_perf_filter_start() {
+    // Times are since the first event of the input, even if its process is filtered out.
+    let input = "\
+a 1/1 [000] 100.000000: cycles:
+	    55d4c1a0b2e0 parse+0x20 (/opt/app/bin/app)
+
+b 2/2 [000] 101.500000: cycles:
+	    55d4c1a0b2e0 parse+0x20 (/opt/app/bin/app)
+
+b 2/2 [000] 102.500000: cycles:
+	    55d4c1a0b400 compute+0x20 (/opt/app/bin/app)
+
+";
+    for &n in &[1, 2] {
+        let mut folder = Folder::from(Options {
+            nthreads: n,
+            only_pids: vec![2],
+            start: Some(Time::SinceFirstEvent(Duration::from_secs(2))),
+            ..Default::default()
+        });
+        let stacks = folder.collapse_to_stacks(input.as_bytes()).unwrap();
+        assert_eq!(stacks.to_string(), "b;compute 1\n");
+    }
+
+    let mut child = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--start")
+        .arg("+2")
+        .arg("--only-pid")
+        .arg("2")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to spawn child process");
+    let stdin = child.stdin.as_mut().expect("Failed to open stdin");
+    io::copy(&mut input.as_bytes(), stdin).unwrap();
+    let output = child.wait_with_output().expect("failed to read stdout");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "b;compute 1\n");
+}
+
+#[test]
+fn collapse_perf_split() {
+    let test_file = "./tests/data/collapse-perf/filter.txt";
+    let mut folder = Folder::default();
+    let reader = BufReader::new(File::open(test_file).unwrap());
+    let partitions = folder
+        .collapse_to_partitions(reader, Split::Process)
+        .unwrap();
+    let paths: Vec<_> = partitions
+        .iter()
+        .map(|partition| partition.path("out/{comm}-{pid}.folded"))
+        .collect();
+    assert_eq!(
+        paths,
+        vec![
+            Path::new("out/swapper-0.folded"),
+            Path::new("out/app-3101.folded"),
+            Path::new("out/db_writer-3300.folded"),
+        ]
+    );
+    let mut stacks = Vec::new();
+    partitions[1].stacks.write_to(&mut stacks).unwrap();
+    assert_eq!(
+        String::from_utf8(stacks).unwrap(),
+        "app-3101;log_thread;flush_log 1\napp-3101;main;handle_request 2\n"
+    );
+
+    let reader = BufReader::new(File::open(test_file).unwrap());
+    let partitions = folder
+        .collapse_to_partitions(reader, Split::Thread)
+        .unwrap();
+    let threads: Vec<_> = partitions
+        .iter()
+        .map(|partition| {
+            (
+                partition.pid.as_ref().unwrap().as_str(),
+                partition.tid.as_ref().unwrap().as_str(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        threads,
+        vec![
+            ("0", "0"),
+            ("3101", "3101"),
+            ("3101", "3105"),
+            ("3300", "3300"),
+            ("3300", "3302"),
+        ]
+    );
+
+    // Without splitting, the folder collapses as usual again.
+    let options = Options::default();
+    let mut expected = Vec::new();
+    Folder::from(options)
+        .collapse(
+            BufReader::new(File::open(test_file).unwrap()),
+            &mut expected,
+        )
+        .unwrap();
+    let mut result = Vec::new();
+    folder
+        .collapse(BufReader::new(File::open(test_file).unwrap()), &mut result)
+        .unwrap();
+    assert_eq!(result, expected);
+}
+
+#[test]
+fn collapse_perf_split_cli() {
+    let input_file = "./tests/data/collapse-perf/filter.txt";
+    let dir = std::env::temp_dir().join(format!("inferno-split-{}", std::process::id()));
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--split=thread")
+        .arg("--split-output")
+        .arg(dir.join("{comm}/{tid}.folded"))
+        .arg("--split-svg")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+
+    let mut files: Vec<_> = fs::read_dir(dir.join("app"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    files.sort();
+    assert_eq!(
+        files,
+        vec!["3101.folded", "3101.svg", "3105.folded", "3105.svg"]
+    );
+    let stacks = fs::read_to_string(dir.join("app/3105.folded")).unwrap();
+    let svg = fs::read_to_string(dir.join("app/3105.svg")).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(stacks, "app-3101/3105;log_thread;flush_log 1\n");
+    assert!(svg.contains("app (TID 3105)"), "{}", svg);
+}
+
+#[test]
+fn collapse_perf_split_svg_requires_split() {
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--split-svg")
+        .arg("./tests/data/collapse-perf/filter.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+}
+
 #[test]
 fn collapse_perf_cli() {
     let input_file = "./flamegraph/test/perf-vertx-stacks-01.txt";
@@ -0,0 +1,139 @@
+mod common;
+
+use std::fs::File;
+use std::io::{self, BufReader, Cursor};
+use std::process::{Command, Stdio};
+
+use assert_cmd::cargo::CommandCargoExt;
+use inferno::collapse::pprof::{Folder, Options};
+use inferno::collapse::Collapse;
+use log::Level;
+use pretty_assertions::assert_eq;
+
+fn test_collapse_pprof(test_file: &str, expected_file: &str, options: Options) -> io::Result<()> {
+    common::test_collapse(Folder::from(options), test_file, expected_file, false)
+}
+
+#[test]
+fn collapse_pprof_default() {
+    let test_file = "./tests/data/collapse-pprof/cpu.pprof";
+    let result_file = "./tests/data/collapse-pprof/results/cpu-default.txt";
+    test_collapse_pprof(test_file, result_file, Options::default()).unwrap()
+}
+
+#[test]
+fn collapse_pprof_uncompressed() {
+    let test_file = "./tests/data/collapse-pprof/cpu-uncompressed.pb";
+    let result_file = "./tests/data/collapse-pprof/results/cpu-default.txt";
+    test_collapse_pprof(test_file, result_file, Options::default()).unwrap()
+}
+
+#[test]
+fn collapse_pprof_sample_type() {
+    let test_file = "./tests/data/collapse-pprof/cpu.pprof";
+    let result_file = "./tests/data/collapse-pprof/results/cpu-samples.txt";
+    test_collapse_pprof(
+        test_file,
+        result_file,
+        Options {
+            sample_type: Some(0),
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+#[test]
+fn collapse_pprof_addrs() {
+    let test_file = "./tests/data/collapse-pprof/cpu.pprof";
+    let result_file = "./tests/data/collapse-pprof/results/cpu-addrs.txt";
+    test_collapse_pprof(
+        test_file,
+        result_file,
+        Options {
+            include_addrs: true,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+#[test]
+fn collapse_pprof_should_reject_unknown_sample_type() {
+    let mut folder = Folder::from(Options {
+        sample_type: Some(2),
+        ..Default::default()
+    });
+    let err = folder
+        .collapse_file(Some("./tests/data/collapse-pprof/cpu.pprof"), io::sink())
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(
+        err.to_string(),
+        "Sample type 2 does not exist; the profile has: 0: samples/count, 1: cpu/nanoseconds"
+    );
+}
+
+#[test]
+fn collapse_pprof_should_reject_truncated_profile() {
+    let mut folder = Folder::default();
+    let bytes = std::fs::read("./tests/data/collapse-pprof/cpu-uncompressed.pb").unwrap();
+    let err = folder
+        .collapse(&bytes[..bytes.len() / 2], io::sink())
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn collapse_pprof_cli() {
+    let input_file = "./tests/data/collapse-pprof/cpu.pprof";
+    let expected_file = "./tests/data/collapse-pprof/results/cpu-samples.txt";
+
+    // Test with file passed in
+    let output = Command::cargo_bin("inferno-collapse-pprof")
+        .unwrap()
+        .arg("--sample-type")
+        .arg("0")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    let expected = BufReader::new(File::open(expected_file).unwrap());
+    common::compare_results(Cursor::new(output.stdout), expected, expected_file, false);
+
+    // Test with STDIN
+    let mut child = Command::cargo_bin("inferno-collapse-pprof")
+        .unwrap()
+        .arg("--sample-type")
+        .arg("0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to spawn child process");
+    let mut input = BufReader::new(File::open(input_file).unwrap());
+    let stdin = child.stdin.as_mut().expect("Failed to open stdin");
+    io::copy(&mut input, stdin).unwrap();
+    let output = child.wait_with_output().expect("Failed to read stdout");
+    let expected = BufReader::new(File::open(expected_file).unwrap());
+    common::compare_results(Cursor::new(output.stdout), expected, expected_file, false);
+}
+
+#[test]
+fn collapse_pprof_should_warn_about_unknown_locations() {
+    common::test_collapse_logs(
+        Folder::default(),
+        "./tests/data/collapse-pprof/unknown-location.pb",
+        |captured_logs| {
+            let nwarnings = captured_logs
+                .iter()
+                .filter(|log| {
+                    log.body == "Sample refers to unknown location 9" && log.level == Level::Warn
+                })
+                .count();
+            assert_eq!(
+                nwarnings, 1,
+                "warning logged {} times, but should be logged exactly once",
+                nwarnings
+            );
+        },
+    );
+}
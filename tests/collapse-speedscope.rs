@@ -0,0 +1,149 @@
+mod common;
+
+use std::fs::File;
+use std::io::{self, BufReader, Cursor};
+use std::process::{Command, Stdio};
+
+use assert_cmd::cargo::CommandCargoExt;
+use inferno::collapse::speedscope::{Folder, Options};
+use inferno::collapse::Collapse;
+use log::Level;
+use pretty_assertions::assert_eq;
+
+fn test_collapse_speedscope(
+    test_file: &str,
+    expected_file: &str,
+    options: Options,
+) -> io::Result<()> {
+    common::test_collapse(Folder::from(options), test_file, expected_file, false)
+}
+
+#[test]
+fn collapse_speedscope_sampled() {
+    let test_file = "./tests/data/collapse-speedscope/sampled.speedscope.json";
+    let result_file = "./tests/data/collapse-speedscope/results/sampled-default.txt";
+    test_collapse_speedscope(test_file, result_file, Options::default()).unwrap()
+}
+
+#[test]
+fn collapse_speedscope_sampled_profile_names() {
+    let test_file = "./tests/data/collapse-speedscope/sampled.speedscope.json";
+    let result_file = "./tests/data/collapse-speedscope/results/sampled-profile-names.txt";
+    test_collapse_speedscope(
+        test_file,
+        result_file,
+        Options {
+            include_profile_name: true,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+#[test]
+fn collapse_speedscope_sampled_single_profile() {
+    let test_file = "./tests/data/collapse-speedscope/sampled.speedscope.json";
+    let result_file = "./tests/data/collapse-speedscope/results/sampled-profile-1.txt";
+    test_collapse_speedscope(
+        test_file,
+        result_file,
+        Options {
+            profile: Some(1),
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+#[test]
+fn collapse_speedscope_evented() {
+    let test_file = "./tests/data/collapse-speedscope/evented.speedscope.json";
+    let result_file = "./tests/data/collapse-speedscope/results/evented-default.txt";
+    test_collapse_speedscope(test_file, result_file, Options::default()).unwrap()
+}
+
+#[test]
+fn collapse_speedscope_should_reject_unknown_profile() {
+    let err = Folder::from(Options {
+        profile: Some(2),
+        ..Default::default()
+    })
+    .collapse_file(
+        Some("./tests/data/collapse-speedscope/sampled.speedscope.json"),
+        io::sink(),
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(
+        err.to_string(),
+        "Profile 2 does not exist; the file has 2 profiles"
+    );
+}
+
+#[test]
+fn collapse_speedscope_should_warn_about_unbalanced_events() {
+    common::test_collapse_logs(
+        Folder::default(),
+        "./tests/data/collapse-speedscope/unbalanced.speedscope.json",
+        |captured_logs| {
+            let nwarnings = captured_logs
+                .iter()
+                .filter(|log| {
+                    log.body == "Frame 1 closed without being opened" && log.level == Level::Warn
+                })
+                .count();
+            assert_eq!(
+                nwarnings, 1,
+                "warning logged {} times, but should be logged exactly once",
+                nwarnings
+            );
+        },
+    );
+}
+
+#[test]
+fn collapse_speedscope_roundtrip() {
+    use inferno::flamegraph::{self, Format, Options as FlamegraphOptions};
+
+    let input = "main;bar 1\nmain;foo 2\n";
+    let mut options = FlamegraphOptions {
+        format: Format::Speedscope,
+        ..Default::default()
+    };
+    let mut file = Vec::new();
+    flamegraph::from_reader(&mut options, input.as_bytes(), &mut file).unwrap();
+    let mut folded = Vec::new();
+    Folder::default().collapse(&file[..], &mut folded).unwrap();
+    assert_eq!(String::from_utf8(folded).unwrap(), input);
+}
+
+#[test]
+fn collapse_speedscope_cli() {
+    let input_file = "./tests/data/collapse-speedscope/sampled.speedscope.json";
+    let expected_file = "./tests/data/collapse-speedscope/results/sampled-profile-names.txt";
+
+    // Test with file passed in
+    let output = Command::cargo_bin("inferno-collapse-speedscope")
+        .unwrap()
+        .arg("--profile-names")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    let expected = BufReader::new(File::open(expected_file).unwrap());
+    common::compare_results(Cursor::new(output.stdout), expected, expected_file, false);
+
+    // Test with STDIN
+    let mut child = Command::cargo_bin("inferno-collapse-speedscope")
+        .unwrap()
+        .arg("--profile-names")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to spawn child process");
+    let mut input = BufReader::new(File::open(input_file).unwrap());
+    let stdin = child.stdin.as_mut().expect("Failed to open stdin");
+    io::copy(&mut input, stdin).unwrap();
+    let output = child.wait_with_output().expect("Failed to read stdout");
+    let expected = BufReader::new(File::open(expected_file).unwrap());
+    common::compare_results(Cursor::new(output.stdout), expected, expected_file, false);
+}
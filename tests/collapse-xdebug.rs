@@ -0,0 +1,105 @@
+mod common;
+
+use std::fs::File;
+use std::io::{self, BufReader, Cursor};
+use std::process::{Command, Stdio};
+
+use assert_cmd::cargo::CommandCargoExt;
+use inferno::collapse::xdebug::{Folder, Options, Weight};
+use log::Level;
+use pretty_assertions::assert_eq;
+
+fn test_collapse_xdebug(test_file: &str, expected_file: &str, options: Options) -> io::Result<()> {
+    common::test_collapse(Folder::from(options), test_file, expected_file, false)
+}
+
+#[test]
+fn collapse_xdebug_exclusive_time() {
+    let test_file = "./tests/data/collapse-xdebug/trace.xt";
+    let result_file = "./tests/data/collapse-xdebug/results/trace-exclusive.txt";
+    test_collapse_xdebug(test_file, result_file, Options::default()).unwrap()
+}
+
+#[test]
+fn collapse_xdebug_inclusive_time() {
+    let test_file = "./tests/data/collapse-xdebug/trace.xt";
+    let result_file = "./tests/data/collapse-xdebug/results/trace-inclusive.txt";
+    test_collapse_xdebug(
+        test_file,
+        result_file,
+        Options {
+            weight: Weight::InclusiveTime,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+#[test]
+fn collapse_xdebug_calls() {
+    let test_file = "./tests/data/collapse-xdebug/trace.xt";
+    let result_file = "./tests/data/collapse-xdebug/results/trace-calls.txt";
+    test_collapse_xdebug(
+        test_file,
+        result_file,
+        Options {
+            weight: Weight::Calls,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+#[test]
+fn collapse_xdebug_should_warn_for_unterminated_trace() {
+    common::test_collapse_logs(
+        Folder::default(),
+        "./tests/data/collapse-xdebug/unterminated.xt",
+        |captured_logs| {
+            let nwarnings = captured_logs
+                .iter()
+                .filter(|log| {
+                    log.body == "File ended before end of trace" && log.level == Level::Warn
+                })
+                .count();
+            assert_eq!(
+                nwarnings, 1,
+                "warning logged {} times, but should be logged exactly once",
+                nwarnings
+            );
+        },
+    );
+}
+
+#[test]
+fn collapse_xdebug_cli() {
+    let input_file = "./tests/data/collapse-xdebug/trace.xt";
+    let expected_file = "./tests/data/collapse-xdebug/results/trace-calls.txt";
+
+    // Test with file passed in
+    let output = Command::cargo_bin("inferno-collapse-xdebug")
+        .unwrap()
+        .arg("--weight")
+        .arg("calls")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    let expected = BufReader::new(File::open(expected_file).unwrap());
+    common::compare_results(Cursor::new(output.stdout), expected, expected_file, false);
+
+    // Test with STDIN
+    let mut child = Command::cargo_bin("inferno-collapse-xdebug")
+        .unwrap()
+        .arg("--weight")
+        .arg("calls")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to spawn child process");
+    let mut input = BufReader::new(File::open(input_file).unwrap());
+    let stdin = child.stdin.as_mut().expect("Failed to open stdin");
+    io::copy(&mut input, stdin).unwrap();
+    let output = child.wait_with_output().expect("Failed to read stdout");
+    let expected = BufReader::new(File::open(expected_file).unwrap());
+    common::compare_results(Cursor::new(output.stdout), expected, expected_file, false);
+}
@@ -6,6 +6,7 @@ use std::process::Command;
 
 use assert_cmd::cargo::CommandCargoExt;
 use inferno::differential::{self, Options};
+use inferno::rules::Rules;
 use log::Level;
 use pretty_assertions::assert_eq;
 
@@ -23,7 +24,7 @@ fn test_diff_folded(
             if e.kind() == io::ErrorKind::NotFound {
                 // be nice to the dev and make the file
                 let mut f = File::create(expected_result_file).unwrap();
-                differential::from_files(options, &infile1, &infile2, &mut f)?;
+                differential::from_files(options.clone(), &infile1, &infile2, &mut f)?;
                 fs::metadata(expected_result_file).unwrap()
             } else {
                 return Err(e.into());
@@ -140,6 +141,20 @@ fn diff_folded_strip_hex() {
     test_diff_folded(infile1, infile2, expected_result_file, opt).unwrap();
 }
 
+#[test]
+fn diff_folded_rules() {
+    let infile1 = "./tests/data/diff-folded/before.txt";
+    let infile2 = "./tests/data/diff-folded/after.txt";
+    let expected_result_file = "./tests/data/diff-folded/results/rules.txt";
+
+    let opt = Options {
+        strip_hex: true,
+        rules: Rules::from_file("./tests/data/diff-folded/unknown.rules").unwrap(),
+        ..Default::default()
+    };
+    test_diff_folded(infile1, infile2, expected_result_file, opt).unwrap();
+}
+
 #[test]
 fn diff_folded_fractional_samples() {
     let infile1 = "./tests/data/diff-folded/before_fractionals.txt";
@@ -207,3 +222,23 @@ fn diff_folded_cli() {
     let expected = BufReader::new(File::open(expected_file).unwrap());
     compare_results(Cursor::new(output.stdout), expected, expected_file);
 }
+
+#[test]
+fn diff_folded_rules_cli() {
+    let infile1 = "./tests/data/diff-folded/before.txt";
+    let infile2 = "./tests/data/diff-folded/after.txt";
+    let expected_file = "./tests/data/diff-folded/results/rules.txt";
+
+    let output = Command::cargo_bin("inferno-diff-folded")
+        .unwrap()
+        .arg("--strip-hex")
+        .arg("--rules")
+        .arg("./tests/data/diff-folded/unknown.rules")
+        .arg(infile1)
+        .arg(infile2)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let expected = BufReader::new(File::open(expected_file).unwrap());
+    compare_results(Cursor::new(output.stdout), expected, expected_file);
+}
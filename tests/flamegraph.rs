@@ -7,8 +7,10 @@ use std::process::{Command, Stdio};
 use std::str::FromStr;
 
 use assert_cmd::cargo::CommandCargoExt;
+use inferno::collapse::{pprof, Collapse};
 use inferno::flamegraph::color::{BackgroundColor, PaletteMap};
-use inferno::flamegraph::{self, Direction, Options, Palette};
+use inferno::flamegraph::{self, Direction, FlameTree, Format, Options, Palette};
+use inferno::folded::Recursion;
 use log::Level;
 use pretty_assertions::assert_eq;
 
@@ -713,6 +715,207 @@ fn search_color_non_default() {
     test_flamegraph(input_file, expected_result_file, options).unwrap();
 }
 
+#[test]
+#[cfg(feature = "png")]
+fn flamegraph_png_cli() {
+    let input_file =
+        "./tests/data/flamegraph/multiple-inputs/perf-vertx-stacks-01-collapsed-all-unsorted-1.txt";
+    let output = Command::cargo_bin("inferno-flamegraph")
+        .unwrap()
+        .arg("--format")
+        .arg("png")
+        .arg("--width")
+        .arg("800")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    assert!(output.stdout.starts_with(b"\x89PNG\r\n\x1a\n"));
+    // the IHDR chunk comes first, and starts with the width
+    assert_eq!(&output.stdout[12..16], b"IHDR");
+    assert_eq!(&output.stdout[16..20], &800u32.to_be_bytes());
+}
+
+#[test]
+#[cfg(not(feature = "png"))]
+fn flamegraph_png_requires_feature() {
+    assert!(Format::from_str("png").is_err());
+}
+
+#[test]
+fn flamegraph_flame_chart() {
+    let input_file = "./tests/data/flamegraph/flame-chart/ordered.txt";
+    let expected_result_file = "./tests/data/flamegraph/flame-chart/ordered.svg";
+    let options = Options {
+        hash: true,
+        flame_chart: true,
+        ..Default::default()
+    };
+    test_flamegraph(input_file, expected_result_file, options).unwrap();
+}
+
+#[test]
+fn flamegraph_flame_chart_with_timestamps() {
+    let input_file = "./tests/data/flamegraph/flame-chart/timestamps.txt";
+    let expected_result_file = "./tests/data/flamegraph/flame-chart/timestamps.svg";
+    let options = Options {
+        hash: true,
+        flame_chart: true,
+        count_name: "us".to_string(),
+        ..Default::default()
+    };
+    test_flamegraph(input_file, expected_result_file, options).unwrap();
+}
+
+#[test]
+fn flamegraph_flame_chart_from_perf() {
+    let input_file = "./tests/data/collapse-perf/results/flame-chart-collapsed.txt";
+    let expected_result_file = "./tests/data/flamegraph/flame-chart/perf.svg";
+    let options = Options {
+        hash: true,
+        flame_chart: true,
+        reverse_stack_order: true,
+        ..Default::default()
+    };
+    test_flamegraph(input_file, expected_result_file, options).unwrap();
+}
+
+#[test]
+fn flamegraph_flame_chart_cli() {
+    let input_file = "./tests/data/flamegraph/flame-chart/ordered.txt";
+    let output = Command::cargo_bin("inferno-flamegraph")
+        .unwrap()
+        .arg("--flame-chart")
+        .arg("--hash")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let svg = String::from_utf8(output.stdout).unwrap();
+    assert!(svg.contains(">Flame Chart</text>"));
+    // `main;init` is split in two by `main;run;work`, so it must not have been sorted and merged.
+    assert_eq!(svg.matches("<title>init (").count(), 2);
+}
+
+#[test]
+fn flamegraph_butterfly() {
+    let input_file =
+        "./tests/data/flamegraph/multiple-inputs/perf-vertx-stacks-01-collapsed-all-unsorted-1.txt";
+    let expected_result_file = "./tests/data/flamegraph/butterfly/getslot.svg";
+    let options = Options {
+        hash: true,
+        butterfly: Some("org/mozilla/javascript/ScriptableObject:.getSlot".to_string()),
+        ..Default::default()
+    };
+    test_flamegraph(input_file, expected_result_file, options).unwrap();
+}
+
+#[test]
+fn flamegraph_butterfly_cli() {
+    let input_file =
+        "./tests/data/flamegraph/multiple-inputs/perf-vertx-stacks-01-collapsed-all-unsorted-1.txt";
+    let output = Command::cargo_bin("inferno-flamegraph")
+        .unwrap()
+        .arg("--butterfly")
+        .arg(".*:.getSlot")
+        .arg("--hash")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let svg = String::from_utf8(output.stdout).unwrap();
+    assert!(svg.contains(">Butterfly Graph: .*:.getSlot</text>"));
+    // All the calls of the function are merged into one frame.
+    assert_eq!(
+        svg.matches("<title>org/mozilla/javascript/ScriptableObject:.getSlot (")
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn flamegraph_butterfly_only_as_svg() {
+    let input_file = "./tests/data/flamegraph/flame-chart/ordered.txt";
+    for format in &["html", "speedscope", "pprof"] {
+        let output = Command::cargo_bin("inferno-flamegraph")
+            .unwrap()
+            .arg("--butterfly")
+            .arg("main")
+            .arg("--format")
+            .arg(format)
+            .arg(input_file)
+            .output()
+            .expect("failed to execute process");
+        assert!(!output.status.success());
+    }
+
+    let mut options = Options {
+        flame_chart: true,
+        butterfly: Some("main".to_string()),
+        ..Default::default()
+    };
+    let input = fs::read_to_string(input_file).unwrap();
+    let mut svg = Vec::new();
+    match flamegraph::from_lines(&mut options, input.lines(), &mut svg) {
+        Err(quick_xml::Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+        other => panic!("expected an invalid input error, got {:?}", other),
+    }
+}
+
+#[test]
+fn flamegraph_collapse_recursion() {
+    let input = "main;parse;parse;parse;lex 2\nmain;eval;apply;eval;print 3\nmain;parse;lex 1\n";
+    for &(recursion, folded) in &[
+        (
+            Recursion::Direct,
+            "main;parse;lex 3\nmain;eval;apply;eval;print 3\n",
+        ),
+        (Recursion::Indirect, "main;parse;lex 3\nmain;eval;print 3\n"),
+    ] {
+        let mut options = Options {
+            hash: true,
+            collapse_recursion: Some(recursion),
+            ..Default::default()
+        };
+        let mut actual = Vec::new();
+        flamegraph::from_lines(&mut options, input.lines(), &mut actual).unwrap();
+
+        let mut options = Options {
+            hash: true,
+            ..Default::default()
+        };
+        let mut expected = Vec::new();
+        flamegraph::from_lines(&mut options, folded.lines(), &mut expected).unwrap();
+        assert_eq!(
+            String::from_utf8(actual).unwrap(),
+            String::from_utf8(expected).unwrap()
+        );
+    }
+}
+
+#[test]
+fn flamegraph_collapse_recursion_cli() {
+    let mut child = Command::cargo_bin("inferno-flamegraph")
+        .unwrap()
+        .arg("--flame-chart")
+        .arg("--collapse-recursion")
+        .arg("--format")
+        .arg("html")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to spawn child process");
+    let input = "10.000 main;parse;parse;lex 1\n10.010 main;parse;lex 1\n";
+    let stdin = child.stdin.as_mut().expect("Failed to open stdin");
+    io::copy(&mut input.as_bytes(), stdin).unwrap();
+    let output = child.wait_with_output().expect("failed to read stdout");
+    assert!(output.status.success());
+
+    // Once the recursion is folded, the two samples are merged into all;main;parse;lex.
+    let data = html_data(&output.stdout);
+    assert_eq!(data["frames"].as_array().unwrap().len(), 4);
+}
+
 #[test]
 fn flamegraph_sorted_input_file() {
     let input_file = "./flamegraph/test/results/perf-vertx-stacks-01-collapsed-all.txt";
@@ -879,3 +1082,269 @@ fn flamegraph_cli() {
     let expected = BufReader::new(File::open(expected_file).unwrap());
     compare_results(Cursor::new(output.stdout), expected, expected_file);
 }
+
+fn pprof_roundtrip(input_file: &str, sample_type: Option<usize>, column: usize) {
+    let input = fs::read_to_string(input_file).unwrap();
+    let mut options = Options {
+        format: Format::Pprof,
+        ..Default::default()
+    };
+    let mut profile = Vec::new();
+    flamegraph::from_reader(&mut options, input.as_bytes(), &mut profile).unwrap();
+    assert_eq!(
+        &profile[..2],
+        &[0x1f, 0x8b],
+        "pprof output should be gzipped"
+    );
+
+    let mut folded = Vec::new();
+    pprof::Folder::from(pprof::Options {
+        sample_type,
+        ..Default::default()
+    })
+    .collapse(&profile[..], &mut folded)
+    .unwrap();
+
+    // The profile should contain exactly the stacks we put in.
+    let mut expected: Vec<String> = input
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.trim().rsplitn(column + 1, ' ');
+            let mut count = fields.next()?;
+            if column == 2 {
+                let before = fields.next()?;
+                if sample_type == Some(0) {
+                    count = before;
+                }
+            }
+            let stack = fields.next()?;
+            if count == "0" {
+                None
+            } else {
+                Some(format!("{} {}", stack, count))
+            }
+        })
+        .collect();
+    expected.sort();
+    let folded: Vec<&str> = std::str::from_utf8(&folded).unwrap().lines().collect();
+    assert_eq!(folded, expected);
+}
+
+#[test]
+fn flamegraph_pprof() {
+    pprof_roundtrip(
+        "./tests/data/flamegraph/multiple-inputs/perf-vertx-stacks-01-collapsed-all-unsorted-1.txt",
+        None,
+        1,
+    );
+}
+
+#[test]
+fn flamegraph_pprof_differential() {
+    let input_file =
+        "./tests/data/flamegraph/differential/perf-cycles-instructions-01-collapsed-all-diff.txt";
+    pprof_roundtrip(input_file, None, 2);
+    pprof_roundtrip(input_file, Some(0), 2);
+}
+
+#[test]
+fn flamegraph_pprof_cli() {
+    let input_file =
+        "./tests/data/flamegraph/multiple-inputs/perf-vertx-stacks-01-collapsed-all-unsorted-1.txt";
+    let output = Command::cargo_bin("inferno-flamegraph")
+        .unwrap()
+        .arg("--format")
+        .arg("pprof")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+
+    let mut options = Options {
+        format: Format::Pprof,
+        ..Default::default()
+    };
+    let mut expected = Vec::new();
+    flamegraph::from_files(
+        &mut options,
+        &[PathBuf::from_str(input_file).unwrap()],
+        &mut expected,
+    )
+    .unwrap();
+
+    // The gzip header contains a timestamp, so compare the decoded profiles instead.
+    let mut folded = Vec::new();
+    pprof::Folder::default()
+        .collapse(&output.stdout[..], &mut folded)
+        .unwrap();
+    let mut expected_folded = Vec::new();
+    pprof::Folder::default()
+        .collapse(&expected[..], &mut expected_folded)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(folded).unwrap(),
+        String::from_utf8(expected_folded).unwrap()
+    );
+}
+
+#[test]
+fn flamegraph_speedscope_cli() {
+    let input_file =
+        "./tests/data/flamegraph/multiple-inputs/perf-vertx-stacks-01-collapsed-all-unsorted-1.txt";
+    let output = Command::cargo_bin("inferno-flamegraph")
+        .unwrap()
+        .arg("--format")
+        .arg("speedscope")
+        .arg("--title")
+        .arg("vertx")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let file: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(file["name"], "vertx");
+
+    // Every stack should be in there, with each frame name only once in the frame table.
+    let frames: Vec<&str> = file["shared"]["frames"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|frame| frame["name"].as_str().unwrap())
+        .collect();
+    let mut unique = frames.clone();
+    unique.sort();
+    unique.dedup();
+    assert_eq!(unique.len(), frames.len());
+
+    let profile = &file["profiles"][0];
+    let mut stacks: Vec<String> = profile["samples"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .zip(profile["weights"].as_array().unwrap())
+        .map(|(sample, weight)| {
+            let stack: Vec<&str> = sample
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|i| frames[i.as_u64().unwrap() as usize])
+                .collect();
+            format!("{} {}", stack.join(";"), weight.as_f64().unwrap())
+        })
+        .collect();
+    stacks.sort();
+    let mut expected: Vec<String> = fs::read_to_string(input_file)
+        .unwrap()
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect();
+    expected.sort();
+    assert_eq!(stacks, expected);
+}
+
+fn html_data(html: &[u8]) -> serde_json::Value {
+    let html = std::str::from_utf8(html).unwrap();
+    let marker = r#"<script type="application/json" id="inferno-data">"#;
+    let start = html.find(marker).unwrap() + marker.len();
+    let end = html[start..].find("</script>").unwrap() + start;
+    serde_json::from_str(&html[start..end]).unwrap()
+}
+
+#[test]
+fn flamegraph_html_cli() {
+    let input_file =
+        "./tests/data/flamegraph/multiple-inputs/perf-vertx-stacks-01-collapsed-all-unsorted-1.txt";
+    let output = Command::cargo_bin("inferno-flamegraph")
+        .unwrap()
+        .arg("--format")
+        .arg("html")
+        .arg("--title")
+        .arg("vertx")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    assert!(output.stdout.starts_with(b"<!DOCTYPE html>"));
+
+    let data = html_data(&output.stdout);
+    assert_eq!(data["title"], "vertx");
+    let total: u64 = fs::read_to_string(input_file)
+        .unwrap()
+        .lines()
+        .filter_map(|line| line.rsplit(' ').next())
+        .filter_map(|samples| samples.parse::<u64>().ok())
+        .sum();
+    assert_eq!(data["total"], total);
+    let root = &data["frames"][0];
+    assert_eq!(data["names"][root[0].as_u64().unwrap() as usize], "all");
+    assert_eq!(root[3], total);
+}
+
+#[test]
+fn flamegraph_html_colors_match_svg() {
+    let input_file =
+        "./tests/data/flamegraph/multiple-inputs/perf-vertx-stacks-01-collapsed-all-unsorted-1.txt";
+    let mut svg = Vec::new();
+    let mut options = Options {
+        hash: true,
+        min_width: 0.0,
+        ..Default::default()
+    };
+    flamegraph::from_files(&mut options, &[PathBuf::from(input_file)], &mut svg).unwrap();
+    let svg = String::from_utf8(svg).unwrap();
+
+    let mut html = Vec::new();
+    let mut options = Options {
+        hash: true,
+        format: Format::Html,
+        ..Default::default()
+    };
+    flamegraph::from_files(&mut options, &[PathBuf::from(input_file)], &mut html).unwrap();
+    let data = html_data(&html);
+
+    for color in data["colors"].as_array().unwrap() {
+        let fill = format!(r#"fill="{}""#, color.as_str().unwrap());
+        assert!(svg.contains(&fill), "{} is not used in the SVG", color);
+    }
+}
+
+#[test]
+fn flamegraph_tree_queries() {
+    let input = fs::read_to_string(
+        "./tests/data/flamegraph/multiple-inputs/perf-vertx-stacks-01-collapsed-all-unsorted-1.txt",
+    )
+    .unwrap();
+    let tree: FlameTree = input.parse().unwrap();
+    assert_eq!(tree.total(), 134);
+    assert_eq!(
+        tree.root().children().map(|node| node.total()).sum::<u64>(),
+        134
+    );
+
+    let top = tree.top_functions(3);
+    assert_eq!(top.len(), 3);
+    assert!(top[0].self_count >= top[1].self_count && top[1].self_count >= top[2].self_count);
+    let self_total: u64 = tree
+        .top_functions(usize::MAX)
+        .iter()
+        .map(|f| f.self_count)
+        .sum();
+    assert_eq!(self_total, 134);
+
+    let paths = tree.paths_to("java_start");
+    assert!(!paths.is_empty());
+    assert!(paths.iter().all(|path| path.last() == Some(&"java_start")));
+    let zoomed = tree.zoom("java_start");
+    let java_start: u64 = tree
+        .find("java_start")
+        .iter()
+        .map(|node| node.total())
+        .sum();
+    assert_eq!(zoomed.total(), java_start);
+    assert_eq!(zoomed.root().children().count(), 1);
+
+    // Serializing the tree and parsing it again gives the same tree.
+    let folded = tree.to_string();
+    assert_eq!(folded.parse::<FlameTree>().unwrap().to_string(), folded);
+}
@@ -0,0 +1,164 @@
+use std::fs;
+use std::io::BufReader;
+
+use inferno::collapse::{
+    chrome, dtrace, firefox, guess, perf, pprof, sample, speedscope, xdebug, Collapse,
+};
+use inferno::flamegraph::{self, Format, Options};
+use inferno::folded::{FoldedStacks, ParseErrorKind};
+use pretty_assertions::assert_eq;
+
+fn collapse_both_ways<C: Collapse>(folder: &mut C, input: &str) {
+    let bytes = fs::read(input).unwrap();
+    let mut expected = Vec::new();
+    folder.collapse(&bytes[..], &mut expected).unwrap();
+    let stacks = folder.collapse_to_stacks(&bytes[..]).unwrap();
+    assert!(!stacks.is_empty());
+    assert_eq!(stacks.to_string(), String::from_utf8(expected).unwrap());
+}
+
+#[test]
+fn folded_collapse_to_stacks_matches_collapse() {
+    collapse_both_ways(
+        &mut perf::Folder::default(),
+        "./tests/data/collapse-perf/java-inline.txt",
+    );
+    collapse_both_ways(
+        &mut perf::Folder::from(perf::Options {
+            nthreads: 4,
+            ..Default::default()
+        }),
+        "./tests/data/collapse-perf/go-stacks.txt",
+    );
+    collapse_both_ways(
+        &mut dtrace::Folder::default(),
+        "./tests/data/collapse-dtrace/java.txt",
+    );
+    collapse_both_ways(
+        &mut chrome::Folder::default(),
+        "./tests/data/collapse-chrome/profile.cpuprofile",
+    );
+    collapse_both_ways(
+        &mut firefox::Folder::default(),
+        "./tests/data/collapse-firefox/profile.json",
+    );
+    collapse_both_ways(
+        &mut pprof::Folder::default(),
+        "./tests/data/collapse-pprof/cpu.pprof",
+    );
+    collapse_both_ways(
+        &mut sample::Folder::default(),
+        "./tests/data/collapse-sample/sample.txt",
+    );
+    collapse_both_ways(
+        &mut speedscope::Folder::default(),
+        "./tests/data/collapse-speedscope/evented.speedscope.json",
+    );
+    collapse_both_ways(
+        &mut xdebug::Folder::default(),
+        "./tests/data/collapse-xdebug/trace.xt",
+    );
+    collapse_both_ways(
+        &mut guess::Folder::default(),
+        "./tests/data/collapse-sample/sample.txt",
+    );
+    collapse_both_ways(
+        &mut guess::Folder::default(),
+        "./tests/data/collapse-pprof/cpu.pprof",
+    );
+}
+
+#[test]
+fn folded_round_trips() {
+    for input in &[
+        "./tests/data/flamegraph/multiple-inputs/perf-vertx-stacks-01-collapsed-all-unsorted-1.txt",
+        "./tests/data/flamegraph/differential/perf-cycles-instructions-01-collapsed-all-diff.txt",
+    ] {
+        let input = fs::read_to_string(input).unwrap();
+        let stacks: FoldedStacks = input.parse().unwrap();
+        assert_eq!(stacks.len(), input.lines().count());
+        let mut output = Vec::new();
+        stacks.write_to(&mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), input);
+    }
+}
+
+#[test]
+fn folded_flamegraph_matches_lines() {
+    for input in &[
+        "./tests/data/flamegraph/multiple-inputs/perf-vertx-stacks-01-collapsed-all-unsorted-1.txt",
+        "./tests/data/flamegraph/differential/perf-cycles-instructions-01-collapsed-all-diff.txt",
+    ] {
+        let input = fs::read_to_string(input).unwrap();
+        let stacks = FoldedStacks::parse(&input).unwrap();
+        let variants: Vec<fn(&mut Options<'static>)> = vec![
+            |_| {},
+            |opt| opt.reverse_stack_order = true,
+            |opt| opt.flame_chart = true,
+            |opt| opt.butterfly = Some("main|start_thread".to_string()),
+            |opt| opt.format = Format::Html,
+            |opt| opt.format = Format::Speedscope,
+        ];
+        for variant in variants {
+            let mut expected = Vec::new();
+            let mut opt = options();
+            variant(&mut opt);
+            flamegraph::from_lines(&mut opt, input.lines(), &mut expected).unwrap();
+
+            let mut actual = Vec::new();
+            let mut opt = options();
+            variant(&mut opt);
+            flamegraph::from_folded_stacks(&mut opt, &stacks, &mut actual).unwrap();
+            assert_eq!(
+                String::from_utf8(actual).unwrap(),
+                String::from_utf8(expected).unwrap()
+            );
+        }
+    }
+}
+
+#[test]
+fn folded_flamegraph_keeps_numeric_frames() {
+    // As a line, this stack would have two sample counts.
+    let mut stacks = FoldedStacks::new();
+    stacks.push(vec!["main", "sleep 12"], 3);
+    let mut svg = Vec::new();
+    flamegraph::from_folded_stacks(&mut options(), &stacks, &mut svg).unwrap();
+    let svg = String::from_utf8(svg).unwrap();
+    assert!(
+        svg.contains("<title>sleep 12 (3 samples, 100.00%)"),
+        "{}",
+        svg
+    );
+}
+
+#[test]
+fn folded_flamegraph_from_collapsed_stacks() {
+    let input = BufReader::new(fs::File::open("./tests/data/collapse-perf/go-stacks.txt").unwrap());
+    let mut stacks = perf::Folder::default().collapse_to_stacks(input).unwrap();
+    let total = stacks.total();
+    stacks.retain(|stack| stack.frames().any(|frame| frame == "main.init"));
+    assert!(stacks.total() < total);
+
+    let mut svg = Vec::new();
+    flamegraph::from_folded_stacks(&mut options(), &stacks, &mut svg).unwrap();
+    let svg = String::from_utf8(svg).unwrap();
+    assert!(svg.contains(&format!("<title>all ({} samples, 100%)", stacks.total())));
+}
+
+#[test]
+fn folded_parse_errors() {
+    let input = fs::read_to_string("./tests/data/flamegraph/bad-lines/bad-lines.txt").unwrap();
+    let error = FoldedStacks::parse(&input).unwrap_err();
+    let line = input.lines().nth(error.line() - 1).unwrap();
+    assert!(!line.trim().is_empty(), "{} points at an empty line", error);
+    assert!(error.column() <= line.chars().count() + 1);
+    assert_ne!(error.kind(), ParseErrorKind::EmptyStack);
+}
+
+fn options() -> Options<'static> {
+    Options {
+        hash: true,
+        ..Default::default()
+    }
+}
@@ -0,0 +1,100 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::process::Command;
+
+use assert_cmd::cargo::CommandCargoExt;
+use inferno::report::{self, Options, SortBy};
+use pretty_assertions::assert_eq;
+
+fn test_report(input_file: &str, expected_result_file: &str, options: Options) -> io::Result<()> {
+    let input = vec![PathBuf::from(input_file)];
+    if fs::metadata(expected_result_file).is_err() {
+        // be nice to the dev and make the file
+        let f = File::create(expected_result_file).unwrap();
+        report::from_files(&options, &input, f)?;
+    }
+
+    let mut result = Vec::new();
+    report::from_files(&options, &input, &mut result)?;
+    let mut expected = String::new();
+    File::open(expected_result_file)?.read_to_string(&mut expected)?;
+    assert_eq!(
+        String::from_utf8(result).unwrap(),
+        expected,
+        "{}",
+        expected_result_file
+    );
+    Ok(())
+}
+
+#[test]
+fn report_default() {
+    test_report(
+        "./tests/data/flamegraph/multiple-inputs/perf-vertx-stacks-01-collapsed-all-unsorted-1.txt",
+        "./tests/data/report/results/vertx-default.txt",
+        Default::default(),
+    )
+    .unwrap();
+}
+
+#[test]
+fn report_sort_by_total() {
+    test_report(
+        "./tests/data/flamegraph/multiple-inputs/perf-vertx-stacks-01-collapsed-all-unsorted-1.txt",
+        "./tests/data/report/results/vertx-total.txt",
+        Options {
+            top: 10,
+            sort_by: SortBy::Total,
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn report_differential() {
+    test_report(
+        "./tests/data/flamegraph/differential/perf-cycles-instructions-01-collapsed-all-diff.txt",
+        "./tests/data/report/results/differential.txt",
+        Options {
+            top: 0,
+            sort_by: SortBy::Delta,
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn report_invalid_input() {
+    let input = "main;foo 1\nmain;bar\n";
+    let error =
+        report::from_readers(&Options::default(), vec![input.as_bytes()], io::sink()).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert!(
+        error.to_string().starts_with("line 2, column 9:"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn report_cli() {
+    let input_file =
+        "./tests/data/flamegraph/multiple-inputs/perf-vertx-stacks-01-collapsed-all-unsorted-1.txt";
+    let expected_file = "./tests/data/report/results/vertx-total.txt";
+
+    let output = Command::cargo_bin("inferno-report")
+        .unwrap()
+        .arg("--sort")
+        .arg("total")
+        .arg("-n")
+        .arg("10")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        fs::read_to_string(expected_file).unwrap()
+    );
+}
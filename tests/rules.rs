@@ -0,0 +1,101 @@
+use std::fs;
+use std::process::Command;
+
+use assert_cmd::cargo::CommandCargoExt;
+use inferno::collapse::perf::{Folder, Options};
+use inferno::collapse::Collapse;
+use inferno::flamegraph;
+use inferno::rules::Rules;
+use pretty_assertions::assert_eq;
+
+const RULES_FILE: &str = "./tests/data/rules/java.rules";
+
+#[test]
+fn rules_collapse_perf() {
+    let test_file = "./tests/data/collapse-perf/java-inline.txt";
+    let result_file = "./tests/data/rules/results/java-inline-collapsed.txt";
+    let input = fs::read(test_file).unwrap();
+    let expected = fs::read_to_string(result_file).unwrap();
+    for &n in &[1, 2] {
+        let options = Options {
+            nthreads: n,
+            rules: Rules::from_file(RULES_FILE).unwrap(),
+            ..Default::default()
+        };
+        let mut result = Vec::new();
+        Folder::from(options)
+            .collapse(&input[..], &mut result)
+            .unwrap();
+        assert_eq!(String::from_utf8(result).unwrap(), expected);
+    }
+}
+
+#[test]
+fn rules_flamegraph_same_as_collapsing_with_rules() {
+    let collapsed =
+        fs::read_to_string("./tests/data/collapse-perf/results/java-inline-collapsed.txt").unwrap();
+    let collapsed_with_rules =
+        fs::read_to_string("./tests/data/rules/results/java-inline-collapsed.txt").unwrap();
+
+    // Hash the colors, which are random otherwise.
+    let mut options = flamegraph::Options {
+        hash: true,
+        ..Default::default()
+    };
+    let mut expected = Vec::new();
+    flamegraph::from_lines(&mut options, collapsed_with_rules.lines(), &mut expected).unwrap();
+
+    options.rules = Rules::from_file(RULES_FILE).unwrap();
+    let mut result = Vec::new();
+    flamegraph::from_lines(&mut options, collapsed.lines(), &mut result).unwrap();
+    assert_eq!(
+        String::from_utf8(result).unwrap(),
+        String::from_utf8(expected).unwrap()
+    );
+}
+
+#[test]
+fn rules_cli() {
+    let input_file = "./tests/data/collapse-perf/java-inline.txt";
+    let expected_file = "./tests/data/rules/results/java-inline-collapsed.txt";
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--rules")
+        .arg(RULES_FILE)
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        fs::read_to_string(expected_file).unwrap()
+    );
+
+    let output = Command::cargo_bin("inferno-flamegraph")
+        .unwrap()
+        .arg("--rules")
+        .arg(RULES_FILE)
+        .arg("./tests/data/collapse-perf/results/java-inline-collapsed.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let svg = String::from_utf8(output.stdout).unwrap();
+    assert!(svg.contains("io.PrintStream:::println"));
+    assert!(!svg.contains("java/io/PrintStream:::println"));
+}
+
+#[test]
+fn rules_cli_invalid_rules() {
+    let rules_file = "./tests/data/rules/invalid.rules";
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--rules")
+        .arg(rules_file)
+        .arg("./tests/data/collapse-perf/java-inline.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("invalid.rules"), "{}", stderr);
+    assert!(stderr.contains("line 3"), "{}", stderr);
+}